@@ -0,0 +1,343 @@
+//! Multiple APIC Description Table.
+//!
+//! Lists the local APICs and I/O APICs of the system along with the mapping
+//! of ISA IRQs to global system interrupts (GSIs).
+//!
+//! See the ACPI Specification 6.4, Section 5.2.12.
+
+use bitflags::bitflags;
+
+use super::{read_u16, read_u32, read_u64, AcpiError, SdtHeader};
+use crate::apic::ioapic::IoApic;
+use crate::apic::{ApicId, PinPolarity, TriggerMode};
+
+bitflags! {
+    /// Flags of local APIC and local x2APIC entries.
+    pub struct LocalApicFlags: u32 {
+        /// The processor is ready for use.
+        const ENABLED = 1 << 0;
+        /// A disabled processor can be enabled at runtime.
+        const ONLINE_CAPABLE = 1 << 1;
+    }
+}
+
+/// MPS INTI flags describing polarity and trigger mode of an interrupt.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MpsIntiFlags(pub u16);
+
+impl MpsIntiFlags {
+    /// The polarity, or `None` if it conforms to the bus specification.
+    pub fn polarity(&self) -> Option<PinPolarity> {
+        match self.0 & 0b11 {
+            0b01 => Some(PinPolarity::ActiveHigh),
+            0b11 => Some(PinPolarity::ActiveLow),
+            _ => None,
+        }
+    }
+
+    /// The trigger mode, or `None` if it conforms to the bus specification.
+    pub fn trigger_mode(&self) -> Option<TriggerMode> {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => Some(TriggerMode::Edge),
+            0b11 => Some(TriggerMode::Level),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the MADT's interrupt controller structure list.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MadtEntry<'a> {
+    /// Processor with a local APIC (type 0).
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: LocalApicFlags,
+    },
+    /// I/O APIC (type 1).
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// ISA IRQ `source` is connected to `gsi` instead of the identical GSI
+    /// (type 2).
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: MpsIntiFlags,
+    },
+    /// GSI to be configured as NMI (type 3).
+    NmiSource { flags: MpsIntiFlags, gsi: u32 },
+    /// Local APIC LINT pin connected to NMI; `processor_id` 0xff applies to
+    /// all processors (type 4).
+    LocalApicNmi {
+        processor_id: u8,
+        flags: MpsIntiFlags,
+        lint: u8,
+    },
+    /// 64-bit physical address of the local APICs (type 5).
+    LocalApicAddressOverride { address: u64 },
+    /// Processor with an x2APIC ID (type 9).
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: LocalApicFlags,
+        processor_uid: u32,
+    },
+    /// Local x2APIC LINT pin connected to NMI; `processor_uid` 0xffffffff
+    /// applies to all processors (type 0xa).
+    LocalX2ApicNmi {
+        flags: MpsIntiFlags,
+        processor_uid: u32,
+        lint: u8,
+    },
+    /// Any other or malformed entry, `data` excludes type and length.
+    Unknown { entry_type: u8, data: &'a [u8] },
+}
+
+impl<'a> MadtEntry<'a> {
+    /// Decode an entry from its type and the bytes following the length.
+    fn parse(entry_type: u8, data: &'a [u8]) -> MadtEntry<'a> {
+        let unknown = MadtEntry::Unknown { entry_type, data };
+        let inti = |offset| MpsIntiFlags(read_u16(data, offset));
+        match (entry_type, data.len()) {
+            (0, 6) => MadtEntry::LocalApic {
+                processor_id: data[0],
+                apic_id: data[1],
+                flags: LocalApicFlags::from_bits_truncate(read_u32(data, 2)),
+            },
+            (1, 10) => MadtEntry::IoApic {
+                id: data[0],
+                address: read_u32(data, 2),
+                gsi_base: read_u32(data, 6),
+            },
+            (2, 8) => MadtEntry::InterruptSourceOverride {
+                bus: data[0],
+                source: data[1],
+                gsi: read_u32(data, 2),
+                flags: inti(6),
+            },
+            (3, 6) => MadtEntry::NmiSource {
+                flags: inti(0),
+                gsi: read_u32(data, 2),
+            },
+            (4, 4) => MadtEntry::LocalApicNmi {
+                processor_id: data[0],
+                flags: inti(1),
+                lint: data[3],
+            },
+            (5, 10) => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(data, 2),
+            },
+            (9, 14) => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(data, 2),
+                flags: LocalApicFlags::from_bits_truncate(read_u32(data, 6)),
+                processor_uid: read_u32(data, 10),
+            },
+            (0xa, 10) => MadtEntry::LocalX2ApicNmi {
+                flags: inti(0),
+                processor_uid: read_u32(data, 2),
+                lint: data[6],
+            },
+            _ => unknown,
+        }
+    }
+}
+
+/// Iterator over the entries of a MADT.
+///
+/// Ends at the first entry that doesn't fit in the table.
+#[derive(Debug, Clone)]
+pub struct MadtEntries<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Iterator for MadtEntries<'a> {
+    type Item = MadtEntry<'a>;
+
+    fn next(&mut self) -> Option<MadtEntry<'a>> {
+        let entry_type = *self.remaining.first()?;
+        let length = *self.remaining.get(1)? as usize;
+        if length < 2 || length > self.remaining.len() {
+            self.remaining = &[];
+            return None;
+        }
+        let (entry, rest) = self.remaining.split_at(length);
+        self.remaining = rest;
+        Some(MadtEntry::parse(entry_type, &entry[2..]))
+    }
+}
+
+/// An I/O APIC described by the MADT.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoApicInfo {
+    /// I/O APIC ID.
+    pub id: u8,
+    /// Physical address of the registers.
+    pub address: u32,
+    /// First GSI handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+impl IoApicInfo {
+    /// Create an [`IoApic`] for this I/O APIC, with its registers mapped at
+    /// `offset + address`.
+    ///
+    /// # Safety
+    /// The registers must be mapped uncached at `offset + address`.
+    pub unsafe fn io_apic(&self, offset: usize) -> IoApic {
+        IoApic::new(offset + self.address as usize)
+    }
+}
+
+/// Routing of an ISA IRQ.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IsaIrq {
+    /// GSI the IRQ is connected to.
+    pub gsi: u32,
+    /// Polarity of the interrupt input.
+    pub polarity: PinPolarity,
+    /// Trigger mode of the interrupt input.
+    pub trigger_mode: TriggerMode,
+}
+
+/// A parsed Multiple APIC Description Table.
+#[derive(Debug, Copy, Clone)]
+pub struct Madt<'a> {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+    entries: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    /// Signature of the MADT.
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+
+    /// Flags: the system also has a dual 8259 PIC setup.
+    const PCAT_COMPAT: u32 = 1 << 0;
+
+    /// Verify and parse the MADT in `table`.
+    pub fn parse(table: &'a [u8]) -> Result<Madt<'a>, AcpiError> {
+        let (header, table) = SdtHeader::verify(table, Madt::SIGNATURE)?;
+        if table.len() < SdtHeader::SIZE + 8 {
+            return Err(AcpiError::InvalidLength);
+        }
+        Ok(Madt {
+            header,
+            local_apic_address: read_u32(table, SdtHeader::SIZE),
+            flags: read_u32(table, SdtHeader::SIZE + 4),
+            entries: &table[SdtHeader::SIZE + 8..],
+        })
+    }
+
+    /// The table header.
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    /// Physical address of the local APICs, taking a local APIC address
+    /// override entry into account.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+
+    /// True if the system has 8259 PICs which need to be disabled when
+    /// using the APICs.
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & Madt::PCAT_COMPAT != 0
+    }
+
+    /// All entries of the table.
+    pub fn entries(&self) -> MadtEntries<'a> {
+        MadtEntries {
+            remaining: self.entries,
+        }
+    }
+
+    /// APIC IDs of all enabled processors, as used in IPI destinations.
+    ///
+    /// Processors that are only online capable are skipped.
+    pub fn apic_ids(&self) -> impl Iterator<Item = ApicId> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. }
+                if flags.contains(LocalApicFlags::ENABLED) =>
+            {
+                Some(ApicId::XApic(apic_id))
+            }
+            MadtEntry::LocalX2Apic {
+                x2apic_id, flags, ..
+            } if flags.contains(LocalApicFlags::ENABLED) => Some(ApicId::X2Apic(x2apic_id)),
+            _ => None,
+        })
+    }
+
+    /// All I/O APICs.
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApicInfo> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } => Some(IoApicInfo {
+                id,
+                address,
+                gsi_base,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Routing of ISA IRQ `irq`.
+    ///
+    /// Without an interrupt source override the IRQ is connected to the
+    /// identical GSI, edge-triggered and active high.
+    pub fn isa_irq(&self, irq: u8) -> IsaIrq {
+        let mut routing = IsaIrq {
+            gsi: irq as u32,
+            polarity: PinPolarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        };
+        for entry in self.entries() {
+            if let MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source,
+                gsi,
+                flags,
+            } = entry
+            {
+                if source == irq {
+                    routing.gsi = gsi;
+                    routing.polarity = flags.polarity().unwrap_or(routing.polarity);
+                    routing.trigger_mode = flags.trigger_mode().unwrap_or(routing.trigger_mode);
+                }
+            }
+        }
+        routing
+    }
+
+    /// LINT pins (with their flags) connected to NMI on the processor with
+    /// ACPI processor UID `processor_uid`.
+    pub fn local_apic_nmis(
+        &self,
+        processor_uid: u32,
+    ) -> impl Iterator<Item = (u8, MpsIntiFlags)> + 'a {
+        self.entries().filter_map(move |entry| match entry {
+            MadtEntry::LocalApicNmi {
+                processor_id,
+                flags,
+                lint,
+            } if processor_id == 0xff || processor_id as u32 == processor_uid => {
+                Some((lint, flags))
+            }
+            MadtEntry::LocalX2ApicNmi {
+                flags,
+                processor_uid: uid,
+                lint,
+            } if uid == u32::MAX || uid == processor_uid => Some((lint, flags)),
+            _ => None,
+        })
+    }
+}
@@ -0,0 +1,693 @@
+//! Minimal reader for the ACPI tables needed to set up interrupts.
+//!
+//! The RSDP is located by scanning a memory region supplied by the caller
+//! ([`Rsdp::search`]), the RSDT or XSDT it points to is walked with
+//! [`RootTable`], and the MADT is decoded by [`madt::Madt`]. Physical memory
+//! is accessed through [`PhysicalMemory`] so the tables can be read from any
+//! mapping (or from captured blobs). All checksums are verified.
+//!
+//! See the ACPI Specification 6.4, Sections 5.2.5 to 5.2.12.
+
+use core::fmt;
+use core::slice;
+
+pub mod madt;
+
+/// Physical address of the pointer (a real mode segment) to the EBDA.
+pub const EBDA_POINTER: u64 = 0x40e;
+/// Start of the BIOS read-only memory area searched for the RSDP.
+pub const BIOS_AREA_START: u64 = 0xe0000;
+/// End (exclusive) of the BIOS read-only memory area searched for the RSDP.
+pub const BIOS_AREA_END: u64 = 0x100000;
+
+/// Errors when reading ACPI tables.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AcpiError {
+    /// The structure does not carry the expected signature.
+    InvalidSignature,
+    /// The bytes of the structure don't sum up to zero.
+    InvalidChecksum,
+    /// The structure is shorter than its header or its length field claims.
+    InvalidLength,
+    /// [`PhysicalMemory`] could not map the given physical address.
+    Unmapped(u64),
+    /// No table with the requested signature is present.
+    NotFound,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::InvalidSignature => write!(f, "invalid signature"),
+            AcpiError::InvalidChecksum => write!(f, "invalid checksum"),
+            AcpiError::InvalidLength => write!(f, "invalid length"),
+            AcpiError::Unmapped(address) => write!(f, "{:#x} is not mapped", address),
+            AcpiError::NotFound => write!(f, "table not found"),
+        }
+    }
+}
+
+/// Access to the physical memory holding the ACPI tables.
+pub trait PhysicalMemory {
+    /// Returns the `length` bytes starting at physical `address`, or `None`
+    /// if they are not accessible.
+    fn map(&self, address: u64, length: usize) -> Option<&[u8]>;
+}
+
+/// Physical memory mapped at a fixed offset in the virtual address space
+/// (an offset of zero for identity mapping).
+#[derive(Debug)]
+pub struct OffsetMapping {
+    offset: usize,
+}
+
+impl OffsetMapping {
+    /// # Safety
+    /// All physical memory the tables reference must be readable at
+    /// `offset + address` for as long as this exists.
+    pub const unsafe fn new(offset: usize) -> OffsetMapping {
+        OffsetMapping { offset }
+    }
+}
+
+impl PhysicalMemory for OffsetMapping {
+    fn map(&self, address: u64, length: usize) -> Option<&[u8]> {
+        let start = (address as usize).checked_add(self.offset)?;
+        start.checked_add(length)?;
+        Some(unsafe { slice::from_raw_parts(start as *const u8, length) })
+    }
+}
+
+/// True if all bytes of `bytes` sum up to zero (modulo 256).
+pub fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut raw = [0; 4];
+    raw.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(raw)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut raw = [0; 8];
+    raw.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(raw)
+}
+
+/// Root System Description Pointer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Rsdp {
+    /// OEM identification.
+    pub oem_id: [u8; 6],
+    /// 0 for ACPI 1.0, 2 for ACPI 2.0 and later.
+    pub revision: u8,
+    /// Physical address of the RSDT.
+    pub rsdt_address: u32,
+    /// Physical address of the XSDT (revision 2 and later).
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    /// Signature at the start of the RSDP.
+    pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    /// Size of the ACPI 1.0 structure covered by the first checksum.
+    pub const V1_SIZE: usize = 20;
+    /// Size of the ACPI 2.0 structure.
+    pub const V2_SIZE: usize = 36;
+
+    /// Parse and verify the RSDP at the start of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Rsdp, AcpiError> {
+        if bytes.len() < Rsdp::V1_SIZE {
+            return Err(AcpiError::InvalidLength);
+        }
+        if &bytes[..8] != Rsdp::SIGNATURE {
+            return Err(AcpiError::InvalidSignature);
+        }
+        if !checksum_valid(&bytes[..Rsdp::V1_SIZE]) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        let revision = bytes[15];
+        let xsdt_address = if revision >= 2 {
+            if bytes.len() < Rsdp::V2_SIZE {
+                return Err(AcpiError::InvalidLength);
+            }
+            let length = read_u32(bytes, 20) as usize;
+            if length < Rsdp::V2_SIZE || length > bytes.len() {
+                return Err(AcpiError::InvalidLength);
+            }
+            if !checksum_valid(&bytes[..length]) {
+                return Err(AcpiError::InvalidChecksum);
+            }
+            Some(read_u64(bytes, 24)).filter(|address| *address != 0)
+        } else {
+            None
+        };
+
+        Ok(Rsdp {
+            oem_id,
+            revision,
+            rsdt_address: read_u32(bytes, 16),
+            xsdt_address,
+        })
+    }
+
+    /// Search `region` for a valid RSDP on a 16-byte boundary.
+    ///
+    /// Returns the offset of the RSDP within `region` along with it. On BIOS
+    /// systems `region` is the first KiB of the EBDA or the memory between
+    /// [`BIOS_AREA_START`] and [`BIOS_AREA_END`]; UEFI systems pass the RSDP
+    /// address in the configuration table instead.
+    pub fn search(region: &[u8]) -> Option<(usize, Rsdp)> {
+        (0..region.len()).step_by(16).find_map(|offset| {
+            Rsdp::parse(&region[offset..])
+                .ok()
+                .map(|rsdp| (offset, rsdp))
+        })
+    }
+}
+
+/// Header common to all system description tables.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SdtHeader {
+    /// Table identifier, e.g. `APIC` for the MADT.
+    pub signature: [u8; 4],
+    /// Length of the table including the header.
+    pub length: u32,
+    /// Revision of the table structure.
+    pub revision: u8,
+    /// OEM identification.
+    pub oem_id: [u8; 6],
+    /// OEM identification of this table.
+    pub oem_table_id: [u8; 8],
+    /// OEM revision of this table.
+    pub oem_revision: u32,
+    /// Vendor ID of the tool that created the table.
+    pub creator_id: [u8; 4],
+    /// Revision of the tool that created the table.
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Size of the header.
+    pub const SIZE: usize = 36;
+
+    /// Parse the header at the start of `bytes`.
+    ///
+    /// Only the length of the header is checked, use [`SdtHeader::verify`]
+    /// to validate a complete table.
+    pub fn parse(bytes: &[u8]) -> Result<SdtHeader, AcpiError> {
+        if bytes.len() < SdtHeader::SIZE {
+            return Err(AcpiError::InvalidLength);
+        }
+        let mut header = SdtHeader {
+            signature: [0; 4],
+            length: read_u32(bytes, 4),
+            revision: bytes[8],
+            oem_id: [0; 6],
+            oem_table_id: [0; 8],
+            oem_revision: read_u32(bytes, 24),
+            creator_id: [0; 4],
+            creator_revision: read_u32(bytes, 32),
+        };
+        header.signature.copy_from_slice(&bytes[0..4]);
+        header.oem_id.copy_from_slice(&bytes[10..16]);
+        header.oem_table_id.copy_from_slice(&bytes[16..24]);
+        header.creator_id.copy_from_slice(&bytes[28..32]);
+        if (header.length as usize) < SdtHeader::SIZE {
+            return Err(AcpiError::InvalidLength);
+        }
+        Ok(header)
+    }
+
+    /// Parse the header of `table` and check its signature, length and
+    /// checksum.
+    ///
+    /// Returns the header and the table trimmed to its length.
+    pub fn verify<'a>(
+        table: &'a [u8],
+        signature: &[u8; 4],
+    ) -> Result<(SdtHeader, &'a [u8]), AcpiError> {
+        let header = SdtHeader::parse(table)?;
+        if &header.signature != signature {
+            return Err(AcpiError::InvalidSignature);
+        }
+        let table = table
+            .get(..header.length as usize)
+            .ok_or(AcpiError::InvalidLength)?;
+        if !checksum_valid(table) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        Ok((header, table))
+    }
+}
+
+/// Map the complete table at physical `address`.
+fn map_table<M: PhysicalMemory>(memory: &M, address: u64) -> Result<&[u8], AcpiError> {
+    let header = memory
+        .map(address, SdtHeader::SIZE)
+        .ok_or(AcpiError::Unmapped(address))?;
+    let header = SdtHeader::parse(header)?;
+    memory
+        .map(address, header.length as usize)
+        .ok_or(AcpiError::Unmapped(address))
+}
+
+/// The RSDT or XSDT, listing the physical addresses of all other tables.
+pub struct RootTable<'m, M: PhysicalMemory> {
+    memory: &'m M,
+    header: SdtHeader,
+    table: &'m [u8],
+    entry_size: usize,
+}
+
+impl<'m, M: PhysicalMemory> RootTable<'m, M> {
+    /// Read and verify the root table referenced by `rsdp`.
+    ///
+    /// The XSDT is preferred if present.
+    pub fn new(memory: &'m M, rsdp: &Rsdp) -> Result<RootTable<'m, M>, AcpiError> {
+        let (address, signature, entry_size) = match rsdp.xsdt_address {
+            Some(address) => (address, b"XSDT", 8),
+            None => (rsdp.rsdt_address as u64, b"RSDT", 4),
+        };
+        let (header, table) = SdtHeader::verify(map_table(memory, address)?, signature)?;
+        Ok(RootTable {
+            memory,
+            header,
+            table,
+            entry_size,
+        })
+    }
+
+    /// The header of the RSDT or XSDT.
+    pub fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+
+    /// Physical addresses of the tables listed in the root table.
+    pub fn addresses(&self) -> impl Iterator<Item = u64> + 'm {
+        let entry_size = self.entry_size;
+        self.table[SdtHeader::SIZE..]
+            .chunks_exact(entry_size)
+            .map(move |entry| match entry_size {
+                8 => read_u64(entry, 0),
+                _ => read_u32(entry, 0) as u64,
+            })
+    }
+
+    /// Headers of all listed tables along with their physical addresses.
+    ///
+    /// Tables that can't be mapped or have a malformed header are reported
+    /// as errors; checksums are not verified.
+    pub fn headers(&self) -> impl Iterator<Item = Result<(u64, SdtHeader), AcpiError>> + 'm {
+        let memory = self.memory;
+        self.addresses().map(move |address| {
+            let bytes = memory
+                .map(address, SdtHeader::SIZE)
+                .ok_or(AcpiError::Unmapped(address))?;
+            SdtHeader::parse(bytes).map(|header| (address, header))
+        })
+    }
+
+    /// Find and verify the first table with `signature`.
+    ///
+    /// Tables whose header can't be mapped or parsed are skipped; if no table
+    /// matches, the first such error is returned instead of
+    /// [`AcpiError::NotFound`] since the table may have been among them.
+    pub fn find(&self, signature: &[u8; 4]) -> Result<&'m [u8], AcpiError> {
+        let mut skipped = None;
+        for entry in self.headers() {
+            let (address, header) = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    skipped.get_or_insert(error);
+                    continue;
+                }
+            };
+            if &header.signature == signature {
+                let (_, table) = SdtHeader::verify(map_table(self.memory, address)?, signature)?;
+                return Ok(table);
+            }
+        }
+        Err(skipped.unwrap_or(AcpiError::NotFound))
+    }
+
+    /// Find and parse the MADT.
+    pub fn madt(&self) -> Result<madt::Madt<'m>, AcpiError> {
+        madt::Madt::parse(self.find(madt::Madt::SIGNATURE)?)
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::madt::*;
+    use super::*;
+    use crate::apic::{ApicId, PinPolarity, TriggerMode};
+
+    extern crate std;
+    use std::vec;
+    use std::vec::Vec;
+
+    // Tables captured from QEMU (q35, two CPUs) with a disabled CPU and an
+    // x2APIC entry added to the MADT.
+    #[rustfmt::skip]
+    const RSDP: [u8; 36] = [
+        0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0x3b, 0x42, 0x4f, 0x43,
+        0x48, 0x53, 0x20, 0x02, 0x00, 0x10, 0xfe, 0x07, 0x24, 0x00, 0x00, 0x00,
+        0x00, 0x18, 0xfe, 0x07, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x00,
+    ];
+
+    #[rustfmt::skip]
+    const RSDT: [u8; 44] = [
+        0x52, 0x53, 0x44, 0x54, 0x2c, 0x00, 0x00, 0x00, 0x01, 0x14, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x52, 0x53, 0x44, 0x54,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x20, 0xfe, 0x07, 0x00, 0x30, 0xfe, 0x07,
+    ];
+
+    #[rustfmt::skip]
+    const XSDT: [u8; 52] = [
+        0x58, 0x53, 0x44, 0x54, 0x34, 0x00, 0x00, 0x00, 0x01, 0x00, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x58, 0x53, 0x44, 0x54,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x20, 0xfe, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0xfe, 0x07,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[rustfmt::skip]
+    const MADT: [u8; 152] = [
+        0x41, 0x50, 0x49, 0x43, 0x98, 0x00, 0x00, 0x00, 0x01, 0x98, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x41, 0x50, 0x49, 0x43,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x08, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x09, 0x10, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00,
+        0x02, 0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a,
+        0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x09,
+        0x09, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0a, 0x0a, 0x00,
+        0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0b, 0x0b, 0x00, 0x00, 0x00,
+        0x0d, 0x00, 0x04, 0x06, 0xff, 0x00, 0x00, 0x01,
+    ];
+
+    #[rustfmt::skip]
+    const HPET: [u8; 56] = [
+        0x48, 0x50, 0x45, 0x54, 0x38, 0x00, 0x00, 0x00, 0x01, 0x03, 0x42, 0x4f,
+        0x43, 0x48, 0x53, 0x20, 0x42, 0x58, 0x50, 0x43, 0x48, 0x50, 0x45, 0x54,
+        0x01, 0x00, 0x00, 0x00, 0x42, 0x58, 0x50, 0x43, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0xa2, 0x86, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xd0, 0xfe,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const RSDP_ADDRESS: u64 = 0xf5a40;
+    const RSDT_ADDRESS: u64 = 0x7fe1000;
+    const XSDT_ADDRESS: u64 = 0x7fe1800;
+    const MADT_ADDRESS: u64 = 0x7fe2000;
+    const HPET_ADDRESS: u64 = 0x7fe3000;
+
+    struct Memory(Vec<(u64, Vec<u8>)>);
+
+    impl Memory {
+        fn qemu() -> Memory {
+            Memory(vec![
+                (RSDP_ADDRESS, RSDP.to_vec()),
+                (RSDT_ADDRESS, RSDT.to_vec()),
+                (XSDT_ADDRESS, XSDT.to_vec()),
+                (MADT_ADDRESS, MADT.to_vec()),
+                (HPET_ADDRESS, HPET.to_vec()),
+            ])
+        }
+
+        fn table(&mut self, address: u64) -> &mut Vec<u8> {
+            &mut self.0.iter_mut().find(|(a, _)| *a == address).unwrap().1
+        }
+    }
+
+    impl PhysicalMemory for Memory {
+        fn map(&self, address: u64, length: usize) -> Option<&[u8]> {
+            self.0.iter().find_map(|(base, bytes)| {
+                let offset = address.checked_sub(*base)? as usize;
+                bytes.get(offset..offset + length)
+            })
+        }
+    }
+
+    #[test]
+    fn rsdp() {
+        let rsdp = Rsdp::parse(&RSDP).unwrap();
+        assert_eq!(&rsdp.oem_id, b"BOCHS ");
+        assert_eq!(rsdp.revision, 2);
+        assert_eq!(rsdp.rsdt_address, RSDT_ADDRESS as u32);
+        assert_eq!(rsdp.xsdt_address, Some(XSDT_ADDRESS));
+
+        let mut corrupt = RSDP;
+        corrupt[8] ^= 1;
+        assert_eq!(Rsdp::parse(&corrupt), Err(AcpiError::InvalidChecksum));
+        let mut corrupt = RSDP;
+        corrupt[32] ^= 1;
+        assert_eq!(Rsdp::parse(&corrupt), Err(AcpiError::InvalidChecksum));
+        assert_eq!(Rsdp::parse(&RSDP[1..]), Err(AcpiError::InvalidSignature));
+        assert_eq!(Rsdp::parse(&RSDP[..24]), Err(AcpiError::InvalidLength));
+
+        // ACPI 1.0 only has the first 20 bytes.
+        let mut v1 = RSDP;
+        v1[15] = 0;
+        v1[8] = v1[8].wrapping_add(2);
+        let v1 = Rsdp::parse(&v1[..Rsdp::V1_SIZE]).unwrap();
+        assert_eq!(v1.revision, 0);
+        assert_eq!(v1.xsdt_address, None);
+    }
+
+    #[test]
+    fn rsdp_search() {
+        let mut region = vec![0u8; 0x1000];
+        // A signature with bad checksum and an unaligned copy are skipped.
+        region[0x100..0x108].copy_from_slice(Rsdp::SIGNATURE);
+        region[0x208..0x208 + RSDP.len()].copy_from_slice(&RSDP);
+        assert_eq!(Rsdp::search(&region), None);
+
+        region[0xa40..0xa40 + RSDP.len()].copy_from_slice(&RSDP);
+        let (offset, rsdp) = Rsdp::search(&region).unwrap();
+        assert_eq!(offset, 0xa40);
+        assert_eq!(rsdp.xsdt_address, Some(XSDT_ADDRESS));
+    }
+
+    #[test]
+    fn sdt_header() {
+        let header = SdtHeader::parse(&MADT).unwrap();
+        assert_eq!(&header.signature, b"APIC");
+        assert_eq!(header.length as usize, MADT.len());
+        assert_eq!(header.revision, 1);
+        assert_eq!(&header.oem_id, b"BOCHS ");
+        assert_eq!(&header.oem_table_id, b"BXPCAPIC");
+        assert_eq!(&header.creator_id, b"BXPC");
+
+        assert!(SdtHeader::verify(&MADT, b"APIC").is_ok());
+        assert_eq!(
+            SdtHeader::verify(&MADT, b"HPET"),
+            Err(AcpiError::InvalidSignature)
+        );
+        assert_eq!(
+            SdtHeader::verify(&MADT[..MADT.len() - 1], b"APIC"),
+            Err(AcpiError::InvalidLength)
+        );
+        let mut corrupt = MADT;
+        corrupt[40] ^= 0x80;
+        assert_eq!(
+            SdtHeader::verify(&corrupt, b"APIC"),
+            Err(AcpiError::InvalidChecksum)
+        );
+    }
+
+    #[test]
+    fn root_tables() {
+        let memory = Memory::qemu();
+        let rsdp = Rsdp::parse(memory.map(RSDP_ADDRESS, Rsdp::V2_SIZE).unwrap()).unwrap();
+
+        let xsdt = RootTable::new(&memory, &rsdp).unwrap();
+        assert_eq!(&xsdt.header().signature, b"XSDT");
+        let signatures: Vec<_> = xsdt.headers().map(|h| h.unwrap().1.signature).collect();
+        assert_eq!(signatures, [*b"APIC", *b"HPET"]);
+        assert_eq!(xsdt.find(b"HPET").unwrap(), &HPET[..]);
+        assert_eq!(xsdt.find(b"FACP"), Err(AcpiError::NotFound));
+
+        let rsdp = Rsdp {
+            xsdt_address: None,
+            ..rsdp
+        };
+        let rsdt = RootTable::new(&memory, &rsdp).unwrap();
+        assert_eq!(&rsdt.header().signature, b"RSDT");
+        let addresses: Vec<_> = rsdt.addresses().collect();
+        assert_eq!(addresses, [MADT_ADDRESS, HPET_ADDRESS]);
+        assert_eq!(rsdt.find(b"APIC").unwrap(), &MADT[..]);
+    }
+
+    #[test]
+    fn root_table_errors() {
+        let mut memory = Memory::qemu();
+        memory.0.retain(|(address, _)| *address != MADT_ADDRESS);
+        let rsdp = Rsdp::parse(&RSDP).unwrap();
+        let xsdt = RootTable::new(&memory, &rsdp).unwrap();
+        assert_eq!(xsdt.find(b"APIC"), Err(AcpiError::Unmapped(MADT_ADDRESS)));
+        assert_eq!(xsdt.headers().nth(1).unwrap().unwrap().0, HPET_ADDRESS);
+        assert_eq!(xsdt.find(b"HPET"), Ok(&HPET[..]));
+        assert_eq!(xsdt.find(b"SSDT"), Err(AcpiError::Unmapped(MADT_ADDRESS)));
+
+        memory.table(XSDT_ADDRESS)[20] ^= 1;
+        assert_eq!(
+            RootTable::new(&memory, &rsdp).err(),
+            Some(AcpiError::InvalidChecksum)
+        );
+    }
+
+    #[test]
+    fn madt_entries() {
+        let madt = Madt::parse(&MADT).unwrap();
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        assert!(madt.has_legacy_pics());
+
+        let entries: Vec<_> = madt.entries().collect();
+        assert_eq!(entries.len(), 11);
+        assert_eq!(
+            entries[0],
+            MadtEntry::LocalApic {
+                processor_id: 0,
+                apic_id: 0,
+                flags: LocalApicFlags::ENABLED,
+            }
+        );
+        assert_eq!(
+            entries[3],
+            MadtEntry::LocalX2Apic {
+                x2apic_id: 0x100,
+                flags: LocalApicFlags::ENABLED,
+                processor_uid: 3,
+            }
+        );
+        assert_eq!(
+            entries[4],
+            MadtEntry::IoApic {
+                id: 0,
+                address: 0xfec0_0000,
+                gsi_base: 0,
+            }
+        );
+        assert_eq!(
+            entries[6],
+            MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source: 5,
+                gsi: 5,
+                flags: MpsIntiFlags(0xd),
+            }
+        );
+        assert_eq!(
+            entries[10],
+            MadtEntry::LocalApicNmi {
+                processor_id: 0xff,
+                flags: MpsIntiFlags(0),
+                lint: 1,
+            }
+        );
+
+        assert_eq!(Madt::parse(&HPET).err(), Some(AcpiError::InvalidSignature));
+    }
+
+    #[test]
+    fn madt_other_entries() {
+        // Table body followed by NMI source, LAPIC address override, x2APIC
+        // NMI and an unknown entry.
+        let mut table = MADT[..SdtHeader::SIZE + 8].to_vec();
+        table.extend_from_slice(&[3, 8, 0x0f, 0, 0x17, 0, 0, 0]);
+        table.extend_from_slice(&[5, 12, 0, 0, 0, 0, 0xe0, 0xfe, 0, 0, 0, 0]);
+        table.extend_from_slice(&[0xa, 12, 0x05, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+        table.extend_from_slice(&[0x7f, 4, 0xaa, 0xbb]);
+        // A truncated entry ends the iteration.
+        table.extend_from_slice(&[0, 8, 0]);
+        let length = table.len() as u32;
+        table[4..8].copy_from_slice(&length.to_le_bytes());
+        table[9] = 0;
+        table[9] = 0u8.wrapping_sub(table.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+
+        let madt = Madt::parse(&table).unwrap();
+        let entries: Vec<_> = madt.entries().collect();
+        assert_eq!(
+            entries,
+            [
+                MadtEntry::NmiSource {
+                    flags: MpsIntiFlags(0xf),
+                    gsi: 0x17,
+                },
+                MadtEntry::LocalApicAddressOverride {
+                    address: 0xfee0_0000,
+                },
+                MadtEntry::LocalX2ApicNmi {
+                    flags: MpsIntiFlags(0x5),
+                    processor_uid: 4,
+                    lint: 0,
+                },
+                MadtEntry::Unknown {
+                    entry_type: 0x7f,
+                    data: &[0xaa, 0xbb],
+                },
+            ]
+        );
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        assert_eq!(madt.apic_ids().count(), 0);
+    }
+
+    #[test]
+    fn inti_flags() {
+        assert_eq!(MpsIntiFlags(0).polarity(), None);
+        assert_eq!(MpsIntiFlags(0).trigger_mode(), None);
+        assert_eq!(MpsIntiFlags(0xd).polarity(), Some(PinPolarity::ActiveHigh));
+        assert_eq!(MpsIntiFlags(0xd).trigger_mode(), Some(TriggerMode::Level));
+        assert_eq!(MpsIntiFlags(0x7).polarity(), Some(PinPolarity::ActiveLow));
+        assert_eq!(MpsIntiFlags(0x7).trigger_mode(), Some(TriggerMode::Edge));
+    }
+
+    #[test]
+    fn apic_setup() {
+        let memory = Memory::qemu();
+        let rsdp = Rsdp::parse(&RSDP).unwrap();
+        let madt = RootTable::new(&memory, &rsdp).unwrap().madt().unwrap();
+
+        let ids: Vec<_> = madt.apic_ids().collect();
+        assert_eq!(
+            ids,
+            [ApicId::XApic(0), ApicId::XApic(1), ApicId::X2Apic(0x100)]
+        );
+
+        let io_apics: Vec<_> = madt.io_apics().collect();
+        assert_eq!(
+            io_apics,
+            [IoApicInfo {
+                id: 0,
+                address: 0xfec0_0000,
+                gsi_base: 0,
+            }]
+        );
+
+        let timer = madt.isa_irq(0);
+        assert_eq!(timer.gsi, 2);
+        assert_eq!(timer.polarity, PinPolarity::ActiveHigh);
+        assert_eq!(timer.trigger_mode, TriggerMode::Edge);
+        let sci = madt.isa_irq(9);
+        assert_eq!(sci.gsi, 9);
+        assert_eq!(sci.trigger_mode, TriggerMode::Level);
+        let keyboard = madt.isa_irq(1);
+        assert_eq!(keyboard.gsi, 1);
+        assert_eq!(keyboard.trigger_mode, TriggerMode::Edge);
+
+        let nmis: Vec<_> = madt.local_apic_nmis(3).collect();
+        assert_eq!(nmis, [(1, MpsIntiFlags(0))]);
+    }
+}
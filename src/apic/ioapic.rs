@@ -6,31 +6,199 @@
 //! entry can be a pain.
 
 use bit_field::BitField;
-use bitflags::bitflags;
-
-bitflags! {
-    /// The redirection table starts at REG_TABLE and uses
-    /// two registers to configure each interrupt.
-    /// The first (low) register in a pair contains configuration bits.
-    /// The second (high) register contains a bitmask telling which
-    /// CPUs can serve that interrupt.
-    struct RedirectionEntry: u32 {
-        /// Interrupt disabled
-        const DISABLED  = 0x00010000;
-        /// Level-triggered (vs edge)
-        const LEVEL     = 0x00008000;
-        /// Active low (vs high)
-        const ACTIVELOW = 0x00002000;
-        /// Destination is CPU id (vs APIC ID)
-        const LOGICAL   = 0x00000800;
-        /// None
-        const NONE		= 0x00000000;
+
+use super::*;
+
+/// Access to the registers of an I/O APIC.
+///
+/// Registers are accessed indirectly by selecting them in `IOREGSEL` and
+/// then reading or writing `IOWIN`. Implemented by [`MmioRegisters`], other
+/// implementations can be used to mock the device.
+pub trait IoApicRegisters {
+    /// Read register `reg`.
+    fn read(&self, reg: u8) -> u32;
+
+    /// Write `value` to register `reg`.
+    fn write(&self, reg: u8, value: u32);
+
+    /// Write `vector` to the EOI register (I/O APIC version 0x20 and later).
+    fn eoi(&self, vector: u8);
+}
+
+/// Memory-mapped I/O APIC registers.
+#[derive(Debug)]
+pub struct MmioRegisters {
+    base: *mut u32,
+}
+
+impl MmioRegisters {
+    /// Offset of `IOWIN` from the base address.
+    const IOWIN: usize = 0x10;
+
+    /// Offset of the EOI register from the base address.
+    const EOI: usize = 0x40;
+
+    /// # Safety
+    /// `addr` must point to the base of the IoApic.
+    pub unsafe fn new(addr: usize) -> Self {
+        MmioRegisters {
+            base: addr as *mut u32,
+        }
+    }
+}
+
+impl IoApicRegisters for MmioRegisters {
+    fn read(&self, reg: u8) -> u32 {
+        unsafe {
+            self.base.write_volatile(reg as u32);
+            self.base.add(MmioRegisters::IOWIN / 4).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u8, value: u32) {
+        unsafe {
+            self.base.write_volatile(reg as u32);
+            self.base
+                .add(MmioRegisters::IOWIN / 4)
+                .write_volatile(value);
+        }
+    }
+
+    fn eoi(&self, vector: u8) {
+        unsafe {
+            self.base
+                .add(MmioRegisters::EOI / 4)
+                .write_volatile(vector as u32);
+        }
     }
 }
 
-pub struct IoApic {
-    reg: *mut u32,
-    data: *mut u32,
+/// An entry of the I/O redirection table (see the 82093AA I/O APIC
+/// datasheet, Section 3.2.4 and Intel VT-d Section 5.1.5.1 for the
+/// remappable format).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RedirectionEntry {
+    /// Interrupt vector number.
+    pub vector: u8,
+    /// Delivery mode (ignored for remapped interrupts).
+    pub delivery_mode: DeliveryMode,
+    /// Destination mode (ignored for remapped interrupts).
+    pub destination_mode: DestinationMode,
+    /// Polarity of the interrupt pin.
+    pub polarity: PinPolarity,
+    /// Trigger mode of the interrupt pin.
+    pub trigger_mode: TriggerMode,
+    /// Set while a level-triggered interrupt is in service (read-only).
+    pub remote_irr: bool,
+    /// Inhibits the interrupt.
+    pub masked: bool,
+    /// Interrupt destination.
+    pub destination: InterruptDestination,
+}
+
+impl RedirectionEntry {
+    /// An unmasked, edge-triggered, active-high, fixed and physical entry.
+    pub const fn new(vector: u8, destination: InterruptDestination) -> RedirectionEntry {
+        RedirectionEntry {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            destination_mode: DestinationMode::Physical,
+            polarity: PinPolarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+            remote_irr: false,
+            masked: false,
+            destination,
+        }
+    }
+
+    /// Decode a redirection table entry.
+    ///
+    /// Destinations up to 255 decode as [`ApicId::XApic`].
+    pub fn from_raw(raw: u64) -> RedirectionEntry {
+        let remapped = raw.get_bit(48);
+        let destination = if remapped {
+            let handle = raw.get_bits(49..=63) | (raw.get_bit(11) as u64) << 15;
+            InterruptDestination::Remapped(handle as u16)
+        } else {
+            let id = raw.get_bits(56..=63) | raw.get_bits(49..=55) << 8;
+            if id > 0xff {
+                InterruptDestination::Apic(ApicId::X2Apic(id as u32))
+            } else {
+                InterruptDestination::Apic(ApicId::XApic(id as u8))
+            }
+        };
+
+        let (delivery_mode, destination_mode) = if remapped {
+            (DeliveryMode::Fixed, DestinationMode::Physical)
+        } else {
+            (
+                DeliveryMode::from_bits(raw.get_bits(8..=10) as u32),
+                if raw.get_bit(11) {
+                    DestinationMode::Logical
+                } else {
+                    DestinationMode::Physical
+                },
+            )
+        };
+
+        RedirectionEntry {
+            vector: raw.get_bits(0..=7) as u8,
+            delivery_mode,
+            destination_mode,
+            polarity: if raw.get_bit(13) {
+                PinPolarity::ActiveLow
+            } else {
+                PinPolarity::ActiveHigh
+            },
+            trigger_mode: if raw.get_bit(15) {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+            remote_irr: raw.get_bit(14),
+            masked: raw.get_bit(16),
+            destination,
+        }
+    }
+
+    /// Encode as redirection table entry.
+    ///
+    /// # Panics
+    /// If an x2APIC destination does not fit in 15 bits.
+    pub fn to_raw(&self) -> u64 {
+        let mut raw = (self.masked as u64) << 16
+            | (self.trigger_mode as u64) << 15
+            | (self.remote_irr as u64) << 14
+            | (self.polarity as u64) << 13
+            | self.vector as u64;
+
+        match self.destination {
+            InterruptDestination::Apic(id) => {
+                let id = match id {
+                    ApicId::XApic(id) => id as u64,
+                    ApicId::X2Apic(id) => {
+                        assert!(id < 1 << 15, "x2APIC ID doesn't fit in 15 bits.");
+                        id as u64
+                    }
+                };
+                raw |= (self.destination_mode as u64) << 11 | (self.delivery_mode as u64) << 8;
+                raw.set_bits(56..=63, id.get_bits(0..8));
+                raw.set_bits(49..=55, id.get_bits(8..15));
+            }
+            InterruptDestination::Remapped(handle) => {
+                let handle = handle as u64;
+                raw.set_bit(48, true);
+                raw.set_bit(11, handle.get_bit(15));
+                raw.set_bits(49..=63, handle.get_bits(0..15));
+            }
+        }
+
+        raw
+    }
+}
+
+pub struct IoApic<R: IoApicRegisters = MmioRegisters> {
+    regs: R,
 }
 
 impl IoApic {
@@ -39,57 +207,125 @@ impl IoApic {
     /// # Safety
     /// `addr` must point to the base of the IoApic.
     pub unsafe fn new(addr: usize) -> Self {
-        IoApic {
-            reg: addr as *mut u32,
-            data: (addr + 0x10) as *mut u32,
-        }
+        IoApic::with_registers(MmioRegisters::new(addr))
     }
+}
+
+impl<R: IoApicRegisters> IoApic<R> {
+    /// Instantiate an IoApic accessed through `regs`.
+    pub fn with_registers(regs: R) -> Self {
+        IoApic { regs }
+    }
+
     pub fn disable_all(&mut self) {
         // Mark all interrupts edge-triggered, active high, disabled,
         // and not routed to any CPUs.
         for i in 0..self.supported_interrupts() {
-            self.write_irq(i, RedirectionEntry::DISABLED, 0);
+            let entry = RedirectionEntry {
+                masked: true,
+                ..RedirectionEntry::new(T_IRQ0 + i, InterruptDestination::Apic(ApicId::XApic(0)))
+            };
+            self.set_redirection(i, entry);
         }
     }
 
-    unsafe fn read(&mut self, reg: u8) -> u32 {
-        self.reg.write_volatile(reg as u32);
-        self.data.read_volatile()
+    pub fn enable(&mut self, irq: u8, cpunum: u8) {
+        // Mark interrupt edge-triggered, active high,
+        // enabled, and routed to the given cpunum,
+        // which happens to be that cpu's APIC ID.
+        let entry = RedirectionEntry::new(
+            T_IRQ0 + irq,
+            InterruptDestination::Apic(ApicId::XApic(cpunum)),
+        );
+        self.set_redirection(irq, entry);
+    }
+
+    /// Register index of the low half of the redirection entry for `pin`.
+    fn table_register(&self, pin: u8) -> u8 {
+        assert!(
+            pin < self.supported_interrupts(),
+            "Pin not supported by this I/O APIC."
+        );
+        REG_TABLE + 2 * pin
     }
 
-    unsafe fn write(&mut self, reg: u8, data: u32) {
-        self.reg.write_volatile(reg as u32);
-        self.data.write_volatile(data);
+    /// Read the redirection entry of `pin`.
+    pub fn redirection(&self, pin: u8) -> RedirectionEntry {
+        let reg = self.table_register(pin);
+        let low = self.regs.read(reg) as u64;
+        let high = self.regs.read(reg + 1) as u64;
+        RedirectionEntry::from_raw(high << 32 | low)
     }
 
-    fn write_irq(&mut self, irq: u8, flags: RedirectionEntry, dest: u8) {
-        unsafe {
-            self.write(REG_TABLE + 2 * irq, (T_IRQ0 + irq) as u32 | flags.bits());
-            self.write(REG_TABLE + 2 * irq + 1, (dest as u32) << 24);
-        }
+    /// Write the redirection entry of `pin`.
+    ///
+    /// The pin is masked while the entry is updated.
+    pub fn set_redirection(&mut self, pin: u8, entry: RedirectionEntry) {
+        let reg = self.table_register(pin);
+        let raw = entry.to_raw();
+        self.regs.write(reg, raw as u32 | 1 << 16);
+        self.regs.write(reg + 1, (raw >> 32) as u32);
+        self.regs.write(reg, raw as u32);
     }
 
-    pub fn enable(&mut self, irq: u8, cpunum: u8) {
-        // Mark interrupt edge-triggered, active high,
-        // enabled, and routed to the given cpunum,
-        // which happens to be that cpu's APIC ID.
-        self.write_irq(irq, RedirectionEntry::NONE, cpunum);
+    /// Mask `pin`.
+    pub fn mask(&mut self, pin: u8) {
+        let reg = self.table_register(pin);
+        let low = self.regs.read(reg);
+        self.regs.write(reg, low | 1 << 16);
     }
 
-    pub fn id(&mut self) -> u8 {
-        unsafe { self.read(REG_ID).get_bits(24..28) as u8 }
+    /// Unmask `pin`.
+    pub fn unmask(&mut self, pin: u8) {
+        let reg = self.table_register(pin);
+        let low = self.regs.read(reg);
+        self.regs.write(reg, low & !(1 << 16));
     }
 
-    pub fn version(&mut self) -> u8 {
-        unsafe { self.read(REG_VER).get_bits(0..8) as u8 }
+    /// Does this I/O APIC have an EOI register (version 0x20 and later)?
+    pub fn has_eoi_register(&self) -> bool {
+        self.version() >= 0x20
+    }
+
+    /// Signal end of interrupt for level-triggered interrupts with `vector`.
+    ///
+    /// Only needed if EOI broadcasts are suppressed in the local APIC. Older
+    /// I/O APICs without EOI register get their remote IRR bit cleared by
+    /// temporarily switching the pin to edge-triggered mode.
+    pub fn eoi(&mut self, vector: u8) {
+        if self.has_eoi_register() {
+            self.regs.eoi(vector);
+            return;
+        }
+
+        for pin in 0..self.supported_interrupts() {
+            let entry = self.redirection(pin);
+            if entry.vector == vector && entry.remote_irr {
+                let edge = RedirectionEntry {
+                    trigger_mode: TriggerMode::Edge,
+                    masked: true,
+                    ..entry
+                };
+                self.set_redirection(pin, edge);
+                self.set_redirection(pin, entry);
+            }
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.regs.read(REG_ID).get_bits(24..28) as u8
+    }
+
+    pub fn version(&self) -> u8 {
+        self.regs.read(REG_VER).get_bits(0..8) as u8
     }
 
     /// Number of supported interrupts by this IO APIC.
     ///
     /// Max Redirection Entry = "how many IRQs can this I/O APIC handle - 1"
     /// The -1 is silly so we add one back to it.
-    pub fn supported_interrupts(&mut self) -> u8 {
-        unsafe { (self.read(REG_VER).get_bits(16..24) + 1) as u8 }
+    pub fn supported_interrupts(&self) -> u8 {
+        (self.regs.read(REG_VER).get_bits(16..24) + 1) as u8
     }
 }
 
@@ -103,3 +339,147 @@ const REG_VER: u8 = 0x01;
 const REG_TABLE: u8 = 0x10;
 
 const T_IRQ0: u8 = 32;
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Register window of an I/O APIC with 24 pins.
+    struct MockRegisters {
+        version: u32,
+        regs: [Cell<u32>; 0x40],
+        eoi: Cell<Option<u8>>,
+        writes: Cell<usize>,
+    }
+
+    impl MockRegisters {
+        fn new(version: u32) -> Self {
+            MockRegisters {
+                version,
+                regs: core::array::from_fn(|_| Cell::new(0)),
+                eoi: Cell::new(None),
+                writes: Cell::new(0),
+            }
+        }
+    }
+
+    impl IoApicRegisters for MockRegisters {
+        fn read(&self, reg: u8) -> u32 {
+            match reg {
+                REG_ID => 0x0500_0000,
+                REG_VER => 23 << 16 | self.version,
+                _ => self.regs[reg as usize].get(),
+            }
+        }
+
+        fn write(&self, reg: u8, value: u32) {
+            assert!(reg >= REG_TABLE);
+            self.writes.set(self.writes.get() + 1);
+            self.regs[reg as usize].set(value);
+        }
+
+        fn eoi(&self, vector: u8) {
+            self.eoi.set(Some(vector));
+        }
+    }
+
+    #[test]
+    fn redirection_entry_encoding() {
+        // ISA IRQ 9 (ACPI SCI): level-triggered, active-low
+        let entry = RedirectionEntry {
+            polarity: PinPolarity::ActiveLow,
+            trigger_mode: TriggerMode::Level,
+            ..RedirectionEntry::new(0x29, InterruptDestination::Apic(ApicId::XApic(3)))
+        };
+        assert_eq!(entry.to_raw(), 0x0300_0000_0000_a029);
+        assert_eq!(RedirectionEntry::from_raw(entry.to_raw()), entry);
+
+        let entry = RedirectionEntry {
+            delivery_mode: DeliveryMode::LowestPriority,
+            destination_mode: DestinationMode::Logical,
+            masked: true,
+            ..RedirectionEntry::new(0x40, InterruptDestination::Apic(ApicId::XApic(0xf)))
+        };
+        assert_eq!(entry.to_raw(), 0x0f00_0000_0001_0940);
+        assert_eq!(RedirectionEntry::from_raw(entry.to_raw()), entry);
+
+        // Extended destination ID
+        let entry = RedirectionEntry::new(0x50, InterruptDestination::Apic(ApicId::X2Apic(0x1234)));
+        assert_eq!(entry.to_raw(), 0x3424_0000_0000_0050);
+        assert_eq!(RedirectionEntry::from_raw(entry.to_raw()), entry);
+
+        // Remappable format
+        let entry = RedirectionEntry::new(0x60, InterruptDestination::Remapped(0x8001));
+        assert_eq!(entry.to_raw(), 0x0003_0000_0000_0860);
+        assert_eq!(RedirectionEntry::from_raw(entry.to_raw()), entry);
+    }
+
+    #[test]
+    #[should_panic]
+    fn redirection_entry_x2apic_too_large() {
+        RedirectionEntry::new(0x50, InterruptDestination::Apic(ApicId::X2Apic(1 << 15))).to_raw();
+    }
+
+    #[test]
+    fn ioapic_registers() {
+        let mut ioapic = IoApic::with_registers(MockRegisters::new(0x11));
+        assert_eq!(ioapic.id(), 5);
+        assert_eq!(ioapic.version(), 0x11);
+        assert_eq!(ioapic.supported_interrupts(), 24);
+
+        ioapic.disable_all();
+        assert_eq!(ioapic.regs.regs[0x10].get(), 0x1_0020);
+        assert_eq!(ioapic.regs.regs[0x10 + 2 * 23].get(), 0x1_0037);
+
+        ioapic.enable(1, 2);
+        assert_eq!(ioapic.regs.regs[0x12].get(), 0x21);
+        assert_eq!(ioapic.regs.regs[0x13].get(), 0x0200_0000);
+
+        let entry = RedirectionEntry {
+            trigger_mode: TriggerMode::Level,
+            polarity: PinPolarity::ActiveLow,
+            ..RedirectionEntry::new(0x30, InterruptDestination::Apic(ApicId::XApic(1)))
+        };
+        ioapic.set_redirection(16, entry);
+        assert_eq!(ioapic.redirection(16), entry);
+
+        ioapic.mask(16);
+        assert!(ioapic.redirection(16).masked);
+        ioapic.unmask(16);
+        assert_eq!(ioapic.redirection(16), entry);
+    }
+
+    #[test]
+    #[should_panic]
+    fn ioapic_pin_out_of_range() {
+        let ioapic = IoApic::with_registers(MockRegisters::new(0x20));
+        ioapic.redirection(24);
+    }
+
+    #[test]
+    fn ioapic_eoi() {
+        let mut ioapic = IoApic::with_registers(MockRegisters::new(0x20));
+        assert!(ioapic.has_eoi_register());
+        ioapic.eoi(0x30);
+        assert_eq!(ioapic.regs.eoi.get(), Some(0x30));
+
+        // Older version: toggles the trigger mode of pins in service
+        let mut ioapic = IoApic::with_registers(MockRegisters::new(0x11));
+        assert!(!ioapic.has_eoi_register());
+        let entry = RedirectionEntry {
+            trigger_mode: TriggerMode::Level,
+            ..RedirectionEntry::new(0x30, InterruptDestination::Apic(ApicId::XApic(1)))
+        };
+        ioapic.set_redirection(4, entry);
+        ioapic.set_redirection(5, entry);
+        ioapic.regs.regs[0x18].set(ioapic.regs.regs[0x18].get() | 1 << 14);
+
+        let writes = ioapic.regs.writes.get();
+        ioapic.eoi(0x30);
+        assert_eq!(ioapic.regs.eoi.get(), None);
+        // Two entry updates for pin 4 only
+        assert_eq!(ioapic.regs.writes.get() - writes, 6);
+        assert_eq!(ioapic.redirection(5), entry);
+    }
+}
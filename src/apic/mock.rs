@@ -0,0 +1,195 @@
+//! A local APIC model for unit tests of code generic over [`ApicControl`].
+
+use core::cell::Cell;
+
+use super::*;
+
+extern crate std;
+use std::vec::Vec;
+
+/// Keeps the local APIC registers in memory (with their reset values) and
+/// records the IPIs it sends.
+#[derive(Debug)]
+pub(crate) struct MockApic {
+    pub id: u32,
+    pub lvt_timer: u32,
+    pub timer_divide: u32,
+    pub timer_initial_count: u32,
+    pub timer_current_count: u32,
+    pub tsc_deadline: Cell<u64>,
+    pub lvt: [u32; 6],
+    pub svr: u32,
+    /// Errors reported by the next [`ApicControl::error_status`].
+    pub esr: u32,
+    pub eois: usize,
+    pub sent: Vec<Icr>,
+}
+
+impl Default for MockApic {
+    fn default() -> Self {
+        MockApic {
+            id: 0,
+            lvt_timer: 1 << 16,
+            timer_divide: 0,
+            timer_initial_count: 0,
+            timer_current_count: 0,
+            tsc_deadline: Cell::new(0),
+            lvt: [1 << 16; 6],
+            svr: 0xff,
+            esr: 0,
+            eois: 0,
+            sent: Vec::new(),
+        }
+    }
+}
+
+impl MockApic {
+    fn lvt_index(lvt: Lvt) -> usize {
+        match lvt {
+            Lvt::Cmci => 0,
+            Lvt::Thermal => 1,
+            Lvt::PerformanceMonitor => 2,
+            Lvt::Lint0 => 3,
+            Lvt::Lint1 => 4,
+            Lvt::Error => 5,
+        }
+    }
+
+    fn icr(
+        vector: u8,
+        core: ApicId,
+        mode: DeliveryMode,
+        level: Level,
+        trigger: TriggerMode,
+    ) -> Icr {
+        Icr::for_xapic(
+            vector,
+            core,
+            DestinationShorthand::NoShorthand,
+            mode,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            level,
+            trigger,
+        )
+    }
+}
+
+impl ApicControl for MockApic {
+    fn bsp(&self) -> bool {
+        self.id == 0
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn logical_id(&self) -> u32 {
+        0
+    }
+
+    fn version(&self) -> u32 {
+        0x5_0014
+    }
+
+    fn eoi(&mut self) {
+        self.eois += 1;
+    }
+
+    fn tsc_enable(&mut self, vector: u8) {
+        self.timer_set_lvt(LvtTimer::new(vector, TimerMode::TscDeadline));
+    }
+
+    fn tsc_set(&self, value: u64) {
+        self.tsc_deadline.set(value);
+    }
+
+    fn timer_lvt(&self) -> LvtTimer {
+        LvtTimer::from_raw(self.lvt_timer)
+    }
+
+    fn timer_set_lvt(&mut self, lvt: LvtTimer) {
+        self.lvt_timer = lvt.to_raw();
+    }
+
+    fn timer_divide(&self) -> TimerDivide {
+        TimerDivide::from_raw(self.timer_divide)
+    }
+
+    fn timer_set_divide(&mut self, divide: TimerDivide) {
+        self.timer_divide = divide.to_raw();
+    }
+
+    fn timer_initial_count(&self) -> u32 {
+        self.timer_initial_count
+    }
+
+    fn timer_set_initial_count(&mut self, count: u32) {
+        self.timer_initial_count = count;
+        self.timer_current_count = count;
+    }
+
+    fn timer_current_count(&self) -> u32 {
+        self.timer_current_count
+    }
+
+    fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry::from_raw(self.lvt[MockApic::lvt_index(lvt)])
+    }
+
+    fn lvt_set(&mut self, lvt: Lvt, entry: LvtEntry) {
+        self.lvt[MockApic::lvt_index(lvt)] = entry.to_raw();
+    }
+
+    fn svr(&self) -> SpuriousVector {
+        SpuriousVector::from_raw(self.svr)
+    }
+
+    fn svr_set(&mut self, svr: SpuriousVector) {
+        self.svr = svr.to_raw();
+    }
+
+    fn error_status(&mut self) -> ErrorStatus {
+        ErrorStatus::from_bits_truncate(core::mem::take(&mut self.esr))
+    }
+
+    unsafe fn ipi_init(&mut self, core: ApicId) {
+        let icr = MockApic::icr(
+            0,
+            core,
+            DeliveryMode::Init,
+            Level::Assert,
+            TriggerMode::Level,
+        );
+        self.send_ipi(icr);
+    }
+
+    unsafe fn ipi_init_deassert(&mut self) {
+        let icr = Icr::for_xapic(
+            0,
+            ApicId::XApic(0),
+            DestinationShorthand::AllIncludingSelf,
+            DeliveryMode::Init,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Deassert,
+            TriggerMode::Level,
+        );
+        self.send_ipi(icr);
+    }
+
+    unsafe fn ipi_startup(&mut self, core: ApicId, start_page: u8) {
+        let icr = MockApic::icr(
+            start_page,
+            core,
+            DeliveryMode::StartUp,
+            Level::Assert,
+            TriggerMode::Edge,
+        );
+        self.send_ipi(icr);
+    }
+
+    unsafe fn send_ipi(&mut self, icr: Icr) {
+        self.sent.push(icr);
+    }
+}
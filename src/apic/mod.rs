@@ -1,15 +1,21 @@
 //! Register information and driver to program xAPIC, X2APIC and I/O APIC
 
 use bit_field::BitField;
+use bitflags::bitflags;
 
 pub mod ioapic;
+#[cfg(all(test, feature = "utest"))]
+pub(crate) mod mock;
+pub mod msi;
+pub mod smp;
 pub mod x2apic;
 pub mod xapic;
 
 /// Specify IPI Delivery Mode
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
+#[non_exhaustive]
 pub enum DeliveryMode {
     /// Delivers the interrupt specified in the vector field to the target processor or processors.
     Fixed = 0b000,
@@ -35,10 +41,30 @@ pub enum DeliveryMode {
     /// APIC is unable to deliver it. It is up to the software to deter- mine if the
     /// SIPI was not successfully delivered and to reissue the SIPI if necessary.
     StartUp = 0b110,
+    /// Causes the processor to respond to the interrupt as if it originated in an externally
+    /// connected (8259A-compatible) interrupt controller. Only valid for LVT entries (LINT0,
+    /// LINT1) and I/O APIC redirection entries.
+    ExtInt = 0b111,
+}
+
+impl DeliveryMode {
+    /// Decode a 3-bit delivery mode field.
+    fn from_bits(bits: u32) -> DeliveryMode {
+        match bits & 0b111 {
+            0b000 => DeliveryMode::Fixed,
+            0b001 => DeliveryMode::LowestPriority,
+            0b010 => DeliveryMode::SMI,
+            0b100 => DeliveryMode::NMI,
+            0b101 => DeliveryMode::Init,
+            0b110 => DeliveryMode::StartUp,
+            0b111 => DeliveryMode::ExtInt,
+            _ => DeliveryMode::_Reserved,
+        }
+    }
 }
 
 /// Specify IPI Destination Mode.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
 pub enum DestinationMode {
     Physical = 0,
@@ -46,7 +72,7 @@ pub enum DestinationMode {
 }
 
 /// Specify Delivery Status
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
 pub enum DeliveryStatus {
     Idle = 0,
@@ -54,7 +80,7 @@ pub enum DeliveryStatus {
 }
 
 /// IPI Level
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
 pub enum Level {
     Deassert = 0,
@@ -62,7 +88,7 @@ pub enum Level {
 }
 
 /// IPI Trigger Mode
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
 pub enum TriggerMode {
     Edge = 0,
@@ -70,7 +96,7 @@ pub enum TriggerMode {
 }
 
 /// IPI Destination Shorthand
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
 pub enum DestinationShorthand {
     NoShorthand = 0b00,
@@ -235,6 +261,18 @@ impl ApicId {
     }
 }
 
+/// Destination of an interrupt routed by an I/O APIC or sent as MSI.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum InterruptDestination {
+    /// An (x)APIC ID or logical destination.
+    ///
+    /// x2APIC IDs above 255 use the extended destination ID format (15-bit
+    /// IDs), which is understood by some hypervisors but not by hardware.
+    Apic(ApicId),
+    /// An interrupt remapping table entry (handle) of the IOMMU.
+    Remapped(u16),
+}
+
 #[allow(clippy::clippy::from_over_into)]
 impl Into<usize> for ApicId {
     fn into(self) -> usize {
@@ -245,6 +283,338 @@ impl Into<usize> for ApicId {
     }
 }
 
+/// APIC timer mode (bits 18:17 of the LVT timer register).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u64)]
+pub enum TimerMode {
+    /// One-shot mode using a count-down value.
+    OneShot = 0b00,
+    /// Periodic mode reloading a count-down value.
+    Periodic = 0b01,
+    /// TSC-Deadline mode using absolute target value in `IA32_TSC_DEADLINE` MSR.
+    TscDeadline = 0b10,
+    /// Reserved
+    _Reserved = 0b11,
+}
+
+/// APIC timer divide configuration (Divide Configuration Register).
+///
+/// The discriminant is the DCR encoding (bits 0, 1 and 3), see Intel Vol. 3A
+/// Figure 10-10.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum TimerDivide {
+    Div2 = 0b0000,
+    Div4 = 0b0001,
+    Div8 = 0b0010,
+    Div16 = 0b0011,
+    Div32 = 0b1000,
+    Div64 = 0b1001,
+    Div128 = 0b1010,
+    Div1 = 0b1011,
+}
+
+impl TimerDivide {
+    /// Decode the divide configuration register.
+    pub fn from_raw(raw: u32) -> TimerDivide {
+        match raw & 0b1011 {
+            0b0000 => TimerDivide::Div2,
+            0b0001 => TimerDivide::Div4,
+            0b0010 => TimerDivide::Div8,
+            0b0011 => TimerDivide::Div16,
+            0b1000 => TimerDivide::Div32,
+            0b1001 => TimerDivide::Div64,
+            0b1010 => TimerDivide::Div128,
+            _ => TimerDivide::Div1,
+        }
+    }
+
+    /// The divide configuration register value.
+    pub fn to_raw(self) -> u32 {
+        self as u32
+    }
+
+    /// The value the timer input clock is divided by.
+    pub fn divisor(self) -> u32 {
+        match self {
+            TimerDivide::Div1 => 1,
+            TimerDivide::Div2 => 2,
+            TimerDivide::Div4 => 4,
+            TimerDivide::Div8 => 8,
+            TimerDivide::Div16 => 16,
+            TimerDivide::Div32 => 32,
+            TimerDivide::Div64 => 64,
+            TimerDivide::Div128 => 128,
+        }
+    }
+}
+
+/// Local vector table entry of the APIC timer (see Intel Vol. 3A Figure 10-8).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LvtTimer {
+    /// Interrupt vector number.
+    pub vector: u8,
+    /// Timer mode.
+    pub mode: TimerMode,
+    /// Inhibits reception of the interrupt.
+    pub masked: bool,
+}
+
+impl LvtTimer {
+    /// An unmasked timer entry.
+    pub const fn new(vector: u8, mode: TimerMode) -> LvtTimer {
+        LvtTimer {
+            vector,
+            mode,
+            masked: false,
+        }
+    }
+
+    /// Decode the LVT timer register.
+    pub fn from_raw(raw: u32) -> LvtTimer {
+        let mode = match raw.get_bits(17..=18) {
+            0b00 => TimerMode::OneShot,
+            0b01 => TimerMode::Periodic,
+            0b10 => TimerMode::TscDeadline,
+            _ => TimerMode::_Reserved,
+        };
+        LvtTimer {
+            vector: raw.get_bits(0..=7) as u8,
+            mode,
+            masked: raw.get_bit(16),
+        }
+    }
+
+    /// Encode as LVT timer register value.
+    pub fn to_raw(&self) -> u32 {
+        (self.mode as u32) << 17 | (self.masked as u32) << 16 | self.vector as u32
+    }
+}
+
+/// Interrupt input pin polarity.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u64)]
+pub enum PinPolarity {
+    ActiveHigh = 0,
+    ActiveLow = 1,
+}
+
+/// Local vector table registers (other than the timer, see [`LvtTimer`]).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Lvt {
+    /// Corrected machine-check error interrupt.
+    Cmci,
+    /// Thermal sensor interrupt.
+    Thermal,
+    /// Performance monitoring counter overflow interrupt.
+    PerformanceMonitor,
+    /// Local interrupt pin 0.
+    Lint0,
+    /// Local interrupt pin 1.
+    Lint1,
+    /// Internal APIC error interrupt.
+    Error,
+}
+
+/// Local vector table entry (see Intel Vol. 3A Figure 10-8).
+///
+/// Not every field applies to every entry: polarity, remote IRR and trigger
+/// mode only exist for LINT0 and LINT1, the error entry has no delivery
+/// mode. Unsupported fields are reserved and should be left at their default.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LvtEntry {
+    /// Interrupt vector number.
+    pub vector: u8,
+    /// Delivery mode (Fixed, SMI, NMI, INIT or ExtINT).
+    pub delivery_mode: DeliveryMode,
+    /// Polarity of the interrupt pin.
+    pub polarity: PinPolarity,
+    /// Trigger mode of the interrupt pin.
+    pub trigger_mode: TriggerMode,
+    /// Set while a level-triggered interrupt is in service (read-only).
+    pub remote_irr: bool,
+    /// Inhibits reception of the interrupt.
+    pub masked: bool,
+}
+
+impl LvtEntry {
+    /// An unmasked, edge-triggered, active-high entry.
+    pub const fn new(vector: u8, delivery_mode: DeliveryMode) -> LvtEntry {
+        LvtEntry {
+            vector,
+            delivery_mode,
+            polarity: PinPolarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+            remote_irr: false,
+            masked: false,
+        }
+    }
+
+    /// Decode a local vector table register.
+    pub fn from_raw(raw: u32) -> LvtEntry {
+        LvtEntry {
+            vector: raw.get_bits(0..=7) as u8,
+            delivery_mode: DeliveryMode::from_bits(raw.get_bits(8..=10)),
+            polarity: if raw.get_bit(13) {
+                PinPolarity::ActiveLow
+            } else {
+                PinPolarity::ActiveHigh
+            },
+            trigger_mode: if raw.get_bit(15) {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+            remote_irr: raw.get_bit(14),
+            masked: raw.get_bit(16),
+        }
+    }
+
+    /// Encode as local vector table register value.
+    pub fn to_raw(&self) -> u32 {
+        (self.masked as u32) << 16
+            | (self.trigger_mode as u32) << 15
+            | (self.remote_irr as u32) << 14
+            | (self.polarity as u32) << 13
+            | (self.delivery_mode as u32) << 8
+            | self.vector as u32
+    }
+}
+
+/// Spurious-interrupt vector register (see Intel Vol. 3A Figure 10-23).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SpuriousVector {
+    /// Vector delivered for spurious interrupts.
+    pub vector: u8,
+    /// APIC software enable.
+    pub apic_enabled: bool,
+    /// Disable focus processor checking for lowest-priority delivery.
+    pub focus_disabled: bool,
+    /// Suppress EOI broadcasts to I/O APICs for level-triggered interrupts
+    /// (if supported, see bit 24 of the version register).
+    pub suppress_eoi_broadcast: bool,
+}
+
+impl SpuriousVector {
+    /// An enabled APIC with spurious interrupts delivered to `vector`.
+    pub const fn new(vector: u8) -> SpuriousVector {
+        SpuriousVector {
+            vector,
+            apic_enabled: true,
+            focus_disabled: false,
+            suppress_eoi_broadcast: false,
+        }
+    }
+
+    /// Decode the spurious-interrupt vector register.
+    pub fn from_raw(raw: u32) -> SpuriousVector {
+        SpuriousVector {
+            vector: raw.get_bits(0..=7) as u8,
+            apic_enabled: raw.get_bit(8),
+            focus_disabled: raw.get_bit(9),
+            suppress_eoi_broadcast: raw.get_bit(12),
+        }
+    }
+
+    /// Encode as spurious-interrupt vector register value.
+    pub fn to_raw(&self) -> u32 {
+        (self.suppress_eoi_broadcast as u32) << 12
+            | (self.focus_disabled as u32) << 9
+            | (self.apic_enabled as u32) << 8
+            | self.vector as u32
+    }
+}
+
+bitflags! {
+    /// Error Status Register (see Intel Vol. 3A Section 10.5.3).
+    pub struct ErrorStatus: u32 {
+        /// Checksum error for a message sent on the APIC bus (P6, Pentium only).
+        const SEND_CHECKSUM = 1 << 0;
+        /// Checksum error for a message received on the APIC bus (P6, Pentium only).
+        const RECEIVE_CHECKSUM = 1 << 1;
+        /// Sent message was not accepted by any APIC (P6, Pentium only).
+        const SEND_ACCEPT = 1 << 2;
+        /// Received message was not accepted by any APIC (P6, Pentium only).
+        const RECEIVE_ACCEPT = 1 << 3;
+        /// Attempt to send a lowest-priority IPI that is not supported.
+        const REDIRECTABLE_IPI = 1 << 4;
+        /// Sent an IPI with an illegal vector.
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        /// Received an interrupt with an illegal vector.
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+        /// Access to an unimplemented register (xAPIC only).
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
+}
+
+/// Compute the APIC timer input frequency (in Hz, before division).
+///
+/// `elapsed` is the number of timer counts observed with divide
+/// configuration `divide` while a reference clock running at `reference_hz`
+/// advanced by `reference_ticks`. Returns `None` if `reference_ticks` is 0.
+pub fn timer_frequency(
+    elapsed: u32,
+    divide: TimerDivide,
+    reference_hz: u64,
+    reference_ticks: u64,
+) -> Option<u64> {
+    let counts = elapsed as u128 * divide.divisor() as u128;
+    let hz = (counts * reference_hz as u128).checked_div(reference_ticks as u128)?;
+    Some(hz.min(u64::MAX as u128) as u64)
+}
+
+/// Calibrate the APIC timer against a reference clock.
+///
+/// `reference` returns the current tick count of a clock running at
+/// `reference_hz` (e.g., the PIT, the HPET or an invariant TSC). The timer
+/// runs masked in one-shot mode with `divide` for `reference_ticks` ticks of
+/// the reference clock. Returns the timer input frequency in Hz (before
+/// division), or `None` if the timer expired during calibration. The
+/// previous timer configuration is restored afterwards.
+pub fn calibrate_timer<A, F>(
+    apic: &mut A,
+    divide: TimerDivide,
+    mut reference: F,
+    reference_hz: u64,
+    reference_ticks: u64,
+) -> Option<u64>
+where
+    A: ApicControl + ?Sized,
+    F: FnMut() -> u64,
+{
+    let lvt = apic.timer_lvt();
+    let dcr = apic.timer_divide();
+
+    apic.timer_set_lvt(LvtTimer {
+        vector: lvt.vector,
+        mode: TimerMode::OneShot,
+        masked: true,
+    });
+    apic.timer_set_divide(divide);
+
+    // Start on a reference clock edge
+    let edge = reference();
+    let mut start = reference();
+    while start == edge {
+        start = reference();
+    }
+
+    apic.timer_set_initial_count(u32::MAX);
+    while reference().wrapping_sub(start) < reference_ticks {
+        core::hint::spin_loop();
+    }
+    let remaining = apic.timer_current_count();
+
+    apic.timer_stop();
+    apic.timer_set_divide(dcr);
+    apic.timer_set_lvt(lvt);
+
+    if remaining == 0 {
+        return None;
+    }
+    timer_frequency(u32::MAX - remaining, divide, reference_hz, reference_ticks)
+}
+
 /// Abstracts common interface of local APIC (x2APIC, xAPIC) hardware devices.
 pub trait ApicControl {
     /// Is a bootstrap processor?
@@ -268,6 +638,73 @@ pub trait ApicControl {
     /// Set TSC deadline value.
     fn tsc_set(&self, value: u64);
 
+    /// Read the timer local vector table entry.
+    fn timer_lvt(&self) -> LvtTimer;
+
+    /// Write the timer local vector table entry.
+    fn timer_set_lvt(&mut self, lvt: LvtTimer);
+
+    /// Read the timer divide configuration.
+    fn timer_divide(&self) -> TimerDivide;
+
+    /// Set the timer divide configuration.
+    fn timer_set_divide(&mut self, divide: TimerDivide);
+
+    /// Read the timer initial count.
+    fn timer_initial_count(&self) -> u32;
+
+    /// Set the timer initial count.
+    ///
+    /// In one-shot and periodic mode, a non-zero value (re-)starts the
+    /// timer and zero stops it.
+    fn timer_set_initial_count(&mut self, count: u32);
+
+    /// Read the timer current count.
+    fn timer_current_count(&self) -> u32;
+
+    /// Fire `vector` once after `count` ticks of the divided timer clock.
+    fn timer_one_shot(&mut self, vector: u8, divide: TimerDivide, count: u32) {
+        self.timer_set_divide(divide);
+        self.timer_set_lvt(LvtTimer::new(vector, TimerMode::OneShot));
+        self.timer_set_initial_count(count);
+    }
+
+    /// Fire `vector` every `count` ticks of the divided timer clock.
+    fn timer_periodic(&mut self, vector: u8, divide: TimerDivide, count: u32) {
+        self.timer_set_divide(divide);
+        self.timer_set_lvt(LvtTimer::new(vector, TimerMode::Periodic));
+        self.timer_set_initial_count(count);
+    }
+
+    /// Stop the timer (one-shot and periodic mode).
+    fn timer_stop(&mut self) {
+        self.timer_set_initial_count(0);
+    }
+
+    /// Mask or unmask the timer interrupt.
+    fn timer_mask(&mut self, masked: bool) {
+        let mut lvt = self.timer_lvt();
+        lvt.masked = masked;
+        self.timer_set_lvt(lvt);
+    }
+
+    /// Read a local vector table entry.
+    fn lvt(&self, lvt: Lvt) -> LvtEntry;
+
+    /// Write a local vector table entry.
+    fn lvt_set(&mut self, lvt: Lvt, entry: LvtEntry);
+
+    /// Read the spurious-interrupt vector register.
+    fn svr(&self) -> SpuriousVector;
+
+    /// Write the spurious-interrupt vector register.
+    fn svr_set(&mut self, svr: SpuriousVector);
+
+    /// Read and clear the Error Status Register.
+    ///
+    /// Returns the errors detected since the last call.
+    fn error_status(&mut self) -> ErrorStatus;
+
     /// Send a INIT IPI to a core.
     ///
     /// # Safety
@@ -292,3 +729,126 @@ pub trait ApicControl {
     /// Interrupts one or multiple cores.
     unsafe fn send_ipi(&mut self, icr: Icr);
 }
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer_divide() {
+        let all = [
+            (TimerDivide::Div1, 0b1011, 1),
+            (TimerDivide::Div2, 0b0000, 2),
+            (TimerDivide::Div4, 0b0001, 4),
+            (TimerDivide::Div8, 0b0010, 8),
+            (TimerDivide::Div16, 0b0011, 16),
+            (TimerDivide::Div32, 0b1000, 32),
+            (TimerDivide::Div64, 0b1001, 64),
+            (TimerDivide::Div128, 0b1010, 128),
+        ];
+        for (divide, raw, divisor) in all {
+            assert_eq!(divide.to_raw(), raw);
+            assert_eq!(divide.divisor(), divisor);
+            assert_eq!(TimerDivide::from_raw(raw), divide);
+            // Bit 2 and upper bits are reserved
+            assert_eq!(TimerDivide::from_raw(raw | 0xffff_fff4), divide);
+        }
+    }
+
+    #[test]
+    fn lvt_timer() {
+        let lvt = LvtTimer::new(0x30, TimerMode::Periodic);
+        assert_eq!(lvt.to_raw(), 0x2_0030);
+        assert_eq!(LvtTimer::from_raw(0x2_0030), lvt);
+
+        let lvt = LvtTimer {
+            vector: 0xef,
+            mode: TimerMode::TscDeadline,
+            masked: true,
+        };
+        assert_eq!(lvt.to_raw(), 0x5_00ef);
+        // Delivery status (bit 12) is ignored
+        assert_eq!(LvtTimer::from_raw(0x5_10ef), lvt);
+
+        assert_eq!(LvtTimer::from_raw(0x1_0000).mode, TimerMode::OneShot);
+        assert!(LvtTimer::from_raw(0x1_0000).masked);
+    }
+
+    #[test]
+    fn timer_calibration_math() {
+        // 1'000'000 counts at divide 16 during 10 ms of a 1.193182 MHz PIT
+        assert_eq!(
+            timer_frequency(1_000_000, TimerDivide::Div16, 1_193_182, 11_932),
+            Some(1_599_975_863)
+        );
+        assert_eq!(
+            timer_frequency(25_000_000, TimerDivide::Div1, 1_000, 1_000),
+            Some(25_000_000)
+        );
+        assert_eq!(timer_frequency(1, TimerDivide::Div1, 1, 0), None);
+    }
+
+    #[test]
+    fn timer_helpers() {
+        let mut apic = mock::MockApic::default();
+        apic.timer_one_shot(0x30, TimerDivide::Div16, 1000);
+        assert_eq!(apic.lvt_timer, 0x30);
+        assert_eq!(apic.timer_divide(), TimerDivide::Div16);
+        assert_eq!(apic.timer_initial_count(), 1000);
+
+        apic.timer_periodic(0x31, TimerDivide::Div1, 50);
+        assert_eq!(apic.timer_lvt(), LvtTimer::new(0x31, TimerMode::Periodic));
+        apic.timer_mask(true);
+        assert_eq!(apic.lvt_timer, 0x2_0031 | 1 << 16);
+        apic.timer_stop();
+        assert_eq!(apic.timer_initial_count(), 0);
+        assert!(apic.timer_lvt().masked);
+    }
+
+    #[test]
+    fn lvt_entry() {
+        // LINT0 as masked, level-triggered ExtINT
+        let lint0 = LvtEntry {
+            trigger_mode: TriggerMode::Level,
+            masked: true,
+            ..LvtEntry::new(0x20, DeliveryMode::ExtInt)
+        };
+        assert_eq!(lint0.to_raw(), 1 << 16 | 1 << 15 | 0b111 << 8 | 0x20);
+        assert_eq!(LvtEntry::from_raw(lint0.to_raw()), lint0);
+
+        // LINT1 as NMI
+        let lint1 = LvtEntry::new(0, DeliveryMode::NMI);
+        assert_eq!(lint1.to_raw(), 0x400);
+
+        let raw = 1 << 14 | 1 << 13 | 0x31;
+        let entry = LvtEntry::from_raw(raw);
+        assert_eq!(entry.vector, 0x31);
+        assert_eq!(entry.delivery_mode, DeliveryMode::Fixed);
+        assert_eq!(entry.polarity, PinPolarity::ActiveLow);
+        assert_eq!(entry.trigger_mode, TriggerMode::Edge);
+        assert!(entry.remote_irr);
+        assert!(!entry.masked);
+        assert_eq!(entry.to_raw(), raw);
+    }
+
+    #[test]
+    fn spurious_vector() {
+        assert_eq!(SpuriousVector::new(0xff).to_raw(), 0x1ff);
+        let svr = SpuriousVector::from_raw(0x11ff);
+        assert_eq!(svr.vector, 0xff);
+        assert!(svr.apic_enabled);
+        assert!(!svr.focus_disabled);
+        assert!(svr.suppress_eoi_broadcast);
+        assert_eq!(svr.to_raw(), 0x11ff);
+        assert!(SpuriousVector::from_raw(0x200).focus_disabled);
+    }
+
+    #[test]
+    fn error_status() {
+        let esr = ErrorStatus::from_bits_truncate(0xffff_ff60);
+        assert_eq!(
+            esr,
+            ErrorStatus::SEND_ILLEGAL_VECTOR | ErrorStatus::RECEIVE_ILLEGAL_VECTOR
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! Message signalled interrupts (MSI and MSI-X).
+//!
+//! An MSI is a DWORD write of the message data to the message address, the
+//! format of both is defined in Intel Vol. 3A Section 10.11 (and Intel VT-d
+//! Section 5.1.5.2 for the remappable format).
+
+use bit_field::BitField;
+
+use super::*;
+
+/// Fixed upper bits (31:20) of the message address.
+const MSI_ADDRESS_BASE: u32 = 0xfee;
+
+/// A composed MSI message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsiMessage {
+    /// Message address.
+    pub address: u64,
+    /// Message data (only the lower 16 bits unless extended message data is
+    /// enabled).
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// Lower 32 bits of the message address.
+    pub fn address_lo(&self) -> u32 {
+        self.address as u32
+    }
+
+    /// Upper 32 bits of the message address (for 64-bit capable functions).
+    pub fn address_hi(&self) -> u32 {
+        (self.address >> 32) as u32
+    }
+
+    /// 16-bit message data.
+    pub fn data_u16(&self) -> u16 {
+        self.data as u16
+    }
+
+    /// Decode the message, e.g., to emulate an intercepted MSI write.
+    ///
+    /// Returns `None` if the address is not in the interrupt address range
+    /// (0xfeex_xxxx).
+    ///
+    /// The message doesn't record whether it was composed for an xAPIC or an
+    /// x2APIC, so destination IDs below 256 decode as [`ApicId::XApic`] and
+    /// larger ones as [`ApicId::X2Apic`]: a round trip of `X2Apic(id)` with
+    /// `id < 256` yields `XApic(id)`.
+    pub fn decode(&self) -> Option<MsiFields> {
+        let address = self.address_lo();
+        if self.address_hi() != 0 || address.get_bits(20..=31) != MSI_ADDRESS_BASE {
+            return None;
+        }
+
+        if address.get_bit(4) {
+            let handle = address.get_bits(5..=19) | (address.get_bit(2) as u32) << 15;
+            let shv = address.get_bit(3);
+            return Some(MsiFields {
+                destination: InterruptDestination::Remapped(handle as u16),
+                subhandle: if shv {
+                    Some(self.data.get_bits(0..=15) as u16)
+                } else {
+                    None
+                },
+                ..MsiFields::new(0, InterruptDestination::Remapped(0))
+            });
+        }
+
+        let id = address.get_bits(12..=19) | address.get_bits(5..=11) << 8;
+        let destination = if id > 0xff {
+            InterruptDestination::Apic(ApicId::X2Apic(id))
+        } else {
+            InterruptDestination::Apic(ApicId::XApic(id as u8))
+        };
+
+        Some(MsiFields {
+            vector: self.data.get_bits(0..=7) as u8,
+            delivery_mode: DeliveryMode::from_bits(self.data.get_bits(8..=10)),
+            destination_mode: if address.get_bit(2) {
+                DestinationMode::Logical
+            } else {
+                DestinationMode::Physical
+            },
+            trigger_mode: if self.data.get_bit(15) {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+            level: if self.data.get_bit(14) {
+                Level::Assert
+            } else {
+                Level::Deassert
+            },
+            redirection_hint: address.get_bit(3),
+            destination,
+            subhandle: None,
+        })
+    }
+}
+
+/// The fields of an MSI message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsiFields {
+    /// Interrupt vector number (ignored for remapped interrupts).
+    pub vector: u8,
+    /// Delivery mode (ignored for remapped interrupts).
+    pub delivery_mode: DeliveryMode,
+    /// Destination mode (ignored for remapped interrupts).
+    pub destination_mode: DestinationMode,
+    /// Trigger mode (ignored for remapped interrupts).
+    pub trigger_mode: TriggerMode,
+    /// Level for level-triggered interrupts (ignored for remapped
+    /// interrupts).
+    pub level: Level,
+    /// Redirection hint: deliver to the processor with the lowest interrupt
+    /// priority among the destinations (ignored for remapped interrupts).
+    pub redirection_hint: bool,
+    /// Interrupt destination.
+    pub destination: InterruptDestination,
+    /// Subhandle added to the handle of a remapped interrupt.
+    pub subhandle: Option<u16>,
+}
+
+impl MsiFields {
+    /// A fixed, edge-triggered interrupt in physical destination mode.
+    pub const fn new(vector: u8, destination: InterruptDestination) -> MsiFields {
+        MsiFields {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            destination_mode: DestinationMode::Physical,
+            trigger_mode: TriggerMode::Edge,
+            level: Level::Deassert,
+            redirection_hint: false,
+            destination,
+            subhandle: None,
+        }
+    }
+
+    /// Compose the message address and data.
+    ///
+    /// # Panics
+    /// If an x2APIC destination does not fit in 15 bits (extended
+    /// destination ID).
+    pub fn compose(&self) -> MsiMessage {
+        let mut address = MSI_ADDRESS_BASE << 20;
+        let mut data = 0u32;
+
+        match self.destination {
+            InterruptDestination::Apic(id) => {
+                let id = match id {
+                    ApicId::XApic(id) => id as u32,
+                    ApicId::X2Apic(id) => {
+                        assert!(id < 1 << 15, "x2APIC ID doesn't fit in 15 bits.");
+                        id
+                    }
+                };
+                address.set_bits(12..=19, id.get_bits(0..8));
+                address.set_bits(5..=11, id.get_bits(8..15));
+                address.set_bit(3, self.redirection_hint);
+                address.set_bit(2, self.destination_mode == DestinationMode::Logical);
+
+                data = (self.trigger_mode as u32) << 15
+                    | (self.level as u32) << 14
+                    | (self.delivery_mode as u32) << 8
+                    | self.vector as u32;
+            }
+            InterruptDestination::Remapped(handle) => {
+                let handle = handle as u32;
+                address.set_bits(5..=19, handle.get_bits(0..15));
+                address.set_bit(4, true);
+                address.set_bit(3, self.subhandle.is_some());
+                address.set_bit(2, handle.get_bit(15));
+                if let Some(subhandle) = self.subhandle {
+                    data = subhandle as u32;
+                }
+            }
+        }
+
+        MsiMessage {
+            address: address as u64,
+            data,
+        }
+    }
+}
+
+/// Builder for [`MsiMessage`].
+#[derive(Debug, Copy, Clone)]
+pub struct MsiMessageBuilder {
+    fields: MsiFields,
+}
+
+impl MsiMessageBuilder {
+    /// Start with a fixed, edge-triggered interrupt in physical destination
+    /// mode.
+    pub const fn new(vector: u8, destination: ApicId) -> MsiMessageBuilder {
+        MsiMessageBuilder {
+            fields: MsiFields::new(vector, InterruptDestination::Apic(destination)),
+        }
+    }
+
+    /// Start with a remappable-format interrupt using interrupt remapping
+    /// table entry `handle`.
+    pub const fn remapped(handle: u16) -> MsiMessageBuilder {
+        MsiMessageBuilder {
+            fields: MsiFields::new(0, InterruptDestination::Remapped(handle)),
+        }
+    }
+
+    /// Set the delivery mode.
+    pub const fn delivery_mode(mut self, delivery_mode: DeliveryMode) -> MsiMessageBuilder {
+        self.fields.delivery_mode = delivery_mode;
+        self
+    }
+
+    /// Use logical destination mode.
+    pub const fn logical(mut self) -> MsiMessageBuilder {
+        self.fields.destination_mode = DestinationMode::Logical;
+        self
+    }
+
+    /// Make the interrupt level-triggered (asserted).
+    pub const fn level_triggered(mut self) -> MsiMessageBuilder {
+        self.fields.trigger_mode = TriggerMode::Level;
+        self.fields.level = Level::Assert;
+        self
+    }
+
+    /// Set the redirection hint.
+    pub const fn redirection_hint(mut self) -> MsiMessageBuilder {
+        self.fields.redirection_hint = true;
+        self
+    }
+
+    /// Add a subhandle (in the message data) to a remapped interrupt.
+    pub const fn subhandle(mut self, subhandle: u16) -> MsiMessageBuilder {
+        self.fields.subhandle = Some(subhandle);
+        self
+    }
+
+    /// Compose the message.
+    pub fn finish(self) -> MsiMessage {
+        self.fields.compose()
+    }
+}
+
+/// An entry of the MSI-X table (see PCI Local Bus Specification 3.0,
+/// Section 6.8.2.6).
+///
+/// The table lives in a BAR of the device, entries are accessed with
+/// volatile reads and writes.
+#[repr(C)]
+#[derive(Debug)]
+pub struct MsixTableEntry {
+    address_lo: u32,
+    address_hi: u32,
+    data: u32,
+    vector_control: u32,
+}
+
+impl MsixTableEntry {
+    /// Read the message.
+    pub fn message(&self) -> MsiMessage {
+        unsafe {
+            let lo = core::ptr::read_volatile(&self.address_lo) as u64;
+            let hi = core::ptr::read_volatile(&self.address_hi) as u64;
+            MsiMessage {
+                address: hi << 32 | lo,
+                data: core::ptr::read_volatile(&self.data),
+            }
+        }
+    }
+
+    /// Write the message.
+    ///
+    /// The entry should be masked while its message is changed, see
+    /// [`MsixTableEntry::update`].
+    pub fn set_message(&mut self, message: MsiMessage) {
+        unsafe {
+            core::ptr::write_volatile(&mut self.address_lo, message.address_lo());
+            core::ptr::write_volatile(&mut self.address_hi, message.address_hi());
+            core::ptr::write_volatile(&mut self.data, message.data);
+        }
+    }
+
+    /// Is the vector masked?
+    pub fn is_masked(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&self.vector_control).get_bit(0) }
+    }
+
+    /// Mask or unmask the vector.
+    pub fn set_masked(&mut self, masked: bool) {
+        unsafe {
+            let mut control = core::ptr::read_volatile(&self.vector_control);
+            control.set_bit(0, masked);
+            core::ptr::write_volatile(&mut self.vector_control, control);
+        }
+    }
+
+    /// Mask the vector, write the message and unmask it again.
+    pub fn update(&mut self, message: MsiMessage) {
+        self.set_masked(true);
+        self.set_message(message);
+        self.set_masked(false);
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compose_xapic() {
+        let msg = MsiMessageBuilder::new(0x41, ApicId::XApic(3)).finish();
+        assert_eq!(msg.address, 0xfee0_3000);
+        assert_eq!(msg.data, 0x41);
+        assert_eq!(msg.address_hi(), 0);
+
+        let msg = MsiMessageBuilder::new(0x41, ApicId::XApic(3))
+            .delivery_mode(DeliveryMode::LowestPriority)
+            .logical()
+            .level_triggered()
+            .redirection_hint()
+            .finish();
+        assert_eq!(msg.address_lo(), 0xfee0_300c);
+        assert_eq!(msg.data_u16(), 0xc141);
+    }
+
+    #[test]
+    fn compose_x2apic_extended_id() {
+        let msg = MsiMessageBuilder::new(0x50, ApicId::X2Apic(0x1234)).finish();
+        assert_eq!(msg.address, 0xfee3_4240);
+        assert_eq!(msg.data, 0x50);
+    }
+
+    #[test]
+    #[should_panic]
+    fn compose_x2apic_too_large() {
+        MsiMessageBuilder::new(0x50, ApicId::X2Apic(0x8000)).finish();
+    }
+
+    #[test]
+    fn compose_remapped() {
+        let msg = MsiMessageBuilder::remapped(0x8005).subhandle(7).finish();
+        assert_eq!(msg.address, 0xfee0_00bc);
+        assert_eq!(msg.data, 7);
+
+        let msg = MsiMessageBuilder::remapped(0x12).finish();
+        assert_eq!(msg.address, 0xfee0_0250);
+        assert_eq!(msg.data, 0);
+    }
+
+    #[test]
+    fn decode() {
+        let fields = MsiFields {
+            delivery_mode: DeliveryMode::NMI,
+            destination_mode: DestinationMode::Logical,
+            redirection_hint: true,
+            trigger_mode: TriggerMode::Level,
+            level: Level::Assert,
+            ..MsiFields::new(0x22, InterruptDestination::Apic(ApicId::XApic(0x80)))
+        };
+        assert_eq!(fields.compose().decode(), Some(fields));
+
+        let fields = MsiFields::new(0x23, InterruptDestination::Apic(ApicId::X2Apic(0x7fff)));
+        assert_eq!(fields.compose().decode(), Some(fields));
+
+        // Small x2APIC IDs are indistinguishable from xAPIC IDs
+        let fields = MsiFields::new(0x24, InterruptDestination::Apic(ApicId::X2Apic(0x12)));
+        assert_eq!(
+            fields.compose().decode(),
+            Some(MsiFields::new(
+                0x24,
+                InterruptDestination::Apic(ApicId::XApic(0x12))
+            ))
+        );
+
+        let fields = MsiFields {
+            subhandle: Some(0xffff),
+            ..MsiFields::new(0, InterruptDestination::Remapped(0xffff))
+        };
+        assert_eq!(fields.compose().decode(), Some(fields));
+
+        let bad = MsiMessage {
+            address: 0xfed0_0000,
+            data: 0,
+        };
+        assert_eq!(bad.decode(), None);
+        let bad = MsiMessage {
+            address: 0x1_fee0_0000,
+            data: 0,
+        };
+        assert_eq!(bad.decode(), None);
+    }
+
+    #[test]
+    fn msix_table_entry() {
+        let mut table = [
+            MsixTableEntry {
+                address_lo: 0,
+                address_hi: 0,
+                data: 0,
+                vector_control: 1,
+            },
+            MsixTableEntry {
+                address_lo: 0,
+                address_hi: 0,
+                data: 0,
+                vector_control: 1,
+            },
+        ];
+        assert_eq!(core::mem::size_of::<MsixTableEntry>(), 16);
+        assert!(table[1].is_masked());
+
+        let msg = MsiMessageBuilder::new(0x60, ApicId::XApic(1)).finish();
+        table[1].update(msg);
+        assert!(!table[1].is_masked());
+        assert_eq!(table[1].message(), msg);
+        assert!(table[0].is_masked());
+
+        table[1].set_masked(true);
+        assert!(table[1].is_masked());
+        assert_eq!(table[1].message(), msg);
+    }
+}
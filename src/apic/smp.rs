@@ -0,0 +1,452 @@
+//! Starting application processors (APs).
+//!
+//! Implements the INIT-SIPI-SIPI sequence (see Intel Vol. 3A Section 8.4.4)
+//! and provides a real-mode trampoline that takes an AP from 16-bit real mode
+//! through 32-bit protected mode to 64-bit long mode and calls a Rust entry
+//! point.
+//!
+//! The trampoline is position-dependent: [`relocate_trampoline`] copies it to
+//! its start page (below 1 MiB) and patches in the addresses and the
+//! parameters from [`TrampolineConfig`].
+
+use crate::segmentation::SegmentSelector;
+
+use super::*;
+
+/// Errors when starting an AP.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SmpError {
+    /// The buffer for the trampoline is smaller than [`TRAMPOLINE`].
+    BufferTooSmall,
+    /// The page-table root is above 4 GiB (CR3 is loaded in 32-bit mode).
+    Cr3AboveFourGiB,
+    /// The stack is not 16-byte aligned.
+    UnalignedStack,
+    /// The AP did not come up.
+    Timeout,
+}
+
+/// Machine code of the AP trampoline (assembled at offset 0).
+///
+/// Immediates of zero are patched by [`relocate_trampoline`].
+#[rustfmt::skip]
+pub const TRAMPOLINE: [u8; 0xda] = [
+    // .code16
+    0xfa,                                       // cli
+    0xfc,                                       // cld
+    0x8c, 0xc8,                                 // mov %cs, %ax
+    0x8e, 0xd8,                                 // mov %ax, %ds
+    0x66, 0x0f, 0x01, 0x16, 0xc8, 0x00,         // lgdtl (gdtr16)
+    0x0f, 0x20, 0xc0,                           // mov %cr0, %eax
+    0x66, 0x83, 0xc8, 0x01,                     // or $PE, %eax
+    0x0f, 0x22, 0xc0,                           // mov %eax, %cr0
+    0x66, 0xea, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, // ljmpl $0x08, $pm32
+    // .code32 (pm32)
+    0x66, 0xb8, 0x10, 0x00,                     // mov $0x10, %ax
+    0x8e, 0xd8,                                 // mov %eax, %ds
+    0x8e, 0xc0,                                 // mov %eax, %es
+    0x8e, 0xd0,                                 // mov %eax, %ss
+    0x0f, 0x20, 0xe0,                           // mov %cr4, %eax
+    0x83, 0xc8, 0x20,                           // or $PAE, %eax
+    0x0f, 0x22, 0xe0,                           // mov %eax, %cr4
+    0xb8, 0x00, 0x00, 0x00, 0x00,               // mov $cr3, %eax
+    0x0f, 0x22, 0xd8,                           // mov %eax, %cr3
+    0xb9, 0x80, 0x00, 0x00, 0xc0,               // mov $IA32_EFER, %ecx
+    0x0f, 0x32,                                 // rdmsr
+    0x0d, 0x00, 0x00, 0x00, 0x00,               // or $efer_bits, %eax
+    0x0f, 0x30,                                 // wrmsr
+    0x0f, 0x20, 0xc0,                           // mov %cr0, %eax
+    0x0d, 0x00, 0x00, 0x00, 0x80,               // or $PG, %eax
+    0x0f, 0x22, 0xc0,                           // mov %eax, %cr0
+    0xea, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00,   // ljmp $0x18, $lm64
+    // .code64 (lm64)
+    0x0f, 0x01, 0x15, 0x70, 0x00, 0x00, 0x00,   // lgdt gdtr64(%rip)
+    0xb8, 0x00, 0x00, 0x00, 0x00,               // mov $data_selector, %eax
+    0x8e, 0xd8,                                 // mov %eax, %ds
+    0x8e, 0xc0,                                 // mov %eax, %es
+    0x8e, 0xd0,                                 // mov %eax, %ss
+    0x31, 0xc9,                                 // xor %ecx, %ecx
+    0x8e, 0xe1,                                 // mov %ecx, %fs
+    0x8e, 0xe9,                                 // mov %ecx, %gs
+    0x48, 0xbc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // movabs $stack_top, %rsp
+    0x48, 0xbf, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // movabs $argument, %rdi
+    0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // movabs $entry, %rax
+    0x68, 0x00, 0x00, 0x00, 0x00,               // push $code_selector
+    0x48, 0x8d, 0x0d, 0x03, 0x00, 0x00, 0x00,   // lea 1f(%rip), %rcx
+    0x51,                                       // push %rcx
+    0x48, 0xcb,                                 // lretq
+    0x6a, 0x00,                                 // 1: push $0 (return address)
+    0xff, 0xe0,                                 // jmp *%rax
+    0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00,         // (padding)
+    // gdt: null, 32-bit code (0x08), data (0x10), 64-bit code (0x18)
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0x00, 0x00, 0x00, 0x9a, 0xcf, 0x00,
+    0xff, 0xff, 0x00, 0x00, 0x00, 0x92, 0xcf, 0x00,
+    0xff, 0xff, 0x00, 0x00, 0x00, 0x9a, 0xaf, 0x00,
+    // gdtr16: limit, base
+    0x1f, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x66, 0x90,                                 // (padding)
+    // gdtr64: limit, base
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Offsets of the patched fields in [`TRAMPOLINE`].
+mod offsets {
+    pub const PM32_JUMP: usize = 0x18;
+    pub const PM32: usize = 0x1e;
+    pub const CR3: usize = 0x32;
+    pub const EFER_BITS: usize = 0x41;
+    pub const LM64_JUMP: usize = 0x53;
+    pub const LM64: usize = 0x59;
+    pub const DATA_SELECTOR: usize = 0x61;
+    pub const STACK_TOP: usize = 0x73;
+    pub const ARGUMENT: usize = 0x7d;
+    pub const ENTRY: usize = 0x87;
+    pub const CODE_SELECTOR: usize = 0x90;
+    pub const GDT: usize = 0xa8;
+    pub const GDTR16_BASE: usize = 0xca;
+    pub const GDTR64_LIMIT: usize = 0xd0;
+    pub const GDTR64_BASE: usize = 0xd2;
+}
+
+/// `IA32_EFER.LME`
+const EFER_LME: u32 = 1 << 8;
+/// `IA32_EFER.NXE`
+const EFER_NXE: u32 = 1 << 11;
+
+/// State the trampoline sets up before calling the entry point.
+#[derive(Debug, Copy, Clone)]
+pub struct TrampolineConfig {
+    /// Physical address of the PML4 (below 4 GiB); the page tables must
+    /// identity-map the trampoline page.
+    pub cr3: u64,
+    /// Set `IA32_EFER.NXE` (needed if the page tables use execute-disable).
+    pub nxe: bool,
+    /// Base address of the GDT loaded in long mode.
+    pub gdt_base: u64,
+    /// Limit of the GDT loaded in long mode.
+    pub gdt_limit: u16,
+    /// 64-bit code segment in the GDT.
+    pub code_selector: SegmentSelector,
+    /// Data segment in the GDT (loaded into DS, ES and SS).
+    pub data_selector: SegmentSelector,
+    /// Top of the AP stack (16-byte aligned).
+    pub stack_top: u64,
+    /// Entry point, called with `argument` and interrupts disabled.
+    pub entry: extern "C" fn(u64) -> !,
+    /// Argument passed to `entry`.
+    pub argument: u64,
+}
+
+fn patch_u16(code: &mut [u8], offset: usize, value: u16) {
+    code[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn patch_u32(code: &mut [u8], offset: usize, value: u32) {
+    code[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn patch_u64(code: &mut [u8], offset: usize, value: u64) {
+    code[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Copy the trampoline to `code` and patch it to run at page `start_page`.
+///
+/// `code` is the (mapped) memory at physical address `start_page * 4096`.
+pub fn relocate_trampoline(
+    code: &mut [u8],
+    start_page: u8,
+    config: &TrampolineConfig,
+) -> Result<(), SmpError> {
+    if code.len() < TRAMPOLINE.len() {
+        return Err(SmpError::BufferTooSmall);
+    }
+    if config.cr3 > u32::MAX as u64 {
+        return Err(SmpError::Cr3AboveFourGiB);
+    }
+    if config.stack_top & 0xf != 0 {
+        return Err(SmpError::UnalignedStack);
+    }
+
+    let base = start_page as u32 * 0x1000;
+    let efer = if config.nxe {
+        EFER_LME | EFER_NXE
+    } else {
+        EFER_LME
+    };
+
+    let code = &mut code[..TRAMPOLINE.len()];
+    code.copy_from_slice(&TRAMPOLINE);
+    patch_u32(code, offsets::GDTR16_BASE, base + offsets::GDT as u32);
+    patch_u32(code, offsets::PM32_JUMP, base + offsets::PM32 as u32);
+    patch_u32(code, offsets::CR3, config.cr3 as u32);
+    patch_u32(code, offsets::EFER_BITS, efer);
+    patch_u32(code, offsets::LM64_JUMP, base + offsets::LM64 as u32);
+    patch_u16(code, offsets::GDTR64_LIMIT, config.gdt_limit);
+    patch_u64(code, offsets::GDTR64_BASE, config.gdt_base);
+    patch_u32(
+        code,
+        offsets::DATA_SELECTOR,
+        config.data_selector.bits() as u32,
+    );
+    patch_u32(
+        code,
+        offsets::CODE_SELECTOR,
+        config.code_selector.bits() as u32,
+    );
+    patch_u64(code, offsets::STACK_TOP, config.stack_top);
+    patch_u64(code, offsets::ARGUMENT, config.argument);
+    patch_u64(code, offsets::ENTRY, config.entry as usize as u64);
+
+    Ok(())
+}
+
+/// How long to wait for an AP after the second STARTUP IPI (in ms).
+const STARTUP_TIMEOUT_MS: u32 = 100;
+
+/// Send INIT-SIPI-SIPI to `core`, which starts executing at page
+/// `start_page` (physical address `start_page * 4096`) in real mode.
+///
+/// `delay_us` busy-waits for the given number of microseconds, `is_up`
+/// checks if the AP signalled that it is running (e.g., through a flag its
+/// entry point sets).
+///
+/// # Safety
+/// The code at `start_page` must be valid real-mode code and `core` must be
+/// an AP that is not running yet.
+pub unsafe fn start_ap<A, D, F>(
+    apic: &mut A,
+    core: ApicId,
+    start_page: u8,
+    mut delay_us: D,
+    mut is_up: F,
+) -> Result<(), SmpError>
+where
+    A: ApicControl + ?Sized,
+    D: FnMut(u32),
+    F: FnMut() -> bool,
+{
+    apic.ipi_init(core);
+    apic.ipi_init_deassert();
+    delay_us(10_000);
+
+    for _ in 0..2 {
+        apic.ipi_startup(core, start_page);
+        delay_us(200);
+    }
+
+    for _ in 0..STARTUP_TIMEOUT_MS {
+        if is_up() {
+            return Ok(());
+        }
+        delay_us(1_000);
+    }
+    if is_up() {
+        Ok(())
+    } else {
+        Err(SmpError::Timeout)
+    }
+}
+
+/// Install the trampoline at `start_page` and start `core` with it.
+///
+/// `trampoline` is the (mapped) memory at physical address
+/// `start_page * 4096`. See [`relocate_trampoline`] and [`start_ap`].
+///
+/// # Safety
+/// `core` must be an AP that is not running yet, `config` must describe valid
+/// page tables, GDT, stack and entry point for it.
+pub unsafe fn boot_ap<A, D, F>(
+    apic: &mut A,
+    core: ApicId,
+    start_page: u8,
+    trampoline: &mut [u8],
+    config: &TrampolineConfig,
+    delay_us: D,
+    is_up: F,
+) -> Result<(), SmpError>
+where
+    A: ApicControl + ?Sized,
+    D: FnMut(u32),
+    F: FnMut() -> bool,
+{
+    relocate_trampoline(trampoline, start_page, config)?;
+    start_ap(apic, core, start_page, delay_us, is_up)
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    extern "C" fn ap_entry(_argument: u64) -> ! {
+        loop {}
+    }
+
+    fn config() -> TrampolineConfig {
+        TrampolineConfig {
+            cr3: 0x7f_f000,
+            nxe: true,
+            gdt_base: 0xffff_8000_0010_0000,
+            gdt_limit: 0x37,
+            code_selector: SegmentSelector::from_raw(0x08),
+            data_selector: SegmentSelector::from_raw(0x10),
+            stack_top: 0xffff_8000_0020_0000,
+            entry: ap_entry,
+            argument: 3,
+        }
+    }
+
+    fn u16_at(code: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([code[offset], code[offset + 1]])
+    }
+
+    fn u32_at(code: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&code[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn u64_at(code: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&code[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn trampoline_layout() {
+        // Patched immediates follow their opcodes
+        assert_eq!(TRAMPOLINE[offsets::PM32_JUMP - 1], 0xea);
+        assert_eq!(TRAMPOLINE[offsets::CR3 - 1], 0xb8);
+        assert_eq!(TRAMPOLINE[offsets::EFER_BITS - 1], 0x0d);
+        assert_eq!(TRAMPOLINE[offsets::LM64_JUMP - 1], 0xea);
+        assert_eq!(TRAMPOLINE[offsets::DATA_SELECTOR - 1], 0xb8);
+        assert_eq!(TRAMPOLINE[offsets::STACK_TOP - 1], 0xbc);
+        assert_eq!(TRAMPOLINE[offsets::ARGUMENT - 1], 0xbf);
+        assert_eq!(TRAMPOLINE[offsets::ENTRY - 1], 0xb8);
+        assert_eq!(TRAMPOLINE[offsets::CODE_SELECTOR - 1], 0x68);
+        // lgdtl (gdtr16) and lgdt gdtr64(%rip)
+        assert_eq!(u16_at(&TRAMPOLINE, 0x0a) as usize, offsets::GDTR16_BASE - 2);
+        assert_eq!(
+            offsets::LM64 + 7 + u32_at(&TRAMPOLINE, offsets::LM64 + 3) as usize,
+            offsets::GDTR64_LIMIT
+        );
+        assert_eq!(u64_at(&TRAMPOLINE, offsets::GDT + 8), 0x00cf_9a00_0000_ffff);
+        assert_eq!(
+            u64_at(&TRAMPOLINE, offsets::GDT + 24),
+            0x00af_9a00_0000_ffff
+        );
+    }
+
+    #[test]
+    fn relocate() {
+        let mut page = [0xccu8; 4096];
+        relocate_trampoline(&mut page, 0x8, &config()).unwrap();
+
+        assert_eq!(u32_at(&page, offsets::GDTR16_BASE), 0x80a8);
+        assert_eq!(u32_at(&page, offsets::PM32_JUMP), 0x801e);
+        assert_eq!(u32_at(&page, offsets::LM64_JUMP), 0x8059);
+        assert_eq!(u32_at(&page, offsets::CR3), 0x7f_f000);
+        assert_eq!(u32_at(&page, offsets::EFER_BITS), 0x900);
+        assert_eq!(u16_at(&page, offsets::GDTR64_LIMIT), 0x37);
+        assert_eq!(u64_at(&page, offsets::GDTR64_BASE), 0xffff_8000_0010_0000);
+        assert_eq!(u32_at(&page, offsets::CODE_SELECTOR), 0x08);
+        assert_eq!(u32_at(&page, offsets::DATA_SELECTOR), 0x10);
+        assert_eq!(u64_at(&page, offsets::STACK_TOP), 0xffff_8000_0020_0000);
+        assert_eq!(u64_at(&page, offsets::ARGUMENT), 3);
+        assert_eq!(
+            u64_at(&page, offsets::ENTRY),
+            ap_entry as extern "C" fn(u64) -> ! as usize as u64
+        );
+
+        // Everything else is copied verbatim
+        let patched = [
+            (offsets::GDTR16_BASE, 4),
+            (offsets::PM32_JUMP, 4),
+            (offsets::LM64_JUMP, 4),
+            (offsets::CR3, 4),
+            (offsets::EFER_BITS, 4),
+            (offsets::GDTR64_LIMIT, 2),
+            (offsets::GDTR64_BASE, 8),
+            (offsets::CODE_SELECTOR, 4),
+            (offsets::DATA_SELECTOR, 4),
+            (offsets::STACK_TOP, 8),
+            (offsets::ARGUMENT, 8),
+            (offsets::ENTRY, 8),
+        ];
+        for (i, byte) in TRAMPOLINE.iter().enumerate() {
+            if !patched.iter().any(|(o, len)| (*o..*o + *len).contains(&i)) {
+                assert_eq!(page[i], *byte, "offset {:#x}", i);
+            }
+        }
+        assert!(page[TRAMPOLINE.len()..].iter().all(|b| *b == 0xcc));
+
+        let mut without_nx = config();
+        without_nx.nxe = false;
+        relocate_trampoline(&mut page, 0x9f, &without_nx).unwrap();
+        assert_eq!(u32_at(&page, offsets::EFER_BITS), 0x100);
+        assert_eq!(u32_at(&page, offsets::PM32_JUMP), 0x9f01e);
+    }
+
+    #[test]
+    fn start_ap_sequence() {
+        let mut apic = mock::MockApic::default();
+        let mut waited = 0;
+        let mut polls = 0;
+        let result = unsafe {
+            start_ap(
+                &mut apic,
+                ApicId::XApic(3),
+                0x08,
+                |us| waited += us,
+                || {
+                    polls += 1;
+                    polls == 3
+                },
+            )
+        };
+        assert_eq!(result, Ok(()));
+        assert_eq!(waited, 10_000 + 2 * 200 + 2 * 1_000);
+
+        // INIT assert, INIT deassert (all including self), SIPI, SIPI
+        let sent: Vec<(u32, u32)> = apic.sent.iter().map(|i| (i.upper(), i.lower())).collect();
+        assert_eq!(
+            sent,
+            [
+                (3 << 24, 1 << 15 | 1 << 14 | 0b101 << 8),
+                (0, 0b10 << 18 | 1 << 15 | 0b101 << 8),
+                (3 << 24, 1 << 14 | 0b110 << 8 | 0x08),
+                (3 << 24, 1 << 14 | 0b110 << 8 | 0x08),
+            ]
+        );
+
+        let result = unsafe { start_ap(&mut apic, ApicId::XApic(3), 0x08, |_| {}, || false) };
+        assert_eq!(result, Err(SmpError::Timeout));
+    }
+
+    #[test]
+    fn relocate_errors() {
+        let mut small = [0u8; 16];
+        assert_eq!(
+            relocate_trampoline(&mut small, 0x8, &config()),
+            Err(SmpError::BufferTooSmall)
+        );
+
+        let mut page = [0u8; 4096];
+        let mut high_cr3 = config();
+        high_cr3.cr3 = 0x1_0000_0000;
+        assert_eq!(
+            relocate_trampoline(&mut page, 0x8, &high_cr3),
+            Err(SmpError::Cr3AboveFourGiB)
+        );
+
+        let mut unaligned = config();
+        unaligned.stack_top -= 8;
+        assert_eq!(
+            relocate_trampoline(&mut page, 0x8, &unaligned),
+            Err(SmpError::UnalignedStack)
+        );
+    }
+}
@@ -3,8 +3,10 @@ use bit_field::BitField;
 
 use super::*;
 use crate::msr::{
-    rdmsr, wrmsr, IA32_APIC_BASE, IA32_TSC_DEADLINE, IA32_X2APIC_APICID, IA32_X2APIC_EOI,
-    IA32_X2APIC_ESR, IA32_X2APIC_ICR, IA32_X2APIC_LDR, IA32_X2APIC_LVT_LINT0,
+    rdmsr, wrmsr, IA32_APIC_BASE, IA32_TSC_DEADLINE, IA32_X2APIC_APICID, IA32_X2APIC_CUR_COUNT,
+    IA32_X2APIC_DIV_CONF, IA32_X2APIC_EOI, IA32_X2APIC_ESR, IA32_X2APIC_ICR,
+    IA32_X2APIC_INIT_COUNT, IA32_X2APIC_LDR, IA32_X2APIC_LVT_CMCI, IA32_X2APIC_LVT_ERROR,
+    IA32_X2APIC_LVT_LINT0, IA32_X2APIC_LVT_LINT1, IA32_X2APIC_LVT_PMI, IA32_X2APIC_LVT_THERMAL,
     IA32_X2APIC_LVT_TIMER, IA32_X2APIC_SELF_IPI, IA32_X2APIC_SIVR, IA32_X2APIC_VERSION,
 };
 
@@ -41,17 +43,22 @@ impl X2APIC {
             self.base.set_bit(10, true); // Enable x2APIC
             self.base.set_bit(11, true); // Enable xAPIC
             wrmsr(IA32_APIC_BASE, self.base);
+        }
 
-            // Enable this XAPIC (set bit 8, spurious IRQ vector 15)
-            let svr: u64 = 1 << 8 | 15;
-            wrmsr(IA32_X2APIC_SIVR, svr);
+        // Enable this XAPIC (set bit 8, spurious IRQ vector 15)
+        self.svr_set(SpuriousVector::new(15));
 
-            // TODO: Fix magic number?
-            let lint0 = 1 << 16 | (1 << 15) | (0b111 << 8) | 0x20;
-            wrmsr(IA32_X2APIC_LVT_LINT0, lint0);
+        // Mask LINT0 (level-triggered ExtINT)
+        self.lvt_set(
+            Lvt::Lint0,
+            LvtEntry {
+                trigger_mode: TriggerMode::Level,
+                masked: true,
+                ..LvtEntry::new(0x20, DeliveryMode::ExtInt)
+            },
+        );
 
-            let _esr = rdmsr(IA32_X2APIC_ESR);
-        }
+        let _esr = self.error_status();
     }
 
     /// Detach from APIC (disable x2APIC and xAPIC mode).
@@ -64,6 +71,18 @@ impl X2APIC {
         }
     }
 
+    /// MSR of a local vector table entry.
+    fn lvt_msr(lvt: Lvt) -> u32 {
+        match lvt {
+            Lvt::Cmci => IA32_X2APIC_LVT_CMCI,
+            Lvt::Thermal => IA32_X2APIC_LVT_THERMAL,
+            Lvt::PerformanceMonitor => IA32_X2APIC_LVT_PMI,
+            Lvt::Lint0 => IA32_X2APIC_LVT_LINT0,
+            Lvt::Lint1 => IA32_X2APIC_LVT_LINT1,
+            Lvt::Error => IA32_X2APIC_LVT_ERROR,
+        }
+    }
+
     /// Send an IPI to yourself.
     ///
     /// # Safety
@@ -99,19 +118,8 @@ impl ApicControl for X2APIC {
     fn tsc_enable(&mut self, vector: u8) {
         unsafe {
             wrmsr(IA32_TSC_DEADLINE, 0);
-
-            let mut lvt: u64 = rdmsr(IA32_X2APIC_LVT_TIMER);
-            lvt &= !0xff;
-            lvt |= vector as u64;
-
-            // Unmask timer IRQ
-            lvt.set_bit(16, false);
-
-            // Enable TSC deadline mode
-            lvt.set_bit(17, false);
-            lvt.set_bit(18, true);
-            wrmsr(IA32_X2APIC_LVT_TIMER, lvt);
         }
+        self.timer_set_lvt(LvtTimer::new(vector, TimerMode::TscDeadline));
     }
 
     /// Set tsc deadline.
@@ -122,6 +130,80 @@ impl ApicControl for X2APIC {
         }
     }
 
+    /// Read the timer local vector table entry.
+    fn timer_lvt(&self) -> LvtTimer {
+        unsafe { LvtTimer::from_raw(rdmsr(IA32_X2APIC_LVT_TIMER) as u32) }
+    }
+
+    /// Write the timer local vector table entry.
+    fn timer_set_lvt(&mut self, lvt: LvtTimer) {
+        unsafe {
+            wrmsr(IA32_X2APIC_LVT_TIMER, lvt.to_raw() as u64);
+        }
+    }
+
+    /// Read the timer divide configuration.
+    fn timer_divide(&self) -> TimerDivide {
+        unsafe { TimerDivide::from_raw(rdmsr(IA32_X2APIC_DIV_CONF) as u32) }
+    }
+
+    /// Set the timer divide configuration.
+    fn timer_set_divide(&mut self, divide: TimerDivide) {
+        unsafe {
+            wrmsr(IA32_X2APIC_DIV_CONF, divide.to_raw() as u64);
+        }
+    }
+
+    /// Read the timer initial count.
+    fn timer_initial_count(&self) -> u32 {
+        unsafe { rdmsr(IA32_X2APIC_INIT_COUNT) as u32 }
+    }
+
+    /// Set the timer initial count.
+    fn timer_set_initial_count(&mut self, count: u32) {
+        unsafe {
+            wrmsr(IA32_X2APIC_INIT_COUNT, count as u64);
+        }
+    }
+
+    /// Read the timer current count.
+    fn timer_current_count(&self) -> u32 {
+        unsafe { rdmsr(IA32_X2APIC_CUR_COUNT) as u32 }
+    }
+
+    /// Read a local vector table entry.
+    fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        unsafe { LvtEntry::from_raw(rdmsr(X2APIC::lvt_msr(lvt)) as u32) }
+    }
+
+    /// Write a local vector table entry.
+    fn lvt_set(&mut self, lvt: Lvt, entry: LvtEntry) {
+        unsafe {
+            wrmsr(X2APIC::lvt_msr(lvt), entry.to_raw() as u64);
+        }
+    }
+
+    /// Read the spurious-interrupt vector register.
+    fn svr(&self) -> SpuriousVector {
+        unsafe { SpuriousVector::from_raw(rdmsr(IA32_X2APIC_SIVR) as u32) }
+    }
+
+    /// Write the spurious-interrupt vector register.
+    fn svr_set(&mut self, svr: SpuriousVector) {
+        unsafe {
+            wrmsr(IA32_X2APIC_SIVR, svr.to_raw() as u64);
+        }
+    }
+
+    /// Read and clear the Error Status Register.
+    fn error_status(&mut self) -> ErrorStatus {
+        unsafe {
+            // A write (of zero) latches the errors detected since the last write
+            wrmsr(IA32_X2APIC_ESR, 0);
+            ErrorStatus::from_bits_truncate(rdmsr(IA32_X2APIC_ESR) as u32)
+        }
+    }
+
     /// End Of Interrupt -- Acknowledge interrupt delivery.
     fn eoi(&mut self) {
         unsafe {
@@ -286,8 +286,7 @@ impl XAPIC<'_> {
             wrmsr(IA32_APIC_BASE, self.base);
 
             // Enable this XAPIC (set bit 8, spurious IRQ vector 15)
-            let svr: u32 = 1 << 8 | 15;
-            self.write(ApicRegister::XAPIC_SVR, svr);
+            self.svr_set(SpuriousVector::new(15));
         }
     }
 
@@ -300,6 +299,18 @@ impl XAPIC<'_> {
         }
     }
 
+    /// Register offset of a local vector table entry.
+    fn lvt_register(lvt: Lvt) -> ApicRegister {
+        match lvt {
+            Lvt::Cmci => ApicRegister::XAPIC_LVT_CMCI,
+            Lvt::Thermal => ApicRegister::XAPIC_LVT_THERMAL,
+            Lvt::PerformanceMonitor => ApicRegister::XAPIC_LVT_PMI,
+            Lvt::Lint0 => ApicRegister::XAPIC_LVT_LINT0,
+            Lvt::Lint1 => ApicRegister::XAPIC_LVT_LINT1,
+            Lvt::Error => ApicRegister::XAPIC_LVT_ERROR,
+        }
+    }
+
     /// Read a register from the MMIO region.
     fn read(&self, offset: ApicRegister) -> u32 {
         assert!(offset as usize % 4 == 0);
@@ -342,14 +353,7 @@ impl ApicControl for XAPIC<'_> {
 
     /// Enable TSC timer.
     fn tsc_enable(&mut self, vector: u8) {
-        let mut lvt: u32 = self.read(ApicRegister::XAPIC_LVT_TIMER);
-        lvt &= !0xff;
-        lvt |= vector as u32;
-
-        lvt.set_bit(16, false);
-        lvt.set_bit(17, false);
-        lvt.set_bit(18, true);
-        self.write(ApicRegister::XAPIC_LVT_TIMER, lvt);
+        self.timer_set_lvt(LvtTimer::new(vector, TimerMode::TscDeadline));
     }
 
     /// Set TSC deadline value.
@@ -359,6 +363,68 @@ impl ApicControl for XAPIC<'_> {
         }
     }
 
+    /// Read the timer local vector table entry.
+    fn timer_lvt(&self) -> LvtTimer {
+        LvtTimer::from_raw(self.read(ApicRegister::XAPIC_LVT_TIMER))
+    }
+
+    /// Write the timer local vector table entry.
+    fn timer_set_lvt(&mut self, lvt: LvtTimer) {
+        self.write(ApicRegister::XAPIC_LVT_TIMER, lvt.to_raw());
+    }
+
+    /// Read the timer divide configuration.
+    fn timer_divide(&self) -> TimerDivide {
+        TimerDivide::from_raw(self.read(ApicRegister::XAPIC_TIMER_DIV_CONF))
+    }
+
+    /// Set the timer divide configuration.
+    fn timer_set_divide(&mut self, divide: TimerDivide) {
+        self.write(ApicRegister::XAPIC_TIMER_DIV_CONF, divide.to_raw());
+    }
+
+    /// Read the timer initial count.
+    fn timer_initial_count(&self) -> u32 {
+        self.read(ApicRegister::XAPIC_TIMER_INIT_COUNT)
+    }
+
+    /// Set the timer initial count.
+    fn timer_set_initial_count(&mut self, count: u32) {
+        self.write(ApicRegister::XAPIC_TIMER_INIT_COUNT, count);
+    }
+
+    /// Read the timer current count.
+    fn timer_current_count(&self) -> u32 {
+        self.read(ApicRegister::XAPIC_TIMER_CURRENT_COUNT)
+    }
+
+    /// Read a local vector table entry.
+    fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry::from_raw(self.read(XAPIC::lvt_register(lvt)))
+    }
+
+    /// Write a local vector table entry.
+    fn lvt_set(&mut self, lvt: Lvt, entry: LvtEntry) {
+        self.write(XAPIC::lvt_register(lvt), entry.to_raw());
+    }
+
+    /// Read the spurious-interrupt vector register.
+    fn svr(&self) -> SpuriousVector {
+        SpuriousVector::from_raw(self.read(ApicRegister::XAPIC_SVR))
+    }
+
+    /// Write the spurious-interrupt vector register.
+    fn svr_set(&mut self, svr: SpuriousVector) {
+        self.write(ApicRegister::XAPIC_SVR, svr.to_raw());
+    }
+
+    /// Read and clear the Error Status Register.
+    fn error_status(&mut self) -> ErrorStatus {
+        // A write latches the errors detected since the last write
+        self.write(ApicRegister::XAPIC_ESR, 0);
+        ErrorStatus::from_bits_truncate(self.read(ApicRegister::XAPIC_ESR))
+    }
+
     /// Send a INIT IPI to a core.
     unsafe fn ipi_init(&mut self, core: ApicId) {
         let icr = Icr::for_xapic(
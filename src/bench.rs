@@ -0,0 +1,276 @@
+//! Micro-benchmark harness built on the time stamp counter.
+//!
+//! Timestamps are taken with the fencing pattern from Intel's "How to
+//! Benchmark Code Execution Times on Intel IA-32 and IA-64 Instruction Set
+//! Architectures" white paper:
+//!
+//! ```text
+//! cpuid; rdtsc    <- start()
+//! ... code under test ...
+//! rdtscp; cpuid   <- stop()
+//! ```
+//!
+//! CPUID keeps earlier instructions from leaking into the measured region,
+//! RDTSCP waits for the code under test to finish and the final CPUID keeps
+//! later instructions from executing before the counter is read.
+//!
+//! [`Bench`] subtracts the harness overhead from every sample and [`Summary`]
+//! computes statistics over a sample buffer (integer only, so it can be used
+//! where floating point is unavailable).
+
+use crate::cpuid::native_cpuid::cpuid_count;
+use crate::time::{rdtsc, rdtscp};
+
+/// Read the time stamp counter at the start of a measured region
+/// (`cpuid; rdtsc`).
+///
+/// # Safety
+/// * Causes a GP fault if the TSD flag in register CR4 is set and the CPL
+///   is greater than 0.
+#[inline(always)]
+pub unsafe fn start() -> u64 {
+    cpuid_count(0, 0);
+    rdtsc()
+}
+
+/// Read the time stamp counter at the end of a measured region
+/// (`rdtscp; cpuid`).
+///
+/// # Safety
+/// * Causes a GP fault if the TSD flag in register CR4 is set and the CPL
+///   is greater than 0.
+/// * Needs RDTSCP support (CPUID.80000001H:EDX\[27\]).
+#[inline(always)]
+pub unsafe fn stop() -> u64 {
+    let (tsc, _aux) = rdtscp();
+    cpuid_count(0, 0);
+    tsc
+}
+
+/// Measure the cycles spent in `f` (including the harness overhead).
+///
+/// # Safety
+/// See [`start`] and [`stop`].
+#[inline(always)]
+pub unsafe fn measure<F: FnMut()>(mut f: F) -> u64 {
+    let begin = start();
+    f();
+    let end = stop();
+    end.wrapping_sub(begin)
+}
+
+/// A calibrated benchmark harness.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Bench {
+    overhead: u64,
+}
+
+impl Bench {
+    /// Create a harness with a known overhead (in cycles).
+    pub const fn with_overhead(overhead: u64) -> Bench {
+        Bench { overhead }
+    }
+
+    /// Create a harness and calibrate its overhead.
+    ///
+    /// The overhead is the minimum of `rounds` empty measurements.
+    ///
+    /// # Safety
+    /// See [`start`] and [`stop`].
+    pub unsafe fn calibrate(rounds: usize) -> Bench {
+        let overhead = (0..rounds.max(1)).map(|_| measure(|| {})).min();
+        Bench::with_overhead(overhead.unwrap_or(0))
+    }
+
+    /// Overhead (in cycles) subtracted from every sample.
+    pub fn overhead(&self) -> u64 {
+        self.overhead
+    }
+
+    /// Measure the cycles spent in `f`, minus the overhead.
+    ///
+    /// # Safety
+    /// See [`start`] and [`stop`].
+    #[inline(always)]
+    pub unsafe fn measure<F: FnMut()>(&self, f: F) -> u64 {
+        measure(f).saturating_sub(self.overhead)
+    }
+
+    /// Measure `f` once for every entry in `samples` and summarize the
+    /// result.
+    ///
+    /// `samples` is sorted in-place, returns `None` if it is empty.
+    ///
+    /// # Safety
+    /// See [`start`] and [`stop`].
+    pub unsafe fn run<F: FnMut()>(&self, samples: &mut [u64], mut f: F) -> Option<Summary> {
+        for sample in samples.iter_mut() {
+            *sample = self.measure(&mut f);
+        }
+        Summary::from_samples(samples)
+    }
+}
+
+/// Summary statistics of a set of samples.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Summary {
+    /// Number of samples.
+    pub count: usize,
+    /// Smallest sample.
+    pub min: u64,
+    /// Largest sample.
+    pub max: u64,
+    /// Arithmetic mean (rounded down).
+    pub mean: u64,
+    /// 50th percentile.
+    pub median: u64,
+    /// 90th percentile.
+    pub p90: u64,
+    /// 99th percentile.
+    pub p99: u64,
+    /// Population variance (rounded down).
+    pub variance: u64,
+}
+
+impl Summary {
+    /// Compute statistics for `samples`, sorting them in-place.
+    ///
+    /// Returns `None` if `samples` is empty.
+    pub fn from_samples(samples: &mut [u64]) -> Option<Summary> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        Summary::from_sorted(samples)
+    }
+
+    /// Compute statistics for already sorted `samples`.
+    ///
+    /// Returns `None` if `samples` is empty.
+    pub fn from_sorted(samples: &[u64]) -> Option<Summary> {
+        debug_assert!(samples.windows(2).all(|w| w[0] <= w[1]));
+        let count = samples.len();
+        let n = count as u128;
+
+        let sum: u128 = samples.iter().map(|s| *s as u128).sum();
+        let mean = sum.checked_div(n)? as u64;
+        let squares: u128 = samples
+            .iter()
+            .map(|s| {
+                let d = s.abs_diff(mean) as u128;
+                d * d
+            })
+            .fold(0, u128::saturating_add);
+
+        Some(Summary {
+            count,
+            min: samples[0],
+            max: samples[count - 1],
+            mean,
+            median: percentile(samples, 50)?,
+            p90: percentile(samples, 90)?,
+            p99: percentile(samples, 99)?,
+            variance: (squares / n).min(u64::MAX as u128) as u64,
+        })
+    }
+
+    /// Integer square root of the variance.
+    pub fn std_dev(&self) -> u64 {
+        isqrt(self.variance)
+    }
+}
+
+/// The `p`-th percentile (nearest-rank) of sorted `samples`.
+///
+/// Returns `None` if `samples` is empty or `p > 100`.
+pub fn percentile(samples: &[u64], p: u8) -> Option<u64> {
+    if samples.is_empty() || p > 100 {
+        return None;
+    }
+    let rank = (p as usize * samples.len()).div_ceil(100);
+    Some(samples[rank.saturating_sub(1)])
+}
+
+fn isqrt(n: u64) -> u64 {
+    // Newton's method, starting from a value >= sqrt(n)
+    if n < 2 {
+        return n;
+    }
+    let mut x = 1u64 << ((64 - n.leading_zeros()).div_ceil(2));
+    loop {
+        let y = (x + n / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let samples: [u64; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(percentile(&samples, 0), Some(1));
+        assert_eq!(percentile(&samples, 10), Some(1));
+        assert_eq!(percentile(&samples, 11), Some(2));
+        assert_eq!(percentile(&samples, 50), Some(5));
+        assert_eq!(percentile(&samples, 90), Some(9));
+        assert_eq!(percentile(&samples, 100), Some(10));
+        assert_eq!(percentile(&samples, 101), None);
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn summary() {
+        let mut samples: [u64; 8] = [9, 2, 4, 4, 5, 5, 4, 7];
+        let s = Summary::from_samples(&mut samples).unwrap();
+        assert_eq!(samples, [2, 4, 4, 4, 5, 5, 7, 9]);
+        assert_eq!(s.count, 8);
+        assert_eq!((s.min, s.max), (2, 9));
+        assert_eq!(s.mean, 5);
+        assert_eq!(s.median, 4);
+        assert_eq!(s.p90, 9);
+        assert_eq!(s.variance, 4);
+        assert_eq!(s.std_dev(), 2);
+
+        let mut single = [42];
+        let s = Summary::from_samples(&mut single).unwrap();
+        assert_eq!((s.min, s.median, s.p99, s.max), (42, 42, 42, 42));
+        assert_eq!(s.variance, 0);
+
+        let s = Summary::from_sorted(&[u64::MAX, u64::MAX]).unwrap();
+        assert_eq!(s.mean, u64::MAX);
+        assert!(Summary::from_samples(&mut []).is_none());
+    }
+
+    #[test]
+    fn integer_sqrt() {
+        for n in [0, 1, 2, 3, 4, 15, 16, 17, 1 << 40, u64::MAX] {
+            let r = isqrt(n);
+            assert!(r as u128 * r as u128 <= n as u128, "{}", n);
+            assert!((r as u128 + 1) * (r as u128 + 1) > n as u128, "{}", n);
+        }
+    }
+
+    #[test]
+    fn measure_overhead() {
+        let cpuid = crate::cpuid::CpuId::new();
+        let has_rdtscp = cpuid
+            .get_extended_processor_and_feature_identifiers()
+            .map_or(false, |einfo| einfo.has_rdtscp());
+
+        if has_rdtscp {
+            unsafe {
+                let bench = Bench::calibrate(100);
+                assert!(bench.overhead() > 0);
+                let mut samples = [0; 32];
+                let s = bench.run(&mut samples, || {}).unwrap();
+                assert_eq!(s.count, 32);
+                assert!(s.min <= s.median && s.median <= s.max);
+            }
+        }
+    }
+}
@@ -3,6 +3,7 @@
 pub mod eflags;
 pub mod paging;
 pub mod segmentation;
+pub mod sysenter;
 pub mod task;
 
 #[cfg(target_arch = "x86")]
@@ -0,0 +1,392 @@
+//! Fast system calls with SYSENTER and SYSEXIT.
+//!
+//! SYSENTER switches to CPL 0 with CS, EIP, SS and ESP derived from the
+//! `IA32_SYSENTER_CS/ESP/EIP` MSRs, which are programmed with
+//! [`enable_sysenter`]. It saves neither the return address nor the user
+//! stack, so the wrappers in this module follow the i386 Linux convention
+//! and build the same stack frame as the `__kernel_vsyscall` vDSO entry:
+//!
+//! * The number of the syscall is passed in %eax, the result is returned in %eax.
+//! * Up to six arguments are passed in %ebx, %ecx, %edx, %esi, %edi and %ebp.
+//! * %ecx, %edx and %ebp are pushed after the return address and %ebp is set
+//!   to the user stack pointer, so the kernel finds the second, third and
+//!   sixth argument at `8(%ebp)`, `4(%ebp)` and `0(%ebp)`.
+//! * The kernel returns with SYSEXIT to the address at `12(%ebp)` (%edx) and
+//!   with the stack pointer set to %ebp (%ecx).
+//! * All other registers are preserved.
+//!
+//! SYSENTER is available in 32-bit protected mode and, on Intel processors,
+//! in compatibility mode; AMD processors only support it in legacy mode.
+//!
+//! See Intel 3a, Section 5.8.7 "Performing Fast Calls to System Procedures
+//! with the SYSENTER and SYSEXIT Instructions".
+
+use core::arch::asm;
+
+use crate::msr::{wrmsr, IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP};
+use crate::segmentation::SegmentSelector;
+use crate::Ring;
+
+#[macro_export]
+macro_rules! sysenter {
+    ($arg0:expr) => {
+        x86::bits32::sysenter::sysenter0($arg0 as u32)
+    };
+
+    ($arg0:expr, $arg1:expr) => {
+        x86::bits32::sysenter::sysenter1($arg0 as u32, $arg1 as u32)
+    };
+
+    ($arg0:expr, $arg1:expr, $arg2:expr) => {
+        x86::bits32::sysenter::sysenter2($arg0 as u32, $arg1 as u32, $arg2 as u32)
+    };
+
+    ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr) => {
+        x86::bits32::sysenter::sysenter3($arg0 as u32, $arg1 as u32, $arg2 as u32, $arg3 as u32)
+    };
+
+    ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr) => {
+        x86::bits32::sysenter::sysenter4(
+            $arg0 as u32,
+            $arg1 as u32,
+            $arg2 as u32,
+            $arg3 as u32,
+            $arg4 as u32,
+        )
+    };
+
+    ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr) => {
+        x86::bits32::sysenter::sysenter5(
+            $arg0 as u32,
+            $arg1 as u32,
+            $arg2 as u32,
+            $arg3 as u32,
+            $arg4 as u32,
+            $arg5 as u32,
+        )
+    };
+
+    ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr, $arg6:expr) => {
+        x86::bits32::sysenter::sysenter6(
+            $arg0 as u32,
+            $arg1 as u32,
+            $arg2 as u32,
+            $arg3 as u32,
+            $arg4 as u32,
+            $arg5 as u32,
+            $arg6 as u32,
+        )
+    };
+}
+
+/// Reasons a GDT layout can't be used with SYSENTER/SYSEXIT.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SysenterError {
+    /// The selector refers to the LDT.
+    LdtSelector(SegmentSelector),
+    /// A kernel selector doesn't have RPL 0 or a user selector doesn't have
+    /// RPL 3.
+    InvalidRpl(SegmentSelector),
+    /// The kernel stack segment doesn't directly follow the kernel code
+    /// segment (SYSENTER loads SS with `IA32_SYSENTER_CS + 8`).
+    KernelStackNotAfterCode,
+    /// The user code segment doesn't directly follow the kernel stack
+    /// segment (SYSEXIT loads CS with `IA32_SYSENTER_CS + 16`).
+    UserCodeNotAfterKernelStack,
+    /// The user stack segment doesn't directly follow the user code segment
+    /// (SYSEXIT loads SS with `IA32_SYSENTER_CS + 24`).
+    UserStackNotAfterCode,
+}
+
+/// The segments SYSENTER and SYSEXIT switch between.
+///
+/// The selectors are derived from `IA32_SYSENTER_CS`, so the GDT entries
+/// must be consecutive: kernel code, kernel stack, user code, user stack.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SysenterSelectors {
+    /// Kernel code segment (RPL 0).
+    pub kernel_code: SegmentSelector,
+    /// Kernel stack segment (RPL 0).
+    pub kernel_stack: SegmentSelector,
+    /// 32-bit user code segment (RPL 3).
+    pub user_code: SegmentSelector,
+    /// User stack segment (RPL 3).
+    pub user_stack: SegmentSelector,
+}
+
+impl SysenterSelectors {
+    /// Check the selectors and compute the `IA32_SYSENTER_CS` value for
+    /// them.
+    pub fn sysenter_cs(&self) -> Result<u16, SysenterError> {
+        let selectors = [
+            (self.kernel_code, Ring::Ring0),
+            (self.kernel_stack, Ring::Ring0),
+            (self.user_code, Ring::Ring3),
+            (self.user_stack, Ring::Ring3),
+        ];
+        for (selector, rpl) in selectors.iter() {
+            if selector.contains(SegmentSelector::TI_LDT) {
+                return Err(SysenterError::LdtSelector(*selector));
+            }
+            if selector.bits() & 0b11 != *rpl as u16 {
+                return Err(SysenterError::InvalidRpl(*selector));
+            }
+        }
+
+        let base = self.kernel_code.index();
+        if self.kernel_stack.index() != base + 1 {
+            return Err(SysenterError::KernelStackNotAfterCode);
+        }
+        if self.user_code.index() != base + 2 {
+            return Err(SysenterError::UserCodeNotAfterKernelStack);
+        }
+        if self.user_stack.index() != base + 3 {
+            return Err(SysenterError::UserStackNotAfterCode);
+        }
+        Ok(self.kernel_code.bits())
+    }
+}
+
+/// Enable SYSENTER with `entry` as target and `stack` as kernel stack.
+///
+/// A per-CPU `stack` is needed, SYSENTER doesn't consult the TSS. In
+/// IA-32e mode both addresses are 64 bits wide and SYSENTER enters 64-bit
+/// mode.
+///
+/// # Safety
+/// Needs CPL 0 and the GDT must contain the segments described by
+/// `selectors`.
+pub unsafe fn enable_sysenter(
+    selectors: &SysenterSelectors,
+    entry: u64,
+    stack: u64,
+) -> Result<(), SysenterError> {
+    let cs = selectors.sysenter_cs()?;
+    wrmsr(IA32_SYSENTER_CS, cs as u64);
+    wrmsr(IA32_SYSENTER_ESP, stack);
+    wrmsr(IA32_SYSENTER_EIP, entry);
+    Ok(())
+}
+
+/// Disable SYSENTER, which then raises `#GP(0)`.
+///
+/// # Safety
+/// Needs CPL 0.
+pub unsafe fn disable_sysenter() {
+    wrmsr(IA32_SYSENTER_CS, 0);
+}
+
+/// Return to user space at `eip` with stack `esp` and `ret` in %eax.
+///
+/// With the convention of this module, `eip` is the address at `12(%ebp)`
+/// and `esp` the value of %ebp at the time of the SYSENTER.
+///
+/// # Safety
+/// Needs CPL 0 and a valid `IA32_SYSENTER_CS`. Returns to 32-bit code in
+/// compatibility mode when called from 64-bit mode.
+#[inline(always)]
+pub unsafe fn sysexit(eip: u32, esp: u32, ret: u32) -> ! {
+    #[cfg(target_arch = "x86")]
+    asm!("sysexit", in("edx") eip, in("ecx") esp, in("eax") ret,
+        options(att_syntax, noreturn));
+    #[cfg(target_arch = "x86_64")]
+    asm!("sysexitl", in("edx") eip, in("ecx") esp, in("eax") ret,
+        options(att_syntax, noreturn));
+}
+
+/// Invoke a syscall with up to six arguments following the convention of
+/// this module.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+unsafe fn sysenter_raw(arg0: u32, args: &[u32; 6]) -> u32 {
+    let ret: u32;
+    // %esi and %ebp can't be used as operands, so all arguments are loaded
+    // from `args` and the registers are restored from the stack afterwards.
+    asm!(
+        "pushl %ebp",
+        "pushl %esi",
+        "pushl %edi",
+        "pushl %ebx",
+        "movl 0(%ecx), %ebx",
+        "movl 8(%ecx), %edx",
+        "movl 12(%ecx), %esi",
+        "movl 16(%ecx), %edi",
+        "movl 20(%ecx), %ebp",
+        "movl 4(%ecx), %ecx",
+        "call 1f",
+        "1: addl $(2f - 1b), (%esp)",
+        "pushl %ecx",
+        "pushl %edx",
+        "pushl %ebp",
+        "movl %esp, %ebp",
+        "sysenter",
+        "2:",
+        "popl %ebp",
+        "popl %edx",
+        "popl %ecx",
+        "addl $4, %esp",
+        "popl %ebx",
+        "popl %edi",
+        "popl %esi",
+        "popl %ebp",
+        inlateout("eax") arg0 => ret,
+        inlateout("ecx") args.as_ptr() => _,
+        lateout("edx") _,
+        options(att_syntax),
+    );
+    ret
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter0(arg0: u32) -> u32 {
+    sysenter_raw(arg0, &[0; 6])
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter1(arg0: u32, arg1: u32) -> u32 {
+    sysenter_raw(arg0, &[arg1, 0, 0, 0, 0, 0])
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter2(arg0: u32, arg1: u32, arg2: u32) -> u32 {
+    sysenter_raw(arg0, &[arg1, arg2, 0, 0, 0, 0])
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter3(arg0: u32, arg1: u32, arg2: u32, arg3: u32) -> u32 {
+    sysenter_raw(arg0, &[arg1, arg2, arg3, 0, 0, 0])
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter4(arg0: u32, arg1: u32, arg2: u32, arg3: u32, arg4: u32) -> u32 {
+    sysenter_raw(arg0, &[arg1, arg2, arg3, arg4, 0, 0])
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter5(arg0: u32, arg1: u32, arg2: u32, arg3: u32, arg4: u32, arg5: u32) -> u32 {
+    sysenter_raw(arg0, &[arg1, arg2, arg3, arg4, arg5, 0])
+}
+
+/// Invoke a syscall.
+///
+/// # Safety
+/// Throws `#GP` if `IA32_SYSENTER_CS` is 0.
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+pub unsafe fn sysenter6(
+    arg0: u32,
+    arg1: u32,
+    arg2: u32,
+    arg3: u32,
+    arg4: u32,
+    arg5: u32,
+    arg6: u32,
+) -> u32 {
+    sysenter_raw(arg0, &[arg1, arg2, arg3, arg4, arg5, arg6])
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    fn selectors() -> SysenterSelectors {
+        SysenterSelectors {
+            kernel_code: SegmentSelector::new(1, Ring::Ring0),
+            kernel_stack: SegmentSelector::new(2, Ring::Ring0),
+            user_code: SegmentSelector::new(3, Ring::Ring3),
+            user_stack: SegmentSelector::new(4, Ring::Ring3),
+        }
+    }
+
+    #[test]
+    fn sysenter_cs() {
+        assert_eq!(selectors().sysenter_cs(), Ok(0x08));
+
+        let shifted = SysenterSelectors {
+            kernel_code: SegmentSelector::new(5, Ring::Ring0),
+            kernel_stack: SegmentSelector::new(6, Ring::Ring0),
+            user_code: SegmentSelector::new(7, Ring::Ring3),
+            user_stack: SegmentSelector::new(8, Ring::Ring3),
+        };
+        assert_eq!(shifted.sysenter_cs(), Ok(0x28));
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        let swapped = SysenterSelectors {
+            kernel_stack: SegmentSelector::new(3, Ring::Ring0),
+            ..selectors()
+        };
+        assert_eq!(
+            swapped.sysenter_cs(),
+            Err(SysenterError::KernelStackNotAfterCode)
+        );
+
+        // The SYSCALL layout has the user stack before the user code.
+        let syscall = SysenterSelectors {
+            user_code: SegmentSelector::new(4, Ring::Ring3),
+            user_stack: SegmentSelector::new(3, Ring::Ring3),
+            ..selectors()
+        };
+        assert_eq!(
+            syscall.sysenter_cs(),
+            Err(SysenterError::UserCodeNotAfterKernelStack)
+        );
+
+        let gap = SysenterSelectors {
+            user_stack: SegmentSelector::new(5, Ring::Ring3),
+            ..selectors()
+        };
+        assert_eq!(gap.sysenter_cs(), Err(SysenterError::UserStackNotAfterCode));
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        let user_code = SegmentSelector::new(3, Ring::Ring0);
+        let wrong_rpl = SysenterSelectors {
+            user_code,
+            ..selectors()
+        };
+        assert_eq!(
+            wrong_rpl.sysenter_cs(),
+            Err(SysenterError::InvalidRpl(user_code))
+        );
+
+        let ldt = SegmentSelector::new(1, Ring::Ring0) | SegmentSelector::TI_LDT;
+        let in_ldt = SysenterSelectors {
+            kernel_code: ldt,
+            ..selectors()
+        };
+        assert_eq!(in_ldt.sysenter_cs(), Err(SysenterError::LdtSelector(ldt)));
+    }
+}
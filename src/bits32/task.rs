@@ -1,8 +1,26 @@
 //! Helpers to program the task state segment.
 //! See Intel 3a, Chapter 7
 
+#[cfg(target_arch = "x86")]
+use core::arch::asm;
 use core::mem::size_of;
 
+use crate::bits32::eflags::EFlags;
+use crate::segmentation::{
+    BuildDescriptor, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector,
+    TaskGateDescriptorBuilder,
+};
+use crate::Ring;
+
+/// Number of I/O ports covered by the I/O permission bitmap.
+pub const IO_PORTS: usize = 0x10000;
+
+/// Size of the I/O permission bitmap including the trailing all-ones byte.
+pub const IO_BITMAP_SIZE: usize = IO_PORTS / 8 + 1;
+
+/// Busy flag in the type field of a TSS descriptor.
+const TSS_BUSY: u32 = 1 << 9;
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
 pub struct TaskStateSegment {
@@ -90,4 +108,319 @@ impl TaskStateSegment {
             iobp_offset: size_of::<TaskStateSegment>() as u16,
         }
     }
+
+    /// Creates the TSS of a task that starts executing at `eip` with stack
+    /// `esp`, page tables `cr3`, code segment `code` and `data` in all data
+    /// segment registers.
+    ///
+    /// Interrupts are disabled when the task starts; use
+    /// [`TaskStateSegment::set_stack`] to add stacks for privilege
+    /// changes.
+    pub const fn new_task(
+        cr3: u32,
+        eip: u32,
+        esp: u32,
+        code: SegmentSelector,
+        data: SegmentSelector,
+    ) -> TaskStateSegment {
+        let mut tss = TaskStateSegment::new();
+        tss.cr3 = cr3;
+        tss.eip = eip;
+        tss.esp = esp;
+        tss.eflags = EFlags::FLAGS_A1.bits();
+        tss.cs = code.bits();
+        tss.ss = data.bits();
+        tss.ds = data.bits();
+        tss.es = data.bits();
+        tss.fs = data.bits();
+        tss.gs = data.bits();
+        tss
+    }
+
+    /// Sets the stack (`ss`:`esp`) loaded when an interrupt or call gate
+    /// changes the privilege level to `pl`.
+    pub fn set_stack(&mut self, pl: Ring, ss: SegmentSelector, esp: u32) {
+        match pl {
+            Ring::Ring0 => {
+                self.ss0 = ss.bits();
+                self.esp0 = esp;
+            }
+            Ring::Ring1 => {
+                self.ss1 = ss.bits();
+                self.esp1 = esp;
+            }
+            Ring::Ring2 => {
+                self.ss2 = ss.bits();
+                self.esp2 = esp;
+            }
+            Ring::Ring3 => unreachable!("Can't set stack for PL3"),
+        }
+    }
+
+    /// The task that switched to this one with a call, interrupt or
+    /// exception (the back-link field).
+    ///
+    /// Only meaningful while [`EFlags::FLAGS_NT`] is set in the saved
+    /// (or current) EFLAGS of this task.
+    pub fn previous_task(&self) -> Option<SegmentSelector> {
+        match self.link {
+            0 => None,
+            link => Some(SegmentSelector::from_raw(link)),
+        }
+    }
+
+    /// Builds an available 32-bit TSS descriptor for this TSS.
+    ///
+    /// The descriptor records the current address of the TSS, which must
+    /// not move while the descriptor is in use.
+    ///
+    /// Only available on 32-bit x86, where the address fits the 32-bit base;
+    /// use [`tss_descriptor`] with the linear address otherwise.
+    #[cfg(target_arch = "x86")]
+    pub fn descriptor(&self) -> Descriptor {
+        tss_descriptor(self as *const _ as u32, size_of::<TaskStateSegment>())
+    }
+}
+
+impl Default for TaskStateSegment {
+    fn default() -> TaskStateSegment {
+        TaskStateSegment::new()
+    }
+}
+
+/// A 32-bit TSS followed by an I/O permission bitmap.
+///
+/// Each bit of the bitmap denies access to one I/O port when CPL > IOPL.
+/// All ports are denied initially.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+pub struct TaskStateSegmentWithIoBitmap {
+    pub tss: TaskStateSegment,
+    io_bitmap: [u8; IO_BITMAP_SIZE],
+}
+
+impl TaskStateSegmentWithIoBitmap {
+    /// Creates a TSS with an I/O permission bitmap denying all ports.
+    pub const fn new(tss: TaskStateSegment) -> TaskStateSegmentWithIoBitmap {
+        let mut tss = tss;
+        tss.iobp_offset = size_of::<TaskStateSegment>() as u16;
+        TaskStateSegmentWithIoBitmap {
+            tss,
+            io_bitmap: [0xff; IO_BITMAP_SIZE],
+        }
+    }
+
+    /// True if code with CPL > IOPL may access `port`.
+    pub fn is_allowed(&self, port: u16) -> bool {
+        self.io_bitmap[port as usize / 8] & (1 << (port % 8)) == 0
+    }
+
+    /// Allow or deny access to `port`.
+    pub fn set_allowed(&mut self, port: u16, allowed: bool) {
+        let byte = &mut self.io_bitmap[port as usize / 8];
+        if allowed {
+            *byte &= !(1 << (port % 8));
+        } else {
+            *byte |= 1 << (port % 8);
+        }
+    }
+
+    /// Allow or deny access to `count` consecutive ports starting at `port`,
+    /// e.g., 8 for the registers of a UART.
+    ///
+    /// # Panics
+    /// If the range extends past port 0xffff; the bitmap is left unchanged
+    /// in that case.
+    pub fn set_range_allowed(&mut self, port: u16, count: u16, allowed: bool) {
+        assert!(
+            port as usize + count as usize <= IO_PORTS,
+            "Port range exceeds 0xffff"
+        );
+        for port in port as usize..port as usize + count as usize {
+            self.set_allowed(port as u16, allowed);
+        }
+    }
+
+    /// The I/O permission bitmap.
+    pub fn io_bitmap(&self) -> &[u8; IO_BITMAP_SIZE] {
+        &self.io_bitmap
+    }
+
+    /// Builds an available 32-bit TSS descriptor covering the TSS and the
+    /// I/O permission bitmap.
+    ///
+    /// The descriptor records the current address of the TSS, which must
+    /// not move while the descriptor is in use.
+    ///
+    /// Only available on 32-bit x86, where the address fits the 32-bit base;
+    /// use [`tss_descriptor`] with the linear address otherwise.
+    #[cfg(target_arch = "x86")]
+    pub fn descriptor(&self) -> Descriptor {
+        tss_descriptor(
+            self as *const _ as u32,
+            size_of::<TaskStateSegmentWithIoBitmap>(),
+        )
+    }
+}
+
+/// Builds a present, available 32-bit TSS descriptor with DPL 0 for a TSS
+/// of `size` bytes at `base`.
+///
+/// # Panics
+/// If `size` is zero.
+pub fn tss_descriptor(base: u32, size: usize) -> Descriptor {
+    assert!(size > 0, "TSS size must not be zero");
+    <DescriptorBuilder as GateDescriptorBuilder<u32>>::tss_descriptor(
+        base.into(),
+        size as u64 - 1,
+        true,
+    )
+    .present()
+    .dpl(Ring::Ring0)
+    .finish()
+}
+
+/// Builds a present IDT task gate switching to the task with TSS descriptor
+/// `tss_selector`, e.g., to handle double faults on a known-good stack.
+pub fn task_gate(tss_selector: SegmentSelector) -> Descriptor {
+    DescriptorBuilder::task_gate_descriptor(tss_selector)
+        .present()
+        .dpl(Ring::Ring0)
+        .finish()
+}
+
+/// True if the TSS descriptor is marked busy, i.e., its task is running or
+/// was switched away from with a call or interrupt and is waiting for an
+/// `iret`.
+pub fn is_busy(descriptor: &Descriptor) -> bool {
+    descriptor.upper & TSS_BUSY != 0
+}
+
+/// Set or clear the busy flag of a TSS descriptor.
+///
+/// Clearing it is needed to reuse a task that never returned, e.g., the
+/// double fault task. The processor caches the descriptor of the current
+/// task, so don't change the busy flag of the task in TR.
+pub fn set_busy(descriptor: &mut Descriptor, busy: bool) {
+    let upper = descriptor.upper;
+    descriptor.upper = if busy {
+        upper | TSS_BUSY
+    } else {
+        upper & !TSS_BUSY
+    };
+}
+
+/// Switches to the task with TSS descriptor `selector` with a far jump.
+///
+/// Returns when another task switches back to the current one.
+///
+/// # Safety
+/// Needs CPL 0 (or a task gate); the target task must be available.
+#[cfg(target_arch = "x86")]
+pub unsafe fn jump_to_task(selector: SegmentSelector) {
+    let target: [u16; 3] = [0, 0, selector.bits()];
+    asm!("ljmpl *({0})", in(reg) target.as_ptr(), options(att_syntax));
+}
+
+/// Switches to the task with TSS descriptor `selector` with a far call,
+/// which sets its back-link and `EFLAGS.NT` so it can return with `iret`.
+///
+/// # Safety
+/// Needs CPL 0 (or a task gate); the target task must be available.
+#[cfg(target_arch = "x86")]
+pub unsafe fn call_task(selector: SegmentSelector) {
+    let target: [u16; 3] = [0, 0, selector.bits()];
+    asm!("lcalll *({0})", in(reg) target.as_ptr(), options(att_syntax));
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use core::mem::size_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<TaskStateSegment>(), 104);
+        let offset = TaskStateSegment::new().iobp_offset;
+        assert_eq!(offset, 104);
+        assert_eq!(size_of::<TaskStateSegmentWithIoBitmap>(), 104 + 8193);
+    }
+
+    #[test]
+    fn new_task() {
+        let code = SegmentSelector::new(1, Ring::Ring0);
+        let data = SegmentSelector::new(2, Ring::Ring0);
+        let mut tss = TaskStateSegment::new_task(0x1000, 0x10_0000, 0x20_0000, code, data);
+        tss.set_stack(Ring::Ring0, data, 0x30_0000);
+        let (cr3, eip, esp, eflags) = (tss.cr3, tss.eip, tss.esp, tss.eflags);
+        assert_eq!((cr3, eip, esp, eflags), (0x1000, 0x10_0000, 0x20_0000, 0x2));
+        let (cs, ss, ds, gs) = (tss.cs, tss.ss, tss.ds, tss.gs);
+        assert_eq!((cs, ss, ds, gs), (0x8, 0x10, 0x10, 0x10));
+        let (ss0, esp0) = (tss.ss0, tss.esp0);
+        assert_eq!((ss0, esp0), (0x10, 0x30_0000));
+
+        assert_eq!(tss.previous_task(), None);
+        tss.link = 0x28;
+        assert_eq!(
+            tss.previous_task(),
+            Some(SegmentSelector::new(5, Ring::Ring0))
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_tss_descriptor() {
+        tss_descriptor(0x1000, 0);
+    }
+
+    #[test]
+    fn descriptors() {
+        let mut tss = tss_descriptor(0x0012_3450, size_of::<TaskStateSegment>());
+        assert_eq!(tss.as_u64(), 0x0000_8912_3450_0067);
+        assert!(!is_busy(&tss));
+        set_busy(&mut tss, true);
+        assert!(is_busy(&tss));
+        assert_eq!(tss.as_u64(), 0x0000_8b12_3450_0067);
+        set_busy(&mut tss, false);
+        assert_eq!(tss.as_u64(), 0x0000_8912_3450_0067);
+
+        let with_bitmap = tss_descriptor(0, size_of::<TaskStateSegmentWithIoBitmap>());
+        assert_eq!(with_bitmap.lower & 0xffff, 104 + 8193 - 1);
+
+        let gate = task_gate(SegmentSelector::new(5, Ring::Ring0));
+        assert_eq!(gate.as_u64(), 0x0000_8500_0028_0000);
+    }
+
+    #[test]
+    fn io_bitmap() {
+        extern crate std;
+        let mut tss =
+            std::boxed::Box::new(TaskStateSegmentWithIoBitmap::new(TaskStateSegment::new()));
+        let offset = tss.tss.iobp_offset;
+        assert_eq!(offset, 104);
+        assert!(!tss.is_allowed(0x3f8));
+        assert_eq!(tss.io_bitmap()[IO_BITMAP_SIZE - 1], 0xff);
+
+        tss.set_range_allowed(0x3f8, 8, true);
+        assert!(tss.is_allowed(0x3f8));
+        assert!(tss.is_allowed(0x3ff));
+        assert!(!tss.is_allowed(0x400));
+        assert_eq!(tss.io_bitmap()[0x3f8 / 8], 0);
+
+        tss.set_allowed(0x3f9, false);
+        assert!(!tss.is_allowed(0x3f9));
+        assert_eq!(tss.io_bitmap()[0x3f8 / 8], 0b10);
+
+        tss.set_allowed(0xffff, true);
+        assert!(tss.is_allowed(0xffff));
+        assert_eq!(tss.io_bitmap()[IO_BITMAP_SIZE - 1], 0xff);
+
+        tss.set_range_allowed(0xfff0, 0x10, true);
+        assert!(tss.is_allowed(0xfff0));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tss.set_range_allowed(0xffe0, 0x21, true)
+        }));
+        assert!(result.is_err());
+        assert!(!tss.is_allowed(0xffe0));
+    }
 }
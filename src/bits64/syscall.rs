@@ -14,10 +14,19 @@
 //! * Only values of class INTEGER or class MEMORY are passed to the kernel.
 //!
 //! This code is inspired by the syscall.rs (https://github.com/kmcallister/syscall.rs/) project.
+//!
+//! The kernel side is configured with [`enable_syscall`], which checks the GDT
+//! layout SYSCALL and SYSRET depend on (see [`SyscallSelectors`]) before
+//! programming `IA32_STAR`, `IA32_LSTAR`, `IA32_FMASK` and `IA32_EFER.SCE`.
 
 #[cfg(target_arch = "x86_64")]
 use core::arch::asm;
 
+use crate::bits64::rflags::RFlags;
+use crate::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
+use crate::segmentation::SegmentSelector;
+use crate::Ring;
+
 #[macro_export]
 macro_rules! syscall {
     ($arg0:expr) => {
@@ -220,3 +229,222 @@ pub unsafe fn syscall6(
     );
     ret
 }
+
+/// `IA32_EFER`: enable SYSCALL/SYSRET.
+const EFER_SCE: u64 = 1 << 0;
+
+/// RFLAGS cleared on SYSCALL by default: interrupts, single-stepping,
+/// direction, alignment checking and nested task.
+pub const DEFAULT_RFLAGS_MASK: RFlags = RFlags::from_bits_truncate(
+    RFlags::FLAGS_IF.bits()
+        | RFlags::FLAGS_TF.bits()
+        | RFlags::FLAGS_DF.bits()
+        | RFlags::FLAGS_AC.bits()
+        | RFlags::FLAGS_NT.bits(),
+);
+
+/// Reasons a GDT layout can't be used with SYSCALL/SYSRET.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SyscallError {
+    /// The selector refers to the LDT.
+    LdtSelector(SegmentSelector),
+    /// A kernel selector doesn't have RPL 0 or a user selector doesn't have
+    /// RPL 3.
+    InvalidRpl(SegmentSelector),
+    /// The kernel stack segment doesn't directly follow the kernel code
+    /// segment (SYSCALL loads SS with `STAR[47:32] + 8`).
+    KernelStackNotAfterCode,
+    /// The user stack segment doesn't directly precede the 64-bit user code
+    /// segment (SYSRET loads SS with `STAR[63:48] + 8` and CS with
+    /// `STAR[63:48] + 16`).
+    UserStackNotBeforeCode,
+    /// The 32-bit user code segment doesn't directly precede the user stack
+    /// segment (SYSRET to compatibility mode loads CS with `STAR[63:48]`).
+    UserCode32NotBeforeStack,
+}
+
+/// The segments SYSCALL and SYSRET switch between.
+///
+/// SYSCALL and SYSRET don't read the descriptors but derive the selectors
+/// from `IA32_STAR`, so the GDT entries must be ordered kernel code, kernel
+/// stack, (32-bit user code,) user stack, 64-bit user code.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SyscallSelectors {
+    /// Kernel code segment (RPL 0).
+    pub kernel_code: SegmentSelector,
+    /// Kernel stack segment (RPL 0).
+    pub kernel_stack: SegmentSelector,
+    /// Compatibility mode user code segment (RPL 3), if 32-bit processes
+    /// are supported.
+    pub user_code32: Option<SegmentSelector>,
+    /// User stack segment (RPL 3).
+    pub user_stack: SegmentSelector,
+    /// 64-bit user code segment (RPL 3).
+    pub user_code: SegmentSelector,
+}
+
+impl SyscallSelectors {
+    /// Check the selectors and compute the `IA32_STAR` value for them
+    /// (with a SYSCALL target of 0 for 32-bit legacy mode, which is unused).
+    pub fn star(&self) -> Result<u64, SyscallError> {
+        let kernel = [self.kernel_code, self.kernel_stack];
+        let user = [
+            Some(self.user_stack),
+            Some(self.user_code),
+            self.user_code32,
+        ];
+        for (selector, rpl) in kernel
+            .iter()
+            .map(|s| (*s, Ring::Ring0))
+            .chain(user.iter().flatten().map(|s| (*s, Ring::Ring3)))
+        {
+            if selector.contains(SegmentSelector::TI_LDT) {
+                return Err(SyscallError::LdtSelector(selector));
+            }
+            if selector.bits() & 0b11 != rpl as u16 {
+                return Err(SyscallError::InvalidRpl(selector));
+            }
+        }
+
+        if self.kernel_stack.index() != self.kernel_code.index() + 1 {
+            return Err(SyscallError::KernelStackNotAfterCode);
+        }
+        if self.user_code.index() != self.user_stack.index() + 1 {
+            return Err(SyscallError::UserStackNotBeforeCode);
+        }
+        if self.user_stack.index() == 0 {
+            return Err(SyscallError::UserCode32NotBeforeStack);
+        }
+        let sysret_base = SegmentSelector::new(self.user_stack.index() - 1, Ring::Ring3);
+        if let Some(user_code32) = self.user_code32 {
+            if user_code32 != sysret_base {
+                return Err(SyscallError::UserCode32NotBeforeStack);
+            }
+        }
+
+        Ok(((sysret_base.bits() as u64) << 48) | ((self.kernel_code.bits() as u64) << 32))
+    }
+}
+
+/// Enable SYSCALL/SYSRET with `entry` as the 64-bit mode SYSCALL target.
+///
+/// `rflags_mask` lists the RFLAGS bits cleared on SYSCALL, usually
+/// [`DEFAULT_RFLAGS_MASK`]. The compatibility mode target `IA32_CSTAR` is
+/// only used on AMD processors and left unchanged.
+///
+/// # Safety
+/// Needs CPL 0. `entry` must switch to a kernel stack before using it and
+/// the GDT must contain the segments described by `selectors`.
+pub unsafe fn enable_syscall(
+    selectors: &SyscallSelectors,
+    entry: u64,
+    rflags_mask: RFlags,
+) -> Result<(), SyscallError> {
+    let star = selectors.star()?;
+    wrmsr(IA32_STAR, star);
+    wrmsr(IA32_LSTAR, entry);
+    wrmsr(IA32_FMASK, rflags_mask.bits());
+    wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SCE);
+    Ok(())
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    /// Linux's GDT: kernel CS 0x10, kernel SS 0x18, user CS32 0x23, user SS
+    /// 0x2b, user CS 0x33.
+    fn linux() -> SyscallSelectors {
+        SyscallSelectors {
+            kernel_code: SegmentSelector::new(2, Ring::Ring0),
+            kernel_stack: SegmentSelector::new(3, Ring::Ring0),
+            user_code32: Some(SegmentSelector::new(4, Ring::Ring3)),
+            user_stack: SegmentSelector::new(5, Ring::Ring3),
+            user_code: SegmentSelector::new(6, Ring::Ring3),
+        }
+    }
+
+    #[test]
+    fn star() {
+        assert_eq!(linux().star(), Ok(0x0023_0010_0000_0000));
+
+        // Without compatibility mode the slot before the user stack is unused.
+        let selectors = SyscallSelectors {
+            kernel_code: SegmentSelector::new(1, Ring::Ring0),
+            kernel_stack: SegmentSelector::new(2, Ring::Ring0),
+            user_code32: None,
+            user_stack: SegmentSelector::new(3, Ring::Ring3),
+            user_code: SegmentSelector::new(4, Ring::Ring3),
+        };
+        assert_eq!(selectors.star(), Ok(0x0013_0008_0000_0000));
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        // The common "kernel code, kernel data, user code, user data" layout.
+        let selectors = SyscallSelectors {
+            kernel_code: SegmentSelector::new(1, Ring::Ring0),
+            kernel_stack: SegmentSelector::new(2, Ring::Ring0),
+            user_code32: None,
+            user_stack: SegmentSelector::new(4, Ring::Ring3),
+            user_code: SegmentSelector::new(3, Ring::Ring3),
+        };
+        assert_eq!(selectors.star(), Err(SyscallError::UserStackNotBeforeCode));
+
+        let selectors = SyscallSelectors {
+            kernel_stack: SegmentSelector::new(1, Ring::Ring0),
+            kernel_code: SegmentSelector::new(2, Ring::Ring0),
+            ..linux()
+        };
+        assert_eq!(selectors.star(), Err(SyscallError::KernelStackNotAfterCode));
+
+        let selectors = SyscallSelectors {
+            user_code32: Some(SegmentSelector::new(7, Ring::Ring3)),
+            ..linux()
+        };
+        assert_eq!(
+            selectors.star(),
+            Err(SyscallError::UserCode32NotBeforeStack)
+        );
+
+        let selectors = SyscallSelectors {
+            user_stack: SegmentSelector::new(0, Ring::Ring3),
+            user_code: SegmentSelector::new(1, Ring::Ring3),
+            user_code32: None,
+            ..linux()
+        };
+        assert_eq!(
+            selectors.star(),
+            Err(SyscallError::UserCode32NotBeforeStack)
+        );
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        let user_stack = SegmentSelector::new(5, Ring::Ring0);
+        let selectors = SyscallSelectors {
+            user_stack,
+            ..linux()
+        };
+        assert_eq!(selectors.star(), Err(SyscallError::InvalidRpl(user_stack)));
+
+        let kernel_code = SegmentSelector::new(2, Ring::Ring3);
+        let selectors = SyscallSelectors {
+            kernel_code,
+            ..linux()
+        };
+        assert_eq!(selectors.star(), Err(SyscallError::InvalidRpl(kernel_code)));
+
+        let ldt = SegmentSelector::new(3, Ring::Ring0) | SegmentSelector::TI_LDT;
+        let selectors = SyscallSelectors {
+            kernel_stack: ldt,
+            ..linux()
+        };
+        assert_eq!(selectors.star(), Err(SyscallError::LdtSelector(ldt)));
+    }
+
+    #[test]
+    fn rflags_mask() {
+        assert_eq!(DEFAULT_RFLAGS_MASK.bits(), 0x44700);
+    }
+}
@@ -5,6 +5,8 @@
 //!   the last debug exception.
 //! * The dr7 register enables or disables breakpoints and sets breakpoint
 //!   conditions.
+//! * The `IA32_DEBUGCTL` MSR enables branch recording (LBR, BTS) and
+//!   single-stepping on branches.
 //!
 //! See Intel Vol. 3a Chapter 17, "Debug, Branch, Profile, TSC ... Features"
 //!
@@ -19,6 +21,8 @@ use bitflags::bitflags;
 
 use core::arch::asm;
 
+use crate::msr::{rdmsr, wrmsr, IA32_DEBUGCTL};
+
 /// An array list of all available breakpoint registers.
 pub const BREAKPOINT_REGS: [Breakpoint; 4] = [
     Breakpoint::Dr0,
@@ -482,3 +486,53 @@ pub unsafe fn dr7() -> Dr7 {
 pub unsafe fn dr7_write(val: Dr7) {
     asm!("mov {}, %dr7", in(reg) val.0, options(att_syntax));
 }
+
+bitflags! {
+    /// Debug control MSR (`IA32_DEBUGCTL`) flags.
+    ///
+    /// See Intel Vol. 3b Section 17.4.1, "IA32_DEBUGCTL MSR".
+    pub struct DebugCtl: u64 {
+        /// Record branches, interrupts and exceptions in the LBR stack.
+        const LBR = 1 << 0;
+        /// Single-step on branches instead of instructions (with RFLAGS.TF).
+        const BTF = 1 << 1;
+        /// Raise #DB on split or uncacheable bus locks.
+        const BLD = 1 << 2;
+        /// Send branch trace messages (BTMs).
+        const TR = 1 << 6;
+        /// Store branch trace messages in the BTS buffer of the DS area.
+        const BTS = 1 << 7;
+        /// Raise an interrupt once the BTS buffer is full (circular buffer otherwise).
+        const BTINT = 1 << 8;
+        /// Do not store branches in the BTS buffer while CPL is 0.
+        const BTS_OFF_OS = 1 << 9;
+        /// Do not store branches in the BTS buffer while CPL is above 0.
+        const BTS_OFF_USR = 1 << 10;
+        /// Clear `LBR` when a PMI is delivered.
+        const FREEZE_LBRS_ON_PMI = 1 << 11;
+        /// Freeze the performance counters when a PMI is delivered.
+        const FREEZE_PERFMON_ON_PMI = 1 << 12;
+        /// Allow the uncore PMU to forward interrupts to this processor.
+        const ENABLE_UNCORE_PMI = 1 << 13;
+        /// Freeze LBR, BTS and performance counters while in SMM.
+        const FREEZE_WHILE_SMM = 1 << 14;
+        /// Enable advanced debugging of RTM regions.
+        const RTM_DEBUG = 1 << 15;
+    }
+}
+
+/// Read `IA32_DEBUGCTL`.
+///
+/// # Safety
+/// Needs CPL 0.
+pub unsafe fn debugctl() -> DebugCtl {
+    DebugCtl::from_bits_truncate(rdmsr(IA32_DEBUGCTL))
+}
+
+/// Write `IA32_DEBUGCTL`.
+///
+/// # Safety
+/// Needs CPL 0. Setting unsupported flags raises #GP.
+pub unsafe fn debugctl_write(val: DebugCtl) {
+    wrmsr(IA32_DEBUGCTL, val.bits());
+}
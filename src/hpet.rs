@@ -0,0 +1,693 @@
+//! High Precision Event Timer.
+//!
+//! The HPET is a memory-mapped block with a free-running main counter and
+//! up to 32 comparators that raise interrupts through the I/O APIC, the
+//! legacy IRQ 0/8 lines or as MSI (FSB) messages. Its base address is found
+//! in the ACPI HPET table.
+//!
+//! See the IA-PC HPET Specification 1.0a, Section 2.3.
+
+use bit_field::BitField;
+
+use crate::apic::msi::MsiMessage;
+use crate::apic::TriggerMode;
+
+/// General capabilities and ID register.
+pub const CAPABILITIES: usize = 0x000;
+/// General configuration register.
+pub const CONFIGURATION: usize = 0x010;
+/// General interrupt status register.
+pub const INTERRUPT_STATUS: usize = 0x020;
+/// Main counter value register.
+pub const MAIN_COUNTER: usize = 0x0f0;
+/// Configuration and capability register of timer 0, the registers of timer
+/// `n` start at `TIMER0 + n * TIMER_STRIDE`.
+pub const TIMER0: usize = 0x100;
+/// Distance between the register sets of two timers.
+pub const TIMER_STRIDE: usize = 0x20;
+/// Size of the register block.
+pub const REGION_SIZE: usize = 0x400;
+
+/// Offset of the comparator value within a timer's registers.
+const TIMER_COMPARATOR: usize = 0x08;
+/// Offset of the FSB interrupt route within a timer's registers.
+const TIMER_FSB_ROUTE: usize = 0x10;
+
+/// General configuration: the main counter runs and interrupts are enabled.
+const ENABLE: u32 = 1 << 0;
+/// General configuration: timers 0 and 1 are routed to IRQ 0 and IRQ 8.
+const LEGACY_REPLACEMENT: u32 = 1 << 1;
+
+/// Timer configuration: level-triggered interrupt.
+const TIMER_LEVEL: u32 = 1 << 1;
+/// Timer configuration: interrupt enabled.
+const TIMER_ENABLE: u32 = 1 << 2;
+/// Timer configuration: periodic mode.
+const TIMER_PERIODIC: u32 = 1 << 3;
+/// Timer capability: periodic mode supported.
+const TIMER_PERIODIC_CAPABLE: u32 = 1 << 4;
+/// Timer capability: 64-bit comparator.
+const TIMER_64BIT_CAPABLE: u32 = 1 << 5;
+/// Timer configuration: the next comparator write sets the accumulator.
+const TIMER_VALUE_SET: u32 = 1 << 6;
+/// Timer configuration: operate a 64-bit timer in 32-bit mode.
+const TIMER_32BIT_MODE: u32 = 1 << 8;
+/// Timer configuration: deliver interrupts as FSB messages.
+const TIMER_FSB_ENABLE: u32 = 1 << 14;
+/// Timer capability: FSB interrupt delivery supported.
+const TIMER_FSB_CAPABLE: u32 = 1 << 15;
+
+/// Femtoseconds per nanosecond.
+const FS_PER_NS: u128 = 1_000_000;
+/// Femtoseconds per second.
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+/// Largest counter period allowed by the specification (100 ns).
+const MAX_PERIOD_FS: u32 = 100_000_000;
+
+/// Errors when configuring the HPET.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HpetError {
+    /// The timer does not exist.
+    InvalidTimer(u8),
+    /// The timer does not support periodic mode.
+    PeriodicUnsupported,
+    /// The timer does not support FSB interrupt delivery.
+    FsbUnsupported,
+    /// The timer can't be routed to this I/O APIC input.
+    RouteUnsupported(u8),
+    /// Legacy replacement routing is not supported (or only for timers 0
+    /// and 1).
+    LegacyUnsupported,
+}
+
+/// Decoded general capabilities and ID register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Revision of the implemented function.
+    pub revision: u8,
+    /// Number of timers (comparators).
+    pub timers: u8,
+    /// The main counter is 64 bits wide.
+    pub counter_64bit: bool,
+    /// Legacy replacement routing is supported.
+    pub legacy_replacement: bool,
+    /// PCI vendor ID of the implementation.
+    pub vendor_id: u16,
+    /// Period of the main counter in femtoseconds.
+    pub period_fs: u32,
+}
+
+impl Capabilities {
+    /// Decode the general capabilities and ID register.
+    pub fn from_raw(raw: u64) -> Capabilities {
+        Capabilities {
+            revision: raw.get_bits(0..8) as u8,
+            timers: raw.get_bits(8..13) as u8 + 1,
+            counter_64bit: raw.get_bit(13),
+            legacy_replacement: raw.get_bit(15),
+            vendor_id: raw.get_bits(16..32) as u16,
+            period_fs: raw.get_bits(32..64) as u32,
+        }
+    }
+
+    /// Main counter frequency in Hz (0 for an invalid period).
+    pub fn frequency(&self) -> u64 {
+        if self.period_fs == 0 || self.period_fs > MAX_PERIOD_FS {
+            0
+        } else {
+            FS_PER_SECOND / self.period_fs as u64
+        }
+    }
+
+    /// Convert main counter ticks to nanoseconds.
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FS_PER_NS) as u64
+    }
+
+    /// Convert nanoseconds to main counter ticks (rounded up, at least one
+    /// tick for a non-zero duration).
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        let fs = ns as u128 * FS_PER_NS;
+        let period = self.period_fs.max(1) as u128;
+        fs.div_ceil(period) as u64
+    }
+}
+
+/// Decoded capabilities of a timer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimerCapabilities {
+    /// Periodic mode is supported.
+    pub periodic: bool,
+    /// The comparator is 64 bits wide.
+    pub comparator_64bit: bool,
+    /// Interrupts can be delivered as FSB (MSI) messages.
+    pub fsb: bool,
+    /// Bitmap of the I/O APIC inputs the timer can be routed to.
+    pub routes: u32,
+}
+
+impl TimerCapabilities {
+    /// True if the timer can be routed to I/O APIC input `input`.
+    pub fn can_route_to(&self, input: u8) -> bool {
+        input < 32 && self.routes.get_bit(input as usize)
+    }
+}
+
+/// Operating mode of a timer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TimerMode {
+    /// Interrupt once when the main counter matches the comparator.
+    OneShot,
+    /// Interrupt every period, the comparator advances automatically.
+    Periodic,
+}
+
+/// Where a timer's interrupt is delivered.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TimerRouting {
+    /// To I/O APIC input `input`.
+    IoApic {
+        input: u8,
+        trigger_mode: TriggerMode,
+    },
+    /// To IRQ 0 (timer 0) or IRQ 8 (timer 1) with legacy replacement
+    /// routing, see [`Hpet::set_legacy_replacement`].
+    Legacy,
+    /// As an MSI message written by the HPET.
+    Fsb(MsiMessage),
+}
+
+/// Extends a 32-bit main counter to 64 bits.
+///
+/// Works as long as [`CounterExtender::update`] is called at least once per
+/// counter wraparound (about five minutes at 14.318 MHz).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CounterExtender {
+    last: u64,
+}
+
+impl CounterExtender {
+    /// Create an extender starting at counter value zero.
+    pub const fn new() -> CounterExtender {
+        CounterExtender { last: 0 }
+    }
+
+    /// Feed a new 32-bit counter value, returns the extended 64-bit value.
+    pub fn update(&mut self, raw: u32) -> u64 {
+        let elapsed = raw.wrapping_sub(self.last as u32);
+        self.last += elapsed as u64;
+        self.last
+    }
+}
+
+/// Program the comparator of a periodic timer at `offset` to fire at `first`
+/// and then every `period` ticks.
+///
+/// A comparator write with value-set sets the accumulator (the first
+/// deadline); value-set clears itself after each write, so the writes
+/// without it only set the period.
+fn write_periodic<W: FnMut(usize, u32)>(
+    mut write: W,
+    offset: usize,
+    config: u32,
+    first: u64,
+    period: u64,
+) {
+    write(offset, config | TIMER_VALUE_SET);
+    write(offset + TIMER_COMPARATOR, first as u32);
+    write(offset, config | TIMER_VALUE_SET);
+    write(offset + TIMER_COMPARATOR + 4, (first >> 32) as u32);
+    write(offset + TIMER_COMPARATOR, period as u32);
+    write(offset + TIMER_COMPARATOR + 4, (period >> 32) as u32);
+}
+
+/// Driver for a memory-mapped HPET register block.
+pub struct Hpet<'a> {
+    region: &'a mut [u32],
+    capabilities: Capabilities,
+}
+
+impl<'a> Hpet<'a> {
+    /// Attach to the HPET registers in `region`.
+    ///
+    /// `region` must cover the [`REGION_SIZE`] bytes of the register block
+    /// and be mapped uncached.
+    pub fn new(region: &'a mut [u32]) -> Hpet<'a> {
+        assert!(region.len() * 4 >= REGION_SIZE);
+        let mut hpet = Hpet {
+            region,
+            capabilities: Capabilities::from_raw(0),
+        };
+        hpet.capabilities = Capabilities::from_raw(hpet.read64(CAPABILITIES));
+        hpet
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(&self.region[offset / 4]) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile(&mut self.region[offset / 4], value) }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        ((self.read(offset + 4) as u64) << 32) | self.read(offset) as u64
+    }
+
+    fn write64(&mut self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+
+    fn timer_offset(&self, timer: u8) -> Result<usize, HpetError> {
+        if timer < self.capabilities.timers {
+            Ok(TIMER0 + timer as usize * TIMER_STRIDE)
+        } else {
+            Err(HpetError::InvalidTimer(timer))
+        }
+    }
+
+    /// Capabilities read when attaching.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// True if the main counter is running.
+    pub fn is_enabled(&self) -> bool {
+        self.read(CONFIGURATION) & ENABLE != 0
+    }
+
+    /// Start or halt the main counter (and timer interrupts).
+    pub fn set_enabled(&mut self, enabled: bool) {
+        let config = self.read(CONFIGURATION) & !ENABLE;
+        self.write(CONFIGURATION, config | if enabled { ENABLE } else { 0 });
+    }
+
+    /// True if legacy replacement routing is enabled.
+    pub fn legacy_replacement(&self) -> bool {
+        self.read(CONFIGURATION) & LEGACY_REPLACEMENT != 0
+    }
+
+    /// Route timer 0 to IRQ 0 (I/O APIC input 2) and timer 1 to IRQ 8
+    /// instead of the PIT and RTC.
+    pub fn set_legacy_replacement(&mut self, enabled: bool) -> Result<(), HpetError> {
+        if enabled && !self.capabilities.legacy_replacement {
+            return Err(HpetError::LegacyUnsupported);
+        }
+        let config = self.read(CONFIGURATION) & !LEGACY_REPLACEMENT;
+        let legacy = if enabled { LEGACY_REPLACEMENT } else { 0 };
+        self.write(CONFIGURATION, config | legacy);
+        Ok(())
+    }
+
+    /// Read the main counter.
+    ///
+    /// A 64-bit counter is read as two halves, retrying if the upper half
+    /// changed in between. Use [`CounterExtender`] or [`Hpet::elapsed`] for
+    /// 32-bit counters.
+    pub fn counter(&self) -> u64 {
+        if !self.capabilities.counter_64bit {
+            return self.read(MAIN_COUNTER) as u64;
+        }
+        loop {
+            let high = self.read(MAIN_COUNTER + 4);
+            let low = self.read(MAIN_COUNTER);
+            if self.read(MAIN_COUNTER + 4) == high {
+                return ((high as u64) << 32) | low as u64;
+            }
+        }
+    }
+
+    /// Set the main counter, should only be done while it is halted.
+    pub fn set_counter(&mut self, value: u64) {
+        self.write64(MAIN_COUNTER, value);
+    }
+
+    /// Ticks from counter value `start` to `end`, accounting for one
+    /// wraparound of the counter.
+    pub fn elapsed(&self, start: u64, end: u64) -> u64 {
+        if self.capabilities.counter_64bit {
+            end.wrapping_sub(start)
+        } else {
+            (end as u32).wrapping_sub(start as u32) as u64
+        }
+    }
+
+    /// Convert main counter ticks to nanoseconds.
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        self.capabilities.ticks_to_ns(ticks)
+    }
+
+    /// Convert nanoseconds to main counter ticks.
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        self.capabilities.ns_to_ticks(ns)
+    }
+
+    /// Capabilities of `timer`.
+    pub fn timer_capabilities(&self, timer: u8) -> Result<TimerCapabilities, HpetError> {
+        let offset = self.timer_offset(timer)?;
+        let config = self.read(offset);
+        Ok(TimerCapabilities {
+            periodic: config & TIMER_PERIODIC_CAPABLE != 0,
+            comparator_64bit: config & TIMER_64BIT_CAPABLE != 0,
+            fsb: config & TIMER_FSB_CAPABLE != 0,
+            routes: self.read(offset + 4),
+        })
+    }
+
+    /// Program `timer` and enable its interrupt.
+    ///
+    /// In one-shot mode the interrupt fires when the main counter reaches
+    /// `value`; in periodic mode `value` is the period in ticks and the
+    /// first interrupt fires one period from now.
+    pub fn configure_timer(
+        &mut self,
+        timer: u8,
+        mode: TimerMode,
+        value: u64,
+        routing: TimerRouting,
+    ) -> Result<(), HpetError> {
+        let offset = self.timer_offset(timer)?;
+        let capabilities = self.timer_capabilities(timer)?;
+        if mode == TimerMode::Periodic && !capabilities.periodic {
+            return Err(HpetError::PeriodicUnsupported);
+        }
+
+        // Keep the read-only and reserved bits, clear everything we set.
+        let mut config = self.read(offset)
+            & !(TIMER_LEVEL
+                | TIMER_ENABLE
+                | TIMER_PERIODIC
+                | TIMER_VALUE_SET
+                | TIMER_32BIT_MODE
+                | TIMER_FSB_ENABLE
+                | (0x1f << 9));
+        match routing {
+            TimerRouting::IoApic {
+                input,
+                trigger_mode,
+            } => {
+                if !capabilities.can_route_to(input) {
+                    return Err(HpetError::RouteUnsupported(input));
+                }
+                config |= (input as u32) << 9;
+                if trigger_mode == TriggerMode::Level {
+                    config |= TIMER_LEVEL;
+                }
+            }
+            TimerRouting::Legacy => {
+                if timer > 1 || !self.legacy_replacement() {
+                    return Err(HpetError::LegacyUnsupported);
+                }
+            }
+            TimerRouting::Fsb(message) => {
+                if !capabilities.fsb {
+                    return Err(HpetError::FsbUnsupported);
+                }
+                self.write(offset + TIMER_FSB_ROUTE, message.data);
+                self.write(offset + TIMER_FSB_ROUTE + 4, message.address_lo());
+                config |= TIMER_FSB_ENABLE;
+            }
+        }
+
+        // Disable the timer while the comparator is updated.
+        self.write(offset, config);
+        match mode {
+            TimerMode::OneShot => {
+                self.write64(offset + TIMER_COMPARATOR, value);
+            }
+            TimerMode::Periodic => {
+                config |= TIMER_PERIODIC;
+                let first = self.counter().wrapping_add(value);
+                write_periodic(|o, v| self.write(o, v), offset, config, first, value);
+            }
+        }
+        self.write(offset, config | TIMER_ENABLE);
+        Ok(())
+    }
+
+    /// Disable the interrupt of `timer`.
+    pub fn disable_timer(&mut self, timer: u8) -> Result<(), HpetError> {
+        let offset = self.timer_offset(timer)?;
+        let config = self.read(offset);
+        self.write(offset, config & !(TIMER_ENABLE | TIMER_PERIODIC));
+        Ok(())
+    }
+
+    /// Current comparator value of `timer`.
+    pub fn comparator(&self, timer: u8) -> Result<u64, HpetError> {
+        let offset = self.timer_offset(timer)?;
+        Ok(self.read64(offset + TIMER_COMPARATOR))
+    }
+
+    /// Bitmap of timers with an active level-triggered interrupt.
+    pub fn interrupt_status(&self) -> u32 {
+        self.read(INTERRUPT_STATUS)
+    }
+
+    /// Clear the level-triggered interrupt status of `timer`.
+    pub fn acknowledge(&mut self, timer: u8) -> Result<(), HpetError> {
+        self.timer_offset(timer)?;
+        self.write(INTERRUPT_STATUS, 1 << timer);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use crate::apic::msi::MsiMessageBuilder;
+    use crate::apic::ApicId;
+
+    extern crate std;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Register block of QEMU's HPET: three 64-bit timers on a 100 MHz
+    /// 64-bit counter with legacy replacement support.
+    fn qemu() -> Vec<u32> {
+        let mut region = vec![0u32; REGION_SIZE / 4];
+        region[0] = 0x8086_a201;
+        region[1] = 10_000_000;
+        for timer in 0..3 {
+            let index = (TIMER0 + timer * TIMER_STRIDE) / 4;
+            region[index] = 0x30 | TIMER_FSB_CAPABLE;
+            region[index + 1] = 0x00ff_0004;
+            region[index + 2] = u32::MAX;
+            region[index + 3] = u32::MAX;
+        }
+        region
+    }
+
+    #[test]
+    fn capabilities() {
+        let caps = Capabilities::from_raw(0x0098_9680_8086_a201);
+        assert_eq!(caps.revision, 1);
+        assert_eq!(caps.timers, 3);
+        assert!(caps.counter_64bit);
+        assert!(caps.legacy_replacement);
+        assert_eq!(caps.vendor_id, 0x8086);
+        assert_eq!(caps.period_fs, 10_000_000);
+        assert_eq!(caps.frequency(), 100_000_000);
+
+        // ICH: 14.318 MHz, 32-bit counter.
+        let ich = Capabilities::from_raw(0x0429_b17f_8086_8201);
+        assert!(!ich.counter_64bit);
+        assert_eq!(ich.frequency(), 14_318_179);
+        assert_eq!(ich.ticks_to_ns(14_318_180), 1_000_000_004);
+        assert_eq!(ich.ns_to_ticks(1_000), 15);
+        assert_eq!(ich.ns_to_ticks(0), 0);
+
+        assert_eq!(Capabilities::from_raw(0).frequency(), 0);
+    }
+
+    #[test]
+    fn counter() {
+        let mut region = qemu();
+        region[MAIN_COUNTER / 4] = 0xdead_beef;
+        region[MAIN_COUNTER / 4 + 1] = 0x1;
+        let mut hpet = Hpet::new(&mut region);
+        assert_eq!(hpet.capabilities().timers, 3);
+        assert_eq!(hpet.counter(), 0x1_dead_beef);
+        assert_eq!(hpet.elapsed(u64::MAX, 4), 5);
+
+        assert!(!hpet.is_enabled());
+        hpet.set_enabled(true);
+        assert!(hpet.is_enabled());
+        hpet.set_counter(0x2_0000_0001);
+        assert_eq!(hpet.counter(), 0x2_0000_0001);
+        assert_eq!(hpet.ticks_to_ns(250), 2_500);
+        assert_eq!(hpet.ns_to_ticks(2_501), 251);
+
+        let mut region = qemu();
+        region[0] &= !(1 << 13);
+        region[MAIN_COUNTER / 4] = 10;
+        region[MAIN_COUNTER / 4 + 1] = 0x1234;
+        let hpet = Hpet::new(&mut region);
+        assert_eq!(hpet.counter(), 10);
+        assert_eq!(hpet.elapsed(0xffff_fff0, 0x10), 0x20);
+    }
+
+    #[test]
+    fn counter_extender() {
+        let mut counter = CounterExtender::new();
+        assert_eq!(counter.update(100), 100);
+        assert_eq!(counter.update(0xffff_0000), 0xffff_0000);
+        assert_eq!(counter.update(5), 0x1_0000_0005);
+        assert_eq!(counter.update(0x8000_0000), 0x1_8000_0000);
+        assert_eq!(counter.update(0x10), 0x2_0000_0010);
+    }
+
+    #[test]
+    fn one_shot() {
+        let mut region = qemu();
+        let mut hpet = Hpet::new(&mut region);
+        let routing = TimerRouting::IoApic {
+            input: 2,
+            trigger_mode: TriggerMode::Edge,
+        };
+        hpet.configure_timer(1, TimerMode::OneShot, 0x1_0000_0000, routing)
+            .unwrap();
+        assert_eq!(hpet.comparator(1), Ok(0x1_0000_0000));
+        assert_eq!(
+            hpet.configure_timer(1, TimerMode::OneShot, 0, TimerRouting::Legacy),
+            Err(HpetError::LegacyUnsupported)
+        );
+        assert_eq!(
+            hpet.configure_timer(3, TimerMode::OneShot, 0, routing),
+            Err(HpetError::InvalidTimer(3))
+        );
+        assert_eq!(
+            hpet.configure_timer(
+                0,
+                TimerMode::OneShot,
+                0,
+                TimerRouting::IoApic {
+                    input: 3,
+                    trigger_mode: TriggerMode::Level,
+                }
+            ),
+            Err(HpetError::RouteUnsupported(3))
+        );
+        hpet.acknowledge(2).unwrap();
+        drop(hpet);
+
+        let timer1 = (TIMER0 + TIMER_STRIDE) / 4;
+        assert_eq!(
+            region[timer1],
+            0x30 | TIMER_FSB_CAPABLE | (2 << 9) | TIMER_ENABLE
+        );
+        assert_eq!(region[INTERRUPT_STATUS / 4], 1 << 2);
+    }
+
+    /// Comparator of a periodic timer as modelled by QEMU (`hw/timer/hpet.c`).
+    #[derive(Debug, Default)]
+    struct PeriodicTimer {
+        config: u32,
+        accumulator: u64,
+        period: u64,
+    }
+
+    impl PeriodicTimer {
+        fn write(&mut self, offset: usize, value: u32) {
+            let half = match offset - TIMER0 {
+                0 => {
+                    self.config = value;
+                    return;
+                }
+                TIMER_COMPARATOR => 0..32,
+                _ => 32..64,
+            };
+            if self.config & TIMER_VALUE_SET != 0 {
+                self.accumulator.set_bits(half.clone(), value as u64);
+            }
+            self.period.set_bits(half, value as u64);
+            self.config &= !TIMER_VALUE_SET;
+        }
+    }
+
+    #[test]
+    fn periodic_value_set() {
+        let mut timer = PeriodicTimer::default();
+        let first = 0x1_0000_0200;
+        write_periodic(
+            |o, v| timer.write(o, v),
+            TIMER0,
+            TIMER_PERIODIC,
+            first,
+            0x3_0000_0100,
+        );
+        assert_eq!(timer.accumulator, first);
+        assert_eq!(timer.period, 0x3_0000_0100);
+        assert_eq!(timer.config, TIMER_PERIODIC);
+    }
+
+    #[test]
+    fn periodic_legacy() {
+        let mut region = qemu();
+        region[MAIN_COUNTER / 4] = 1000;
+        let mut hpet = Hpet::new(&mut region);
+        hpet.set_legacy_replacement(true).unwrap();
+        assert!(hpet.legacy_replacement());
+        hpet.configure_timer(0, TimerMode::Periodic, 100_000, TimerRouting::Legacy)
+            .unwrap();
+        hpet.disable_timer(2).unwrap();
+        assert_eq!(
+            hpet.configure_timer(2, TimerMode::OneShot, 0, TimerRouting::Legacy),
+            Err(HpetError::LegacyUnsupported)
+        );
+        drop(hpet);
+
+        // The last comparator writes set the period.
+        assert_eq!(region[TIMER0 / 4 + 2], 100_000);
+        assert_eq!(region[TIMER0 / 4 + 3], 0);
+        assert_eq!(
+            region[TIMER0 / 4],
+            0x30 | TIMER_FSB_CAPABLE | TIMER_PERIODIC | TIMER_ENABLE
+        );
+        assert_eq!(region[CONFIGURATION / 4], LEGACY_REPLACEMENT);
+
+        let mut region = qemu();
+        region[0] &= !(1 << 15);
+        region[TIMER0 / 4] &= !TIMER_PERIODIC_CAPABLE;
+        let mut hpet = Hpet::new(&mut region);
+        assert_eq!(
+            hpet.set_legacy_replacement(true),
+            Err(HpetError::LegacyUnsupported)
+        );
+        let routing = TimerRouting::IoApic {
+            input: 2,
+            trigger_mode: TriggerMode::Edge,
+        };
+        assert_eq!(
+            hpet.configure_timer(0, TimerMode::Periodic, 10, routing),
+            Err(HpetError::PeriodicUnsupported)
+        );
+    }
+
+    #[test]
+    fn fsb() {
+        let message = MsiMessageBuilder::new(0x40, ApicId::XApic(1)).finish();
+        let mut region = qemu();
+        let mut hpet = Hpet::new(&mut region);
+        assert!(hpet.timer_capabilities(2).unwrap().fsb);
+        hpet.configure_timer(2, TimerMode::OneShot, 500, TimerRouting::Fsb(message))
+            .unwrap();
+        drop(hpet);
+
+        let timer2 = (TIMER0 + 2 * TIMER_STRIDE) / 4;
+        assert_eq!(region[timer2 + 4], message.data);
+        assert_eq!(region[timer2 + 5], message.address_lo());
+        assert_eq!(
+            region[timer2],
+            0x30 | TIMER_FSB_CAPABLE | TIMER_FSB_ENABLE | TIMER_ENABLE
+        );
+
+        region[timer2] &= !TIMER_FSB_CAPABLE;
+        let mut hpet = Hpet::new(&mut region);
+        assert_eq!(
+            hpet.configure_timer(2, TimerMode::OneShot, 0, TimerRouting::Fsb(message)),
+            Err(HpetError::FsbUnsupported)
+        );
+    }
+}
@@ -1,6 +1,7 @@
 //! I/O port functionality.
 
 use core::arch::asm;
+use core::marker::PhantomData;
 
 /// Write 8 bits to port
 ///
@@ -62,6 +63,380 @@ pub unsafe fn inl(port: u16) -> u32 {
     ret
 }
 
+/// Read 8-bit values from port into `buf` (`rep insb`).
+///
+/// # Safety
+/// Needs IO privileges.
+#[inline]
+pub unsafe fn insb(port: u16, buf: &mut [u8]) {
+    asm!(
+        "rep insb",
+        in("dx") port,
+        inout("edi") buf.as_mut_ptr() => _,
+        inout("ecx") buf.len() => _,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Read 16-bit values from port into `buf` (`rep insw`).
+///
+/// # Safety
+/// Needs IO privileges.
+#[inline]
+pub unsafe fn insw(port: u16, buf: &mut [u16]) {
+    asm!(
+        "rep insw",
+        in("dx") port,
+        inout("edi") buf.as_mut_ptr() => _,
+        inout("ecx") buf.len() => _,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Read 32-bit values from port into `buf` (`rep insd`).
+///
+/// # Safety
+/// Needs IO privileges.
+#[inline]
+pub unsafe fn insd(port: u16, buf: &mut [u32]) {
+    asm!(
+        "rep insd",
+        in("dx") port,
+        inout("edi") buf.as_mut_ptr() => _,
+        inout("ecx") buf.len() => _,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Write the 8-bit values in `buf` to port (`rep outsb`).
+///
+/// # Safety
+/// Needs IO privileges.
+#[inline]
+pub unsafe fn outsb(port: u16, buf: &[u8]) {
+    asm!(
+        "rep outsb",
+        in("dx") port,
+        inout("esi") buf.as_ptr() => _,
+        inout("ecx") buf.len() => _,
+        options(nostack, preserves_flags, readonly)
+    );
+}
+
+/// Write the 16-bit values in `buf` to port (`rep outsw`).
+///
+/// # Safety
+/// Needs IO privileges.
+#[inline]
+pub unsafe fn outsw(port: u16, buf: &[u16]) {
+    asm!(
+        "rep outsw",
+        in("dx") port,
+        inout("esi") buf.as_ptr() => _,
+        inout("ecx") buf.len() => _,
+        options(nostack, preserves_flags, readonly)
+    );
+}
+
+/// Write the 32-bit values in `buf` to port (`rep outsd`).
+///
+/// # Safety
+/// Needs IO privileges.
+#[inline]
+pub unsafe fn outsd(port: u16, buf: &[u32]) {
+    asm!(
+        "rep outsd",
+        in("dx") port,
+        inout("esi") buf.as_ptr() => _,
+        inout("ecx") buf.len() => _,
+        options(nostack, preserves_flags, readonly)
+    );
+}
+
+/// A value that can be transferred with port I/O (`u8`, `u16` or `u32`).
+pub trait PortValue: Copy {
+    /// Read a value from `port`.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    unsafe fn read_from_port(port: u16) -> Self;
+
+    /// Write `value` to `port`.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    unsafe fn write_to_port(port: u16, value: Self);
+
+    /// Read values from `port` into `buf`.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    unsafe fn read_slice_from_port(port: u16, buf: &mut [Self]);
+
+    /// Write the values in `buf` to `port`.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    unsafe fn write_slice_to_port(port: u16, buf: &[Self]);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_from_port(port: u16) -> u8 {
+        inb(port)
+    }
+
+    unsafe fn write_to_port(port: u16, value: u8) {
+        outb(port, value)
+    }
+
+    unsafe fn read_slice_from_port(port: u16, buf: &mut [u8]) {
+        insb(port, buf)
+    }
+
+    unsafe fn write_slice_to_port(port: u16, buf: &[u8]) {
+        outsb(port, buf)
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from_port(port: u16) -> u16 {
+        inw(port)
+    }
+
+    unsafe fn write_to_port(port: u16, value: u16) {
+        outw(port, value)
+    }
+
+    unsafe fn read_slice_from_port(port: u16, buf: &mut [u16]) {
+        insw(port, buf)
+    }
+
+    unsafe fn write_slice_to_port(port: u16, buf: &[u16]) {
+        outsw(port, buf)
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from_port(port: u16) -> u32 {
+        inl(port)
+    }
+
+    unsafe fn write_to_port(port: u16, value: u32) {
+        outl(port, value)
+    }
+
+    unsafe fn read_slice_from_port(port: u16, buf: &mut [u32]) {
+        insd(port, buf)
+    }
+
+    unsafe fn write_slice_to_port(port: u16, buf: &[u32]) {
+        outsd(port, buf)
+    }
+}
+
+/// An I/O port that is read and written with values of type `T`.
+#[derive(Debug)]
+pub struct Port<T: PortValue> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    /// Create a port at address `port`.
+    pub const fn new(port: u16) -> Port<T> {
+        Port {
+            port,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Address of the port.
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Read a value from the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, reading may have side effects on the device.
+    #[inline]
+    pub unsafe fn read(&mut self) -> T {
+        T::read_from_port(self.port)
+    }
+
+    /// Write a value to the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, writing may have side effects on the device.
+    #[inline]
+    pub unsafe fn write(&mut self, value: T) {
+        T::write_to_port(self.port, value)
+    }
+
+    /// Fill `buf` with values read from the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, reading may have side effects on the device.
+    #[inline]
+    pub unsafe fn read_slice(&mut self, buf: &mut [T]) {
+        T::read_slice_from_port(self.port, buf)
+    }
+
+    /// Write all values in `buf` to the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, writing may have side effects on the device.
+    #[inline]
+    pub unsafe fn write_slice(&mut self, buf: &[T]) {
+        T::write_slice_to_port(self.port, buf)
+    }
+}
+
+/// An I/O port that can only be read.
+#[derive(Debug)]
+pub struct PortReadOnly<T: PortValue> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> PortReadOnly<T> {
+    /// Create a read-only port at address `port`.
+    pub const fn new(port: u16) -> PortReadOnly<T> {
+        PortReadOnly {
+            port,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Address of the port.
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Read a value from the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, reading may have side effects on the device.
+    #[inline]
+    pub unsafe fn read(&mut self) -> T {
+        T::read_from_port(self.port)
+    }
+
+    /// Fill `buf` with values read from the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, reading may have side effects on the device.
+    #[inline]
+    pub unsafe fn read_slice(&mut self, buf: &mut [T]) {
+        T::read_slice_from_port(self.port, buf)
+    }
+}
+
+/// An I/O port that can only be written.
+#[derive(Debug)]
+pub struct PortWriteOnly<T: PortValue> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> PortWriteOnly<T> {
+    /// Create a write-only port at address `port`.
+    pub const fn new(port: u16) -> PortWriteOnly<T> {
+        PortWriteOnly {
+            port,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Address of the port.
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Write a value to the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, writing may have side effects on the device.
+    #[inline]
+    pub unsafe fn write(&mut self, value: T) {
+        T::write_to_port(self.port, value)
+    }
+
+    /// Write all values in `buf` to the port.
+    ///
+    /// # Safety
+    /// Needs IO privileges, writing may have side effects on the device.
+    #[inline]
+    pub unsafe fn write_slice(&mut self, buf: &[T]) {
+        T::write_slice_to_port(self.port, buf)
+    }
+}
+
+/// Access to I/O ports.
+///
+/// Device drivers in this crate (e.g., [`crate::pic8259`]) do port I/O through
+/// this trait, so they can be tested with a backend that records or emulates
+/// the device. [`NativePortIo`] uses the `in` and `out` instructions.
+pub trait PortIo {
+    /// Read 8 bits from `port`.
+    fn read_u8(&mut self, port: u16) -> u8;
+
+    /// Write 8 bits to `port`.
+    fn write_u8(&mut self, port: u16, val: u8);
+
+    /// Read 16 bits from `port`.
+    fn read_u16(&mut self, port: u16) -> u16;
+
+    /// Write 16 bits to `port`.
+    fn write_u16(&mut self, port: u16, val: u16);
+
+    /// Read 32 bits from `port`.
+    fn read_u32(&mut self, port: u16) -> u32;
+
+    /// Write 32 bits to `port`.
+    fn write_u32(&mut self, port: u16, val: u32);
+}
+
+/// Port I/O with the `in` and `out` instructions.
+#[derive(Debug)]
+pub struct NativePortIo {
+    _private: (),
+}
+
+impl NativePortIo {
+    /// # Safety
+    /// Needs IO privileges for all ports accessed through it.
+    pub const unsafe fn new() -> NativePortIo {
+        NativePortIo { _private: () }
+    }
+}
+
+impl PortIo for NativePortIo {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        unsafe { inb(port) }
+    }
+
+    fn write_u8(&mut self, port: u16, val: u8) {
+        unsafe { outb(port, val) }
+    }
+
+    fn read_u16(&mut self, port: u16) -> u16 {
+        unsafe { inw(port) }
+    }
+
+    fn write_u16(&mut self, port: u16, val: u16) {
+        unsafe { outw(port, val) }
+    }
+
+    fn read_u32(&mut self, port: u16) -> u32 {
+        unsafe { inl(port) }
+    }
+
+    fn write_u32(&mut self, port: u16, val: u32) {
+        unsafe { outl(port, val) }
+    }
+}
+
 #[cfg(all(test, feature = "vmtest"))]
 mod x86testing {
     use super::*;
@@ -139,4 +514,81 @@ mod x86testing {
             );
         }
     }
+
+    #[x86test(ioport(0x0, 0xaf))]
+    fn check_outsb() {
+        unsafe {
+            outsb(0x0, &[0xaf, 0xaf, 0xaf]);
+        }
+    }
+
+    #[x86test(ioport(0x1, 0xad))]
+    fn check_insb() {
+        let mut buf = [0u8; 4];
+        unsafe {
+            insb(0x1, &mut buf);
+        }
+        kassert!(
+            buf == [0xad; 4],
+            "`rep insb` instruction didn't read the correct values"
+        );
+    }
+
+    #[x86test(ioport(0x2, 0x99))]
+    fn check_outsw() {
+        unsafe {
+            outsw(0x2, &[0x99, 0x99]);
+        }
+    }
+
+    #[x86test(ioport(0x3, 0xfefe))]
+    fn check_insw() {
+        let mut buf = [0u16; 3];
+        unsafe {
+            insw(0x3, &mut buf);
+        }
+        kassert!(
+            buf == [0xfefe; 3],
+            "`rep insw` instruction didn't read the correct values"
+        );
+    }
+
+    #[x86test(ioport(0x5, 0xbeefaaaa))]
+    fn check_outsd() {
+        unsafe {
+            outsd(0x5, &[0xbeefaaaa, 0xbeefaaaa]);
+        }
+    }
+
+    #[x86test(ioport(0x4, 0xdeadbeef))]
+    fn check_insd() {
+        let mut buf = [0u32; 2];
+        unsafe {
+            insd(0x4, &mut buf);
+        }
+        kassert!(
+            buf == [0xdeadbeef; 2],
+            "`rep insd` instruction didn't read the correct values"
+        );
+    }
+
+    #[x86test(ioport(0x0, 0xaf))]
+    fn check_port_write() {
+        static mut PORT: PortWriteOnly<u8> = PortWriteOnly::new(0x0);
+        unsafe {
+            PORT.write(0xaf);
+        }
+    }
+
+    #[x86test(ioport(0x4, 0xdeadbeef))]
+    fn check_port_read() {
+        let mut port: Port<u32> = Port::new(0x4);
+        let mut read_only: PortReadOnly<u32> = PortReadOnly::new(0x4);
+        unsafe {
+            kassert!(
+                port.read() == 0xdeadbeef && read_only.read() == 0xdeadbeef,
+                "`Port::read` didn't read the correct value"
+            );
+        }
+    }
 }
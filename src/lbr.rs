@@ -0,0 +1,801 @@
+//! Last branch recording (LBR) and the branch trace store (BTS).
+//!
+//! Two flavors of LBR exist:
+//!
+//! * Model-specific LBRs: a stack of FROM/TO (and on newer parts INFO) MSRs
+//!   whose depth and addresses depend on the microarchitecture, with a
+//!   top-of-stack pointer and filtering through `MSR_LBR_SELECT`. They are
+//!   enabled with [`DebugCtl::LBR`](crate::debugregs::DebugCtl::LBR).
+//! * Architectural LBRs (CPUID leaf 0x1C): a uniform MSR layout controlled
+//!   by `IA32_LBR_CTL` and `IA32_LBR_DEPTH`, where entry 0 always holds the
+//!   most recent branch.
+//!
+//! The branch trace store writes branch records to the BTS buffer of the
+//! Debug Store (DS) area in memory instead.
+//!
+//! See Intel Vol. 3b Chapter 17, "Last Branch, Interrupt, and Exception
+//! Recording" and Section 18.6.3.4, "Debug Store (DS) Mechanism".
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use crate::cpuid::{native_cpuid::cpuid_count, CpuId, CpuIdResult};
+use crate::msr::{rdmsr, wrmsr, IA32_DS_AREA, IA32_PERF_CAPABILITIES, MSR_LBR_SELECT};
+
+/// Top-of-stack index of the model-specific LBR stack (Core and later).
+pub const MSR_LBR_TOS: u32 = 0x1c9;
+/// First `FROM_IP` MSR of the Core 2 and Atom LBR stack.
+pub const MSR_LBR_CORE_FROM: u32 = 0x40;
+/// First `TO_IP` MSR of the Core 2 and Atom LBR stack.
+pub const MSR_LBR_CORE_TO: u32 = 0x60;
+/// First `FROM_IP` MSR of the LBR stack since Nehalem.
+pub const MSR_LBR_NHM_FROM: u32 = 0x680;
+/// First `TO_IP` MSR of the LBR stack since Nehalem.
+pub const MSR_LBR_NHM_TO: u32 = 0x6c0;
+/// First `MSR_LBR_INFO` MSR (Skylake, Goldmont Plus and later).
+pub const MSR_LBR_INFO_0: u32 = 0xdc0;
+
+/// Architectural LBR control.
+pub const IA32_LBR_CTL: u32 = 0x14ce;
+/// Architectural LBR depth.
+pub const IA32_LBR_DEPTH: u32 = 0x14cf;
+/// First architectural LBR `INFO` MSR.
+pub const IA32_LBR_0_INFO: u32 = 0x1200;
+/// First architectural LBR `FROM_IP` MSR.
+pub const IA32_LBR_0_FROM_IP: u32 = 0x1500;
+/// First architectural LBR `TO_IP` MSR.
+pub const IA32_LBR_0_TO_IP: u32 = 0x1600;
+
+/// Format of the model-specific LBR records (`IA32_PERF_CAPABILITIES[5:0]`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LbrFormat {
+    /// 32-bit addresses.
+    Lip32,
+    /// 64-bit linear addresses.
+    Lip64,
+    /// 64-bit effective addresses.
+    Eip64,
+    /// 64-bit effective addresses, mispredict flag in `FROM[63]`.
+    Eip64Flags,
+    /// 64-bit effective addresses, mispredict and TSX flags in `FROM[63:61]`.
+    Eip64FlagsTsx,
+    /// 64-bit effective addresses, flags in `MSR_LBR_INFO`.
+    Eip64Info,
+    /// 64-bit linear addresses, mispredict flag in `FROM[63]`, cycle count
+    /// in `TO[63:48]`.
+    Lip64FlagsCycles,
+    /// 64-bit linear addresses, flags in `MSR_LBR_INFO`.
+    Lip64Info,
+    /// Unknown encoding.
+    Unknown(u8),
+}
+
+impl LbrFormat {
+    /// Decode the LBR format from `IA32_PERF_CAPABILITIES`.
+    pub fn from_perf_capabilities(caps: u64) -> LbrFormat {
+        match caps.get_bits(0..=5) as u8 {
+            0b000000 => LbrFormat::Lip32,
+            0b000001 => LbrFormat::Lip64,
+            0b000010 => LbrFormat::Eip64,
+            0b000011 => LbrFormat::Eip64Flags,
+            0b000100 => LbrFormat::Eip64FlagsTsx,
+            0b000101 => LbrFormat::Eip64Info,
+            0b000110 => LbrFormat::Lip64FlagsCycles,
+            0b000111 => LbrFormat::Lip64Info,
+            f => LbrFormat::Unknown(f),
+        }
+    }
+
+    /// Read the LBR format of the current processor.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and `IA32_PERF_CAPABILITIES` (CPUID.01H:ECX\[15\]).
+    pub unsafe fn read() -> LbrFormat {
+        LbrFormat::from_perf_capabilities(rdmsr(IA32_PERF_CAPABILITIES))
+    }
+
+    /// Does this format store flags in a separate `MSR_LBR_INFO` register?
+    pub fn has_info(&self) -> bool {
+        matches!(self, LbrFormat::Eip64Info | LbrFormat::Lip64Info)
+    }
+
+    /// Decode the raw `FROM`, `TO` and (for formats with
+    /// [`LbrFormat::has_info`]) `INFO` values into a record.
+    ///
+    /// For formats without an `INFO` register the flags found in the
+    /// address MSRs are moved to [`LbrRecord::info`].
+    pub fn decode(&self, from: u64, to: u64, info: u64) -> LbrRecord {
+        fn sign_extend(addr: u64, flag_bits: u32) -> u64 {
+            (((addr << flag_bits) as i64) >> flag_bits) as u64
+        }
+
+        match self {
+            LbrFormat::Lip32 => LbrRecord {
+                from: from & 0xffff_ffff,
+                to: to & 0xffff_ffff,
+                info: LbrInfo(0),
+            },
+            LbrFormat::Eip64Flags => LbrRecord {
+                from: sign_extend(from, 1),
+                to,
+                info: LbrInfo(from & LbrInfo::MISPRED),
+            },
+            LbrFormat::Eip64FlagsTsx => LbrRecord {
+                from: sign_extend(from, 3),
+                to,
+                info: LbrInfo(from & (LbrInfo::MISPRED | LbrInfo::IN_TSX | LbrInfo::TSX_ABORT)),
+            },
+            LbrFormat::Lip64FlagsCycles => LbrRecord {
+                from: sign_extend(from, 1),
+                to: sign_extend(to, 16),
+                info: LbrInfo((from & LbrInfo::MISPRED) | LbrInfo::CYC_CNT_VALID | to >> 48),
+            },
+            LbrFormat::Eip64Info | LbrFormat::Lip64Info => LbrRecord {
+                from,
+                to,
+                info: LbrInfo(info),
+            },
+            LbrFormat::Lip64 | LbrFormat::Eip64 | LbrFormat::Unknown(_) => LbrRecord {
+                from,
+                to,
+                info: LbrInfo(0),
+            },
+        }
+    }
+}
+
+/// Branch type of an architectural LBR record.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BranchType {
+    /// Conditional branch.
+    Conditional,
+    /// Near indirect jump.
+    NearIndirectJump,
+    /// Near relative jump.
+    NearRelativeJump,
+    /// Near indirect call.
+    NearIndirectCall,
+    /// Near relative call.
+    NearRelativeCall,
+    /// Near return.
+    NearReturn,
+    /// Other branch (far transfers, interrupts, exceptions, ...).
+    Other,
+    /// Reserved encoding.
+    Reserved(u8),
+}
+
+/// Branch information of an LBR record (the format of `MSR_LBR_INFO` and
+/// `IA32_LBR_x_INFO`).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LbrInfo(pub u64);
+
+impl LbrInfo {
+    const CYC_CNT_VALID: u64 = 1 << 60;
+    const TSX_ABORT: u64 = 1 << 61;
+    const IN_TSX: u64 = 1 << 62;
+    const MISPRED: u64 = 1 << 63;
+
+    /// Was the branch mispredicted?
+    pub fn mispredicted(&self) -> bool {
+        self.0 & LbrInfo::MISPRED != 0
+    }
+
+    /// Was the branch executed inside a transaction?
+    pub fn in_tsx(&self) -> bool {
+        self.0 & LbrInfo::IN_TSX != 0
+    }
+
+    /// Is this a transaction abort?
+    pub fn tsx_abort(&self) -> bool {
+        self.0 & LbrInfo::TSX_ABORT != 0
+    }
+
+    /// Core cycles elapsed since the previous LBR record (saturating), if
+    /// recorded.
+    pub fn cycle_count(&self) -> Option<u16> {
+        if self.0 & LbrInfo::CYC_CNT_VALID != 0 {
+            Some(self.0.get_bits(0..=15) as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Branch type (architectural LBRs with CPUID.1CH:ECX\[2\] only).
+    pub fn branch_type(&self) -> BranchType {
+        match self.0.get_bits(56..=59) as u8 {
+            0b0000 => BranchType::Conditional,
+            0b0001 => BranchType::NearIndirectJump,
+            0b0010 => BranchType::NearRelativeJump,
+            0b0011 => BranchType::NearIndirectCall,
+            0b0100 => BranchType::NearRelativeCall,
+            0b0101 => BranchType::NearReturn,
+            t if t & 0b1000 != 0 => BranchType::Other,
+            t => BranchType::Reserved(t),
+        }
+    }
+}
+
+/// A recorded branch.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LbrRecord {
+    /// Source address of the branch.
+    pub from: u64,
+    /// Target address of the branch.
+    pub to: u64,
+    /// Additional branch information.
+    pub info: LbrInfo,
+}
+
+bitflags! {
+    /// LBR filtering (`MSR_LBR_SELECT`) for model-specific LBRs.
+    ///
+    /// A set bit suppresses recording of the matching branches.
+    pub struct LbrSelect: u64 {
+        /// Do not record branches while CPL is 0.
+        const CPL_EQ_0 = 1 << 0;
+        /// Do not record branches while CPL is above 0.
+        const CPL_NEQ_0 = 1 << 1;
+        /// Do not record conditional branches.
+        const JCC = 1 << 2;
+        /// Do not record near relative calls.
+        const NEAR_REL_CALL = 1 << 3;
+        /// Do not record near indirect calls.
+        const NEAR_IND_CALL = 1 << 4;
+        /// Do not record near returns.
+        const NEAR_RET = 1 << 5;
+        /// Do not record near indirect jumps (other than calls and returns).
+        const NEAR_IND_JMP = 1 << 6;
+        /// Do not record near relative jumps (other than calls).
+        const NEAR_REL_JMP = 1 << 7;
+        /// Do not record far branches.
+        const FAR_BRANCH = 1 << 8;
+        /// Operate the LBR stack as a call stack (Haswell and later).
+        const EN_CALLSTACK = 1 << 9;
+    }
+}
+
+/// Read the LBR filter.
+///
+/// # Safety
+/// Needs CPL 0 and a processor with `MSR_LBR_SELECT` (Nehalem and later).
+pub unsafe fn lbr_select() -> LbrSelect {
+    LbrSelect::from_bits_truncate(rdmsr(MSR_LBR_SELECT))
+}
+
+/// Write the LBR filter.
+///
+/// # Safety
+/// Needs CPL 0 and a processor with `MSR_LBR_SELECT` (Nehalem and later).
+pub unsafe fn lbr_select_write(select: LbrSelect) {
+    wrmsr(MSR_LBR_SELECT, select.bits());
+}
+
+/// Location and depth of the model-specific LBR stack.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LbrStack {
+    /// Number of records.
+    pub depth: u8,
+    /// Address of the first `FROM_IP` MSR.
+    pub from: u32,
+    /// Address of the first `TO_IP` MSR.
+    pub to: u32,
+    /// Address of the first `MSR_LBR_INFO` MSR, if present.
+    pub info: Option<u32>,
+}
+
+impl LbrStack {
+    const fn new(depth: u8, from: u32, to: u32, info: Option<u32>) -> LbrStack {
+        LbrStack {
+            depth,
+            from,
+            to,
+            info,
+        }
+    }
+
+    /// LBR stack for a family 6 Intel processor with the given (display) model.
+    ///
+    /// Returns `None` for unknown models or processors with architectural LBRs.
+    pub fn for_model(family: u8, model: u8) -> Option<LbrStack> {
+        if family != 6 {
+            return None;
+        }
+
+        match model {
+            // Core 2
+            0x0f | 0x16 | 0x17 | 0x1d => {
+                Some(LbrStack::new(4, MSR_LBR_CORE_FROM, MSR_LBR_CORE_TO, None))
+            }
+            // Bonnell, Saltwell, Silvermont, Airmont
+            0x1c | 0x26 | 0x27 | 0x35 | 0x36 | 0x37 | 0x4a | 0x4c | 0x4d | 0x5a | 0x5d => {
+                Some(LbrStack::new(8, MSR_LBR_CORE_FROM, MSR_LBR_CORE_TO, None))
+            }
+            // Nehalem, Westmere, Sandy Bridge, Ivy Bridge, Haswell, Broadwell
+            0x1a | 0x1e | 0x1f | 0x2e | 0x25 | 0x2c | 0x2f | 0x2a | 0x2d | 0x3a | 0x3e | 0x3c
+            | 0x3f | 0x45 | 0x46 | 0x3d | 0x47 | 0x4f | 0x56 => {
+                Some(LbrStack::new(16, MSR_LBR_NHM_FROM, MSR_LBR_NHM_TO, None))
+            }
+            // Knights Landing, Knights Mill
+            0x57 | 0x85 => Some(LbrStack::new(8, MSR_LBR_NHM_FROM, MSR_LBR_NHM_TO, None)),
+            // Goldmont
+            0x5c | 0x5f => Some(LbrStack::new(32, MSR_LBR_NHM_FROM, MSR_LBR_NHM_TO, None)),
+            // Goldmont Plus, Skylake, Kaby Lake, Coffee Lake, Cannon Lake,
+            // Ice Lake, Comet Lake, Rocket Lake, Tiger Lake
+            0x7a | 0x4e | 0x5e | 0x55 | 0x8e | 0x9e | 0x66 | 0x7d | 0x7e | 0x6a | 0x6c | 0xa5
+            | 0xa6 | 0xa7 | 0x8c | 0x8d => Some(LbrStack::new(
+                32,
+                MSR_LBR_NHM_FROM,
+                MSR_LBR_NHM_TO,
+                Some(MSR_LBR_INFO_0),
+            )),
+            _ => None,
+        }
+    }
+
+    /// LBR stack of the current processor.
+    pub fn detect() -> Option<LbrStack> {
+        let cpuid = CpuId::new();
+        let features = cpuid.get_feature_info()?;
+        LbrStack::for_model(features.family_id(), features.model_id())
+    }
+
+    /// Address of the `FROM_IP` MSR of record `i`.
+    pub fn from_msr(&self, i: u8) -> u32 {
+        assert!(i < self.depth, "LBR index out of range.");
+        self.from + i as u32
+    }
+
+    /// Address of the `TO_IP` MSR of record `i`.
+    pub fn to_msr(&self, i: u8) -> u32 {
+        assert!(i < self.depth, "LBR index out of range.");
+        self.to + i as u32
+    }
+
+    /// Address of the `MSR_LBR_INFO` MSR of record `i`, if present.
+    pub fn info_msr(&self, i: u8) -> Option<u32> {
+        assert!(i < self.depth, "LBR index out of range.");
+        self.info.map(|info| info + i as u32)
+    }
+
+    /// Index of the most recent record.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the stack has to match the current processor.
+    pub unsafe fn tos(&self) -> u8 {
+        (rdmsr(MSR_LBR_TOS) % self.depth as u64) as u8
+    }
+
+    /// Read record `i`.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the stack has to match the current processor.
+    pub unsafe fn read(&self, format: LbrFormat, i: u8) -> LbrRecord {
+        let info = match self.info_msr(i) {
+            Some(msr) if format.has_info() => rdmsr(msr),
+            _ => 0,
+        };
+        format.decode(rdmsr(self.from_msr(i)), rdmsr(self.to_msr(i)), info)
+    }
+
+    /// Iterate over all records, starting with the most recent one.
+    ///
+    /// Recording should be stopped (e.g., by clearing
+    /// [`DebugCtl::LBR`](crate::debugregs::DebugCtl::LBR)) while iterating.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the stack has to match the current processor.
+    pub unsafe fn records(&self, format: LbrFormat) -> LbrRecords {
+        LbrRecords {
+            stack: *self,
+            format,
+            tos: self.tos(),
+            read: 0,
+        }
+    }
+}
+
+/// Iterator over the model-specific LBR stack, most recent record first.
+#[derive(Debug, Clone)]
+pub struct LbrRecords {
+    stack: LbrStack,
+    format: LbrFormat,
+    tos: u8,
+    read: u8,
+}
+
+impl Iterator for LbrRecords {
+    type Item = LbrRecord;
+
+    fn next(&mut self) -> Option<LbrRecord> {
+        if self.read >= self.stack.depth {
+            return None;
+        }
+        let depth = self.stack.depth;
+        let i = (self.tos + depth - self.read) % depth;
+        self.read += 1;
+        // Safety: guaranteed by `LbrStack::records`.
+        Some(unsafe { self.stack.read(self.format, i) })
+    }
+}
+
+/// Architectural LBR capabilities (CPUID leaf 0x1C).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ArchLbrInfo {
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+}
+
+impl ArchLbrInfo {
+    /// Decode the result of CPUID leaf 0x1C.
+    pub fn new(leaf: CpuIdResult) -> ArchLbrInfo {
+        ArchLbrInfo {
+            eax: leaf.eax,
+            ebx: leaf.ebx,
+            ecx: leaf.ecx,
+        }
+    }
+
+    /// Read the capabilities of the current processor, returns `None` if
+    /// architectural LBRs are not supported (CPUID.07H:EDX\[19\]).
+    pub fn read() -> Option<ArchLbrInfo> {
+        if cpuid_count(0x0, 0).eax < 0x1c || !cpuid_count(0x7, 0).edx.get_bit(19) {
+            return None;
+        }
+        Some(ArchLbrInfo::new(cpuid_count(0x1c, 0)))
+    }
+
+    /// Is a depth of `depth` records supported?
+    pub fn supports_depth(&self, depth: u8) -> bool {
+        depth & 0x7 == 0
+            && (1..=8).contains(&(depth / 8))
+            && self.eax.get_bit(depth as usize / 8 - 1)
+    }
+
+    /// Largest supported depth.
+    pub fn max_depth(&self) -> u8 {
+        let depths = self.eax.get_bits(0..=7);
+        (32 - depths.leading_zeros()) as u8 * 8
+    }
+
+    /// LBRs are cleared when entering deep C-states.
+    pub fn deep_cstate_reset(&self) -> bool {
+        self.eax.get_bit(30)
+    }
+
+    /// Recorded addresses are linear addresses (include the CS base).
+    pub fn lip(&self) -> bool {
+        self.eax.get_bit(31)
+    }
+
+    /// Filtering by CPL ([`LbrCtl::OS`], [`LbrCtl::USR`]) is supported.
+    pub fn cpl_filtering(&self) -> bool {
+        self.ebx.get_bit(0)
+    }
+
+    /// Filtering by branch type is supported.
+    pub fn branch_filtering(&self) -> bool {
+        self.ebx.get_bit(1)
+    }
+
+    /// Call-stack mode is supported.
+    pub fn call_stack(&self) -> bool {
+        self.ebx.get_bit(2)
+    }
+
+    /// Mispredict information is recorded.
+    pub fn mispredict(&self) -> bool {
+        self.ecx.get_bit(0)
+    }
+
+    /// Cycle counts are recorded (timed LBRs).
+    pub fn timed_lbr(&self) -> bool {
+        self.ecx.get_bit(1)
+    }
+
+    /// The branch type is recorded.
+    pub fn branch_type(&self) -> bool {
+        self.ecx.get_bit(2)
+    }
+}
+
+bitflags! {
+    /// Architectural LBR control (`IA32_LBR_CTL`).
+    pub struct LbrCtl: u64 {
+        /// Enable recording.
+        const LBR_EN = 1 << 0;
+        /// Record branches while CPL is 0.
+        const OS = 1 << 1;
+        /// Record branches while CPL is above 0.
+        const USR = 1 << 2;
+        /// Operate as a call stack.
+        const CALL_STACK = 1 << 3;
+        /// Record conditional branches.
+        const JCC = 1 << 16;
+        /// Record near relative jumps.
+        const NEAR_REL_JMP = 1 << 17;
+        /// Record near indirect jumps.
+        const NEAR_IND_JMP = 1 << 18;
+        /// Record near relative calls.
+        const NEAR_REL_CALL = 1 << 19;
+        /// Record near indirect calls.
+        const NEAR_IND_CALL = 1 << 20;
+        /// Record near returns.
+        const NEAR_RET = 1 << 21;
+        /// Record other branches.
+        const OTHER_BRANCH = 1 << 22;
+        /// Record all branch types.
+        const ALL_BRANCHES = Self::JCC.bits
+            | Self::NEAR_REL_JMP.bits
+            | Self::NEAR_IND_JMP.bits
+            | Self::NEAR_REL_CALL.bits
+            | Self::NEAR_IND_CALL.bits
+            | Self::NEAR_RET.bits
+            | Self::OTHER_BRANCH.bits;
+    }
+}
+
+/// Read the architectural LBR control.
+///
+/// # Safety
+/// Needs CPL 0 and architectural LBR support.
+pub unsafe fn arch_lbr_ctl() -> LbrCtl {
+    LbrCtl::from_bits_truncate(rdmsr(IA32_LBR_CTL))
+}
+
+/// Write the architectural LBR control.
+///
+/// # Safety
+/// Needs CPL 0 and architectural LBR support.
+pub unsafe fn arch_lbr_ctl_write(ctl: LbrCtl) {
+    wrmsr(IA32_LBR_CTL, ctl.bits());
+}
+
+/// Read the number of architectural LBR records in use.
+///
+/// # Safety
+/// Needs CPL 0 and architectural LBR support.
+pub unsafe fn arch_lbr_depth() -> u8 {
+    rdmsr(IA32_LBR_DEPTH) as u8
+}
+
+/// Set the number of architectural LBR records (clears all records).
+///
+/// # Safety
+/// Needs CPL 0 and `depth` must be supported (see
+/// [`ArchLbrInfo::supports_depth`]).
+pub unsafe fn arch_lbr_depth_write(depth: u8) {
+    wrmsr(IA32_LBR_DEPTH, depth as u64);
+}
+
+/// Read architectural LBR record `i` (record 0 is the most recent one).
+///
+/// # Safety
+/// Needs CPL 0, architectural LBR support and `i` below the configured depth.
+pub unsafe fn arch_lbr_read(i: u8) -> LbrRecord {
+    LbrRecord {
+        from: rdmsr(IA32_LBR_0_FROM_IP + i as u32),
+        to: rdmsr(IA32_LBR_0_TO_IP + i as u32),
+        info: LbrInfo(rdmsr(IA32_LBR_0_INFO + i as u32)),
+    }
+}
+
+/// A branch record stored in the BTS buffer (64-bit format).
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct BtsRecord {
+    /// Source address of the branch.
+    pub from: u64,
+    /// Target address of the branch.
+    pub to: u64,
+    /// Bit 4 is set if the branch was predicted.
+    pub flags: u64,
+}
+
+impl BtsRecord {
+    /// Was the branch predicted correctly?
+    pub fn predicted(&self) -> bool {
+        self.flags.get_bit(4)
+    }
+}
+
+/// The Debug Store (DS) save area (64-bit format), referenced by `IA32_DS_AREA`.
+///
+/// All addresses are linear addresses.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct DsArea {
+    /// Start of the BTS buffer.
+    pub bts_buffer_base: u64,
+    /// Address of the next BTS record to be written.
+    pub bts_index: u64,
+    /// End of the BTS buffer (one past the last record that can be written).
+    pub bts_absolute_maximum: u64,
+    /// A PMI is raised (if [`DebugCtl::BTINT`](crate::debugregs::DebugCtl::BTINT)
+    /// is set) once `bts_index` reaches this address.
+    pub bts_interrupt_threshold: u64,
+    /// Start of the PEBS buffer.
+    pub pebs_buffer_base: u64,
+    /// Address of the next PEBS record to be written.
+    pub pebs_index: u64,
+    /// End of the PEBS buffer.
+    pub pebs_absolute_maximum: u64,
+    /// A PMI is raised once `pebs_index` reaches this address.
+    pub pebs_interrupt_threshold: u64,
+    /// Values the general-purpose counters are reset to after a PEBS record
+    /// was written.
+    pub pebs_counter_reset: [u64; 4],
+    _reserved: u64,
+}
+
+impl DsArea {
+    /// Use `buffer` as the BTS buffer, raising an interrupt once `threshold`
+    /// records were written.
+    pub fn set_bts_buffer(&mut self, buffer: &mut [BtsRecord], threshold: usize) {
+        assert!(
+            threshold <= buffer.len(),
+            "Threshold is outside of the buffer."
+        );
+        let base = buffer.as_mut_ptr() as u64;
+        let record = core::mem::size_of::<BtsRecord>() as u64;
+        self.bts_buffer_base = base;
+        self.bts_index = base;
+        self.bts_absolute_maximum = base + buffer.len() as u64 * record;
+        self.bts_interrupt_threshold = base + threshold as u64 * record;
+    }
+
+    /// Number of BTS records written so far.
+    pub fn bts_len(&self) -> usize {
+        ((self.bts_index - self.bts_buffer_base) / core::mem::size_of::<BtsRecord>() as u64)
+            as usize
+    }
+
+    /// The BTS records written so far.
+    ///
+    /// # Safety
+    /// The BTS buffer fields need to reference valid memory (e.g., as set
+    /// by [`DsArea::set_bts_buffer`]).
+    pub unsafe fn bts_records(&self) -> &[BtsRecord] {
+        core::slice::from_raw_parts(self.bts_buffer_base as *const BtsRecord, self.bts_len())
+    }
+}
+
+/// Set the Debug Store area.
+///
+/// # Safety
+/// Needs CPL 0. `area` (and the buffers it references) has to stay valid
+/// while BTS or PEBS are enabled.
+pub unsafe fn set_ds_area(area: &DsArea) {
+    wrmsr(IA32_DS_AREA, area as *const DsArea as u64);
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lbr_formats() {
+        assert_eq!(
+            LbrFormat::from_perf_capabilities(0x33c5),
+            LbrFormat::Eip64Info
+        );
+        assert_eq!(
+            LbrFormat::from_perf_capabilities(0x04),
+            LbrFormat::Eip64FlagsTsx
+        );
+        assert_eq!(
+            LbrFormat::from_perf_capabilities(0x3f),
+            LbrFormat::Unknown(0x3f)
+        );
+
+        // Mispredicted branch inside a kernel function.
+        let rec = LbrFormat::Eip64Flags.decode(0xffff_ffff_8100_1234, 0xffff_ffff_8100_2000, 0);
+        assert_eq!(rec.from, 0xffff_ffff_8100_1234);
+        assert!(rec.info.mispredicted());
+        let rec = LbrFormat::Eip64Flags.decode(0x7fff_ffff_8100_1234, 0xffff_ffff_8100_2000, 0);
+        assert_eq!(rec.from, 0xffff_ffff_8100_1234);
+        assert!(!rec.info.mispredicted());
+
+        // Transaction abort in user space.
+        let rec = LbrFormat::Eip64FlagsTsx.decode(0x6000_7f00_0040_1000, 0x7f00_0040_2000, 0);
+        assert_eq!(rec.from, 0x7f00_0040_1000);
+        assert!(rec.info.in_tsx() && rec.info.tsx_abort() && !rec.info.mispredicted());
+
+        // Cycle count in the upper bits of TO.
+        let rec =
+            LbrFormat::Lip64FlagsCycles.decode(0x0000_0000_0040_1000, 0x002a_0000_0040_2000, 0);
+        assert_eq!(rec.to, 0x40_2000);
+        assert_eq!(rec.info.cycle_count(), Some(42));
+
+        let rec = LbrFormat::Eip64Info.decode(0x40_1000, 0x40_2000, 0x9000_0000_0000_0007);
+        assert_eq!(rec.from, 0x40_1000);
+        assert!(rec.info.mispredicted());
+        assert_eq!(rec.info.cycle_count(), Some(7));
+
+        assert_eq!(
+            LbrInfo(0x0400_0000_0000_0000).branch_type(),
+            BranchType::NearRelativeCall
+        );
+        assert_eq!(
+            LbrInfo(0x0c00_0000_0000_0000).branch_type(),
+            BranchType::Other
+        );
+        assert_eq!(
+            LbrInfo(0x0600_0000_0000_0000).branch_type(),
+            BranchType::Reserved(6)
+        );
+    }
+
+    #[test]
+    fn lbr_stacks() {
+        // Core 2
+        let stack = LbrStack::for_model(6, 0x17).unwrap();
+        assert_eq!(stack.depth, 4);
+        assert_eq!(stack.from_msr(3), 0x43);
+        assert_eq!(stack.to_msr(3), 0x63);
+        assert_eq!(stack.info_msr(3), None);
+
+        // Haswell
+        let stack = LbrStack::for_model(6, 0x3c).unwrap();
+        assert_eq!(stack.depth, 16);
+        assert_eq!(stack.from_msr(15), 0x68f);
+        assert_eq!(stack.to_msr(15), 0x6cf);
+
+        // Skylake
+        let stack = LbrStack::for_model(6, 0x5e).unwrap();
+        assert_eq!(stack.depth, 32);
+        assert_eq!(stack.from_msr(31), 0x69f);
+        assert_eq!(stack.info_msr(31), Some(0xddf));
+
+        // Alder Lake has architectural LBRs.
+        assert_eq!(LbrStack::for_model(6, 0x97), None);
+        assert_eq!(LbrStack::for_model(0xf, 0x02), None);
+    }
+
+    #[test]
+    fn arch_lbr_info() {
+        let info = ArchLbrInfo::new(CpuIdResult {
+            eax: 0x4000_000f,
+            ebx: 0x7,
+            ecx: 0x7,
+            edx: 0,
+        });
+        assert_eq!(info.max_depth(), 32);
+        assert!(info.supports_depth(8) && info.supports_depth(32));
+        assert!(!info.supports_depth(12) && !info.supports_depth(40) && !info.supports_depth(0));
+        assert!(info.deep_cstate_reset() && !info.lip());
+        assert!(info.cpl_filtering() && info.branch_filtering() && info.call_stack());
+        assert!(info.mispredict() && info.timed_lbr() && info.branch_type());
+
+        let ctl = LbrCtl::LBR_EN | LbrCtl::OS | LbrCtl::ALL_BRANCHES;
+        assert_eq!(ctl.bits(), 0x7f_0003);
+    }
+
+    #[test]
+    fn ds_area_layout() {
+        assert_eq!(core::mem::size_of::<BtsRecord>(), 24);
+        assert_eq!(core::mem::size_of::<DsArea>(), 0x68);
+
+        // Simulate the processor writing two records.
+        let mut records = [BtsRecord::default(); 8];
+        records[0] = BtsRecord {
+            from: 0x1000,
+            to: 0x2000,
+            flags: 1 << 4,
+        };
+        let base = records.as_ptr() as u64;
+        let mut ds = DsArea::default();
+        ds.set_bts_buffer(&mut records, 6);
+        assert_eq!(ds.bts_buffer_base, base);
+        assert_eq!(ds.bts_absolute_maximum, base + 8 * 24);
+        assert_eq!(ds.bts_interrupt_threshold, base + 6 * 24);
+        assert_eq!(ds.bts_len(), 0);
+
+        ds.bts_index += 2 * 24;
+        let written = unsafe { ds.bts_records() };
+        assert_eq!(written.len(), 2);
+        assert!(written[0].predicted());
+        assert_eq!(written[0].to, 0x2000);
+    }
+}
@@ -21,17 +21,32 @@ pub mod bits16;
 pub mod bits32;
 pub mod bits64;
 
+pub mod acpi;
 pub mod apic;
+pub mod bench;
 pub mod controlregs;
 pub mod debugregs;
 pub mod dtables;
 pub mod fence;
+pub mod hpet;
 pub mod io;
 pub mod irq;
+pub mod lbr;
+pub mod mca;
 pub mod msr;
+pub mod pci;
+pub mod pic8259;
+pub mod pit;
+pub mod pt;
+pub mod qemu;
 pub mod random;
+pub mod rapl;
+pub mod rdt;
+pub mod rtc;
 pub mod segmentation;
+pub mod serial;
 pub mod task;
+pub mod thermal;
 pub mod time;
 pub mod tlb;
 pub mod vmx;
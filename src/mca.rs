@@ -0,0 +1,906 @@
+//! Machine-check architecture (MCA) register decoding.
+//!
+//! Machine-check errors are reported through a set of global registers
+//! (`IA32_MCG_*`) and a number of error-reporting banks each consisting of
+//! `IA32_MCi_CTL`, `IA32_MCi_STATUS`, `IA32_MCi_ADDR` and `IA32_MCi_MISC`.
+//! A machine-check exception is delivered on
+//! [`MACHINE_CHECK_VECTOR`](crate::irq::MACHINE_CHECK_VECTOR).
+//!
+//! See Intel Vol. 3b Chapter 15, "Machine-Check Architecture" and
+//! Chapter 16, "Interpreting Machine-Check Error Codes".
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use crate::msr::{rdmsr, wrmsr, IA32_MC0_CTL, IA32_MC0_CTL2, IA32_MCG_CAP, IA32_MCG_STATUS};
+
+bitflags! {
+    /// Capability flags in `IA32_MCG_CAP`.
+    pub struct McgCapFlags: u64 {
+        /// `IA32_MCG_CTL` is present.
+        const MCG_CTL_P = 1 << 8;
+        /// Extended machine-check state registers (`IA32_MCG_RAX` ...) are present.
+        const MCG_EXT_P = 1 << 9;
+        /// Corrected machine-check error interrupts (CMCI) are supported.
+        const MCG_CMCI_P = 1 << 10;
+        /// Threshold-based error status (bits 54:53 of `IA32_MCi_STATUS`) is supported.
+        const MCG_TES_P = 1 << 11;
+        /// Software error recovery (UCR errors) is supported.
+        const MCG_SER_P = 1 << 24;
+        /// Enhanced machine-check capability for firmware first signaling is supported.
+        const MCG_EMC_P = 1 << 25;
+        /// Enhanced error logging is supported.
+        const MCG_ELOG_P = 1 << 26;
+        /// Local machine-check exceptions (LMCE) are supported.
+        const MCG_LMCE_P = 1 << 27;
+    }
+}
+
+/// Global machine-check capabilities (`IA32_MCG_CAP`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct McgCap(pub u64);
+
+impl McgCap {
+    /// The capability flags.
+    pub fn flags(&self) -> McgCapFlags {
+        McgCapFlags::from_bits_truncate(self.0)
+    }
+
+    /// Number of error-reporting banks (bits 7:0).
+    pub fn count(&self) -> u8 {
+        self.0.get_bits(0..=7) as u8
+    }
+
+    /// Number of extended machine-check state registers (bits 23:16).
+    pub fn ext_count(&self) -> u8 {
+        self.0.get_bits(16..=23) as u8
+    }
+
+    /// Iterate over all error-reporting banks.
+    pub fn banks(&self) -> McaBanks {
+        McaBanks {
+            next: 0,
+            count: self.count(),
+        }
+    }
+}
+
+bitflags! {
+    /// Global machine-check status (`IA32_MCG_STATUS`).
+    pub struct McgStatus: u64 {
+        /// Restart IP valid: program execution can be restarted reliably at
+        /// the instruction pointed to by the IP pushed on the stack.
+        const RIPV = 1 << 0;
+        /// Error IP valid: the instruction pointed to by the IP pushed on the
+        /// stack is directly associated with the error.
+        const EIPV = 1 << 1;
+        /// Machine check in progress. A second machine-check event while this
+        /// is set causes a shutdown.
+        const MCIP = 1 << 2;
+        /// The machine-check exception was delivered only to this processor.
+        const LMCE_S = 1 << 3;
+    }
+}
+
+/// Read the global machine-check capabilities.
+///
+/// # Safety
+/// Needs CPL 0 and machine-check architecture support (CPUID.01H:EDX\[14\]).
+pub unsafe fn mcg_cap() -> McgCap {
+    McgCap(rdmsr(IA32_MCG_CAP))
+}
+
+/// Read the global machine-check status.
+///
+/// # Safety
+/// Needs CPL 0 and machine-check architecture support (CPUID.01H:EDX\[14\]).
+pub unsafe fn mcg_status() -> McgStatus {
+    McgStatus::from_bits_truncate(rdmsr(IA32_MCG_STATUS))
+}
+
+/// Write the global machine-check status (e.g., to clear MCIP at the end of
+/// the machine-check handler).
+///
+/// # Safety
+/// Needs CPL 0 and machine-check architecture support (CPUID.01H:EDX\[14\]).
+pub unsafe fn mcg_status_write(status: McgStatus) {
+    wrmsr(IA32_MCG_STATUS, status.bits());
+}
+
+/// An error-reporting bank.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct McaBank(pub u8);
+
+impl McaBank {
+    /// Address of `IA32_MCi_CTL`.
+    pub const fn ctl_msr(&self) -> u32 {
+        IA32_MC0_CTL + 4 * self.0 as u32
+    }
+
+    /// Address of `IA32_MCi_STATUS`.
+    pub const fn status_msr(&self) -> u32 {
+        IA32_MC0_CTL + 4 * self.0 as u32 + 1
+    }
+
+    /// Address of `IA32_MCi_ADDR`.
+    pub const fn addr_msr(&self) -> u32 {
+        IA32_MC0_CTL + 4 * self.0 as u32 + 2
+    }
+
+    /// Address of `IA32_MCi_MISC`.
+    pub const fn misc_msr(&self) -> u32 {
+        IA32_MC0_CTL + 4 * self.0 as u32 + 3
+    }
+
+    /// Address of `IA32_MCi_CTL2` (only present if `MCG_CMCI_P` is set).
+    pub const fn ctl2_msr(&self) -> u32 {
+        IA32_MC0_CTL2 + self.0 as u32
+    }
+
+    /// Read the bank's status.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the bank needs to exist.
+    pub unsafe fn status(&self) -> MciStatus {
+        MciStatus(rdmsr(self.status_msr()))
+    }
+
+    /// Clear the bank's status after the error was logged.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the bank needs to exist.
+    pub unsafe fn clear_status(&self) {
+        wrmsr(self.status_msr(), 0);
+    }
+
+    /// Read the address associated with the error, only valid if
+    /// [`MciStatus::addrv`] is set.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the bank needs to exist.
+    pub unsafe fn addr(&self) -> u64 {
+        rdmsr(self.addr_msr())
+    }
+
+    /// Read the additional error information, only valid if
+    /// [`MciStatus::miscv`] is set.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the bank needs to exist.
+    pub unsafe fn misc(&self) -> MciMisc {
+        MciMisc(rdmsr(self.misc_msr()))
+    }
+
+    /// Enable error reporting for all errors of this bank.
+    ///
+    /// # Safety
+    /// Needs CPL 0 and the bank needs to exist.
+    pub unsafe fn enable_all(&self) {
+        wrmsr(self.ctl_msr(), u64::MAX);
+    }
+}
+
+/// Iterator over the error-reporting banks reported in `IA32_MCG_CAP`.
+#[derive(Debug, Clone)]
+pub struct McaBanks {
+    next: u8,
+    count: u8,
+}
+
+impl Iterator for McaBanks {
+    type Item = McaBank;
+
+    fn next(&mut self) -> Option<McaBank> {
+        if self.next < self.count {
+            let bank = McaBank(self.next);
+            self.next += 1;
+            Some(bank)
+        } else {
+            None
+        }
+    }
+}
+
+bitflags! {
+    /// Flags in `IA32_MCi_STATUS`.
+    pub struct MciStatusFlags: u64 {
+        /// Recovery action required for an SRAR error.
+        const AR = 1 << 55;
+        /// Signaling: the error was signaled with a machine-check exception
+        /// (vs. CMCI or polling).
+        const S = 1 << 56;
+        /// Processor context corrupt: the state of the processor might have
+        /// been corrupted and restarting is not reliable.
+        const PCC = 1 << 57;
+        /// `IA32_MCi_ADDR` contains the address where the error occurred.
+        const ADDRV = 1 << 58;
+        /// `IA32_MCi_MISC` contains additional information about the error.
+        const MISCV = 1 << 59;
+        /// Error reporting was enabled (in `IA32_MCi_CTL`) for this error.
+        const EN = 1 << 60;
+        /// The error was not corrected by the processor.
+        const UC = 1 << 61;
+        /// A machine-check error occurred while the results of a previous
+        /// error were still in the register.
+        const OVER = 1 << 62;
+        /// The register contains valid error information.
+        const VAL = 1 << 63;
+    }
+}
+
+/// Threshold-based error status (bits 54:53 of `IA32_MCi_STATUS` if `MCG_TES_P`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ThresholdStatus {
+    /// No hardware status tracking is provided for the structure reporting this event.
+    NoTracking,
+    /// Status tracking is provided and the current status is green.
+    Green,
+    /// Corrected errors reached the threshold, status is yellow.
+    Yellow,
+    /// Reserved encoding.
+    Reserved,
+}
+
+/// Error status of an error-reporting bank (`IA32_MCi_STATUS`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MciStatus(pub u64);
+
+impl MciStatus {
+    /// The status flags.
+    pub fn flags(&self) -> MciStatusFlags {
+        MciStatusFlags::from_bits_truncate(self.0)
+    }
+
+    /// Does the register contain valid error information?
+    pub fn val(&self) -> bool {
+        self.flags().contains(MciStatusFlags::VAL)
+    }
+
+    /// Did an overflow happen (a previous error was lost)?
+    pub fn over(&self) -> bool {
+        self.flags().contains(MciStatusFlags::OVER)
+    }
+
+    /// Was the error uncorrected?
+    pub fn uc(&self) -> bool {
+        self.flags().contains(MciStatusFlags::UC)
+    }
+
+    /// Was error reporting enabled for the error?
+    pub fn en(&self) -> bool {
+        self.flags().contains(MciStatusFlags::EN)
+    }
+
+    /// Is `IA32_MCi_MISC` valid?
+    pub fn miscv(&self) -> bool {
+        self.flags().contains(MciStatusFlags::MISCV)
+    }
+
+    /// Is `IA32_MCi_ADDR` valid?
+    pub fn addrv(&self) -> bool {
+        self.flags().contains(MciStatusFlags::ADDRV)
+    }
+
+    /// Is the processor context corrupt?
+    pub fn pcc(&self) -> bool {
+        self.flags().contains(MciStatusFlags::PCC)
+    }
+
+    /// Was the error signaled with a machine-check exception?
+    pub fn s(&self) -> bool {
+        self.flags().contains(MciStatusFlags::S)
+    }
+
+    /// Is a recovery action required?
+    pub fn ar(&self) -> bool {
+        self.flags().contains(MciStatusFlags::AR)
+    }
+
+    /// Raw architecturally defined MCA error code (bits 15:0).
+    pub fn mca_error_code(&self) -> u16 {
+        self.0.get_bits(0..=15) as u16
+    }
+
+    /// Decoded MCA error code.
+    pub fn error(&self) -> McaErrorCode {
+        McaErrorCode::from_raw(self.mca_error_code())
+    }
+
+    /// Correction report filtering (bit 12 of compound error codes): set if
+    /// further corrected errors of this kind are not reported.
+    pub fn correction_report_filtering(&self) -> bool {
+        self.error().is_compound() && self.0.get_bit(12)
+    }
+
+    /// Model-specific error code (bits 31:16).
+    pub fn model_specific_error_code(&self) -> u16 {
+        self.0.get_bits(16..=31) as u16
+    }
+
+    /// Other model-specific information (bits 37:32).
+    pub fn other_info(&self) -> u8 {
+        self.0.get_bits(32..=37) as u8
+    }
+
+    /// Corrected error count (bits 52:38), valid if `MCG_CMCI_P` is set.
+    pub fn corrected_error_count(&self) -> u16 {
+        self.0.get_bits(38..=52) as u16
+    }
+
+    /// Threshold-based error status, valid if `MCG_TES_P` is set and the
+    /// error was corrected.
+    pub fn threshold_status(&self) -> ThresholdStatus {
+        match self.0.get_bits(53..=54) {
+            0b00 => ThresholdStatus::NoTracking,
+            0b01 => ThresholdStatus::Green,
+            0b10 => ThresholdStatus::Yellow,
+            _ => ThresholdStatus::Reserved,
+        }
+    }
+
+    /// Classification of uncorrected errors with software error recovery
+    /// (`MCG_SER_P`), see Intel Vol. 3b Table 15-6.
+    pub fn severity(&self) -> ErrorSeverity {
+        if !self.uc() {
+            ErrorSeverity::Corrected
+        } else if self.pcc() {
+            ErrorSeverity::Fatal
+        } else if !self.s() {
+            ErrorSeverity::UncorrectedNoAction
+        } else if self.ar() {
+            ErrorSeverity::ActionRequired
+        } else {
+            ErrorSeverity::ActionOptional
+        }
+    }
+}
+
+/// Error classification according to the UC, PCC, S and AR flags.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorSeverity {
+    /// Corrected error (CE).
+    Corrected,
+    /// Uncorrected error, processor context corrupt (PCC = 1).
+    Fatal,
+    /// Uncorrected error, no action required (UCNA).
+    UncorrectedNoAction,
+    /// Software recoverable action optional (SRAO).
+    ActionOptional,
+    /// Software recoverable action required (SRAR).
+    ActionRequired,
+}
+
+/// Cache or TLB level in a compound error code (LL).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryHierarchyLevel {
+    Level0,
+    Level1,
+    Level2,
+    Generic,
+}
+
+impl MemoryHierarchyLevel {
+    fn from_bits(ll: u16) -> MemoryHierarchyLevel {
+        match ll & 0b11 {
+            0b00 => MemoryHierarchyLevel::Level0,
+            0b01 => MemoryHierarchyLevel::Level1,
+            0b10 => MemoryHierarchyLevel::Level2,
+            _ => MemoryHierarchyLevel::Generic,
+        }
+    }
+}
+
+/// Transaction type in a compound error code (TT).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransactionType {
+    Instruction,
+    Data,
+    Generic,
+    Reserved,
+}
+
+impl TransactionType {
+    fn from_bits(tt: u16) -> TransactionType {
+        match tt & 0b11 {
+            0b00 => TransactionType::Instruction,
+            0b01 => TransactionType::Data,
+            0b10 => TransactionType::Generic,
+            _ => TransactionType::Reserved,
+        }
+    }
+}
+
+/// Request type in a compound error code (RRRR).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RequestType {
+    /// Generic error.
+    Generic,
+    /// Generic read.
+    Read,
+    /// Generic write.
+    Write,
+    /// Data read.
+    DataRead,
+    /// Data write.
+    DataWrite,
+    /// Instruction fetch.
+    InstructionFetch,
+    /// Prefetch.
+    Prefetch,
+    /// Eviction.
+    Eviction,
+    /// Snoop.
+    Snoop,
+    /// Reserved encoding.
+    Reserved(u8),
+}
+
+impl RequestType {
+    fn from_bits(rrrr: u16) -> RequestType {
+        match rrrr & 0b1111 {
+            0b0000 => RequestType::Generic,
+            0b0001 => RequestType::Read,
+            0b0010 => RequestType::Write,
+            0b0011 => RequestType::DataRead,
+            0b0100 => RequestType::DataWrite,
+            0b0101 => RequestType::InstructionFetch,
+            0b0110 => RequestType::Prefetch,
+            0b0111 => RequestType::Eviction,
+            0b1000 => RequestType::Snoop,
+            r => RequestType::Reserved(r as u8),
+        }
+    }
+}
+
+/// Participation of the processor in a bus/interconnect error (PP).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Participation {
+    /// Local processor originated the request.
+    Source,
+    /// Local processor responded to the request.
+    Responder,
+    /// Local processor observed the error as a third party.
+    Observer,
+    /// Generic.
+    Generic,
+}
+
+impl Participation {
+    fn from_bits(pp: u16) -> Participation {
+        match pp & 0b11 {
+            0b00 => Participation::Source,
+            0b01 => Participation::Responder,
+            0b10 => Participation::Observer,
+            _ => Participation::Generic,
+        }
+    }
+}
+
+/// Memory or I/O in a bus/interconnect error (II).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryOrIo {
+    Memory,
+    Reserved,
+    Io,
+    Other,
+}
+
+impl MemoryOrIo {
+    fn from_bits(ii: u16) -> MemoryOrIo {
+        match ii & 0b11 {
+            0b00 => MemoryOrIo::Memory,
+            0b01 => MemoryOrIo::Reserved,
+            0b10 => MemoryOrIo::Io,
+            _ => MemoryOrIo::Other,
+        }
+    }
+}
+
+/// Memory transaction type in a memory controller error (MMM).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryTransaction {
+    /// Generic undefined request.
+    Generic,
+    /// Memory read error.
+    Read,
+    /// Memory write error.
+    Write,
+    /// Address/command error.
+    AddressCommand,
+    /// Memory scrubbing error.
+    Scrubbing,
+    /// Reserved encoding.
+    Reserved(u8),
+}
+
+impl MemoryTransaction {
+    fn from_bits(mmm: u16) -> MemoryTransaction {
+        match mmm & 0b111 {
+            0b000 => MemoryTransaction::Generic,
+            0b001 => MemoryTransaction::Read,
+            0b010 => MemoryTransaction::Write,
+            0b011 => MemoryTransaction::AddressCommand,
+            0b100 => MemoryTransaction::Scrubbing,
+            m => MemoryTransaction::Reserved(m as u8),
+        }
+    }
+}
+
+/// Decoded architectural MCA error code (bits 15:0 of `IA32_MCi_STATUS`).
+///
+/// See Intel Vol. 3b Section 15.9.1, "Simple Error Codes" and
+/// Section 15.9.2, "Compound Error Codes".
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum McaErrorCode {
+    /// No error has been reported to this bank.
+    NoError,
+    /// Error has not been classified into the MCA error classes.
+    Unclassified,
+    /// Parity error in internal microcode ROM.
+    MicrocodeRomParity,
+    /// BINIT# from another processor caused this processor to enter machine check.
+    External,
+    /// FRC (functional redundancy check) master/slave error.
+    Frc,
+    /// Internal parity error.
+    InternalParity,
+    /// An attempt was made by the SMM handler to execute outside the ranges
+    /// specified by SMRR.
+    SmmHandlerCodeAccessViolation,
+    /// Internal timer error.
+    InternalTimer,
+    /// Generic I/O error.
+    Io,
+    /// Internal unclassified error (the lower ten bits hold the model
+    /// specific classification).
+    InternalUnclassified(u16),
+    /// Generic cache hierarchy error.
+    GenericCacheHierarchy { level: MemoryHierarchyLevel },
+    /// TLB error.
+    Tlb {
+        transaction: TransactionType,
+        level: MemoryHierarchyLevel,
+    },
+    /// Memory controller error. `channel` is `None` if it is not specified.
+    MemoryController {
+        transaction: MemoryTransaction,
+        channel: Option<u8>,
+    },
+    /// Cache hierarchy error.
+    CacheHierarchy {
+        request: RequestType,
+        transaction: TransactionType,
+        level: MemoryHierarchyLevel,
+    },
+    /// Bus and interconnect error.
+    BusInterconnect {
+        participation: Participation,
+        timeout: bool,
+        request: RequestType,
+        memory_or_io: MemoryOrIo,
+        level: MemoryHierarchyLevel,
+    },
+    /// Encoding that does not match any architecturally defined class.
+    Unknown(u16),
+}
+
+impl McaErrorCode {
+    /// Decode the MCA error code bits 15:0 of `IA32_MCi_STATUS`.
+    pub fn from_raw(code: u16) -> McaErrorCode {
+        match code {
+            0x0000 => return McaErrorCode::NoError,
+            0x0001 => return McaErrorCode::Unclassified,
+            0x0002 => return McaErrorCode::MicrocodeRomParity,
+            0x0003 => return McaErrorCode::External,
+            0x0004 => return McaErrorCode::Frc,
+            0x0005 => return McaErrorCode::InternalParity,
+            0x0006 => return McaErrorCode::SmmHandlerCodeAccessViolation,
+            0x0400 => return McaErrorCode::InternalTimer,
+            0x0e0b => return McaErrorCode::Io,
+            c if c & 0xfc00 == 0x0400 => return McaErrorCode::InternalUnclassified(c & 0x03ff),
+            _ => {}
+        }
+
+        // Compound error codes, bit 12 is the correction report filtering bit.
+        let c = code & !(1 << 12);
+        if c & 0xf800 == 0x0800 {
+            McaErrorCode::BusInterconnect {
+                participation: Participation::from_bits(c >> 9),
+                timeout: c.get_bit(8),
+                request: RequestType::from_bits(c >> 4),
+                memory_or_io: MemoryOrIo::from_bits(c >> 2),
+                level: MemoryHierarchyLevel::from_bits(c),
+            }
+        } else if c & 0xff00 == 0x0100 {
+            McaErrorCode::CacheHierarchy {
+                request: RequestType::from_bits(c >> 4),
+                transaction: TransactionType::from_bits(c >> 2),
+                level: MemoryHierarchyLevel::from_bits(c),
+            }
+        } else if c & 0xff80 == 0x0080 {
+            let channel = c.get_bits(0..=3) as u8;
+            McaErrorCode::MemoryController {
+                transaction: MemoryTransaction::from_bits(c >> 4),
+                channel: if channel == 0b1111 {
+                    None
+                } else {
+                    Some(channel)
+                },
+            }
+        } else if c & 0xfff0 == 0x0010 {
+            McaErrorCode::Tlb {
+                transaction: TransactionType::from_bits(c >> 2),
+                level: MemoryHierarchyLevel::from_bits(c),
+            }
+        } else if c & 0xfffc == 0x000c {
+            McaErrorCode::GenericCacheHierarchy {
+                level: MemoryHierarchyLevel::from_bits(c),
+            }
+        } else {
+            McaErrorCode::Unknown(code)
+        }
+    }
+
+    /// Is this a compound error code (which has a correction report filtering bit)?
+    pub fn is_compound(&self) -> bool {
+        matches!(
+            self,
+            McaErrorCode::GenericCacheHierarchy { .. }
+                | McaErrorCode::Tlb { .. }
+                | McaErrorCode::MemoryController { .. }
+                | McaErrorCode::CacheHierarchy { .. }
+                | McaErrorCode::BusInterconnect { .. }
+        )
+    }
+}
+
+/// Address mode of `IA32_MCi_ADDR` as reported in `IA32_MCi_MISC` (bits 8:6).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AddressMode {
+    /// Segment offset.
+    SegmentOffset,
+    /// Linear address.
+    Linear,
+    /// Physical address.
+    Physical,
+    /// Memory address.
+    Memory,
+    /// Generic.
+    Generic,
+    /// Reserved encoding.
+    Reserved(u8),
+}
+
+/// Additional error information (`IA32_MCi_MISC`) in the format defined
+/// for software error recovery (`MCG_SER_P`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MciMisc(pub u64);
+
+impl MciMisc {
+    /// Position of the least significant valid bit of the address in
+    /// `IA32_MCi_ADDR` (bits 5:0). For example 12 means the address is only
+    /// valid at 4 KiB granularity.
+    pub fn recoverable_address_lsb(&self) -> u8 {
+        self.0.get_bits(0..=5) as u8
+    }
+
+    /// Type of the address in `IA32_MCi_ADDR` (bits 8:6).
+    pub fn address_mode(&self) -> AddressMode {
+        match self.0.get_bits(6..=8) as u8 {
+            0b000 => AddressMode::SegmentOffset,
+            0b001 => AddressMode::Linear,
+            0b010 => AddressMode::Physical,
+            0b011 => AddressMode::Memory,
+            0b111 => AddressMode::Generic,
+            m => AddressMode::Reserved(m),
+        }
+    }
+
+    /// Mask `addr` (read from `IA32_MCi_ADDR`) to the bits that are valid
+    /// according to [`MciMisc::recoverable_address_lsb`].
+    pub fn valid_address(&self, addr: u64) -> u64 {
+        let lsb = self.recoverable_address_lsb();
+        if lsb >= 64 {
+            0
+        } else {
+            addr & !((1u64 << lsb) - 1)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mcg_registers() {
+        // 20 banks, CMCI_P, TES_P, SER_P, EMC_P, ELOG_P, LMCE_P, no CTL_P, no ext. registers.
+        let cap = McgCap(0x0f00_0c14);
+        assert_eq!(cap.count(), 20);
+        assert_eq!(cap.ext_count(), 0);
+        assert_eq!(
+            cap.flags(),
+            McgCapFlags::MCG_CMCI_P
+                | McgCapFlags::MCG_TES_P
+                | McgCapFlags::MCG_SER_P
+                | McgCapFlags::MCG_EMC_P
+                | McgCapFlags::MCG_ELOG_P
+                | McgCapFlags::MCG_LMCE_P
+        );
+        let banks = cap.banks();
+        assert_eq!(banks.clone().count(), 20);
+        assert_eq!(banks.last(), Some(McaBank(19)));
+        assert_eq!(McgCap(0x10a0a).ext_count(), 1);
+        assert_eq!(McgCap(0).banks().next(), None);
+
+        let status = McgStatus::from_bits_truncate(0b0101);
+        assert!(status.contains(McgStatus::RIPV | McgStatus::MCIP));
+        assert!(!status.contains(McgStatus::EIPV));
+    }
+
+    #[test]
+    fn bank_msrs() {
+        assert_eq!(McaBank(0).ctl_msr(), 0x400);
+        assert_eq!(McaBank(0).status_msr(), 0x401);
+        assert_eq!(McaBank(3).ctl_msr(), 0x40c);
+        assert_eq!(McaBank(3).status_msr(), 0x40d);
+        assert_eq!(McaBank(3).addr_msr(), 0x40e);
+        assert_eq!(McaBank(3).misc_msr(), 0x40f);
+        assert_eq!(McaBank(3).ctl2_msr(), 0x283);
+    }
+
+    #[test]
+    fn srar_data_load() {
+        // Software recoverable action required: data load from poisoned memory (DCU).
+        let status = MciStatus(0xbd80_0000_0010_0134);
+        assert!(status.val() && status.uc() && status.en());
+        assert!(status.miscv() && status.addrv() && status.s() && status.ar());
+        assert!(!status.over() && !status.pcc());
+        assert_eq!(status.severity(), ErrorSeverity::ActionRequired);
+        assert_eq!(status.model_specific_error_code(), 0x0010);
+        assert_eq!(
+            status.error(),
+            McaErrorCode::CacheHierarchy {
+                request: RequestType::DataRead,
+                transaction: TransactionType::Data,
+                level: MemoryHierarchyLevel::Level0,
+            }
+        );
+        assert!(!status.correction_report_filtering());
+    }
+
+    #[test]
+    fn srao_patrol_scrub() {
+        // Software recoverable action optional: memory scrubbing error on channel 2.
+        let status = MciStatus(0xbd00_0000_0080_00c2);
+        assert_eq!(status.severity(), ErrorSeverity::ActionOptional);
+        assert_eq!(
+            status.error(),
+            McaErrorCode::MemoryController {
+                transaction: MemoryTransaction::Scrubbing,
+                channel: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn corrected_memory_read() {
+        // Corrected memory read error, channel not specified, filtered, 3 errors, green.
+        let status = MciStatus(0x9c20_00c0_0001_109f);
+        assert_eq!(status.severity(), ErrorSeverity::Corrected);
+        assert_eq!(status.corrected_error_count(), 3);
+        assert_eq!(status.threshold_status(), ThresholdStatus::Green);
+        assert_eq!(status.model_specific_error_code(), 1);
+        assert!(status.correction_report_filtering());
+        assert_eq!(
+            status.error(),
+            McaErrorCode::MemoryController {
+                transaction: MemoryTransaction::Read,
+                channel: None,
+            }
+        );
+    }
+
+    #[test]
+    fn fatal_internal_timer() {
+        let status = MciStatus(0xfe00_0000_0080_0400);
+        assert!(status.over());
+        assert_eq!(status.severity(), ErrorSeverity::Fatal);
+        assert_eq!(status.error(), McaErrorCode::InternalTimer);
+        assert!(!status.correction_report_filtering());
+    }
+
+    #[test]
+    fn simple_error_codes() {
+        assert_eq!(McaErrorCode::from_raw(0x0000), McaErrorCode::NoError);
+        assert_eq!(McaErrorCode::from_raw(0x0001), McaErrorCode::Unclassified);
+        assert_eq!(
+            McaErrorCode::from_raw(0x0002),
+            McaErrorCode::MicrocodeRomParity
+        );
+        assert_eq!(McaErrorCode::from_raw(0x0003), McaErrorCode::External);
+        assert_eq!(McaErrorCode::from_raw(0x0004), McaErrorCode::Frc);
+        assert_eq!(McaErrorCode::from_raw(0x0005), McaErrorCode::InternalParity);
+        assert_eq!(
+            McaErrorCode::from_raw(0x0006),
+            McaErrorCode::SmmHandlerCodeAccessViolation
+        );
+        assert_eq!(McaErrorCode::from_raw(0x0e0b), McaErrorCode::Io);
+        assert_eq!(
+            McaErrorCode::from_raw(0x0405),
+            McaErrorCode::InternalUnclassified(0x5)
+        );
+        assert_eq!(
+            McaErrorCode::from_raw(0x0007),
+            McaErrorCode::Unknown(0x0007)
+        );
+        assert_eq!(
+            McaErrorCode::from_raw(0x2000),
+            McaErrorCode::Unknown(0x2000)
+        );
+    }
+
+    #[test]
+    fn compound_error_codes() {
+        assert_eq!(
+            McaErrorCode::from_raw(0x000e),
+            McaErrorCode::GenericCacheHierarchy {
+                level: MemoryHierarchyLevel::Level2
+            }
+        );
+        assert_eq!(
+            McaErrorCode::from_raw(0x1011),
+            McaErrorCode::Tlb {
+                transaction: TransactionType::Instruction,
+                level: MemoryHierarchyLevel::Level1,
+            }
+        );
+        assert_eq!(
+            McaErrorCode::from_raw(0x017a),
+            McaErrorCode::CacheHierarchy {
+                request: RequestType::Eviction,
+                transaction: TransactionType::Generic,
+                level: MemoryHierarchyLevel::Level2,
+            }
+        );
+        assert_eq!(
+            McaErrorCode::from_raw(0x0f0f),
+            McaErrorCode::BusInterconnect {
+                participation: Participation::Generic,
+                timeout: true,
+                request: RequestType::Generic,
+                memory_or_io: MemoryOrIo::Other,
+                level: MemoryHierarchyLevel::Generic,
+            }
+        );
+        assert_eq!(
+            McaErrorCode::from_raw(0x0813),
+            McaErrorCode::BusInterconnect {
+                participation: Participation::Source,
+                timeout: false,
+                request: RequestType::Read,
+                memory_or_io: MemoryOrIo::Memory,
+                level: MemoryHierarchyLevel::Generic,
+            }
+        );
+    }
+
+    #[test]
+    fn misc_address_mode() {
+        // 64-byte granularity, physical address.
+        let misc = MciMisc(0x86);
+        assert_eq!(misc.recoverable_address_lsb(), 6);
+        assert_eq!(misc.address_mode(), AddressMode::Physical);
+        assert_eq!(misc.valid_address(0x1234_5678), 0x1234_5640);
+
+        let misc = MciMisc(0x8c);
+        assert_eq!(misc.recoverable_address_lsb(), 12);
+        assert_eq!(misc.valid_address(0x1234_5678), 0x1234_5000);
+
+        assert_eq!(MciMisc(0x0 << 6).address_mode(), AddressMode::SegmentOffset);
+        assert_eq!(MciMisc(0x1 << 6).address_mode(), AddressMode::Linear);
+        assert_eq!(MciMisc(0x3 << 6).address_mode(), AddressMode::Memory);
+        assert_eq!(MciMisc(0x7 << 6).address_mode(), AddressMode::Generic);
+        assert_eq!(MciMisc(0x5 << 6).address_mode(), AddressMode::Reserved(5));
+    }
+}
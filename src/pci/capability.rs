@@ -0,0 +1,438 @@
+//! PCI capability lists and the MSI, MSI-X and PCI Express capabilities.
+//!
+//! See the PCI Local Bus Specification 3.0, Section 6.7 and 6.8, and the PCI
+//! Express Base Specification, Section 7.5 and 7.6.
+
+use super::{ConfigAccess, Header, PciAddress};
+use crate::apic::msi::MsiMessage;
+
+/// Power management capability.
+pub const CAP_ID_POWER_MANAGEMENT: u8 = 0x01;
+/// Message signaled interrupts.
+pub const CAP_ID_MSI: u8 = 0x05;
+/// Vendor-specific capability.
+pub const CAP_ID_VENDOR_SPECIFIC: u8 = 0x09;
+/// PCI Express capability.
+pub const CAP_ID_PCI_EXPRESS: u8 = 0x10;
+/// MSI-X.
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// Advanced error reporting.
+pub const EXT_CAP_ID_AER: u16 = 0x0001;
+/// Virtual channel.
+pub const EXT_CAP_ID_VIRTUAL_CHANNEL: u16 = 0x0002;
+/// Device serial number.
+pub const EXT_CAP_ID_SERIAL_NUMBER: u16 = 0x0003;
+/// Single root I/O virtualization.
+pub const EXT_CAP_ID_SRIOV: u16 = 0x0010;
+
+/// Upper bound of list entries, to stop on malformed (cyclic) lists.
+const MAX_CAPABILITIES: usize = (0x1000 - 0x40) / 4;
+
+/// An entry of the capability list.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset in configuration space.
+    pub offset: u8,
+}
+
+/// Iterator over the capability list, see [`capabilities`].
+pub struct Capabilities<'a, C: ConfigAccess + ?Sized> {
+    access: &'a mut C,
+    address: PciAddress,
+    next: u8,
+    remaining: usize,
+}
+
+impl<'a, C: ConfigAccess + ?Sized> Iterator for Capabilities<'a, C> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        let offset = self.next & 0xfc;
+        let header = self.access.read(self.address, offset as u16);
+        self.next = (header >> 8) as u8;
+        self.remaining -= 1;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+/// Iterate over the capability list of a function.
+pub fn capabilities<'a, C: ConfigAccess + ?Sized>(
+    access: &'a mut C,
+    address: PciAddress,
+    header: &Header,
+) -> Capabilities<'a, C> {
+    Capabilities {
+        access,
+        address,
+        next: header.capabilities_pointer.unwrap_or(0),
+        remaining: MAX_CAPABILITIES,
+    }
+}
+
+/// Offset of the first capability with `id`.
+pub fn find_capability<C: ConfigAccess + ?Sized>(
+    access: &mut C,
+    address: PciAddress,
+    header: &Header,
+    id: u8,
+) -> Option<u8> {
+    capabilities(access, address, header)
+        .find(|capability| capability.id == id)
+        .map(|capability| capability.offset)
+}
+
+/// An entry of the PCI Express extended capability list.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    /// Offset in configuration space.
+    pub offset: u16,
+}
+
+/// Iterator over the extended capability list, see
+/// [`extended_capabilities`].
+pub struct ExtendedCapabilities<'a, C: ConfigAccess + ?Sized> {
+    access: &'a mut C,
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl<'a, C: ConfigAccess + ?Sized> Iterator for ExtendedCapabilities<'a, C> {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        if self.next < 0x100 || self.remaining == 0 {
+            return None;
+        }
+        let offset = self.next & 0xffc;
+        let header = self.access.read(self.address, offset);
+        if header == 0 || header == u32::MAX {
+            return None;
+        }
+        self.next = (header >> 20) as u16;
+        self.remaining -= 1;
+        Some(ExtendedCapability {
+            id: header as u16,
+            version: ((header >> 16) & 0xf) as u8,
+            offset,
+        })
+    }
+}
+
+/// Iterate over the extended capability list of a PCI Express function.
+///
+/// Empty if `access` can't reach the extended configuration space.
+pub fn extended_capabilities<C: ConfigAccess + ?Sized>(
+    access: &mut C,
+    address: PciAddress,
+) -> ExtendedCapabilities<'_, C> {
+    let next = if access.has_extended_space() {
+        0x100
+    } else {
+        0
+    };
+    ExtendedCapabilities {
+        access,
+        address,
+        next,
+        remaining: MAX_CAPABILITIES,
+    }
+}
+
+/// Write the upper half (control register) of a capability header.
+fn write_control<C: ConfigAccess + ?Sized>(
+    access: &mut C,
+    address: PciAddress,
+    offset: u8,
+    control: u16,
+) {
+    // ID and next pointer are read-only
+    let header = access.read(address, offset as u16) & 0xffff;
+    access.write(address, offset as u16, header | (control as u32) << 16);
+}
+
+/// The MSI capability.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsiCapability {
+    offset: u8,
+    control: u16,
+}
+
+impl MsiCapability {
+    /// MSI enable.
+    const ENABLE: u16 = 1 << 0;
+    /// Multiple message enable (log2 of the enabled vectors).
+    const MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+    /// 64-bit message address.
+    const ADDRESS_64BIT: u16 = 1 << 7;
+    /// Per-vector masking.
+    const PER_VECTOR_MASKING: u16 = 1 << 8;
+
+    /// Read the MSI capability at `offset`.
+    pub fn read<C: ConfigAccess + ?Sized>(
+        access: &mut C,
+        address: PciAddress,
+        offset: u8,
+    ) -> MsiCapability {
+        MsiCapability {
+            offset,
+            control: (access.read(address, offset as u16) >> 16) as u16,
+        }
+    }
+
+    /// Offset of the capability.
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    /// Does the function support 64-bit message addresses?
+    pub fn is_64bit(&self) -> bool {
+        self.control & MsiCapability::ADDRESS_64BIT != 0
+    }
+
+    /// Does the function support masking individual vectors?
+    pub fn per_vector_masking(&self) -> bool {
+        self.control & MsiCapability::PER_VECTOR_MASKING != 0
+    }
+
+    /// Number of vectors the function can request (1-32).
+    pub fn max_vectors(&self) -> u8 {
+        1 << ((self.control >> 1) & 0b111).min(5)
+    }
+
+    /// Is MSI enabled?
+    pub fn is_enabled(&self) -> bool {
+        self.control & MsiCapability::ENABLE != 0
+    }
+
+    fn data_offset(&self) -> u16 {
+        if self.is_64bit() {
+            self.offset as u16 + 0xc
+        } else {
+            self.offset as u16 + 0x8
+        }
+    }
+
+    /// Read the programmed message.
+    pub fn message<C: ConfigAccess + ?Sized>(
+        &self,
+        access: &mut C,
+        address: PciAddress,
+    ) -> MsiMessage {
+        let offset = self.offset as u16;
+        let mut message_address = access.read(address, offset + 4) as u64;
+        if self.is_64bit() {
+            message_address |= (access.read(address, offset + 8) as u64) << 32;
+        }
+        MsiMessage {
+            address: message_address,
+            data: access.read_u16(address, self.data_offset()) as u32,
+        }
+    }
+
+    /// Program the message (e.g., from [`crate::apic::msi::MsiMessageBuilder`]).
+    pub fn set_message<C: ConfigAccess + ?Sized>(
+        &self,
+        access: &mut C,
+        address: PciAddress,
+        message: MsiMessage,
+    ) {
+        let offset = self.offset as u16;
+        access.write(address, offset + 4, message.address_lo());
+        if self.is_64bit() {
+            access.write(address, offset + 8, message.address_hi());
+        }
+        access.write_u16(address, self.data_offset(), message.data_u16());
+    }
+
+    /// Enable (with a single vector) or disable MSI.
+    pub fn set_enabled<C: ConfigAccess + ?Sized>(
+        &mut self,
+        access: &mut C,
+        address: PciAddress,
+        enabled: bool,
+    ) {
+        self.control &= !(MsiCapability::ENABLE | MsiCapability::MULTIPLE_MESSAGE_ENABLE);
+        if enabled {
+            self.control |= MsiCapability::ENABLE;
+        }
+        write_control(access, address, self.offset, self.control);
+    }
+}
+
+/// The MSI-X capability.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsixCapability {
+    offset: u8,
+    control: u16,
+    table: u32,
+    pba: u32,
+}
+
+impl MsixCapability {
+    /// MSI-X enable.
+    const ENABLE: u16 = 1 << 15;
+    /// Mask all vectors.
+    const FUNCTION_MASK: u16 = 1 << 14;
+
+    /// Read the MSI-X capability at `offset`.
+    pub fn read<C: ConfigAccess + ?Sized>(
+        access: &mut C,
+        address: PciAddress,
+        offset: u8,
+    ) -> MsixCapability {
+        MsixCapability {
+            offset,
+            control: (access.read(address, offset as u16) >> 16) as u16,
+            table: access.read(address, offset as u16 + 4),
+            pba: access.read(address, offset as u16 + 8),
+        }
+    }
+
+    /// Offset of the capability.
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    /// Number of entries in the MSI-X table (1-2048).
+    pub fn table_size(&self) -> u16 {
+        (self.control & 0x7ff) + 1
+    }
+
+    /// BAR (index) containing the MSI-X table.
+    pub fn table_bar(&self) -> u8 {
+        (self.table & 0x7) as u8
+    }
+
+    /// Offset of the MSI-X table in its BAR.
+    pub fn table_offset(&self) -> u32 {
+        self.table & !0x7
+    }
+
+    /// BAR (index) containing the pending bit array.
+    pub fn pba_bar(&self) -> u8 {
+        (self.pba & 0x7) as u8
+    }
+
+    /// Offset of the pending bit array in its BAR.
+    pub fn pba_offset(&self) -> u32 {
+        self.pba & !0x7
+    }
+
+    /// Is MSI-X enabled?
+    pub fn is_enabled(&self) -> bool {
+        self.control & MsixCapability::ENABLE != 0
+    }
+
+    /// Are all vectors masked?
+    pub fn is_function_masked(&self) -> bool {
+        self.control & MsixCapability::FUNCTION_MASK != 0
+    }
+
+    /// Enable or disable MSI-X.
+    pub fn set_enabled<C: ConfigAccess + ?Sized>(
+        &mut self,
+        access: &mut C,
+        address: PciAddress,
+        enabled: bool,
+    ) {
+        if enabled {
+            self.control |= MsixCapability::ENABLE;
+        } else {
+            self.control &= !MsixCapability::ENABLE;
+        }
+        write_control(access, address, self.offset, self.control);
+    }
+
+    /// Mask or unmask all vectors (regardless of their per-entry mask).
+    pub fn set_function_masked<C: ConfigAccess + ?Sized>(
+        &mut self,
+        access: &mut C,
+        address: PciAddress,
+        masked: bool,
+    ) {
+        if masked {
+            self.control |= MsixCapability::FUNCTION_MASK;
+        } else {
+            self.control &= !MsixCapability::FUNCTION_MASK;
+        }
+        write_control(access, address, self.offset, self.control);
+    }
+}
+
+/// Device/port type of a PCI Express function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DevicePortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamPort,
+    DownstreamPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RootComplexIntegratedEndpoint,
+    RootComplexEventCollector,
+    Unknown(u8),
+}
+
+/// The PCI Express capability.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PcieCapability {
+    offset: u8,
+    capabilities: u16,
+}
+
+impl PcieCapability {
+    /// Read the PCI Express capability at `offset`.
+    pub fn read<C: ConfigAccess + ?Sized>(
+        access: &mut C,
+        address: PciAddress,
+        offset: u8,
+    ) -> PcieCapability {
+        PcieCapability {
+            offset,
+            capabilities: (access.read(address, offset as u16) >> 16) as u16,
+        }
+    }
+
+    /// Offset of the capability.
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    /// Capability structure version.
+    pub fn version(&self) -> u8 {
+        (self.capabilities & 0xf) as u8
+    }
+
+    /// Device/port type.
+    pub fn device_port_type(&self) -> DevicePortType {
+        match (self.capabilities >> 4) & 0xf {
+            0x0 => DevicePortType::Endpoint,
+            0x1 => DevicePortType::LegacyEndpoint,
+            0x4 => DevicePortType::RootPort,
+            0x5 => DevicePortType::UpstreamPort,
+            0x6 => DevicePortType::DownstreamPort,
+            0x7 => DevicePortType::PcieToPciBridge,
+            0x8 => DevicePortType::PciToPcieBridge,
+            0x9 => DevicePortType::RootComplexIntegratedEndpoint,
+            0xa => DevicePortType::RootComplexEventCollector,
+            other => DevicePortType::Unknown(other as u8),
+        }
+    }
+
+    /// Interrupt message number used for PCI Express events.
+    pub fn interrupt_message_number(&self) -> u8 {
+        ((self.capabilities >> 9) & 0x1f) as u8
+    }
+}
@@ -0,0 +1,1018 @@
+//! PCI configuration space access, header parsing and enumeration.
+//!
+//! Configuration space is accessed through [`ConfigAccess`], implemented by
+//! [`ConfigPorts`] (configuration mechanism #1 with ports 0xcf8/0xcfc, 256
+//! bytes per function) and [`Ecam`] (memory-mapped PCI Express enhanced
+//! configuration access, 4 KiB per function).
+//!
+//! See the PCI Local Bus Specification 3.0, Chapter 6 and the PCI Express Base
+//! Specification, Section 7.2.
+
+use core::fmt;
+use core::ops::RangeInclusive;
+use core::ptr;
+
+use crate::io::{NativePortIo, PortIo};
+
+pub mod capability;
+
+/// Configuration address port of mechanism #1.
+pub const CONFIG_ADDRESS: u16 = 0xcf8;
+/// Configuration data port of mechanism #1.
+pub const CONFIG_DATA: u16 = 0xcfc;
+
+/// Vendor ID of absent functions.
+const INVALID_VENDOR: u16 = 0xffff;
+
+/// Command register.
+pub const COMMAND: u16 = 0x04;
+/// Status register.
+pub const STATUS: u16 = 0x06;
+/// Base address registers (six for endpoints, two for bridges).
+pub const BAR0: u16 = 0x10;
+/// Capabilities pointer (header types 0 and 1).
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+/// Capabilities pointer of CardBus bridges.
+pub const CARDBUS_CAPABILITIES_POINTER: u16 = 0x14;
+
+/// Command register: respond to I/O space accesses.
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+/// Command register: respond to memory space accesses.
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Command register: allow bus mastering (DMA and MSI).
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Command register: disable INTx interrupts.
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+/// Status register: a capability list is present.
+pub const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+/// Location of a function: segment (PCI Express), bus, device and function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    /// 0-31
+    pub device: u8,
+    /// 0-7
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// # Panics
+    /// If `device` is above 31 or `function` above 7.
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> PciAddress {
+        assert!(device < 32 && function < 8);
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+/// Formats as `segment:bus:device.function`, e.g., `0000:00:1f.3`.
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Access to the configuration space of PCI functions.
+///
+/// Reads of absent functions return all ones. The 8 and 16-bit writes
+/// read-modify-write the surrounding dword, so they rewrite set
+/// write-1-to-clear bits in it (e.g., of the status register).
+pub trait ConfigAccess {
+    /// Read the dword at `offset` (a multiple of 4).
+    fn read(&mut self, address: PciAddress, offset: u16) -> u32;
+
+    /// Write the dword at `offset` (a multiple of 4).
+    fn write(&mut self, address: PciAddress, offset: u16, value: u32);
+
+    /// Is the extended configuration space (offsets 0x100-0xfff) accessible?
+    fn has_extended_space(&self) -> bool;
+
+    /// Read 16 bits at `offset` (a multiple of 2).
+    fn read_u16(&mut self, address: PciAddress, offset: u16) -> u16 {
+        (self.read(address, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+
+    /// Read 8 bits at `offset`.
+    fn read_u8(&mut self, address: PciAddress, offset: u16) -> u8 {
+        (self.read(address, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    /// Write 16 bits at `offset` (a multiple of 2).
+    fn write_u16(&mut self, address: PciAddress, offset: u16, value: u16) {
+        let shift = (offset & 0x2) * 8;
+        let old = self.read(address, offset & !0x3) & !(0xffff << shift);
+        self.write(address, offset & !0x3, old | (value as u32) << shift);
+    }
+
+    /// Write 8 bits at `offset`.
+    fn write_u8(&mut self, address: PciAddress, offset: u16, value: u8) {
+        let shift = (offset & 0x3) * 8;
+        let old = self.read(address, offset & !0x3) & !(0xff << shift);
+        self.write(address, offset & !0x3, old | (value as u32) << shift);
+    }
+}
+
+/// Configuration mechanism #1 through ports 0xcf8 and 0xcfc.
+///
+/// Only supports segment 0 and offsets below 0x100.
+#[derive(Debug)]
+pub struct ConfigPorts<P: PortIo = NativePortIo> {
+    io: P,
+}
+
+impl ConfigPorts {
+    /// # Safety
+    /// Needs IO privileges, and accesses must not be interleaved with other
+    /// users of the configuration ports.
+    pub unsafe fn new() -> Self {
+        ConfigPorts::with_io(NativePortIo::new())
+    }
+}
+
+impl<P: PortIo> ConfigPorts<P> {
+    /// Create a configuration space accessor doing port I/O through `io`.
+    pub fn with_io(io: P) -> Self {
+        ConfigPorts { io }
+    }
+
+    /// Value of the configuration address register to access `offset`.
+    pub fn config_address(address: PciAddress, offset: u16) -> u32 {
+        1 << 31
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    fn reachable(address: PciAddress, offset: u16) -> bool {
+        address.segment == 0 && offset < 0x100
+    }
+}
+
+impl<P: PortIo> ConfigAccess for ConfigPorts<P> {
+    fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+        if !Self::reachable(address, offset) {
+            return u32::MAX;
+        }
+        self.io
+            .write_u32(CONFIG_ADDRESS, Self::config_address(address, offset));
+        self.io.read_u32(CONFIG_DATA)
+    }
+
+    fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+        if Self::reachable(address, offset) {
+            self.io
+                .write_u32(CONFIG_ADDRESS, Self::config_address(address, offset));
+            self.io.write_u32(CONFIG_DATA, value);
+        }
+    }
+
+    fn has_extended_space(&self) -> bool {
+        false
+    }
+}
+
+/// PCI Express enhanced configuration access mechanism (ECAM).
+///
+/// The region maps the configuration space of a segment from `start_bus` on
+/// (as described by the ACPI MCFG table), 1 MiB per bus.
+#[derive(Debug)]
+pub struct Ecam<'a> {
+    region: &'a mut [u32],
+    segment: u16,
+    start_bus: u8,
+}
+
+impl<'a> Ecam<'a> {
+    /// Create an accessor for the memory-mapped `region` (uncached) of
+    /// `segment`, starting at bus `start_bus`.
+    pub fn new(region: &'a mut [u32], segment: u16, start_bus: u8) -> Ecam<'a> {
+        Ecam {
+            region,
+            segment,
+            start_bus,
+        }
+    }
+
+    /// Dword index of `offset` of a function in the region.
+    fn index(&self, address: PciAddress, offset: u16) -> Option<usize> {
+        if address.segment != self.segment || address.bus < self.start_bus || offset >= 0x1000 {
+            return None;
+        }
+        let byte = ((address.bus - self.start_bus) as usize) << 20
+            | (address.device as usize) << 15
+            | (address.function as usize) << 12
+            | (offset & 0xffc) as usize;
+        let index = byte / 4;
+        if index < self.region.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> ConfigAccess for Ecam<'a> {
+    fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+        match self.index(address, offset) {
+            Some(index) => unsafe { ptr::read_volatile(&self.region[index]) },
+            None => u32::MAX,
+        }
+    }
+
+    fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+        if let Some(index) = self.index(address, offset) {
+            unsafe { ptr::write_volatile(&mut self.region[index], value) }
+        }
+    }
+
+    fn has_extended_space(&self) -> bool {
+        true
+    }
+}
+
+/// Layout of the configuration header.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HeaderKind {
+    /// Type 0: a device.
+    Endpoint {
+        subsystem_vendor_id: u16,
+        subsystem_id: u16,
+        interrupt_line: u8,
+        /// 0 for none, 1-4 for INTA#-INTD#.
+        interrupt_pin: u8,
+    },
+    /// Type 1: a PCI-to-PCI bridge.
+    PciBridge {
+        primary_bus: u8,
+        secondary_bus: u8,
+        subordinate_bus: u8,
+    },
+    /// Type 2: a CardBus bridge.
+    CardBusBridge,
+    /// Unknown header type.
+    Unknown(u8),
+}
+
+/// The parsed configuration header of a function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Header {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+    pub revision: u8,
+    pub prog_if: u8,
+    pub subclass: u8,
+    pub class: u8,
+    /// The device has more than one function.
+    pub multifunction: bool,
+    /// Offset of the first capability.
+    pub capabilities_pointer: Option<u8>,
+    pub kind: HeaderKind,
+}
+
+impl Header {
+    /// Parse the first 64 bytes of configuration space (`None` if the
+    /// function is absent).
+    pub fn parse(dwords: &[u32; 16]) -> Option<Header> {
+        let vendor_id = dwords[0] as u16;
+        if vendor_id == INVALID_VENDOR {
+            return None;
+        }
+        let status = (dwords[1] >> 16) as u16;
+        let header_type = (dwords[3] >> 16) as u8;
+
+        let (kind, capabilities) = match header_type & 0x7f {
+            0 => (
+                HeaderKind::Endpoint {
+                    subsystem_vendor_id: dwords[11] as u16,
+                    subsystem_id: (dwords[11] >> 16) as u16,
+                    interrupt_line: dwords[15] as u8,
+                    interrupt_pin: (dwords[15] >> 8) as u8,
+                },
+                dwords[13] as u8,
+            ),
+            1 => (
+                HeaderKind::PciBridge {
+                    primary_bus: dwords[6] as u8,
+                    secondary_bus: (dwords[6] >> 8) as u8,
+                    subordinate_bus: (dwords[6] >> 16) as u8,
+                },
+                dwords[13] as u8,
+            ),
+            2 => (HeaderKind::CardBusBridge, dwords[5] as u8),
+            other => (HeaderKind::Unknown(other), 0),
+        };
+        let capabilities_pointer = if status & STATUS_CAPABILITIES_LIST != 0 && capabilities != 0 {
+            Some(capabilities & 0xfc)
+        } else {
+            None
+        };
+
+        Some(Header {
+            vendor_id,
+            device_id: (dwords[0] >> 16) as u16,
+            command: dwords[1] as u16,
+            status,
+            revision: dwords[2] as u8,
+            prog_if: (dwords[2] >> 8) as u8,
+            subclass: (dwords[2] >> 16) as u8,
+            class: (dwords[2] >> 24) as u8,
+            multifunction: header_type & 0x80 != 0,
+            capabilities_pointer,
+            kind,
+        })
+    }
+
+    /// Read and parse the header of the function at `address`.
+    pub fn read<C: ConfigAccess + ?Sized>(access: &mut C, address: PciAddress) -> Option<Header> {
+        if access.read(address, 0) as u16 == INVALID_VENDOR {
+            return None;
+        }
+        let mut dwords = [0; 16];
+        for (i, dword) in dwords.iter_mut().enumerate() {
+            *dword = access.read(address, i as u16 * 4);
+        }
+        Header::parse(&dwords)
+    }
+
+    /// Number of base address registers of this header type.
+    pub fn bar_count(&self) -> u8 {
+        match self.kind {
+            HeaderKind::Endpoint { .. } => 6,
+            HeaderKind::PciBridge { .. } => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Write the command register (without touching the status register).
+pub fn set_command<C: ConfigAccess + ?Sized>(access: &mut C, address: PciAddress, command: u16) {
+    // Zeroes in the status half don't clear its write-1-to-clear bits
+    access.write(address, COMMAND, command as u32);
+}
+
+/// Type of a base address register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BarKind {
+    /// I/O space.
+    Io,
+    /// 32-bit memory space.
+    Memory32 { prefetchable: bool },
+    /// 64-bit memory space (uses the following BAR for the upper half).
+    Memory64 { prefetchable: bool },
+}
+
+impl BarKind {
+    /// Decode the type bits of a BAR value.
+    pub fn from_raw(raw: u32) -> BarKind {
+        let prefetchable = raw & 0x8 != 0;
+        if raw & 0x1 != 0 {
+            BarKind::Io
+        } else if (raw >> 1) & 0x3 == 0b10 {
+            BarKind::Memory64 { prefetchable }
+        } else {
+            BarKind::Memory32 { prefetchable }
+        }
+    }
+
+    /// Mask of the address bits in the (lower) BAR.
+    fn address_mask(self) -> u32 {
+        match self {
+            BarKind::Io => !0x3,
+            _ => !0xf,
+        }
+    }
+}
+
+/// A decoded and sized base address register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Bar {
+    pub kind: BarKind,
+    /// Assigned address (port for I/O BARs).
+    pub address: u64,
+    /// Size of the region in bytes.
+    pub size: u64,
+}
+
+impl Bar {
+    /// Decode a BAR from its (lower, upper) values and the values read back
+    /// after writing all ones. Returns `None` for unimplemented BARs.
+    pub fn decode(value: (u32, u32), sizing: (u32, u32)) -> Option<Bar> {
+        let kind = BarKind::from_raw(value.0);
+        let (address, mask) = match kind {
+            BarKind::Memory64 { .. } => (
+                (value.1 as u64) << 32 | (value.0 & kind.address_mask()) as u64,
+                (sizing.1 as u64) << 32 | (sizing.0 & kind.address_mask()) as u64,
+            ),
+            BarKind::Io => {
+                let mut mask = sizing.0 & kind.address_mask();
+                // Devices may implement only 16 bits of I/O address
+                if mask & 0xffff_0000 == 0 {
+                    mask |= 0xffff_0000;
+                }
+                (
+                    (value.0 & kind.address_mask()) as u64,
+                    mask as u64 | !0 << 32,
+                )
+            }
+            BarKind::Memory32 { .. } => (
+                (value.0 & kind.address_mask()) as u64,
+                (sizing.0 & kind.address_mask()) as u64 | !0 << 32,
+            ),
+        };
+        if mask == 0 || mask == !0 << 32 {
+            return None;
+        }
+        Some(Bar {
+            kind,
+            address,
+            size: !mask + 1,
+        })
+    }
+
+    /// Read and size BAR `index` of the function at `address`.
+    ///
+    /// Decoding is disabled in the command register while sizing. Returns
+    /// `None` for unimplemented BARs (and the upper half of 64-bit BARs).
+    pub fn read<C: ConfigAccess + ?Sized>(
+        access: &mut C,
+        address: PciAddress,
+        index: u8,
+    ) -> Option<Bar> {
+        let offset = BAR0 + index as u16 * 4;
+        let low = access.read(address, offset);
+        let kind = BarKind::from_raw(low);
+        let is_64bit = matches!(kind, BarKind::Memory64 { .. });
+        let high = if is_64bit {
+            access.read(address, offset + 4)
+        } else {
+            0
+        };
+
+        let command = access.read_u16(address, COMMAND);
+        set_command(
+            access,
+            address,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+        access.write(address, offset, u32::MAX);
+        let low_mask = access.read(address, offset);
+        access.write(address, offset, low);
+        let high_mask = if is_64bit {
+            access.write(address, offset + 4, u32::MAX);
+            let mask = access.read(address, offset + 4);
+            access.write(address, offset + 4, high);
+            mask
+        } else {
+            0
+        };
+        set_command(access, address, command);
+
+        Bar::decode((low, high), (low_mask, high_mask))
+    }
+}
+
+/// Iterator over the BARs of a function, see [`bars`].
+pub struct Bars<'a, C: ConfigAccess + ?Sized> {
+    access: &'a mut C,
+    address: PciAddress,
+    index: u8,
+    count: u8,
+}
+
+impl<'a, C: ConfigAccess + ?Sized> Iterator for Bars<'a, C> {
+    type Item = (u8, Bar);
+
+    fn next(&mut self) -> Option<(u8, Bar)> {
+        while self.index < self.count {
+            let index = self.index;
+            let bar = Bar::read(self.access, self.address, index);
+            self.index += match bar {
+                Some(Bar {
+                    kind: BarKind::Memory64 { .. },
+                    ..
+                }) => 2,
+                _ => 1,
+            };
+            if let Some(bar) = bar {
+                return Some((index, bar));
+            }
+        }
+        None
+    }
+}
+
+/// Iterate over the implemented BARs (index, BAR) of a function.
+pub fn bars<'a, C: ConfigAccess + ?Sized>(
+    access: &'a mut C,
+    address: PciAddress,
+    header: &Header,
+) -> Bars<'a, C> {
+    Bars {
+        access,
+        address,
+        index: 0,
+        count: header.bar_count(),
+    }
+}
+
+/// Iterator over present functions, see [`enumerate`].
+pub struct Enumerator<'a, C: ConfigAccess + ?Sized> {
+    access: &'a mut C,
+    segment: u16,
+    bus: u16,
+    end_bus: u16,
+    device: u8,
+    function: u8,
+}
+
+impl<'a, C: ConfigAccess + ?Sized> Iterator for Enumerator<'a, C> {
+    type Item = (PciAddress, Header);
+
+    fn next(&mut self) -> Option<(PciAddress, Header)> {
+        while self.bus <= self.end_bus {
+            let address = PciAddress::new(self.segment, self.bus as u8, self.device, self.function);
+            let header = Header::read(self.access, address);
+
+            // Only probe functions 1-7 of multi-function devices
+            let next_device = match header {
+                Some(header) => self.function == 7 || (self.function == 0 && !header.multifunction),
+                None => self.function == 0 || self.function == 7,
+            };
+            if next_device {
+                self.function = 0;
+                self.device += 1;
+                if self.device == 32 {
+                    self.device = 0;
+                    self.bus += 1;
+                }
+            } else {
+                self.function += 1;
+            }
+
+            if let Some(header) = header {
+                return Some((address, header));
+            }
+        }
+        None
+    }
+}
+
+/// Scan every device and function on `buses` of `segment`.
+pub fn enumerate<C: ConfigAccess + ?Sized>(
+    access: &mut C,
+    segment: u16,
+    buses: RangeInclusive<u8>,
+) -> Enumerator<'_, C> {
+    Enumerator {
+        access,
+        segment,
+        bus: *buses.start() as u16,
+        end_bus: *buses.end() as u16,
+        device: 0,
+        function: 0,
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::capability::*;
+    use super::*;
+    use crate::apic::msi::MsiMessage;
+
+    extern crate std;
+    use std::format;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Configuration space of a QEMU e1000e NIC (00:02.0), with the
+    /// writable bits of its BARs.
+    fn e1000e() -> ([u32; 1024], [u32; 1024]) {
+        let mut dump = [0; 1024];
+        let header = [
+            0x10d3_8086,
+            0x0010_0007,
+            0x0200_0000,
+            0x0000_0010, // ids, class
+            0xfebc_0000,
+            0x0000_000c,
+            0x0000_0008,
+            0x0000_c061, // BAR0-3
+            0xfebd_0000,
+            0x0000_0000,
+            0x0000_0000,
+            0x0000_8086, // BAR4-5, subsystem
+            0x0000_0000,
+            0x0000_00c8,
+            0x0000_0000,
+            0x0000_010b, // capabilities, irq
+        ];
+        dump[..16].copy_from_slice(&header);
+        // MSI-X: table size 5 in BAR4 at 0, PBA in BAR4 at 0x2000
+        dump[0xa0 / 4..0xac / 4].copy_from_slice(&[0x0004_0011, 0x0000_0004, 0x0000_2004]);
+        // Power management
+        dump[0xc8 / 4] = 0x0022_d001;
+        // MSI, 64-bit
+        dump[0xd0 / 4] = 0x0080_e005;
+        // PCI Express v2 endpoint
+        dump[0xe0 / 4] = 0x0002_a010;
+        // AER, device serial number
+        dump[0x100 / 4] = 0x1402_0001;
+        dump[0x140 / 4] = 0x0001_0003;
+
+        let mut writable = [0; 1024];
+        writable[1] = 0x0000_0547;
+        writable[4] = 0xfffe_0000;
+        writable[5] = 0xfff0_0000;
+        writable[6] = 0xffff_ffff;
+        writable[7] = 0x0000_ffe0;
+        writable[8] = 0xffff_c000;
+        writable[0xa0 / 4] = 0xc000_0000;
+        writable[0xd0 / 4] = 0x0071_0000;
+        writable[0xd4 / 4..0xe0 / 4].copy_from_slice(&[0xffff_fffc, 0xffff_ffff, 0x0000_ffff]);
+        (dump, writable)
+    }
+
+    /// Recorded configuration spaces of a small Q35 machine.
+    struct Dump {
+        functions: Vec<(PciAddress, [u32; 1024], [u32; 1024])>,
+    }
+
+    impl Dump {
+        fn q35() -> Dump {
+            let function = |header: &[u32]| {
+                let mut dump = [0; 1024];
+                dump[..header.len()].copy_from_slice(header);
+                (dump, [0; 1024])
+            };
+            let (nic, nic_writable) = e1000e();
+            let host = function(&[0x29c0_8086, 0x0000_0000, 0x0600_0000, 0x0000_0000]);
+            // Multi-function root ports, bridging to buses 1-2 and 3
+            let port0 = function(&[
+                0x2940_8086,
+                0x0000_0007,
+                0x0604_0002,
+                0x0081_0010,
+                0,
+                0,
+                0x0002_0100,
+            ]);
+            let port1 = function(&[
+                0x2942_8086,
+                0x0000_0007,
+                0x0604_0002,
+                0x0081_0010,
+                0,
+                0,
+                0x0003_0300,
+            ]);
+            let storage = function(&[0x1001_1af4, 0x0000_0000, 0x0100_0000, 0x0000_0000]);
+            Dump {
+                functions: vec![
+                    (PciAddress::new(0, 0, 0, 0), host.0, host.1),
+                    (PciAddress::new(0, 0, 2, 0), nic, nic_writable),
+                    (PciAddress::new(0, 0, 0x1c, 0), port0.0, port0.1),
+                    (PciAddress::new(0, 0, 0x1c, 1), port1.0, port1.1),
+                    (PciAddress::new(0, 1, 0, 0), storage.0, storage.1),
+                ],
+            }
+        }
+    }
+
+    impl ConfigAccess for Dump {
+        fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+            assert_eq!(offset & 0x3, 0);
+            match self.functions.iter().find(|f| f.0 == address) {
+                Some((_, dump, _)) => dump[offset as usize / 4],
+                None => u32::MAX,
+            }
+        }
+
+        fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+            assert_eq!(offset & 0x3, 0);
+            if let Some((_, dump, writable)) = self.functions.iter_mut().find(|f| f.0 == address) {
+                let i = offset as usize / 4;
+                dump[i] = (dump[i] & !writable[i]) | (value & writable[i]);
+            }
+        }
+
+        fn has_extended_space(&self) -> bool {
+            true
+        }
+    }
+
+    const NIC: PciAddress = PciAddress::new(0, 0, 2, 0);
+
+    #[test]
+    fn header() {
+        let mut dump = Dump::q35();
+        let header = Header::read(&mut dump, NIC).unwrap();
+        assert_eq!(header.vendor_id, 0x8086);
+        assert_eq!(header.device_id, 0x10d3);
+        assert_eq!(
+            (header.class, header.subclass, header.prog_if),
+            (0x02, 0x00, 0x00)
+        );
+        assert_eq!(header.command, 0x0007);
+        assert!(!header.multifunction);
+        assert_eq!(header.capabilities_pointer, Some(0xc8));
+        assert_eq!(
+            header.kind,
+            HeaderKind::Endpoint {
+                subsystem_vendor_id: 0x8086,
+                subsystem_id: 0,
+                interrupt_line: 0x0b,
+                interrupt_pin: 1
+            }
+        );
+
+        let bridge = Header::read(&mut dump, PciAddress::new(0, 0, 0x1c, 0)).unwrap();
+        assert!(bridge.multifunction);
+        assert_eq!(bridge.capabilities_pointer, None);
+        assert_eq!(
+            bridge.kind,
+            HeaderKind::PciBridge {
+                primary_bus: 0,
+                secondary_bus: 1,
+                subordinate_bus: 2
+            }
+        );
+        assert_eq!(bridge.bar_count(), 2);
+
+        assert_eq!(Header::read(&mut dump, PciAddress::new(0, 0, 3, 0)), None);
+        assert_eq!(
+            format!("{}", PciAddress::new(0, 0, 0x1f, 3)),
+            "0000:00:1f.3"
+        );
+    }
+
+    #[test]
+    fn bar_sizing() {
+        let mut dump = Dump::q35();
+        let header = Header::read(&mut dump, NIC).unwrap();
+        let bars: Vec<(u8, Bar)> = bars(&mut dump, NIC, &header).collect();
+        assert_eq!(
+            bars,
+            [
+                (
+                    0,
+                    Bar {
+                        kind: BarKind::Memory32 {
+                            prefetchable: false
+                        },
+                        address: 0xfebc_0000,
+                        size: 0x2_0000
+                    }
+                ),
+                (
+                    1,
+                    Bar {
+                        kind: BarKind::Memory64 { prefetchable: true },
+                        address: 0x8_0000_0000,
+                        size: 0x10_0000
+                    }
+                ),
+                (
+                    3,
+                    Bar {
+                        kind: BarKind::Io,
+                        address: 0xc060,
+                        size: 0x20
+                    }
+                ),
+                (
+                    4,
+                    Bar {
+                        kind: BarKind::Memory32 {
+                            prefetchable: false
+                        },
+                        address: 0xfebd_0000,
+                        size: 0x4000
+                    }
+                ),
+            ]
+        );
+
+        // Sizing restores the BARs and the command register
+        assert_eq!(Header::read(&mut dump, NIC), Some(header));
+        assert_eq!(dump.read(NIC, BAR0 + 8), 0x0000_0008);
+
+        assert_eq!(Bar::decode((0, 0), (0, 0)), None);
+        assert_eq!(
+            Bar::decode((0xe001, 0), (0xffff_fff1, 0)),
+            Some(Bar {
+                kind: BarKind::Io,
+                address: 0xe000,
+                size: 0x10
+            })
+        );
+    }
+
+    #[test]
+    fn capability_lists() {
+        let mut dump = Dump::q35();
+        let header = Header::read(&mut dump, NIC).unwrap();
+        let found: Vec<Capability> = capabilities(&mut dump, NIC, &header).collect();
+        assert_eq!(
+            found,
+            [
+                Capability {
+                    id: CAP_ID_POWER_MANAGEMENT,
+                    offset: 0xc8
+                },
+                Capability {
+                    id: CAP_ID_MSI,
+                    offset: 0xd0
+                },
+                Capability {
+                    id: CAP_ID_PCI_EXPRESS,
+                    offset: 0xe0
+                },
+                Capability {
+                    id: CAP_ID_MSIX,
+                    offset: 0xa0
+                },
+            ]
+        );
+        assert_eq!(
+            find_capability(&mut dump, NIC, &header, CAP_ID_MSIX),
+            Some(0xa0)
+        );
+        assert_eq!(find_capability(&mut dump, NIC, &header, 0x09), None);
+
+        let extended: Vec<ExtendedCapability> = extended_capabilities(&mut dump, NIC).collect();
+        assert_eq!(
+            extended,
+            [
+                ExtendedCapability {
+                    id: EXT_CAP_ID_AER,
+                    version: 2,
+                    offset: 0x100
+                },
+                ExtendedCapability {
+                    id: EXT_CAP_ID_SERIAL_NUMBER,
+                    version: 1,
+                    offset: 0x140
+                },
+            ]
+        );
+        let mut ports = ConfigPorts::with_io(Ports::default());
+        assert_eq!(extended_capabilities(&mut ports, NIC).count(), 0);
+        assert!(ports.io.log.is_empty());
+
+        let pcie = PcieCapability::read(&mut dump, NIC, 0xe0);
+        assert_eq!(pcie.version(), 2);
+        assert_eq!(pcie.device_port_type(), DevicePortType::Endpoint);
+    }
+
+    #[test]
+    fn msi_and_msix() {
+        let mut dump = Dump::q35();
+        let mut msi = MsiCapability::read(&mut dump, NIC, 0xd0);
+        assert!(msi.is_64bit());
+        assert!(!msi.per_vector_masking());
+        assert_eq!(msi.max_vectors(), 1);
+        assert!(!msi.is_enabled());
+
+        let message = MsiMessage {
+            address: 0xfee0_1000,
+            data: 0x4031,
+        };
+        msi.set_message(&mut dump, NIC, message);
+        msi.set_enabled(&mut dump, NIC, true);
+        assert!(msi.is_enabled());
+        assert_eq!(dump.read(NIC, 0xd0), 0x0081_e005);
+        assert_eq!(dump.read(NIC, 0xd4), 0xfee0_1000);
+        assert_eq!(dump.read(NIC, 0xd8), 0);
+        assert_eq!(dump.read(NIC, 0xdc), 0x4031);
+        assert_eq!(
+            MsiCapability::read(&mut dump, NIC, 0xd0).message(&mut dump, NIC),
+            message
+        );
+
+        let mut msix = MsixCapability::read(&mut dump, NIC, 0xa0);
+        assert_eq!(msix.table_size(), 5);
+        assert_eq!((msix.table_bar(), msix.table_offset()), (4, 0));
+        assert_eq!((msix.pba_bar(), msix.pba_offset()), (4, 0x2000));
+        assert!(!msix.is_enabled() && !msix.is_function_masked());
+        msix.set_function_masked(&mut dump, NIC, true);
+        msix.set_enabled(&mut dump, NIC, true);
+        assert!(msix.is_enabled() && msix.is_function_masked());
+        assert_eq!(dump.read(NIC, 0xa0), 0xc004_0011);
+    }
+
+    #[test]
+    fn enumeration() {
+        let mut dump = Dump::q35();
+        let found: Vec<(PciAddress, u16)> = enumerate(&mut dump, 0, 0..=255)
+            .map(|(address, header)| (address, header.device_id))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (PciAddress::new(0, 0, 0, 0), 0x29c0),
+                (PciAddress::new(0, 0, 2, 0), 0x10d3),
+                (PciAddress::new(0, 0, 0x1c, 0), 0x2940),
+                (PciAddress::new(0, 0, 0x1c, 1), 0x2942),
+                (PciAddress::new(0, 1, 0, 0), 0x1001),
+            ]
+        );
+        assert_eq!(enumerate(&mut dump, 0, 1..=1).count(), 1);
+        assert_eq!(enumerate(&mut dump, 1, 0..=255).count(), 0);
+    }
+
+    /// Records 32-bit port accesses, reads return `data`.
+    #[derive(Default)]
+    struct Ports {
+        log: Vec<(u16, Option<u32>)>,
+        data: u32,
+    }
+
+    impl PortIo for Ports {
+        fn read_u8(&mut self, _port: u16) -> u8 {
+            unreachable!()
+        }
+
+        fn write_u8(&mut self, _port: u16, _val: u8) {
+            unreachable!()
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, _port: u16, _val: u16) {
+            unreachable!()
+        }
+
+        fn read_u32(&mut self, port: u16) -> u32 {
+            self.log.push((port, None));
+            self.data
+        }
+
+        fn write_u32(&mut self, port: u16, val: u32) {
+            self.log.push((port, Some(val)));
+        }
+    }
+
+    #[test]
+    fn config_ports() {
+        let mut ports = ConfigPorts::with_io(Ports {
+            data: 0x1234_5678,
+            ..Default::default()
+        });
+        let address = PciAddress::new(0, 1, 2, 3);
+        assert_eq!(ports.read_u16(address, 0x12), 0x1234);
+        ports.write(address, 0x14, 0xfeed);
+        assert_eq!(
+            ports.io.log,
+            [
+                (CONFIG_ADDRESS, Some(0x8001_1310)),
+                (CONFIG_DATA, None),
+                (CONFIG_ADDRESS, Some(0x8001_1314)),
+                (CONFIG_DATA, Some(0xfeed)),
+            ]
+        );
+
+        // Other segments and the extended space are not reachable
+        ports.io.log.clear();
+        assert_eq!(ports.read(PciAddress::new(1, 0, 0, 0), 0), u32::MAX);
+        assert_eq!(ports.read(address, 0x100), u32::MAX);
+        ports.write(address, 0x100, 0);
+        assert!(ports.io.log.is_empty());
+    }
+
+    #[test]
+    fn ecam() {
+        let mut region = vec![u32::MAX; 2 << 18];
+        let mut ecam = Ecam::new(&mut region, 0, 8);
+        let address = PciAddress::new(0, 9, 2, 1);
+        ecam.write(address, 0x104, 0x1234_5678);
+        assert_eq!(ecam.read(address, 0x104), 0x1234_5678);
+        assert_eq!(ecam.read_u16(address, 0x106), 0x1234);
+        assert_eq!(ecam.read_u8(address, 0x105), 0x56);
+        ecam.write_u8(address, 0x107, 0xab);
+        ecam.write_u16(address, 0x104, 0xcdef);
+        assert_eq!(ecam.read(address, 0x104), 0xab34_cdef);
+        assert_eq!(ecam.read(PciAddress::new(0, 7, 0, 0), 0), u32::MAX);
+        assert_eq!(ecam.read(PciAddress::new(0, 10, 0, 0), 0), u32::MAX);
+        assert_eq!(ecam.read(PciAddress::new(1, 9, 0, 0), 0), u32::MAX);
+        drop(ecam);
+        assert_eq!(
+            region[(1 << 18) + (2 << 13) + (1 << 10) + 0x41],
+            0xab34_cdef
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Driver for the legacy 8259A programmable interrupt controllers.
+//!
+//! PCs have two cascaded PICs: the slave is connected to IRQ 2 of the master.
+//! By default they deliver IRQs 0-15 on vectors 0x08-0x0f and 0x70-0x77, which
+//! collide with CPU exceptions, so they have to be remapped (and usually
+//! masked) before enabling interrupts, even if only the APIC is used.
+//!
+//! See the Intel 8259A datasheet.
+
+use crate::io::{NativePortIo, PortIo};
+
+/// Command port of the master PIC.
+pub const MASTER_COMMAND: u16 = 0x20;
+
+/// Data port of the master PIC.
+pub const MASTER_DATA: u16 = 0x21;
+
+/// Command port of the slave PIC.
+pub const SLAVE_COMMAND: u16 = 0xa0;
+
+/// Data port of the slave PIC.
+pub const SLAVE_DATA: u16 = 0xa1;
+
+/// Unused port written to wait for the (slow) PIC to process a command.
+const IO_WAIT_PORT: u16 = 0x80;
+
+/// ICW1: ICW4 will be sent.
+const ICW1_ICW4: u8 = 0x01;
+/// ICW1: start initialization.
+const ICW1_INIT: u8 = 0x10;
+/// ICW4: 8086/8088 mode.
+const ICW4_8086: u8 = 0x01;
+/// OCW2: non-specific end of interrupt.
+const OCW2_EOI: u8 = 0x20;
+/// OCW3: read interrupt request register on next read.
+const OCW3_READ_IRR: u8 = 0x0a;
+/// OCW3: read in-service register on next read.
+const OCW3_READ_ISR: u8 = 0x0b;
+
+/// IRQ line of the master the slave is cascaded to.
+const CASCADE_IRQ: u8 = 2;
+
+/// The two cascaded 8259 PICs of a PC.
+#[derive(Debug)]
+pub struct ChainedPics<P: PortIo = NativePortIo> {
+    io: P,
+    master_offset: u8,
+    slave_offset: u8,
+}
+
+impl ChainedPics {
+    /// Create a driver delivering IRQs 0-7 at `master_offset` and IRQs 8-15
+    /// at `slave_offset`.
+    ///
+    /// The PICs are not touched until [`ChainedPics::initialize`] is called.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    pub unsafe fn new(master_offset: u8, slave_offset: u8) -> Self {
+        ChainedPics::with_io(NativePortIo::new(), master_offset, slave_offset)
+    }
+}
+
+impl<P: PortIo> ChainedPics<P> {
+    /// Create a driver doing port I/O through `io`.
+    ///
+    /// # Panics
+    /// If an offset is not a multiple of 8.
+    pub fn with_io(io: P, master_offset: u8, slave_offset: u8) -> Self {
+        assert!(
+            master_offset & 0x7 == 0 && slave_offset & 0x7 == 0,
+            "PIC vector offsets must be multiples of 8."
+        );
+        ChainedPics {
+            io,
+            master_offset,
+            slave_offset,
+        }
+    }
+
+    fn io_wait(&mut self) {
+        self.io.write_u8(IO_WAIT_PORT, 0);
+    }
+
+    /// Initialize both PICs (ICW1-ICW4) with the configured vector offsets.
+    ///
+    /// The interrupt masks are preserved.
+    pub fn initialize(&mut self) {
+        let (master_mask, slave_mask) = self.masks();
+
+        // ICW1: start initialization, expect ICW4
+        self.io.write_u8(MASTER_COMMAND, ICW1_INIT | ICW1_ICW4);
+        self.io_wait();
+        self.io.write_u8(SLAVE_COMMAND, ICW1_INIT | ICW1_ICW4);
+        self.io_wait();
+
+        // ICW2: vector offsets
+        self.io.write_u8(MASTER_DATA, self.master_offset);
+        self.io_wait();
+        self.io.write_u8(SLAVE_DATA, self.slave_offset);
+        self.io_wait();
+
+        // ICW3: slave on master IRQ 2 (bitmask), slave cascade identity
+        self.io.write_u8(MASTER_DATA, 1 << CASCADE_IRQ);
+        self.io_wait();
+        self.io.write_u8(SLAVE_DATA, CASCADE_IRQ);
+        self.io_wait();
+
+        // ICW4: 8086 mode
+        self.io.write_u8(MASTER_DATA, ICW4_8086);
+        self.io_wait();
+        self.io.write_u8(SLAVE_DATA, ICW4_8086);
+        self.io_wait();
+
+        self.set_masks(master_mask, slave_mask);
+    }
+
+    /// Read the interrupt masks (master, slave); a set bit masks the IRQ.
+    pub fn masks(&mut self) -> (u8, u8) {
+        (self.io.read_u8(MASTER_DATA), self.io.read_u8(SLAVE_DATA))
+    }
+
+    /// Set the interrupt masks (master, slave); a set bit masks the IRQ.
+    pub fn set_masks(&mut self, master: u8, slave: u8) {
+        self.io.write_u8(MASTER_DATA, master);
+        self.io.write_u8(SLAVE_DATA, slave);
+    }
+
+    /// Mask IRQ `irq` (0-15).
+    pub fn mask(&mut self, irq: u8) {
+        let (port, bit) = ChainedPics::<P>::mask_bit(irq);
+        let mask = self.io.read_u8(port);
+        self.io.write_u8(port, mask | bit);
+    }
+
+    /// Unmask IRQ `irq` (0-15).
+    ///
+    /// IRQs 8-15 also need the cascade (IRQ 2) unmasked.
+    pub fn unmask(&mut self, irq: u8) {
+        let (port, bit) = ChainedPics::<P>::mask_bit(irq);
+        let mask = self.io.read_u8(port);
+        self.io.write_u8(port, mask & !bit);
+    }
+
+    fn mask_bit(irq: u8) -> (u16, u8) {
+        assert!(irq < 16, "PICs only have IRQs 0-15.");
+        if irq < 8 {
+            (MASTER_DATA, 1 << irq)
+        } else {
+            (SLAVE_DATA, 1 << (irq - 8))
+        }
+    }
+
+    /// Mask all IRQs on both PICs, e.g., before switching to the APIC.
+    pub fn disable(&mut self) {
+        self.set_masks(0xff, 0xff);
+    }
+
+    /// Is `vector` one of the vectors of the master PIC?
+    fn handles_master(&self, vector: u8) -> bool {
+        vector & !0x7 == self.master_offset
+    }
+
+    /// Is `vector` one of the vectors of the slave PIC?
+    fn handles_slave(&self, vector: u8) -> bool {
+        vector & !0x7 == self.slave_offset
+    }
+
+    /// Is `vector` delivered by one of the PICs?
+    pub fn handles_interrupt(&self, vector: u8) -> bool {
+        self.handles_master(vector) || self.handles_slave(vector)
+    }
+
+    /// Signal end of interrupt for `vector`.
+    ///
+    /// Interrupts from the slave need an EOI on both PICs.
+    pub fn notify_end_of_interrupt(&mut self, vector: u8) {
+        if self.handles_slave(vector) {
+            self.io.write_u8(SLAVE_COMMAND, OCW2_EOI);
+        }
+        if self.handles_interrupt(vector) {
+            self.io.write_u8(MASTER_COMMAND, OCW2_EOI);
+        }
+    }
+
+    fn read_register(&mut self, ocw3: u8) -> u16 {
+        self.io.write_u8(MASTER_COMMAND, ocw3);
+        self.io.write_u8(SLAVE_COMMAND, ocw3);
+        let master = self.io.read_u8(MASTER_COMMAND) as u16;
+        let slave = self.io.read_u8(SLAVE_COMMAND) as u16;
+        slave << 8 | master
+    }
+
+    /// Read the combined interrupt request registers (slave in bits 15:8).
+    pub fn irr(&mut self) -> u16 {
+        self.read_register(OCW3_READ_IRR)
+    }
+
+    /// Read the combined in-service registers (slave in bits 15:8).
+    pub fn isr(&mut self) -> u16 {
+        self.read_register(OCW3_READ_ISR)
+    }
+
+    /// Check if `vector` is a spurious IRQ 7 or IRQ 15.
+    ///
+    /// A spurious interrupt has no bit set in the in-service register and
+    /// must not be acknowledged. For a spurious IRQ 15 the master did see
+    /// the cascade interrupt, so this sends the EOI to the master.
+    pub fn is_spurious(&mut self, vector: u8) -> bool {
+        if vector == self.master_offset + 7 {
+            self.io.write_u8(MASTER_COMMAND, OCW3_READ_ISR);
+            self.io.read_u8(MASTER_COMMAND) & 0x80 == 0
+        } else if vector == self.slave_offset + 7 {
+            self.io.write_u8(SLAVE_COMMAND, OCW3_READ_ISR);
+            let spurious = self.io.read_u8(SLAVE_COMMAND) & 0x80 == 0;
+            if spurious {
+                self.io.write_u8(MASTER_COMMAND, OCW2_EOI);
+            }
+            spurious
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    /// Records accesses, emulates the mask, IRR and ISR registers.
+    #[derive(Default)]
+    struct Recorder {
+        log: Vec<Access>,
+        masks: [u8; 2],
+        irr: [u8; 2],
+        isr: [u8; 2],
+        read_isr: [bool; 2],
+    }
+
+    impl Recorder {
+        fn pic(port: u16) -> usize {
+            match port {
+                MASTER_COMMAND | MASTER_DATA => 0,
+                SLAVE_COMMAND | SLAVE_DATA => 1,
+                _ => unreachable!("unexpected port {:#x}", port),
+            }
+        }
+    }
+
+    impl PortIo for Recorder {
+        fn read_u8(&mut self, port: u16) -> u8 {
+            self.log.push(Access::Read(port));
+            let pic = Recorder::pic(port);
+            match port {
+                MASTER_DATA | SLAVE_DATA => self.masks[pic],
+                _ if self.read_isr[pic] => self.isr[pic],
+                _ => self.irr[pic],
+            }
+        }
+
+        fn write_u8(&mut self, port: u16, val: u8) {
+            self.log.push(Access::Write(port, val));
+            match port {
+                IO_WAIT_PORT => {}
+                MASTER_DATA | SLAVE_DATA => self.masks[Recorder::pic(port)] = val,
+                _ if val == OCW3_READ_ISR || val == OCW3_READ_IRR => {
+                    self.read_isr[Recorder::pic(port)] = val == OCW3_READ_ISR
+                }
+                _ => {}
+            }
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, _port: u16, _val: u16) {
+            unreachable!()
+        }
+
+        fn read_u32(&mut self, _port: u16) -> u32 {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, _port: u16, _val: u32) {
+            unreachable!()
+        }
+    }
+
+    fn writes(log: &[Access]) -> Vec<(u16, u8)> {
+        log.iter()
+            .filter_map(|a| match *a {
+                Access::Write(port, val) if port != IO_WAIT_PORT => Some((port, val)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn initialize() {
+        let mut pics = ChainedPics::with_io(Recorder::default(), 0x20, 0x28);
+        pics.io.masks = [0xb8, 0x8e];
+        pics.initialize();
+
+        assert_eq!(
+            writes(&pics.io.log),
+            [
+                (MASTER_COMMAND, 0x11),
+                (SLAVE_COMMAND, 0x11),
+                (MASTER_DATA, 0x20),
+                (SLAVE_DATA, 0x28),
+                (MASTER_DATA, 0x04),
+                (SLAVE_DATA, 0x02),
+                (MASTER_DATA, 0x01),
+                (SLAVE_DATA, 0x01),
+                (MASTER_DATA, 0xb8),
+                (SLAVE_DATA, 0x8e),
+            ]
+        );
+        // Every initialization word is followed by a delay
+        let waits = pics
+            .io
+            .log
+            .iter()
+            .filter(|a| **a == Access::Write(IO_WAIT_PORT, 0))
+            .count();
+        assert_eq!(waits, 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unaligned_offset() {
+        ChainedPics::with_io(Recorder::default(), 0x20, 0x29);
+    }
+
+    #[test]
+    fn masks() {
+        let mut pics = ChainedPics::with_io(Recorder::default(), 0x20, 0x28);
+        pics.disable();
+        assert_eq!(pics.masks(), (0xff, 0xff));
+        pics.unmask(2);
+        pics.unmask(12);
+        assert_eq!(pics.masks(), (0xfb, 0xef));
+        pics.mask(2);
+        assert_eq!(pics.masks(), (0xff, 0xef));
+    }
+
+    #[test]
+    fn end_of_interrupt() {
+        let mut pics = ChainedPics::with_io(Recorder::default(), 0x20, 0x28);
+        assert!(pics.handles_interrupt(0x20));
+        assert!(pics.handles_interrupt(0x2f));
+        assert!(!pics.handles_interrupt(0x30));
+
+        pics.notify_end_of_interrupt(0x21);
+        assert_eq!(writes(&pics.io.log), [(MASTER_COMMAND, 0x20)]);
+
+        pics.io.log.clear();
+        pics.notify_end_of_interrupt(0x2c);
+        assert_eq!(
+            writes(&pics.io.log),
+            [(SLAVE_COMMAND, 0x20), (MASTER_COMMAND, 0x20)]
+        );
+
+        pics.io.log.clear();
+        pics.notify_end_of_interrupt(0x30);
+        assert!(pics.io.log.is_empty());
+    }
+
+    #[test]
+    fn irr_isr() {
+        let mut pics = ChainedPics::with_io(Recorder::default(), 0x20, 0x28);
+        pics.io.irr = [0x01, 0x10];
+        pics.io.isr = [0x04, 0x80];
+        assert_eq!(pics.irr(), 0x1001);
+        assert_eq!(pics.isr(), 0x8004);
+    }
+
+    #[test]
+    fn spurious() {
+        let mut pics = ChainedPics::with_io(Recorder::default(), 0x20, 0x28);
+        assert!(!pics.is_spurious(0x21));
+        assert!(pics.io.log.is_empty());
+
+        // IRQ 7 without ISR bit
+        assert!(pics.is_spurious(0x27));
+        pics.io.isr = [0x80, 0x00];
+        assert!(!pics.is_spurious(0x27));
+
+        // Spurious IRQ 15 still acknowledges the cascade on the master
+        pics.io.log.clear();
+        assert!(pics.is_spurious(0x2f));
+        assert_eq!(
+            writes(&pics.io.log),
+            [(SLAVE_COMMAND, OCW3_READ_ISR), (MASTER_COMMAND, 0x20)]
+        );
+
+        pics.io.isr = [0x04, 0x80];
+        pics.io.log.clear();
+        assert!(!pics.is_spurious(0x2f));
+        assert_eq!(writes(&pics.io.log), [(SLAVE_COMMAND, OCW3_READ_ISR)]);
+    }
+}
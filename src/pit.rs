@@ -0,0 +1,462 @@
+//! Driver for the 8254 programmable interval timer (PIT).
+//!
+//! The PIT has three 16-bit down counters driven by a 1.193182 MHz clock:
+//! channel 0 is connected to IRQ 0, channel 1 is unused on modern PCs and
+//! channel 2 drives the PC speaker. The gate of channel 2 and its output can
+//! be accessed through port 0x61, which makes it usable as a polled reference
+//! clock for calibrating the TSC or the local APIC timer.
+//!
+//! See the Intel 82C54 datasheet.
+
+use core::hint::spin_loop;
+
+use crate::io::{NativePortIo, PortIo};
+
+/// Input clock frequency of the counters (in Hz).
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Data port of channel 0.
+pub const CHANNEL0: u16 = 0x40;
+/// Data port of channel 1.
+pub const CHANNEL1: u16 = 0x41;
+/// Data port of channel 2.
+pub const CHANNEL2: u16 = 0x42;
+/// Mode/command register (write only).
+pub const COMMAND: u16 = 0x43;
+/// NMI status and control port with the gate and output of channel 2.
+pub const GATE_PORT: u16 = 0x61;
+
+/// Port 0x61: gate input of channel 2.
+const GATE2: u8 = 1 << 0;
+/// Port 0x61: connect channel 2 to the speaker.
+const SPEAKER: u8 = 1 << 1;
+/// Port 0x61: output of channel 2 (read only).
+const OUT2: u8 = 1 << 5;
+/// Port 0x61: writable bits (the upper bits read status).
+const CONTROL_BITS: u8 = 0x0f;
+
+/// A counter of the PIT.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Channel {
+    Zero = 0,
+    One = 1,
+    Two = 2,
+}
+
+impl Channel {
+    /// Data port of the channel.
+    pub fn port(self) -> u16 {
+        CHANNEL0 + self as u16
+    }
+}
+
+/// How the count is transferred through the data port.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum AccessMode {
+    /// Latch the current count for a following read (counter latch command).
+    LatchCount = 0b00,
+    LowByte = 0b01,
+    HighByte = 0b10,
+    /// Low byte followed by high byte.
+    LowHighByte = 0b11,
+}
+
+/// Counting mode of a channel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum OperatingMode {
+    /// Mode 0: output goes high when the count reaches zero (one-shot).
+    InterruptOnTerminalCount = 0b000,
+    /// Mode 1: one-shot retriggered by the gate.
+    HardwareOneShot = 0b001,
+    /// Mode 2: periodic low pulse every `count` ticks.
+    RateGenerator = 0b010,
+    /// Mode 3: periodic square wave with a period of `count` ticks.
+    SquareWave = 0b011,
+    /// Mode 4: low pulse when the count reaches zero.
+    SoftwareStrobe = 0b100,
+    /// Mode 5: strobe triggered by the gate.
+    HardwareStrobe = 0b101,
+}
+
+/// Mode/command register value.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Command {
+    pub channel: Channel,
+    pub access: AccessMode,
+    pub mode: OperatingMode,
+    /// Count in BCD (four decades) instead of binary.
+    pub bcd: bool,
+}
+
+impl Command {
+    /// A binary counting command for `channel` using low/high byte access.
+    pub const fn new(channel: Channel, mode: OperatingMode) -> Command {
+        Command {
+            channel,
+            access: AccessMode::LowHighByte,
+            mode,
+            bcd: false,
+        }
+    }
+
+    /// The counter latch command for `channel`.
+    pub const fn latch(channel: Channel) -> Command {
+        Command {
+            channel,
+            access: AccessMode::LatchCount,
+            mode: OperatingMode::InterruptOnTerminalCount,
+            bcd: false,
+        }
+    }
+
+    /// Decode a command register value (`None` for read-back commands).
+    pub fn from_raw(raw: u8) -> Option<Command> {
+        let channel = match raw >> 6 {
+            0 => Channel::Zero,
+            1 => Channel::One,
+            2 => Channel::Two,
+            _ => return None,
+        };
+        let access = match (raw >> 4) & 0b11 {
+            0b00 => AccessMode::LatchCount,
+            0b01 => AccessMode::LowByte,
+            0b10 => AccessMode::HighByte,
+            _ => AccessMode::LowHighByte,
+        };
+        // Modes 6 and 7 alias 2 and 3
+        let mode = match (raw >> 1) & 0b111 {
+            0b000 => OperatingMode::InterruptOnTerminalCount,
+            0b001 => OperatingMode::HardwareOneShot,
+            0b010 | 0b110 => OperatingMode::RateGenerator,
+            0b011 | 0b111 => OperatingMode::SquareWave,
+            0b100 => OperatingMode::SoftwareStrobe,
+            _ => OperatingMode::HardwareStrobe,
+        };
+        Some(Command {
+            channel,
+            access,
+            mode,
+            bcd: raw & 1 != 0,
+        })
+    }
+
+    /// Encoded command register value.
+    pub fn to_raw(&self) -> u8 {
+        (self.channel as u8) << 6
+            | (self.access as u8) << 4
+            | (self.mode as u8) << 1
+            | self.bcd as u8
+    }
+}
+
+/// Number of PIT ticks in `microseconds` (rounded down).
+///
+/// Saturates at `u64::MAX` for durations above about 1.5e19 µs.
+pub const fn ticks_for_us(microseconds: u64) -> u64 {
+    let ticks = microseconds as u128 * FREQUENCY as u128 / 1_000_000;
+    if ticks > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        ticks as u64
+    }
+}
+
+/// Reload value for a periodic interrupt at `hz`, or `None` if `hz` is out
+/// of the range the 16-bit counter can generate.
+pub fn divisor_for(hz: u32) -> Option<u16> {
+    if hz == 0 {
+        return None;
+    }
+    let divisor = (FREQUENCY + hz as u64 / 2) / hz as u64;
+    match divisor {
+        // Modes 2 and 3 don't support a count of 1
+        2..=0xffff => Some(divisor as u16),
+        // A count of 0 is 65536
+        0x10000 => Some(0),
+        _ => None,
+    }
+}
+
+/// The 8254 programmable interval timer.
+#[derive(Debug)]
+pub struct Pit<P: PortIo = NativePortIo> {
+    io: P,
+}
+
+impl Pit {
+    /// Create a driver for the PIT.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    pub unsafe fn new() -> Self {
+        Pit::with_io(NativePortIo::new())
+    }
+}
+
+impl<P: PortIo> Pit<P> {
+    /// Create a driver doing port I/O through `io`.
+    pub fn with_io(io: P) -> Self {
+        Pit { io }
+    }
+
+    /// Write the mode/command register.
+    pub fn command(&mut self, command: Command) {
+        self.io.write_u8(COMMAND, command.to_raw());
+    }
+
+    /// Write a 16-bit count (low byte first) to a channel programmed with
+    /// [`AccessMode::LowHighByte`]. A count of 0 is 65536.
+    pub fn set_count(&mut self, channel: Channel, count: u16) {
+        self.io.write_u8(channel.port(), count as u8);
+        self.io.write_u8(channel.port(), (count >> 8) as u8);
+    }
+
+    /// Latch and read the current count of a channel programmed with
+    /// [`AccessMode::LowHighByte`].
+    pub fn count(&mut self, channel: Channel) -> u16 {
+        self.command(Command::latch(channel));
+        let low = self.io.read_u8(channel.port());
+        let high = self.io.read_u8(channel.port());
+        u16::from_le_bytes([low, high])
+    }
+
+    /// Count down once from `count` (mode 0); the output goes high (and
+    /// channel 0 raises IRQ 0) when it reaches zero.
+    pub fn one_shot(&mut self, channel: Channel, count: u16) {
+        self.command(Command::new(
+            channel,
+            OperatingMode::InterruptOnTerminalCount,
+        ));
+        self.set_count(channel, count);
+    }
+
+    /// Pulse the output every `divisor` ticks (mode 2), e.g., for a periodic
+    /// IRQ 0 with a divisor from [`divisor_for`].
+    pub fn rate_generator(&mut self, channel: Channel, divisor: u16) {
+        self.command(Command::new(channel, OperatingMode::RateGenerator));
+        self.set_count(channel, divisor);
+    }
+
+    /// Count `count` ticks on channel 2 and wait for its output, calling
+    /// `clock` right before counting starts and after it finished.
+    fn countdown<F: FnMut() -> u64>(&mut self, count: u16, mut clock: F) -> u64 {
+        let port = self.io.read_u8(GATE_PORT) & CONTROL_BITS;
+
+        // Hold the counter with the gate low (and keep the speaker off)
+        self.io.write_u8(GATE_PORT, port & !(GATE2 | SPEAKER));
+        self.one_shot(Channel::Two, count);
+
+        // Counting starts on the rising edge of the gate
+        let start = clock();
+        self.io.write_u8(GATE_PORT, (port & !SPEAKER) | GATE2);
+        while self.io.read_u8(GATE_PORT) & OUT2 == 0 {
+            spin_loop();
+        }
+        let end = clock();
+
+        self.io.write_u8(GATE_PORT, port);
+        end.wrapping_sub(start)
+    }
+
+    /// Busy-wait for `microseconds` using channel 2.
+    pub fn sleep_us(&mut self, microseconds: u64) {
+        let mut ticks = ticks_for_us(microseconds);
+        while ticks > 0 {
+            let count = core::cmp::min(ticks, 0xffff);
+            self.countdown(count as u16, || 0);
+            ticks -= count;
+        }
+    }
+
+    /// Measure how much `clock` advances during `count` ticks of channel 2.
+    pub fn measure<F: FnMut() -> u64>(&mut self, count: u16, clock: F) -> u64 {
+        self.countdown(count, clock)
+    }
+
+    /// Estimate the TSC frequency (in Hz) by counting TSC ticks during `count`
+    /// PIT ticks (e.g., `ticks_for_us(10_000)`).
+    ///
+    /// # Safety
+    /// Needs access to the TSC (see [`crate::time::rdtsc`]).
+    pub unsafe fn tsc_frequency(&mut self, count: u16) -> u64 {
+        if count == 0 {
+            return 0;
+        }
+        let tsc = self.measure(count, || crate::time::rdtsc());
+        (tsc as u128 * FREQUENCY as u128 / count as u128) as u64
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Records writes, emulates the output of channel 2 and latched counts.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<(u16, u8)>,
+        gate: u8,
+        /// Reads of port 0x61 until OUT2 goes high once the gate is raised.
+        delay: usize,
+        waiting: usize,
+        latched: Vec<u8>,
+        clock: u64,
+    }
+
+    impl PortIo for Recorder {
+        fn read_u8(&mut self, port: u16) -> u8 {
+            match port {
+                GATE_PORT => {
+                    if self.gate & GATE2 == 0 {
+                        return self.gate;
+                    }
+                    self.clock += 10;
+                    if self.waiting == 0 {
+                        self.gate | OUT2
+                    } else {
+                        self.waiting -= 1;
+                        self.gate
+                    }
+                }
+                _ => self.latched.remove(0),
+            }
+        }
+
+        fn write_u8(&mut self, port: u16, val: u8) {
+            self.writes.push((port, val));
+            if port == GATE_PORT {
+                if val & GATE2 != 0 && self.gate & GATE2 == 0 {
+                    self.waiting = self.delay;
+                }
+                self.gate = val;
+            }
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, _port: u16, _val: u16) {
+            unreachable!()
+        }
+
+        fn read_u32(&mut self, _port: u16) -> u32 {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, _port: u16, _val: u32) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn command_encoding() {
+        let rate = Command::new(Channel::Zero, OperatingMode::RateGenerator);
+        assert_eq!(rate.to_raw(), 0x34);
+        assert_eq!(
+            Command::new(Channel::Two, OperatingMode::InterruptOnTerminalCount).to_raw(),
+            0xb0
+        );
+        assert_eq!(
+            Command::new(Channel::Zero, OperatingMode::SquareWave).to_raw(),
+            0x36
+        );
+        assert_eq!(Command::latch(Channel::Two).to_raw(), 0x80);
+        let bcd = Command {
+            channel: Channel::One,
+            access: AccessMode::LowByte,
+            mode: OperatingMode::HardwareStrobe,
+            bcd: true,
+        };
+        assert_eq!(bcd.to_raw(), 0x5b);
+
+        assert_eq!(Command::from_raw(0x34), Some(rate));
+        assert_eq!(Command::from_raw(0x5b), Some(bcd));
+        assert_eq!(Command::from_raw(0x3c), Some(rate));
+        assert_eq!(Command::from_raw(0xc2), None);
+    }
+
+    #[test]
+    fn divisors() {
+        assert_eq!(divisor_for(1000), Some(1193));
+        assert_eq!(divisor_for(100), Some(11932));
+        assert_eq!(divisor_for(19), Some(62799));
+        assert_eq!(divisor_for(18), None);
+        assert_eq!(divisor_for(0), None);
+        assert_eq!(divisor_for(2_000_000), None);
+        assert_eq!(ticks_for_us(10_000), 11931);
+        assert_eq!(ticks_for_us(20_000_000_000_000), 23_863_640_000_000);
+        assert_eq!(ticks_for_us(u64::MAX), u64::MAX);
+        assert_eq!(Channel::Two.port(), CHANNEL2);
+    }
+
+    #[test]
+    fn programming() {
+        let mut pit = Pit::with_io(Recorder::default());
+        pit.rate_generator(Channel::Zero, 1193);
+        pit.one_shot(Channel::One, 0x1234);
+        assert_eq!(
+            pit.io.writes,
+            [
+                (COMMAND, 0x34),
+                (CHANNEL0, 0xa9),
+                (CHANNEL0, 0x04),
+                (COMMAND, 0x70),
+                (CHANNEL1, 0x34),
+                (CHANNEL1, 0x12),
+            ]
+        );
+
+        pit.io.writes.clear();
+        pit.io.latched = std::vec![0xcd, 0xab];
+        assert_eq!(pit.count(Channel::Two), 0xabcd);
+        assert_eq!(pit.io.writes, [(COMMAND, 0x80)]);
+    }
+
+    #[test]
+    fn channel2_sleep_and_measure() {
+        let mut pit = Pit::with_io(Recorder {
+            gate: 0x03,
+            delay: 4,
+            ..Default::default()
+        });
+        pit.sleep_us(100_000);
+
+        // Two countdowns: 65535 + 53783 ticks
+        let writes = &pit.io.writes;
+        assert_eq!(writes.len(), 2 * 6);
+        assert_eq!(
+            writes[..6],
+            [
+                (GATE_PORT, 0x00),
+                (COMMAND, 0xb0),
+                (CHANNEL2, 0xff),
+                (CHANNEL2, 0xff),
+                (GATE_PORT, 0x01),
+                (GATE_PORT, 0x03),
+            ]
+        );
+        assert_eq!(writes[8], (CHANNEL2, 0x17));
+        assert_eq!(writes[9], (CHANNEL2, 0xd2));
+        assert_eq!(pit.io.gate, 0x03);
+
+        let mut pit = Pit::with_io(Recorder {
+            delay: 9,
+            ..Default::default()
+        });
+        let mut reads = 0;
+        let elapsed = pit.measure(1193, || {
+            reads += 1;
+            reads * 1000
+        });
+        assert_eq!(elapsed, 1000);
+        // Polled until OUT2 went high
+        assert_eq!(pit.io.clock, 100);
+    }
+}
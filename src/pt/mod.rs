@@ -0,0 +1,683 @@
+//! Intel Processor Trace (PT) configuration and output structures.
+//!
+//! Tracing is controlled through `IA32_RTIT_CTL`, filtered by address ranges
+//! (`IA32_RTIT_ADDRn_A/B`) and CR3 (`IA32_RTIT_CR3_MATCH`), and written to
+//! either a single contiguous output region or a Table of Physical Addresses
+//! (ToPA) configured in `IA32_RTIT_OUTPUT_BASE` and
+//! `IA32_RTIT_OUTPUT_MASK_PTRS`.
+//!
+//! See Intel Vol. 3c Chapter 32, "Intel Processor Trace".
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use crate::bits64::paging::PAddr;
+use crate::cpuid::CpuId;
+use crate::msr::{
+    rdmsr, wrmsr, MSR_IA32_ADDR0_END, MSR_IA32_ADDR0_START, MSR_IA32_CR3_MATCH, MSR_IA32_RTIT_CTL,
+    MSR_IA32_RTIT_OUTPUT_BASE, MSR_IA32_RTIT_OUTPUT_MASK_PTRS, MSR_IA32_RTIT_STATUS,
+};
+
+pub mod packet;
+
+bitflags! {
+    /// Trace control register (`IA32_RTIT_CTL`).
+    ///
+    /// The multi-bit fields are included as masks so they survive
+    /// `from_bits_truncate`; use the accessor functions to read or update them.
+    pub struct RtitCtl: u64 {
+        /// Enable tracing.
+        const TRACE_EN = 1 << 0;
+        /// Enable cycle-accurate mode (CYC packets).
+        const CYC_EN = 1 << 1;
+        /// Trace when CPL = 0.
+        const OS = 1 << 2;
+        /// Trace when CPL > 0.
+        const USER = 1 << 3;
+        /// Enable power event trace packets.
+        const PWR_EVT_EN = 1 << 4;
+        /// Generate FUP packets for PTWRITE.
+        const FUP_ON_PTW = 1 << 5;
+        /// Send trace output to the trace transport subsystem.
+        const FABRIC_EN = 1 << 6;
+        /// Only trace when CR3 matches `IA32_RTIT_CR3_MATCH`.
+        const CR3_FILTER = 1 << 7;
+        /// Use the ToPA output scheme (instead of single-range output).
+        const TOPA = 1 << 8;
+        /// Enable MTC timing packets.
+        const MTC_EN = 1 << 9;
+        /// Enable TSC packets.
+        const TSC_EN = 1 << 10;
+        /// Disable return compression.
+        const DIS_RETC = 1 << 11;
+        /// Enable PTWRITE packets.
+        const PTW_EN = 1 << 12;
+        /// Enable COFI-based packets (TNT, TIP, FUP, ...).
+        const BRANCH_EN = 1 << 13;
+        /// MTC packet frequency (bits 17:14).
+        const MTC_FREQ = 0xf << 14;
+        /// Cycle threshold for CYC packets (bits 22:19).
+        const CYC_THRESH = 0xf << 19;
+        /// PSB packet frequency (bits 27:24).
+        const PSB_FREQ = 0xf << 24;
+        /// Configuration of address range 0 (bits 35:32).
+        const ADDR0_CFG = 0xf << 32;
+        /// Configuration of address range 1 (bits 39:36).
+        const ADDR1_CFG = 0xf << 36;
+        /// Configuration of address range 2 (bits 43:40).
+        const ADDR2_CFG = 0xf << 40;
+        /// Configuration of address range 3 (bits 47:44).
+        const ADDR3_CFG = 0xf << 44;
+        /// Inject a PSB+ and PMI when tracing gets enabled.
+        const INJECT_PSB_PMI_ON_ENABLE = 1 << 56;
+    }
+}
+
+impl RtitCtl {
+    fn field(&self, lsb: usize) -> u8 {
+        self.bits().get_bits(lsb..lsb + 4) as u8
+    }
+
+    fn with_field(self, lsb: usize, value: u8) -> RtitCtl {
+        assert!(value < 16, "Field is only 4 bits wide.");
+        let mut bits = self.bits();
+        bits.set_bits(lsb..lsb + 4, value as u64);
+        RtitCtl::from_bits_truncate(bits)
+    }
+
+    /// MTC frequency encoding, the period is 2^(value-1) ART ticks.
+    pub fn mtc_freq(&self) -> u8 {
+        self.field(14)
+    }
+
+    /// Set the MTC frequency encoding (must be in
+    /// [`PtCapabilities::mtc_periods`]).
+    pub fn with_mtc_freq(self, freq: u8) -> RtitCtl {
+        self.with_field(14, freq)
+    }
+
+    /// Cycle threshold encoding for CYC packets.
+    pub fn cyc_thresh(&self) -> u8 {
+        self.field(19)
+    }
+
+    /// Set the cycle threshold encoding (must be in
+    /// [`PtCapabilities::cycle_thresholds`]).
+    pub fn with_cyc_thresh(self, thresh: u8) -> RtitCtl {
+        self.with_field(19, thresh)
+    }
+
+    /// PSB frequency encoding, a PSB is sent every 2K^value bytes of output.
+    pub fn psb_freq(&self) -> u8 {
+        self.field(24)
+    }
+
+    /// Set the PSB frequency encoding (must be in
+    /// [`PtCapabilities::psb_frequencies`]).
+    pub fn with_psb_freq(self, freq: u8) -> RtitCtl {
+        self.with_field(24, freq)
+    }
+
+    /// How address range `n` is used.
+    pub fn addr_cfg(&self, n: u8) -> AddrCfg {
+        assert!(n < 4, "Only four address ranges exist.");
+        AddrCfg::from_raw(self.field(32 + 4 * n as usize))
+    }
+
+    /// Configure how address range `n` is used.
+    pub fn with_addr_cfg(self, n: u8, cfg: AddrCfg) -> RtitCtl {
+        assert!(n < 4, "Only four address ranges exist.");
+        self.with_field(32 + 4 * n as usize, cfg as u8)
+    }
+}
+
+/// Use of an address range configured in `IA32_RTIT_ADDRn_A/B`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum AddrCfg {
+    /// The address range is not used.
+    Disabled = 0,
+    /// Only trace when the instruction pointer is within the range.
+    Filter = 1,
+    /// Stop tracing when an instruction within the range is executed.
+    TraceStop = 2,
+}
+
+impl AddrCfg {
+    fn from_raw(cfg: u8) -> AddrCfg {
+        match cfg {
+            1 => AddrCfg::Filter,
+            2 => AddrCfg::TraceStop,
+            _ => AddrCfg::Disabled,
+        }
+    }
+}
+
+bitflags! {
+    /// Trace status register (`IA32_RTIT_STATUS`).
+    pub struct RtitStatus: u64 {
+        /// The current instruction pointer is within a filter range.
+        const FILTER_EN = 1 << 0;
+        /// The current context (CPL, CR3) is traced.
+        const CONTEXT_EN = 1 << 1;
+        /// Tracing is triggered (`TRACE_EN` set and not stopped).
+        const TRIGGER_EN = 1 << 2;
+        /// An operational error was encountered, tracing stopped.
+        const ERROR = 1 << 4;
+        /// A ToPA STOP entry or TraceStop address range stopped tracing.
+        const STOPPED = 1 << 5;
+        /// A PSB+ is pending.
+        const PEND_PSB = 1 << 6;
+        /// A ToPA PMI is pending.
+        const PEND_TOPA_PMI = 1 << 7;
+        /// Number of packet bytes written since the last PSB (bits 48:32).
+        const PACKET_BYTE_CNT = 0x1ffff << 32;
+    }
+}
+
+impl RtitStatus {
+    /// Number of packet bytes written since the last PSB.
+    pub fn packet_byte_cnt(&self) -> u32 {
+        self.bits().get_bits(32..=48) as u32
+    }
+}
+
+/// Read the trace control register.
+///
+/// # Safety
+/// Needs CPL 0 and Intel PT support.
+pub unsafe fn rtit_ctl() -> RtitCtl {
+    RtitCtl::from_bits_truncate(rdmsr(MSR_IA32_RTIT_CTL))
+}
+
+/// Write the trace control register.
+///
+/// # Safety
+/// Needs CPL 0 and Intel PT support. The output, filter and CR3 match
+/// registers can only be changed while `TRACE_EN` is clear.
+pub unsafe fn rtit_ctl_write(ctl: RtitCtl) {
+    wrmsr(MSR_IA32_RTIT_CTL, ctl.bits());
+}
+
+/// Read the trace status register.
+///
+/// # Safety
+/// Needs CPL 0 and Intel PT support.
+pub unsafe fn rtit_status() -> RtitStatus {
+    RtitStatus::from_bits_truncate(rdmsr(MSR_IA32_RTIT_STATUS))
+}
+
+/// Write the trace status register (e.g., to clear `ERROR` or `STOPPED`).
+///
+/// # Safety
+/// Needs CPL 0 and Intel PT support, `TRACE_EN` must be clear.
+pub unsafe fn rtit_status_write(status: RtitStatus) {
+    wrmsr(MSR_IA32_RTIT_STATUS, status.bits());
+}
+
+/// Program address range `n` to cover `start..=end` (both inclusive).
+///
+/// The range is only used once it is enabled with [`RtitCtl::with_addr_cfg`].
+///
+/// # Safety
+/// Needs CPL 0, `n` needs to be smaller than
+/// [`PtCapabilities::address_ranges`] and `TRACE_EN` must be clear.
+pub unsafe fn set_address_range(n: u8, start: u64, end: u64) {
+    assert!(n < 4, "Only four address ranges exist.");
+    assert!(start <= end, "Address range start needs to be before end.");
+    wrmsr(MSR_IA32_ADDR0_START + 2 * n as u32, start);
+    wrmsr(MSR_IA32_ADDR0_END + 2 * n as u32, end);
+}
+
+/// Only trace when CR3 matches `cr3` (takes effect with
+/// [`RtitCtl::CR3_FILTER`]).
+///
+/// # Safety
+/// Needs CPL 0, CR3 filtering support and `TRACE_EN` must be clear.
+pub unsafe fn set_cr3_match(cr3: u64) {
+    // Bits 4:0 are reserved and compared as zero.
+    wrmsr(MSR_IA32_CR3_MATCH, cr3 & !0x1f);
+}
+
+/// Configure where trace output is written to.
+///
+/// `base` is either the first ToPA table (if [`RtitCtl::TOPA`] is set) or
+/// the start of the single output region.
+///
+/// # Safety
+/// Needs CPL 0 and `TRACE_EN` must be clear. The memory referenced by `base`
+/// (and the ToPA tables) is written by the processor.
+pub unsafe fn set_output(base: PAddr, mask_ptrs: OutputMaskPtrs) {
+    wrmsr(MSR_IA32_RTIT_OUTPUT_BASE, base.as_u64());
+    wrmsr(MSR_IA32_RTIT_OUTPUT_MASK_PTRS, mask_ptrs.0);
+}
+
+/// Read the current output position.
+///
+/// # Safety
+/// Needs CPL 0 and Intel PT support.
+pub unsafe fn output() -> (PAddr, OutputMaskPtrs) {
+    (
+        PAddr(rdmsr(MSR_IA32_RTIT_OUTPUT_BASE)),
+        OutputMaskPtrs(rdmsr(MSR_IA32_RTIT_OUTPUT_MASK_PTRS)),
+    )
+}
+
+/// Output position register (`IA32_RTIT_OUTPUT_MASK_PTRS`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OutputMaskPtrs(pub u64);
+
+impl OutputMaskPtrs {
+    /// Start writing at `output_offset` of the region referenced by entry
+    /// `entry` of the current ToPA table.
+    pub const fn topa(entry: u32, output_offset: u32) -> OutputMaskPtrs {
+        OutputMaskPtrs(0x7f | (entry as u64) << 7 | (output_offset as u64) << 32)
+    }
+
+    /// Use a single output region of `size` bytes (a power of two, at least
+    /// 128 bytes) and start writing at `output_offset`.
+    pub fn single_range(size: u32, output_offset: u32) -> OutputMaskPtrs {
+        assert!(
+            size.is_power_of_two() && size >= 128,
+            "Output region size needs to be a power of two >= 128."
+        );
+        OutputMaskPtrs((size - 1) as u64 | (output_offset as u64) << 32)
+    }
+
+    /// Index of the current ToPA table entry (ToPA output only).
+    pub fn table_entry(&self) -> u32 {
+        self.0.get_bits(7..=31) as u32
+    }
+
+    /// Offset within the current output region.
+    pub fn output_offset(&self) -> u32 {
+        self.0.get_bits(32..=63) as u32
+    }
+}
+
+/// Size of an output region referenced by a ToPA entry.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum TopaSize {
+    Size4K = 0,
+    Size8K = 1,
+    Size16K = 2,
+    Size32K = 3,
+    Size64K = 4,
+    Size128K = 5,
+    Size256K = 6,
+    Size512K = 7,
+    Size1M = 8,
+    Size2M = 9,
+    Size4M = 10,
+    Size8M = 11,
+    Size16M = 12,
+    Size32M = 13,
+    Size64M = 14,
+    Size128M = 15,
+}
+
+impl TopaSize {
+    const SIZES: [TopaSize; 16] = [
+        TopaSize::Size4K,
+        TopaSize::Size8K,
+        TopaSize::Size16K,
+        TopaSize::Size32K,
+        TopaSize::Size64K,
+        TopaSize::Size128K,
+        TopaSize::Size256K,
+        TopaSize::Size512K,
+        TopaSize::Size1M,
+        TopaSize::Size2M,
+        TopaSize::Size4M,
+        TopaSize::Size8M,
+        TopaSize::Size16M,
+        TopaSize::Size32M,
+        TopaSize::Size64M,
+        TopaSize::Size128M,
+    ];
+
+    /// Size in bytes.
+    pub const fn bytes(&self) -> u64 {
+        4096 << (*self as u8)
+    }
+
+    /// Find the encoding for a region of `bytes` bytes.
+    pub fn from_bytes(bytes: u64) -> Option<TopaSize> {
+        TopaSize::SIZES.iter().copied().find(|s| s.bytes() == bytes)
+    }
+}
+
+/// An entry in a Table of Physical Addresses.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct TopaEntry(pub u64);
+
+impl TopaEntry {
+    const END: u64 = 1 << 0;
+    const INT: u64 = 1 << 2;
+    const STOP: u64 = 1 << 4;
+
+    /// Physical address of the output region (or of the next table for END entries).
+    pub fn address(&self) -> PAddr {
+        PAddr(self.0 & !0xfff)
+    }
+
+    /// Size of the output region.
+    pub fn size(&self) -> TopaSize {
+        TopaSize::SIZES[self.0.get_bits(6..=9) as usize]
+    }
+
+    /// Is this the last entry of the table, pointing to the next table?
+    pub fn is_end(&self) -> bool {
+        self.0 & TopaEntry::END != 0
+    }
+
+    /// Is a PMI raised once the region is full?
+    pub fn is_int(&self) -> bool {
+        self.0 & TopaEntry::INT != 0
+    }
+
+    /// Does tracing stop once the region is full?
+    pub fn is_stop(&self) -> bool {
+        self.0 & TopaEntry::STOP != 0
+    }
+}
+
+/// Makes building ToPA entries easier.
+#[derive(Debug, Copy, Clone)]
+pub struct TopaEntryBuilder {
+    base: PAddr,
+    size: TopaSize,
+    end: bool,
+    int: bool,
+    stop: bool,
+}
+
+impl TopaEntryBuilder {
+    /// Entry referencing an output region at `base` (aligned to `size`).
+    pub const fn output(base: PAddr, size: TopaSize) -> TopaEntryBuilder {
+        TopaEntryBuilder {
+            base,
+            size,
+            end: false,
+            int: false,
+            stop: false,
+        }
+    }
+
+    /// Last entry of a table, continuing at the table at `next` (4 KiB aligned).
+    pub const fn end(next: PAddr) -> TopaEntryBuilder {
+        TopaEntryBuilder {
+            base: next,
+            size: TopaSize::Size4K,
+            end: true,
+            int: false,
+            stop: false,
+        }
+    }
+
+    /// Raise a performance-monitoring interrupt once the region is full.
+    pub const fn interrupt(mut self) -> TopaEntryBuilder {
+        self.int = true;
+        self
+    }
+
+    /// Stop tracing once the region is full.
+    pub const fn stop(mut self) -> TopaEntryBuilder {
+        self.stop = true;
+        self
+    }
+
+    /// Create the entry.
+    pub fn finish(&self) -> TopaEntry {
+        let alignment = if self.end { 4096 } else { self.size.bytes() };
+        assert!(
+            self.base.as_u64() & (alignment - 1) == 0,
+            "ToPA address needs to be aligned to the region size."
+        );
+
+        let mut entry = self.base.as_u64();
+        if self.end {
+            entry |= TopaEntry::END;
+        } else {
+            entry |= (self.size as u64) << 6;
+        }
+        if self.int {
+            entry |= TopaEntry::INT;
+        }
+        if self.stop {
+            entry |= TopaEntry::STOP;
+        }
+        TopaEntry(entry)
+    }
+}
+
+/// Fill `table` (located at physical address `table_base`) with entries for
+/// `regions` followed by an END entry pointing back to the table itself, so
+/// the output wraps around. A PMI is raised once the last region is full.
+///
+/// Returns the number of entries used, or `None` if `table` is too small.
+pub fn build_topa_ring(
+    table: &mut [TopaEntry],
+    table_base: PAddr,
+    regions: &[(PAddr, TopaSize)],
+) -> Option<usize> {
+    if regions.is_empty() || table.len() < regions.len() + 1 {
+        return None;
+    }
+
+    for (i, (base, size)) in regions.iter().enumerate() {
+        let mut builder = TopaEntryBuilder::output(*base, *size);
+        if i == regions.len() - 1 {
+            builder = builder.interrupt();
+        }
+        table[i] = builder.finish();
+    }
+    table[regions.len()] = TopaEntryBuilder::end(table_base).finish();
+
+    Some(regions.len() + 1)
+}
+
+/// Intel PT capabilities (CPUID leaf 0x14).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PtCapabilities {
+    /// `IA32_RTIT_CR3_MATCH` and CR3 filtering are supported.
+    pub cr3_filtering: bool,
+    /// Configurable PSB frequency and cycle-accurate mode are supported.
+    pub psb_cyc: bool,
+    /// IP filtering and TraceStop are supported.
+    pub ip_filtering: bool,
+    /// MTC packets and COFI packet suppression are supported.
+    pub mtc: bool,
+    /// PTWRITE is supported.
+    pub ptwrite: bool,
+    /// Power event trace is supported.
+    pub power_event_trace: bool,
+    /// The ToPA output scheme is supported.
+    pub topa: bool,
+    /// ToPA tables can hold more than one output entry.
+    pub topa_multiple_entries: bool,
+    /// The single-range output scheme is supported.
+    pub single_range_output: bool,
+    /// Output to the trace transport subsystem is supported.
+    pub trace_transport: bool,
+    /// IP payloads contain linear addresses (including the CS base).
+    pub lip: bool,
+    /// Number of configurable address ranges.
+    pub address_ranges: u8,
+    /// Bitmap of supported MTC frequency encodings.
+    pub mtc_periods: u16,
+    /// Bitmap of supported cycle threshold encodings.
+    pub cycle_thresholds: u16,
+    /// Bitmap of supported PSB frequency encodings.
+    pub psb_frequencies: u16,
+}
+
+impl PtCapabilities {
+    /// Read the capabilities from `cpuid`, returns `None` if Intel PT is not
+    /// supported.
+    pub fn from_cpuid(cpuid: &CpuId) -> Option<PtCapabilities> {
+        if !cpuid.get_extended_feature_info()?.has_processor_trace() {
+            return None;
+        }
+
+        let info = cpuid.get_processor_trace_info()?;
+        Some(PtCapabilities {
+            cr3_filtering: info.has_rtit_cr3_match(),
+            psb_cyc: info.has_configurable_psb_and_cycle_accurate_mode(),
+            ip_filtering: info.has_ip_tracestop_filtering(),
+            mtc: info.has_mtc_timing_packet_coefi_suppression(),
+            ptwrite: info.has_ptwrite(),
+            power_event_trace: info.has_power_event_trace(),
+            topa: info.has_topa(),
+            topa_multiple_entries: info.has_topa_maximum_entries(),
+            single_range_output: info.has_single_range_output_scheme(),
+            trace_transport: info.has_trace_transport_subsystem(),
+            lip: info.has_lip_with_cs_base(),
+            address_ranges: info.configurable_address_ranges(),
+            mtc_periods: info.supported_mtc_period_encodings(),
+            cycle_thresholds: info.supported_cycle_threshold_value_encodings(),
+            psb_frequencies: info.supported_psb_frequency_encodings(),
+        })
+    }
+
+    /// Read the capabilities of the current processor.
+    pub fn probe() -> Option<PtCapabilities> {
+        PtCapabilities::from_cpuid(&CpuId::new())
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use crate::cpuid::CpuIdResult;
+
+    #[test]
+    fn rtit_ctl_fields() {
+        let ctl = (RtitCtl::TRACE_EN | RtitCtl::OS | RtitCtl::BRANCH_EN | RtitCtl::TOPA)
+            .with_mtc_freq(3)
+            .with_psb_freq(5)
+            .with_cyc_thresh(1)
+            .with_addr_cfg(1, AddrCfg::Filter)
+            .with_addr_cfg(2, AddrCfg::TraceStop);
+        assert_eq!(ctl.bits(), 0x0000_0210_0508_e105);
+        assert_eq!(ctl.mtc_freq(), 3);
+        assert_eq!(ctl.psb_freq(), 5);
+        assert_eq!(ctl.cyc_thresh(), 1);
+        assert_eq!(ctl.addr_cfg(0), AddrCfg::Disabled);
+        assert_eq!(ctl.addr_cfg(1), AddrCfg::Filter);
+        assert_eq!(ctl.addr_cfg(2), AddrCfg::TraceStop);
+        assert!(ctl.contains(RtitCtl::TOPA));
+
+        let ctl = ctl.with_mtc_freq(0).with_addr_cfg(1, AddrCfg::Disabled);
+        assert_eq!(ctl.bits(), 0x0000_0200_0508_2105);
+
+        let status = RtitStatus::from_bits_truncate(0x0000_0123_0000_0023);
+        assert!(status.contains(RtitStatus::FILTER_EN | RtitStatus::CONTEXT_EN));
+        assert!(status.contains(RtitStatus::STOPPED));
+        assert_eq!(status.packet_byte_cnt(), 0x123);
+    }
+
+    #[test]
+    fn output_mask_ptrs() {
+        let ptrs = OutputMaskPtrs::topa(3, 0x200);
+        assert_eq!(ptrs.0, 0x0000_0200_0000_01ff);
+        assert_eq!(ptrs.table_entry(), 3);
+        assert_eq!(ptrs.output_offset(), 0x200);
+
+        let ptrs = OutputMaskPtrs::single_range(0x10000, 0x40);
+        assert_eq!(ptrs.0, 0x0000_0040_0000_ffff);
+        assert_eq!(ptrs.output_offset(), 0x40);
+    }
+
+    #[test]
+    fn topa_entries() {
+        let entry = TopaEntryBuilder::output(PAddr(0x20_0000), TopaSize::Size2M)
+            .interrupt()
+            .finish();
+        assert_eq!(entry.0, 0x20_0244);
+        assert_eq!(entry.address(), PAddr(0x20_0000));
+        assert_eq!(entry.size(), TopaSize::Size2M);
+        assert!(entry.is_int() && !entry.is_stop() && !entry.is_end());
+
+        let entry = TopaEntryBuilder::output(PAddr(0x5000), TopaSize::Size4K)
+            .stop()
+            .finish();
+        assert_eq!(entry.0, 0x5010);
+
+        let entry = TopaEntryBuilder::end(PAddr(0x1000)).finish();
+        assert_eq!(entry.0, 0x1001);
+        assert!(entry.is_end());
+
+        assert_eq!(TopaSize::from_bytes(64 * 1024), Some(TopaSize::Size64K));
+        assert_eq!(TopaSize::from_bytes(1000), None);
+        assert_eq!(TopaSize::Size128M.bytes(), 128 * 1024 * 1024);
+    }
+
+    #[test]
+    #[should_panic]
+    fn topa_misaligned_region() {
+        TopaEntryBuilder::output(PAddr(0x1000), TopaSize::Size8K).finish();
+    }
+
+    #[test]
+    fn topa_ring() {
+        let mut table = [TopaEntry::default(); 4];
+        let regions = [
+            (PAddr(0x10_0000), TopaSize::Size64K),
+            (PAddr(0x11_0000), TopaSize::Size64K),
+        ];
+        assert_eq!(
+            build_topa_ring(&mut table, PAddr(0x3000), &regions),
+            Some(3)
+        );
+        assert_eq!(table[0].0, 0x10_0100);
+        assert_eq!(table[1].0, 0x11_0104);
+        assert_eq!(table[2].0, 0x3001);
+        assert_eq!(table[3], TopaEntry::default());
+
+        let mut small = [TopaEntry::default(); 2];
+        assert_eq!(build_topa_ring(&mut small, PAddr(0x3000), &regions), None);
+        assert_eq!(build_topa_ring(&mut table, PAddr(0x3000), &[]), None);
+    }
+
+    fn cpuid_with_pt(eax: u32, ecx: u32) -> CpuIdResult {
+        let (eax, ebx, ecx, edx) = match (eax, ecx) {
+            // "GenuineIntel", max. leaf 0x14
+            (0x0, _) => (0x14, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            (0x7, 0) => (0, 1 << 25, 0, 0),
+            (0x14, 0) => (1, 0x3f, 0x8000_0007, 0),
+            (0x14, 1) => (0x0249_0002, 0x003f_003f, 0, 0),
+            _ => (0, 0, 0, 0),
+        };
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    fn cpuid_without_pt(eax: u32, ecx: u32) -> CpuIdResult {
+        let mut res = cpuid_with_pt(eax, ecx);
+        if eax == 0x7 {
+            res.ebx = 0;
+        }
+        res
+    }
+
+    #[test]
+    fn capabilities() {
+        let caps = PtCapabilities::from_cpuid(&CpuId::with_cpuid_fn(cpuid_with_pt)).unwrap();
+        assert!(caps.cr3_filtering && caps.psb_cyc && caps.ip_filtering && caps.mtc);
+        assert!(caps.ptwrite && caps.power_event_trace);
+        assert!(caps.topa && caps.topa_multiple_entries && caps.single_range_output);
+        assert!(!caps.trace_transport);
+        assert!(caps.lip);
+        assert_eq!(caps.address_ranges, 2);
+        assert_eq!(caps.mtc_periods, 0x249);
+        assert_eq!(caps.cycle_thresholds, 0x3f);
+        assert_eq!(caps.psb_frequencies, 0x3f);
+
+        assert_eq!(
+            PtCapabilities::from_cpuid(&CpuId::with_cpuid_fn(cpuid_without_pt)),
+            None
+        );
+    }
+}
@@ -0,0 +1,433 @@
+//! A basic decoder for Intel PT packet streams.
+//!
+//! Decodes the packets needed to follow control flow and sanity-check a
+//! trace (PSB, TNT, TIP, FUP, PIP, MODE, CBR and a few timing packets).
+//! Packets that are not understood stop decoding, use
+//! [`PacketDecoder::sync`] to continue at the next PSB.
+//!
+//! See Intel Vol. 3c Section 32.4, "Trace Packets and Data Types".
+
+use core::fmt;
+
+/// A PSB packet is this pattern repeated eight times.
+const PSB_PATTERN: [u8; 2] = [0x02, 0x82];
+const PSB_LEN: usize = 16;
+
+/// Taken/not-taken information for conditional branches.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Tnt {
+    /// Branch outcomes, the oldest branch is the most significant bit.
+    pub bits: u64,
+    /// Number of valid branch outcomes in `bits`.
+    pub count: u8,
+}
+
+impl Tnt {
+    fn from_payload(payload: u64) -> Tnt {
+        if payload == 0 {
+            return Tnt { bits: 0, count: 0 };
+        }
+        // The most significant set bit is the stop bit.
+        let count = 63 - payload.leading_zeros() as u8;
+        Tnt {
+            bits: payload & !(1 << count),
+            count,
+        }
+    }
+
+    /// Was the `i`-th branch (in execution order) taken?
+    pub fn taken(&self, i: u8) -> bool {
+        assert!(i < self.count, "Branch index out of range.");
+        (self.bits >> (self.count - 1 - i)) & 1 == 1
+    }
+}
+
+/// Operating mode reported in a MODE.Exec packet.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExecMode {
+    Mode16,
+    Mode32,
+    Mode64,
+}
+
+/// A decoded Intel PT packet.
+///
+/// IP payloads are reconstructed against the last IP; `None` means the IP
+/// was suppressed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Packet {
+    /// Padding.
+    Pad,
+    /// Packet stream boundary, decoding can start here.
+    Psb,
+    /// End of the PSB+ header packets.
+    PsbEnd,
+    /// Internal buffer overflow, packets were lost.
+    Ovf,
+    /// Conditional branch outcomes (short or long TNT).
+    Tnt(Tnt),
+    /// Target IP of an indirect branch, exception or interrupt.
+    Tip(Option<u64>),
+    /// Packet generation enabled at this IP.
+    TipPge(Option<u64>),
+    /// Packet generation disabled, last target IP.
+    TipPgd(Option<u64>),
+    /// Source IP of an asynchronous event or the first instruction traced.
+    Fup(Option<u64>),
+    /// CR3 value on a paging structure change.
+    Pip {
+        /// The new CR3 value.
+        cr3: u64,
+        /// The change happened in VMX non-root operation.
+        non_root: bool,
+    },
+    /// Execution mode change.
+    ModeExec(ExecMode),
+    /// Transactional state change.
+    ModeTsx {
+        /// Executing inside a transaction.
+        in_tx: bool,
+        /// The transaction was aborted.
+        abort: bool,
+    },
+    /// Core to bus ratio.
+    Cbr(u8),
+    /// Lower 7 bytes of the TSC.
+    Tsc(u64),
+    /// Mini time counter (bits of the ART).
+    Mtc(u8),
+}
+
+/// Errors encountered while decoding a packet stream.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The packet at this offset extends past the end of the buffer.
+    Truncated(usize),
+    /// The packet at this offset is unknown or not supported.
+    UnknownPacket(usize),
+    /// The IP packet at this offset uses a reserved IP compression.
+    ReservedIpCompression(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated(offset) => write!(f, "truncated packet at {:#x}", offset),
+            DecodeError::UnknownPacket(offset) => write!(f, "unknown packet at {:#x}", offset),
+            DecodeError::ReservedIpCompression(offset) => {
+                write!(f, "reserved IP compression at {:#x}", offset)
+            }
+        }
+    }
+}
+
+/// Decodes packets from a buffer of trace output.
+///
+/// The decoder is an iterator over the packets. After an error it
+/// returns `None` until [`PacketDecoder::sync`] finds the next PSB.
+#[derive(Debug, Clone)]
+pub struct PacketDecoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    last_ip: u64,
+    failed: bool,
+}
+
+impl<'a> PacketDecoder<'a> {
+    /// Decode packets starting at the beginning of `buf`.
+    ///
+    /// If `buf` does not start at a packet boundary call
+    /// [`PacketDecoder::sync`] first.
+    pub fn new(buf: &'a [u8]) -> PacketDecoder<'a> {
+        PacketDecoder {
+            buf,
+            pos: 0,
+            last_ip: 0,
+            failed: false,
+        }
+    }
+
+    /// Offset of the next packet in the buffer.
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /// Advance to the next PSB at or after the current position.
+    ///
+    /// Returns `false` (and consumes the buffer) if there is none.
+    pub fn sync(&mut self) -> bool {
+        let start = self.pos;
+        let found = self.buf[start..]
+            .windows(PSB_LEN)
+            .position(|w| w.chunks(2).all(|c| c == PSB_PATTERN));
+
+        match found {
+            Some(offset) => {
+                self.pos = start + offset;
+                self.failed = false;
+                true
+            }
+            None => {
+                self.pos = self.buf.len();
+                false
+            }
+        }
+    }
+
+    fn bytes(&self, len: usize) -> Result<&'a [u8], DecodeError> {
+        self.buf
+            .get(self.pos..self.pos + len)
+            .ok_or(DecodeError::Truncated(self.pos))
+    }
+
+    /// Little-endian value of `len` bytes starting at `offset` of the packet.
+    fn payload(&self, offset: usize, len: usize) -> Result<u64, DecodeError> {
+        let bytes = self.bytes(offset + len)?;
+        Ok(bytes[offset..]
+            .iter()
+            .rev()
+            .fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// Decode an IP packet (TIP, TIP.PGE, TIP.PGD, FUP), returns the IP and
+    /// the packet length.
+    fn ip(&mut self, header: u8) -> Result<(Option<u64>, usize), DecodeError> {
+        let (len, ip): (usize, fn(u64, u64) -> u64) = match header >> 5 {
+            0b000 => return Ok((None, 1)),
+            0b001 => (2, |last: u64, p: u64| (last & !0xffff) | p),
+            0b010 => (4, |last: u64, p: u64| (last & !0xffff_ffff) | p),
+            0b011 => (6, |_last: u64, p: u64| (((p << 16) as i64) >> 16) as u64),
+            0b100 => (6, |last: u64, p: u64| (last & !0xffff_ffff_ffff) | p),
+            0b110 => (8, |_last: u64, p: u64| p),
+            _ => return Err(DecodeError::ReservedIpCompression(self.pos)),
+        };
+
+        let payload = self.payload(1, len)?;
+        self.last_ip = ip(self.last_ip, payload);
+        Ok((Some(self.last_ip), 1 + len))
+    }
+
+    /// Decode a packet with the 0x02 extended opcode header.
+    fn extended(&mut self) -> Result<(Packet, usize), DecodeError> {
+        let opcode = self.bytes(2)?[1];
+        match opcode {
+            0x82 => {
+                let psb = self.bytes(PSB_LEN)?;
+                if !psb.chunks(2).all(|c| c == PSB_PATTERN) {
+                    return Err(DecodeError::UnknownPacket(self.pos));
+                }
+                self.last_ip = 0;
+                Ok((Packet::Psb, PSB_LEN))
+            }
+            0x23 => Ok((Packet::PsbEnd, 2)),
+            0xf3 => Ok((Packet::Ovf, 2)),
+            0xa3 => Ok((Packet::Tnt(Tnt::from_payload(self.payload(2, 6)?)), 8)),
+            0x43 => {
+                let payload = self.payload(2, 6)?;
+                Ok((
+                    Packet::Pip {
+                        cr3: (payload >> 1) << 5,
+                        non_root: payload & 1 == 1,
+                    },
+                    8,
+                ))
+            }
+            0x03 => Ok((Packet::Cbr(self.bytes(4)?[2]), 4)),
+            _ => Err(DecodeError::UnknownPacket(self.pos)),
+        }
+    }
+
+    fn decode(&mut self) -> Result<(Packet, usize), DecodeError> {
+        let header = self.bytes(1)?[0];
+        match header {
+            0x00 => Ok((Packet::Pad, 1)),
+            0x02 => self.extended(),
+            0x19 => Ok((Packet::Tsc(self.payload(1, 7)?), 8)),
+            0x59 => Ok((Packet::Mtc(self.bytes(2)?[1]), 2)),
+            0x99 => {
+                let mode = self.bytes(2)?[1];
+                let packet = match mode >> 5 {
+                    0b000 => Packet::ModeExec(match mode & 0b11 {
+                        0b00 => ExecMode::Mode16,
+                        0b10 => ExecMode::Mode32,
+                        0b01 => ExecMode::Mode64,
+                        _ => return Err(DecodeError::UnknownPacket(self.pos)),
+                    }),
+                    0b001 => Packet::ModeTsx {
+                        in_tx: mode & 0b01 != 0,
+                        abort: mode & 0b10 != 0,
+                    },
+                    _ => return Err(DecodeError::UnknownPacket(self.pos)),
+                };
+                Ok((packet, 2))
+            }
+            h if h & 1 == 0 => Ok((Packet::Tnt(Tnt::from_payload((h >> 1) as u64)), 1)),
+            h => {
+                let packet: fn(Option<u64>) -> Packet = match h & 0x1f {
+                    0x0d => Packet::Tip,
+                    0x11 => Packet::TipPge,
+                    0x01 => Packet::TipPgd,
+                    0x1d => Packet::Fup,
+                    _ => return Err(DecodeError::UnknownPacket(self.pos)),
+                };
+                let (ip, len) = self.ip(h)?;
+                Ok((packet(ip), len))
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for PacketDecoder<'a> {
+    type Item = Result<Packet, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.pos >= self.buf.len() {
+            return None;
+        }
+
+        match self.decode() {
+            Ok((packet, len)) => {
+                self.pos += len;
+                Some(Ok(packet))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    const PSB: [u8; 16] = [
+        0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02,
+        0x82,
+    ];
+
+    fn decode(buf: &[u8]) -> Vec<Result<Packet, DecodeError>> {
+        PacketDecoder::new(buf).collect()
+    }
+
+    #[test]
+    fn psb_header() {
+        // PSB+ as emitted when tracing gets enabled in 64-bit mode.
+        let mut trace = Vec::new();
+        trace.extend_from_slice(&PSB);
+        trace.extend_from_slice(&[0x02, 0x03, 0x2a, 0x00]); // CBR 42
+        trace.extend_from_slice(&[0x19, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22]); // TSC
+        trace.extend_from_slice(&[0x99, 0x01]); // MODE.Exec 64-bit
+        trace.extend_from_slice(&[0x02, 0x43, 0x00, 0x46, 0x02, 0x00, 0x00, 0x00]); // PIP
+        trace.extend_from_slice(&[0x02, 0x23]); // PSBEND
+        trace.extend_from_slice(&[0x00, 0x00]); // PAD
+
+        assert_eq!(
+            decode(&trace),
+            [
+                Ok(Packet::Psb),
+                Ok(Packet::Cbr(42)),
+                Ok(Packet::Tsc(0x22_3344_5566_7788)),
+                Ok(Packet::ModeExec(ExecMode::Mode64)),
+                Ok(Packet::Pip {
+                    cr3: 0x24_6000,
+                    non_root: false
+                }),
+                Ok(Packet::PsbEnd),
+                Ok(Packet::Pad),
+                Ok(Packet::Pad),
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let trace = [
+            0x71, 0x00, 0x00, 0x10, 0x40, 0x00, 0x80, // TIP.PGE, full 48-bit sign-extended IP
+            0x1a, // Short TNT: stop bit + taken, not-taken, taken
+            0x2d, 0x34, 0x12, // TIP with 16-bit update
+            0x4d, 0x78, 0x56, 0x34, 0x12, // TIP with 32-bit update
+            0x1d, // FUP with suppressed IP
+            0x02, 0xa3, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x01, // Long TNT: 40 branches
+            0x99, 0x21, // MODE.TSX: in transaction
+            0xc1, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // TIP.PGD, 64-bit IP
+            0x59, 0x07, // MTC
+            0x02, 0xf3, // OVF
+        ];
+
+        let packets = decode(&trace);
+        assert_eq!(packets[0], Ok(Packet::TipPge(Some(0xffff_8000_4010_0000))));
+        let tnt = match packets[1] {
+            Ok(Packet::Tnt(tnt)) => tnt,
+            _ => panic!("expected TNT"),
+        };
+        assert_eq!(tnt.count, 3);
+        assert!(tnt.taken(0) && !tnt.taken(1) && tnt.taken(2));
+        assert_eq!(packets[2], Ok(Packet::Tip(Some(0xffff_8000_4010_1234))));
+        assert_eq!(packets[3], Ok(Packet::Tip(Some(0xffff_8000_1234_5678))));
+        assert_eq!(packets[4], Ok(Packet::Fup(None)));
+        let tnt = match packets[5] {
+            Ok(Packet::Tnt(tnt)) => tnt,
+            _ => panic!("expected TNT"),
+        };
+        assert_eq!(tnt.count, 40);
+        assert!(tnt.taken(0) && !tnt.taken(1) && !tnt.taken(39));
+        assert_eq!(
+            packets[6],
+            Ok(Packet::ModeTsx {
+                in_tx: true,
+                abort: false
+            })
+        );
+        assert_eq!(packets[7], Ok(Packet::TipPgd(Some(0x2000))));
+        assert_eq!(packets[8], Ok(Packet::Mtc(7)));
+        assert_eq!(packets[9], Ok(Packet::Ovf));
+        assert_eq!(packets.len(), 10);
+    }
+
+    #[test]
+    fn errors_and_sync() {
+        // Truncated TIP.
+        assert_eq!(decode(&[0x4d, 0x78]), [Err(DecodeError::Truncated(0))]);
+        // Reserved IP compression.
+        assert_eq!(
+            decode(&[0x00, 0xad, 0x00]),
+            [Ok(Packet::Pad), Err(DecodeError::ReservedIpCompression(1))]
+        );
+
+        // Start in the middle of a packet, resync at the PSB.
+        let mut trace = Vec::new();
+        trace.extend_from_slice(&[0x34, 0x12, 0xff, 0x02]);
+        trace.extend_from_slice(&PSB);
+        trace.extend_from_slice(&[0x02, 0x23, 0x02, 0xc8]);
+
+        let mut decoder = PacketDecoder::new(&trace);
+        assert_eq!(
+            decoder.next(),
+            Some(Ok(Packet::Tnt(Tnt {
+                bits: 0b1010,
+                count: 4
+            })))
+        );
+        assert_eq!(
+            decoder.next(),
+            Some(Ok(Packet::Tnt(Tnt {
+                bits: 0b001,
+                count: 3
+            })))
+        );
+        assert_eq!(decoder.next(), Some(Err(DecodeError::UnknownPacket(2))));
+        assert_eq!(decoder.next(), None);
+        assert!(decoder.sync());
+        assert_eq!(decoder.offset(), 4);
+        assert_eq!(decoder.next(), Some(Ok(Packet::Psb)));
+        assert_eq!(decoder.next(), Some(Ok(Packet::PsbEnd)));
+        assert_eq!(decoder.next(), Some(Err(DecodeError::UnknownPacket(22))));
+        assert!(!decoder.sync());
+        assert_eq!(decoder.next(), None);
+    }
+}
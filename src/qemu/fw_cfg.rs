@@ -0,0 +1,703 @@
+//! QEMU firmware configuration (fw_cfg) interface.
+//!
+//! Items are selected by writing a 16-bit key to [`SELECTOR`] and read
+//! byte by byte from [`DATA`]. Besides the fixed items, the host can pass
+//! named files (e.g., `etc/e820` or `-fw_cfg name=opt/...`), listed in the
+//! [`FILE_DIR`] item. Hosts reporting [`Features::dma`] also support the
+//! faster DMA interface, see [`DmaAccess`].
+//!
+//! See `docs/specs/fw_cfg.rst` in the QEMU source tree.
+
+use crate::io::{NativePortIo, PortIo};
+
+/// Selector register (16 bits, little-endian).
+pub const SELECTOR: u16 = 0x510;
+/// Data register (8 bits).
+pub const DATA: u16 = 0x511;
+/// DMA address register (64 bits, big-endian, written as two 32-bit halves;
+/// writing the lower half at `DMA_ADDRESS + 4` starts the transfer).
+pub const DMA_ADDRESS: u16 = 0x514;
+
+/// Signature item, reads as `QEMU`.
+pub const SIGNATURE: u16 = 0x00;
+/// Feature bitmap, see [`Features`].
+pub const ID: u16 = 0x01;
+/// VM UUID (16 bytes).
+pub const UUID: u16 = 0x02;
+/// RAM size in bytes (64 bits).
+pub const RAM_SIZE: u16 = 0x03;
+/// Graphics disabled (16 bits).
+pub const NOGRAPHIC: u16 = 0x04;
+/// Number of boot CPUs (16 bits).
+pub const NB_CPUS: u16 = 0x05;
+/// Machine ID (32 bits).
+pub const MACHINE_ID: u16 = 0x06;
+/// Load address of the `-kernel` image (32 bits).
+pub const KERNEL_ADDR: u16 = 0x07;
+/// Size of the `-kernel` image (32 bits).
+pub const KERNEL_SIZE: u16 = 0x08;
+/// Load address of the `-initrd` image (32 bits).
+pub const INITRD_ADDR: u16 = 0x0a;
+/// Size of the `-initrd` image (32 bits).
+pub const INITRD_SIZE: u16 = 0x0b;
+/// Maximum number of CPUs (16 bits).
+pub const MAX_CPUS: u16 = 0x0f;
+/// Entry point of the `-kernel` image (32 bits).
+pub const KERNEL_ENTRY: u16 = 0x10;
+/// Contents of the `-kernel` image.
+pub const KERNEL_DATA: u16 = 0x11;
+/// Contents of the `-initrd` image.
+pub const INITRD_DATA: u16 = 0x12;
+/// Size of the `-append` command line (32 bits).
+pub const CMDLINE_SIZE: u16 = 0x14;
+/// Contents of the `-append` command line (NUL-terminated).
+pub const CMDLINE_DATA: u16 = 0x15;
+/// File directory, see [`FwCfg::files`].
+pub const FILE_DIR: u16 = 0x19;
+
+/// Size of a name in the file directory (including the NUL terminator).
+pub const FILE_NAME_SIZE: usize = 56;
+/// Size of a file directory entry.
+const FILE_ENTRY_SIZE: usize = 64;
+
+/// Size of an entry of `etc/e820`.
+pub const E820_ENTRY_SIZE: usize = 20;
+/// E820 type: usable RAM.
+pub const E820_RAM: u32 = 1;
+/// E820 type: reserved.
+pub const E820_RESERVED: u32 = 2;
+
+/// Errors of the fw_cfg interface.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FwCfgError {
+    /// The signature item does not read `QEMU`.
+    NotPresent,
+    /// The host does not support the DMA interface.
+    DmaUnsupported,
+    /// No file with this name is in the file directory.
+    FileNotFound,
+    /// The host reported an error for a DMA transfer.
+    DmaError,
+}
+
+/// Features reported by the [`ID`] item.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Features {
+    /// The selector/data port interface is available.
+    pub traditional: bool,
+    /// The DMA interface is available.
+    pub dma: bool,
+}
+
+impl Features {
+    /// Decode the [`ID`] item.
+    pub fn from_raw(raw: u32) -> Features {
+        Features {
+            traditional: raw & (1 << 0) != 0,
+            dma: raw & (1 << 1) != 0,
+        }
+    }
+}
+
+/// An entry of the file directory.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct File {
+    /// Size of the file in bytes.
+    pub size: u32,
+    /// Selector key of the file.
+    pub select: u16,
+    name: [u8; FILE_NAME_SIZE],
+}
+
+impl File {
+    /// Decode a big-endian file directory entry.
+    fn parse(entry: &[u8; FILE_ENTRY_SIZE]) -> File {
+        let mut name = [0; FILE_NAME_SIZE];
+        name.copy_from_slice(&entry[8..]);
+        File {
+            size: u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]),
+            select: u16::from_be_bytes([entry[4], entry[5]]),
+            name,
+        }
+    }
+
+    /// The file name (without NUL terminator), `None` if it isn't UTF-8.
+    pub fn name(&self) -> Option<&str> {
+        let length = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(FILE_NAME_SIZE);
+        core::str::from_utf8(&self.name[..length]).ok()
+    }
+}
+
+impl core::fmt::Debug for File {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("File")
+            .field("size", &self.size)
+            .field("select", &self.select)
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+/// An entry of the `etc/e820` memory map.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct E820Entry {
+    /// Start of the range.
+    pub address: u64,
+    /// Length of the range in bytes.
+    pub length: u64,
+    /// Type of the range, e.g. [`E820_RAM`].
+    pub kind: u32,
+}
+
+impl E820Entry {
+    /// Decode a little-endian `etc/e820` entry.
+    pub fn parse(entry: &[u8; E820_ENTRY_SIZE]) -> E820Entry {
+        let mut address = [0; 8];
+        address.copy_from_slice(&entry[0..8]);
+        let mut length = [0; 8];
+        length.copy_from_slice(&entry[8..16]);
+        E820Entry {
+            address: u64::from_le_bytes(address),
+            length: u64::from_le_bytes(length),
+            kind: u32::from_le_bytes([entry[16], entry[17], entry[18], entry[19]]),
+        }
+    }
+}
+
+/// Operation of a [`DmaAccess`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DmaOperation {
+    /// Read from the item into memory.
+    Read,
+    /// Write memory to the (writable) item.
+    Write,
+    /// Advance the offset within the item.
+    Skip,
+}
+
+/// DMA control: the transfer failed.
+const DMA_ERROR: u32 = 1 << 0;
+/// DMA control: read.
+const DMA_READ: u32 = 1 << 1;
+/// DMA control: skip.
+const DMA_SKIP: u32 = 1 << 2;
+/// DMA control: select the item in bits 31:16 first.
+const DMA_SELECT: u32 = 1 << 3;
+/// DMA control: write.
+const DMA_WRITE: u32 = 1 << 4;
+
+/// A DMA access descriptor, read and updated by the host.
+///
+/// All fields are stored big-endian as the host expects them.
+#[derive(Debug)]
+#[repr(C)]
+pub struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+impl DmaAccess {
+    /// Describe a transfer of `length` bytes to or from physical `address`,
+    /// optionally selecting item `select` first.
+    pub fn new(
+        select: Option<u16>,
+        operation: DmaOperation,
+        length: u32,
+        address: u64,
+    ) -> DmaAccess {
+        let mut control = match operation {
+            DmaOperation::Read => DMA_READ,
+            DmaOperation::Write => DMA_WRITE,
+            DmaOperation::Skip => DMA_SKIP,
+        };
+        if let Some(key) = select {
+            control |= DMA_SELECT | ((key as u32) << 16);
+        }
+        DmaAccess {
+            control: control.to_be(),
+            length: length.to_be(),
+            address: address.to_be(),
+        }
+    }
+
+    fn control(&self) -> u32 {
+        u32::from_be(unsafe { core::ptr::read_volatile(&self.control) })
+    }
+
+    /// True once the host finished (or failed) the transfer.
+    pub fn is_complete(&self) -> bool {
+        self.control() & !DMA_ERROR == 0
+    }
+
+    /// True if the host reported an error.
+    pub fn is_error(&self) -> bool {
+        self.control() & DMA_ERROR != 0
+    }
+}
+
+/// Driver for the fw_cfg device.
+pub struct FwCfg<P: PortIo = NativePortIo> {
+    io: P,
+    features: Features,
+}
+
+impl FwCfg {
+    /// Probe the fw_cfg device at the x86 ports.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    pub unsafe fn new() -> Result<Self, FwCfgError> {
+        FwCfg::with_io(NativePortIo::new())
+    }
+}
+
+impl<P: PortIo> FwCfg<P> {
+    /// Probe the fw_cfg device doing port I/O through `io`.
+    ///
+    /// Fails if the signature item doesn't read `QEMU`.
+    pub fn with_io(io: P) -> Result<Self, FwCfgError> {
+        let mut fw_cfg = FwCfg {
+            io,
+            features: Features::from_raw(0),
+        };
+        let mut signature = [0; 4];
+        fw_cfg.read_item(SIGNATURE, &mut signature);
+        if &signature != b"QEMU" {
+            return Err(FwCfgError::NotPresent);
+        }
+        fw_cfg.features = Features::from_raw(fw_cfg.read_u32(ID));
+        Ok(fw_cfg)
+    }
+
+    /// Features reported by the host.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Select item `key` and reset the offset within it.
+    pub fn select(&mut self, key: u16) {
+        self.io.write_u16(SELECTOR, key);
+    }
+
+    /// Read the next `buffer.len()` bytes of the selected item.
+    ///
+    /// Reads past the end of an item return zeros.
+    pub fn read(&mut self, buffer: &mut [u8]) {
+        for byte in buffer.iter_mut() {
+            *byte = self.io.read_u8(DATA);
+        }
+    }
+
+    /// Skip `count` bytes of the selected item.
+    pub fn skip(&mut self, count: usize) {
+        for _ in 0..count {
+            self.io.read_u8(DATA);
+        }
+    }
+
+    /// Select item `key` and read its first `buffer.len()` bytes.
+    pub fn read_item(&mut self, key: u16, buffer: &mut [u8]) {
+        self.select(key);
+        self.read(buffer);
+    }
+
+    /// Read a 16-bit little-endian item such as [`NB_CPUS`].
+    pub fn read_u16(&mut self, key: u16) -> u16 {
+        let mut raw = [0; 2];
+        self.read_item(key, &mut raw);
+        u16::from_le_bytes(raw)
+    }
+
+    /// Read a 32-bit little-endian item such as [`KERNEL_SIZE`].
+    pub fn read_u32(&mut self, key: u16) -> u32 {
+        let mut raw = [0; 4];
+        self.read_item(key, &mut raw);
+        u32::from_le_bytes(raw)
+    }
+
+    /// Read a 64-bit little-endian item such as [`RAM_SIZE`].
+    pub fn read_u64(&mut self, key: u16) -> u64 {
+        let mut raw = [0; 8];
+        self.read_item(key, &mut raw);
+        u64::from_le_bytes(raw)
+    }
+
+    /// Iterate over the file directory.
+    ///
+    /// Selects [`FILE_DIR`]; other accesses end the iteration.
+    pub fn files(&mut self) -> Files<'_, P> {
+        let mut count = [0; 4];
+        self.read_item(FILE_DIR, &mut count);
+        Files {
+            fw_cfg: self,
+            remaining: u32::from_be_bytes(count),
+        }
+    }
+
+    /// Look up the file named `name`.
+    pub fn find_file(&mut self, name: &str) -> Result<File, FwCfgError> {
+        self.files()
+            .find(|file| file.name() == Some(name))
+            .ok_or(FwCfgError::FileNotFound)
+    }
+
+    /// Read the start of `file` into `buffer`.
+    ///
+    /// Returns the number of bytes read, at most the size of the file.
+    pub fn read_file(&mut self, file: &File, buffer: &mut [u8]) -> usize {
+        let length = buffer.len().min(file.size as usize);
+        self.read_item(file.select, &mut buffer[..length]);
+        length
+    }
+
+    /// Read the `etc/e820` memory map into `entries`.
+    ///
+    /// Returns the number of entries read.
+    pub fn e820(&mut self, entries: &mut [E820Entry]) -> Result<usize, FwCfgError> {
+        let file = self.find_file("etc/e820")?;
+        let count = entries.len().min(file.size as usize / E820_ENTRY_SIZE);
+        self.select(file.select);
+        for entry in entries[..count].iter_mut() {
+            let mut raw = [0; E820_ENTRY_SIZE];
+            self.read(&mut raw);
+            *entry = E820Entry::parse(&raw);
+        }
+        Ok(count)
+    }
+
+    /// Run the DMA transfer described by `access` and wait for it to
+    /// complete.
+    ///
+    /// `access` is a raw pointer since the device writes the control field
+    /// while we poll it.
+    ///
+    /// # Safety
+    /// `access` must be valid for reads and writes and not otherwise accessed
+    /// during the transfer, `access_physical` must be its physical address,
+    /// and the buffer it describes must be valid for the transfer.
+    pub unsafe fn dma(
+        &mut self,
+        access: *mut DmaAccess,
+        access_physical: u64,
+    ) -> Result<(), FwCfgError> {
+        if !self.features.dma {
+            return Err(FwCfgError::DmaUnsupported);
+        }
+        // The register is big-endian, `out` writes the lowest byte first.
+        self.io
+            .write_u32(DMA_ADDRESS, ((access_physical >> 32) as u32).to_be());
+        self.io
+            .write_u32(DMA_ADDRESS + 4, (access_physical as u32).to_be());
+        while !(*access).is_complete() {
+            core::hint::spin_loop();
+        }
+        if (*access).is_error() {
+            Err(FwCfgError::DmaError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Iterator over the file directory, see [`FwCfg::files`].
+pub struct Files<'a, P: PortIo> {
+    fw_cfg: &'a mut FwCfg<P>,
+    remaining: u32,
+}
+
+impl<P: PortIo> Iterator for Files<'_, P> {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let mut entry = [0; FILE_ENTRY_SIZE];
+        self.fw_cfg.read(&mut entry);
+        Some(File::parse(&entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl<P: PortIo> ExactSizeIterator for Files<'_, P> {}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use core::ptr::addr_of_mut;
+
+    extern crate std;
+    use std::borrow::ToOwned;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Stand-in for QEMU's fw_cfg device, including DMA to host memory.
+    struct Device {
+        items: Vec<(u16, Vec<u8>, bool)>,
+        selected: u16,
+        offset: usize,
+        dma_high: u32,
+    }
+
+    impl Device {
+        fn new(dma: bool) -> Device {
+            let mut e820 = Vec::new();
+            for (address, length, kind) in [
+                (0u64, 0x9fc00u64, E820_RAM),
+                (0xfeff_c000, 0x4000, E820_RESERVED),
+                (0x10_0000, 0x7ee0_0000, E820_RAM),
+            ] {
+                e820.extend_from_slice(&address.to_le_bytes());
+                e820.extend_from_slice(&length.to_le_bytes());
+                e820.extend_from_slice(&kind.to_le_bytes());
+            }
+            let config = b"answer=42\n".to_vec();
+
+            let mut directory = 3u32.to_be_bytes().to_vec();
+            for (size, select, name) in [
+                (8u32, 0x20u16, &b"bootorder"[..]),
+                (e820.len() as u32, 0x21, b"etc/e820"),
+                (config.len() as u32, 0x22, b"opt/test/config"),
+            ] {
+                directory.extend_from_slice(&size.to_be_bytes());
+                directory.extend_from_slice(&select.to_be_bytes());
+                directory.extend_from_slice(&[0, 0]);
+                let mut padded = [0u8; FILE_NAME_SIZE];
+                padded[..name.len()].copy_from_slice(name);
+                directory.extend_from_slice(&padded);
+            }
+
+            let id: u32 = if dma { 3 } else { 1 };
+            Device {
+                items: vec![
+                    (SIGNATURE, b"QEMU".to_vec(), false),
+                    (ID, id.to_le_bytes().to_vec(), false),
+                    (RAM_SIZE, 0x8000_0000u64.to_le_bytes().to_vec(), false),
+                    (NB_CPUS, 2u16.to_le_bytes().to_vec(), false),
+                    (FILE_DIR, directory, false),
+                    (0x20, vec![0; 8], true),
+                    (0x21, e820, false),
+                    (0x22, config, false),
+                ],
+                selected: 0,
+                offset: 0,
+                dma_high: 0,
+            }
+        }
+
+        fn item(&mut self) -> Option<&mut (u16, Vec<u8>, bool)> {
+            let selected = self.selected;
+            self.items.iter_mut().find(|item| item.0 == selected)
+        }
+
+        /// Process the descriptor at `address`, treating physical addresses
+        /// as pointers.
+        unsafe fn dma(&mut self, address: u64) {
+            let access = address as *mut u32;
+            let control = u32::from_be(access.read());
+            let length = u32::from_be(access.add(1).read()) as usize;
+            let buffer = u64::from_be((access.add(2) as *const u64).read()) as *mut u8;
+            if control & DMA_SELECT != 0 {
+                self.selected = (control >> 16) as u16;
+                self.offset = 0;
+            }
+            let offset = self.offset;
+            let mut result = 0;
+            if control & DMA_READ != 0 {
+                let data = self.item().map(|item| item.1.clone()).unwrap_or_default();
+                for i in 0..length {
+                    *buffer.add(i) = data.get(offset + i).copied().unwrap_or(0);
+                }
+            } else if control & DMA_WRITE != 0 {
+                match self.item() {
+                    Some((_, data, true)) if offset + length <= data.len() => {
+                        for i in 0..length {
+                            data[offset + i] = *buffer.add(i);
+                        }
+                    }
+                    _ => result = DMA_ERROR,
+                }
+            }
+            self.offset += length;
+            access.write(result.to_be());
+        }
+    }
+
+    impl PortIo for Device {
+        fn read_u8(&mut self, port: u16) -> u8 {
+            assert_eq!(port, DATA);
+            let offset = self.offset;
+            self.offset += 1;
+            self.item()
+                .and_then(|item| item.1.get(offset).copied())
+                .unwrap_or(0)
+        }
+
+        fn write_u8(&mut self, _port: u16, _val: u8) {
+            unreachable!()
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, port: u16, val: u16) {
+            assert_eq!(port, SELECTOR);
+            self.selected = val;
+            self.offset = 0;
+        }
+
+        fn read_u32(&mut self, _port: u16) -> u32 {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, port: u16, val: u32) {
+            match port {
+                DMA_ADDRESS => self.dma_high = u32::from_be(val),
+                port if port == DMA_ADDRESS + 4 => unsafe {
+                    self.dma(((self.dma_high as u64) << 32) | u32::from_be(val) as u64)
+                },
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn probe() {
+        let mut fw_cfg = FwCfg::with_io(Device::new(false)).unwrap();
+        assert_eq!(
+            fw_cfg.features(),
+            Features {
+                traditional: true,
+                dma: false
+            }
+        );
+        assert_eq!(fw_cfg.read_u64(RAM_SIZE), 0x8000_0000);
+        assert_eq!(fw_cfg.read_u16(NB_CPUS), 2);
+        assert_eq!(fw_cfg.read_u32(KERNEL_SIZE), 0);
+
+        let mut device = Device::new(false);
+        device.items[0].1 = b"BOCH".to_vec();
+        assert_eq!(FwCfg::with_io(device).err(), Some(FwCfgError::NotPresent));
+    }
+
+    #[test]
+    fn files() {
+        let mut fw_cfg = FwCfg::with_io(Device::new(false)).unwrap();
+        assert_eq!(fw_cfg.files().len(), 3);
+        let names: Vec<_> = fw_cfg
+            .files()
+            .map(|file| (file.name().unwrap().to_owned(), file.select))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("bootorder".to_owned(), 0x20),
+                ("etc/e820".to_owned(), 0x21),
+                ("opt/test/config".to_owned(), 0x22),
+            ]
+        );
+
+        let file = fw_cfg.find_file("opt/test/config").unwrap();
+        assert_eq!(file.size, 10);
+        let mut buffer = [0xff; 16];
+        assert_eq!(fw_cfg.read_file(&file, &mut buffer), 10);
+        assert_eq!(&buffer[..10], b"answer=42\n");
+        assert_eq!(buffer[10], 0xff);
+        assert_eq!(
+            fw_cfg.find_file("opt/missing"),
+            Err(FwCfgError::FileNotFound)
+        );
+
+        fw_cfg.select(0x22);
+        fw_cfg.skip(7);
+        let mut value = [0; 2];
+        fw_cfg.read(&mut value);
+        assert_eq!(&value, b"42");
+    }
+
+    #[test]
+    fn e820() {
+        let mut fw_cfg = FwCfg::with_io(Device::new(false)).unwrap();
+        let mut entries = [E820Entry {
+            address: 0,
+            length: 0,
+            kind: 0,
+        }; 4];
+        assert_eq!(fw_cfg.e820(&mut entries), Ok(3));
+        assert_eq!(
+            entries[2],
+            E820Entry {
+                address: 0x10_0000,
+                length: 0x7ee0_0000,
+                kind: E820_RAM,
+            }
+        );
+        assert_eq!(fw_cfg.e820(&mut entries[..1]), Ok(1));
+        assert_eq!(entries[0].length, 0x9fc00);
+    }
+
+    #[test]
+    fn dma() {
+        let mut fw_cfg = FwCfg::with_io(Device::new(true)).unwrap();
+        assert!(fw_cfg.features().dma);
+
+        let mut buffer = [0u8; 4];
+        let mut access = DmaAccess::new(
+            Some(0x22),
+            DmaOperation::Read,
+            buffer.len() as u32,
+            buffer.as_mut_ptr() as u64,
+        );
+        let access_ptr = addr_of_mut!(access);
+        unsafe { fw_cfg.dma(access_ptr, access_ptr as u64) }.unwrap();
+        assert!(access.is_complete());
+        assert_eq!(&buffer, b"answ");
+
+        let mut access = DmaAccess::new(None, DmaOperation::Skip, 3, 0);
+        let access_ptr = addr_of_mut!(access);
+        unsafe { fw_cfg.dma(access_ptr, access_ptr as u64) }.unwrap();
+        let mut access = DmaAccess::new(None, DmaOperation::Read, 2, buffer.as_mut_ptr() as u64);
+        let access_ptr = addr_of_mut!(access);
+        unsafe { fw_cfg.dma(access_ptr, access_ptr as u64) }.unwrap();
+        assert_eq!(&buffer[..2], b"42");
+
+        let order = *b"/pci@i0";
+        let mut access = DmaAccess::new(
+            Some(0x20),
+            DmaOperation::Write,
+            order.len() as u32,
+            order.as_ptr() as u64,
+        );
+        let access_ptr = addr_of_mut!(access);
+        unsafe { fw_cfg.dma(access_ptr, access_ptr as u64) }.unwrap();
+        let bootorder = fw_cfg.find_file("bootorder").unwrap();
+        let mut written = [0; 8];
+        fw_cfg.read_file(&bootorder, &mut written);
+        assert_eq!(&written, b"/pci@i0\0");
+
+        let mut access = DmaAccess::new(Some(0x22), DmaOperation::Write, 1, order.as_ptr() as u64);
+        let access_ptr = addr_of_mut!(access);
+        assert_eq!(
+            unsafe { fw_cfg.dma(access_ptr, access_ptr as u64) },
+            Err(FwCfgError::DmaError)
+        );
+        assert!(access.is_error());
+
+        let mut fw_cfg = FwCfg::with_io(Device::new(false)).unwrap();
+        let access_ptr = addr_of_mut!(access);
+        assert_eq!(
+            unsafe { fw_cfg.dma(access_ptr, access_ptr as u64) },
+            Err(FwCfgError::DmaUnsupported)
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Interfaces of QEMU's emulated platform devices.
+//!
+//! [`DebugExit`] drives the `isa-debug-exit` device used to terminate a test
+//! guest with a status code, [`fw_cfg`] reads configuration items and files
+//! provided by the host.
+//!
+//! See `docs/specs/fw_cfg.rst` in the QEMU source tree and
+//! `hw/misc/debugexit.c` for the exit device.
+
+use crate::io::{NativePortIo, PortIo};
+
+pub mod fw_cfg;
+
+/// Default I/O port of `-device isa-debug-exit`.
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Exit status of the QEMU process after writing `code` to the
+/// `isa-debug-exit` device.
+///
+/// QEMU exits with `(code << 1) | 1`, so a guest can't produce status 0;
+/// test runners usually map one odd status to success.
+pub const fn exit_status(code: u32) -> u32 {
+    (code << 1) | 1
+}
+
+/// Driver for the `isa-debug-exit` device.
+///
+/// Start QEMU with `-device isa-debug-exit,iobase=0xf4,iosize=0x04` so the
+/// full 32-bit code is passed on; with `iosize=0x01` only the lowest byte is.
+pub struct DebugExit<P: PortIo = NativePortIo> {
+    io: P,
+    port: u16,
+}
+
+impl DebugExit {
+    /// Create a driver for the device at `port`.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    pub unsafe fn new(port: u16) -> Self {
+        DebugExit::with_io(NativePortIo::new(), port)
+    }
+}
+
+impl<P: PortIo> DebugExit<P> {
+    /// Create a driver doing port I/O through `io`.
+    pub fn with_io(io: P, port: u16) -> Self {
+        DebugExit { io, port }
+    }
+
+    /// The I/O port of the device.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Ask QEMU to exit with [`exit_status`]`(code)`.
+    ///
+    /// Returns if the device is not present, e.g., on real hardware.
+    pub fn request_exit(&mut self, code: u32) {
+        self.io.write_u32(self.port, code);
+    }
+
+    /// Ask QEMU to exit with [`exit_status`]`(code)` and spin if the device
+    /// is not present.
+    pub fn exit(&mut self, code: u32) -> ! {
+        self.request_exit(code);
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    struct Ports(Vec<(u16, u32)>);
+
+    impl PortIo for Ports {
+        fn read_u8(&mut self, _port: u16) -> u8 {
+            unreachable!()
+        }
+
+        fn write_u8(&mut self, _port: u16, _val: u8) {
+            unreachable!()
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, _port: u16, _val: u16) {
+            unreachable!()
+        }
+
+        fn read_u32(&mut self, _port: u16) -> u32 {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, port: u16, val: u32) {
+            self.0.push((port, val));
+        }
+    }
+
+    #[test]
+    fn debug_exit() {
+        let mut device = DebugExit::with_io(Ports(Vec::new()), DEBUG_EXIT_PORT);
+        assert_eq!(device.port(), 0xf4);
+        device.request_exit(0x10);
+        assert_eq!(device.io.0, [(0xf4, 0x10)]);
+        assert_eq!(exit_status(0x10), 0x21);
+        assert_eq!(exit_status(0), 1);
+    }
+}
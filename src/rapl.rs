@@ -0,0 +1,517 @@
+//! Running Average Power Limit (RAPL) energy counters and power limits.
+//!
+//! RAPL exposes per-domain energy consumption counters and power limit
+//! controls through a set of MSRs. All values in these MSRs are expressed in
+//! processor specific units which are advertised in `MSR_RAPL_POWER_UNIT`.
+//!
+//! See Intel Vol. 3b Section 14.10, "Platform Specific Power Management
+//! Support", especially Section 14.10.1, "RAPL Interfaces".
+
+use bit_field::BitField;
+
+use crate::msr::{
+    rdmsr, wrmsr, MSR_DRAM_ENERGY_STATUS, MSR_DRAM_POWER_INFO, MSR_DRAM_POWER_LIMIT,
+    MSR_PKG_ENERGY_STATUS, MSR_PKG_POWER_INFO, MSR_PKG_POWER_LIMIT, MSR_PP0_ENERGY_STATUS,
+    MSR_PP0_POWER_LIMIT, MSR_PP1_ENERGY_STATUS, MSR_PP1_POWER_LIMIT, MSR_RAPL_POWER_UNIT,
+};
+
+/// The energy unit used by the DRAM domain on server parts (Haswell-EP and
+/// later) which ignore the energy status unit in `MSR_RAPL_POWER_UNIT` for
+/// DRAM and always count in 15.3 micro-joule increments.
+pub const DRAM_FIXED_ENERGY_UNIT: u8 = 16;
+
+/// A RAPL power domain.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RaplDomain {
+    /// The whole processor package.
+    Package,
+    /// Power plane 0: the processor cores.
+    Core,
+    /// Power plane 1: the uncore (typically the integrated graphics).
+    Uncore,
+    /// The memory controller and attached DRAM.
+    Dram,
+}
+
+impl RaplDomain {
+    /// The MSR holding the energy status counter of this domain.
+    pub const fn energy_status_msr(&self) -> u32 {
+        match self {
+            RaplDomain::Package => MSR_PKG_ENERGY_STATUS,
+            RaplDomain::Core => MSR_PP0_ENERGY_STATUS,
+            RaplDomain::Uncore => MSR_PP1_ENERGY_STATUS,
+            RaplDomain::Dram => MSR_DRAM_ENERGY_STATUS,
+        }
+    }
+
+    /// The MSR holding the power limit control of this domain.
+    pub const fn power_limit_msr(&self) -> u32 {
+        match self {
+            RaplDomain::Package => MSR_PKG_POWER_LIMIT,
+            RaplDomain::Core => MSR_PP0_POWER_LIMIT,
+            RaplDomain::Uncore => MSR_PP1_POWER_LIMIT,
+            RaplDomain::Dram => MSR_DRAM_POWER_LIMIT,
+        }
+    }
+
+    /// The MSR holding the power info of this domain, if it has one (only
+    /// the package and DRAM domains do).
+    pub const fn power_info_msr(&self) -> Option<u32> {
+        match self {
+            RaplDomain::Package => Some(MSR_PKG_POWER_INFO),
+            RaplDomain::Dram => Some(MSR_DRAM_POWER_INFO),
+            RaplDomain::Core | RaplDomain::Uncore => None,
+        }
+    }
+
+    /// Does this domain have a second power limit (only the package does)?
+    pub const fn has_second_limit(&self) -> bool {
+        matches!(self, RaplDomain::Package)
+    }
+}
+
+/// Unit multipliers used by all RAPL interfaces (decoded `MSR_RAPL_POWER_UNIT`).
+///
+/// Each unit is stored as an exponent `n`, the unit then is `1 / 2^n`
+/// watts, joules or seconds respectively.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RaplUnits {
+    /// Power units (bits 3:0), in `1 / 2^power` watts.
+    pub power: u8,
+    /// Energy status units (bits 12:8), in `1 / 2^energy` joules.
+    pub energy: u8,
+    /// Time units (bits 19:16), in `1 / 2^time` seconds.
+    pub time: u8,
+    /// Energy status units for the DRAM domain. This is identical to
+    /// `energy` unless overridden with [`RaplUnits::with_dram_energy_unit`].
+    pub dram_energy: u8,
+}
+
+impl RaplUnits {
+    /// Decode the raw value of `MSR_RAPL_POWER_UNIT`.
+    pub fn from_raw(raw: u64) -> RaplUnits {
+        let energy = raw.get_bits(8..=12) as u8;
+        RaplUnits {
+            power: raw.get_bits(0..=3) as u8,
+            energy,
+            time: raw.get_bits(16..=19) as u8,
+            dram_energy: energy,
+        }
+    }
+
+    /// Use a different energy unit exponent for the DRAM domain
+    /// (see [`DRAM_FIXED_ENERGY_UNIT`]).
+    pub fn with_dram_energy_unit(mut self, exponent: u8) -> RaplUnits {
+        self.dram_energy = exponent;
+        self
+    }
+
+    /// Energy unit exponent used for counters of the given `domain`.
+    pub fn energy_unit(&self, domain: RaplDomain) -> u8 {
+        match domain {
+            RaplDomain::Dram => self.dram_energy,
+            _ => self.energy,
+        }
+    }
+
+    /// Convert `raw` energy units of `domain` to micro-joules.
+    pub fn energy_microjoules(&self, domain: RaplDomain, raw: u64) -> u64 {
+        scale(raw, 1_000_000, self.energy_unit(domain))
+    }
+
+    /// Convert `raw` energy units of `domain` to joules.
+    pub fn energy_joules(&self, domain: RaplDomain, raw: u64) -> f64 {
+        raw as f64 / (1u64 << self.energy_unit(domain)) as f64
+    }
+
+    /// Convert `raw` power units to milli-watts.
+    pub fn power_milliwatts(&self, raw: u64) -> u64 {
+        scale(raw, 1_000, self.power)
+    }
+
+    /// Convert `raw` power units to watts.
+    pub fn power_watts(&self, raw: u64) -> f64 {
+        raw as f64 / (1u64 << self.power) as f64
+    }
+
+    /// Convert milli-watts to power units (as used by the power limit fields),
+    /// saturating at the largest value that fits in a 15-bit limit field.
+    pub fn milliwatts_to_power(&self, milliwatts: u64) -> u16 {
+        let raw = ((milliwatts as u128) << self.power) / 1_000;
+        core::cmp::min(raw, PowerLimit::MAX_POWER as u128) as u16
+    }
+
+    /// Convert `raw` time units to micro-seconds.
+    pub fn time_microseconds(&self, raw: u64) -> u64 {
+        scale(raw, 1_000_000, self.time)
+    }
+
+    /// Length of an encoded power limit time window in micro-seconds.
+    ///
+    /// The window is `2^Y * (1 + Z / 4)` time units where `Y` is stored in
+    /// bits 4:0 and `Z` in bits 6:5 of `window`.
+    pub fn time_window_microseconds(&self, window: u8) -> u64 {
+        let y = window.get_bits(0..=4) as u32;
+        let z = window.get_bits(5..=6) as u64;
+        // 2^Y * (4 + Z) / 4 time units, kept in integers to avoid rounding.
+        let quarter_units = (4 + z) as u128 * (1u128 << y);
+        ((quarter_units * 1_000_000) >> (self.time as u32 + 2)) as u64
+    }
+}
+
+/// Compute `raw * factor / 2^exponent` without intermediate overflow.
+fn scale(raw: u64, factor: u64, exponent: u8) -> u64 {
+    ((raw as u128 * factor as u128) >> exponent) as u64
+}
+
+/// Read and decode `MSR_RAPL_POWER_UNIT`.
+///
+/// # Safety
+/// Needs CPL 0 and a processor that supports RAPL.
+pub unsafe fn units() -> RaplUnits {
+    RaplUnits::from_raw(rdmsr(MSR_RAPL_POWER_UNIT))
+}
+
+/// Read the raw (32-bit, wrapping) energy counter of `domain`.
+///
+/// # Safety
+/// Needs CPL 0 and a processor that supports the given RAPL domain.
+pub unsafe fn energy_status(domain: RaplDomain) -> u32 {
+    rdmsr(domain.energy_status_msr()) as u32
+}
+
+/// Number of energy units that elapsed between two readings of a 32-bit
+/// energy status counter.
+///
+/// The counters wrap around, at high power consumption in about a minute, so
+/// this assumes the counter wrapped at most once between `earlier` and `later`.
+pub fn energy_delta(earlier: u32, later: u32) -> u32 {
+    later.wrapping_sub(earlier)
+}
+
+/// Extends a wrapping 32-bit energy status counter to a 64-bit total.
+///
+/// The counter needs to be sampled (with [`EnergyCounter::update`]) often
+/// enough that it wraps at most once in between two updates.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EnergyCounter {
+    domain: RaplDomain,
+    last: u32,
+    total: u64,
+}
+
+impl EnergyCounter {
+    /// Start accumulating from an initial counter reading `initial`.
+    pub const fn new(domain: RaplDomain, initial: u32) -> EnergyCounter {
+        EnergyCounter {
+            domain,
+            last: initial,
+            total: 0,
+        }
+    }
+
+    /// The domain this counter accumulates.
+    pub fn domain(&self) -> RaplDomain {
+        self.domain
+    }
+
+    /// Feed a new counter reading, returns the energy units consumed since
+    /// the last update.
+    pub fn update(&mut self, sample: u32) -> u32 {
+        let delta = energy_delta(self.last, sample);
+        self.last = sample;
+        self.total += delta as u64;
+        delta
+    }
+
+    /// Read the domain's energy status MSR and feed it to [`EnergyCounter::update`].
+    ///
+    /// # Safety
+    /// Needs CPL 0 and a processor that supports the given RAPL domain.
+    pub unsafe fn sample(&mut self) -> u32 {
+        self.update(energy_status(self.domain))
+    }
+
+    /// Total energy units accumulated so far.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Total energy accumulated so far in micro-joules.
+    pub fn total_microjoules(&self, units: &RaplUnits) -> u64 {
+        units.energy_microjoules(self.domain, self.total)
+    }
+
+    /// Total energy accumulated so far in joules.
+    pub fn total_joules(&self, units: &RaplUnits) -> f64 {
+        units.energy_joules(self.domain, self.total)
+    }
+}
+
+/// A single power limit as encoded in the `*_POWER_LIMIT` MSRs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct PowerLimit {
+    /// Power limit in power units (15 bits).
+    pub power: u16,
+    /// Limit is enabled.
+    pub enabled: bool,
+    /// Allow going below the OS-requested P/T state to meet the limit.
+    pub clamp: bool,
+    /// Encoded time window (7 bits), see [`RaplUnits::time_window_microseconds`].
+    pub time_window: u8,
+}
+
+impl PowerLimit {
+    /// Largest value of the power limit field.
+    pub const MAX_POWER: u16 = 0x7fff;
+
+    /// Decode a power limit from the lower 24 bits of `raw`.
+    pub fn from_raw(raw: u32) -> PowerLimit {
+        PowerLimit {
+            power: raw.get_bits(0..=14) as u16,
+            enabled: raw.get_bit(15),
+            clamp: raw.get_bit(16),
+            time_window: raw.get_bits(17..=23) as u8,
+        }
+    }
+
+    /// Encode the power limit (occupies the lower 24 bits).
+    pub fn to_raw(&self) -> u32 {
+        let mut raw = 0u32;
+        raw.set_bits(0..=14, (self.power & PowerLimit::MAX_POWER) as u32);
+        raw.set_bit(15, self.enabled);
+        raw.set_bit(16, self.clamp);
+        raw.set_bits(17..=23, (self.time_window & 0x7f) as u32);
+        raw
+    }
+}
+
+/// Decoded content of a `*_POWER_LIMIT` MSR.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RaplPowerLimit {
+    /// Power limit #1.
+    pub limit1: PowerLimit,
+    /// Power limit #2 (only present for the package domain).
+    pub limit2: Option<PowerLimit>,
+    /// The register is locked until the next reset.
+    pub locked: bool,
+}
+
+impl RaplPowerLimit {
+    /// Decode the raw value of the power limit MSR of `domain`.
+    pub fn from_raw(domain: RaplDomain, raw: u64) -> RaplPowerLimit {
+        if domain.has_second_limit() {
+            RaplPowerLimit {
+                limit1: PowerLimit::from_raw(raw as u32),
+                limit2: Some(PowerLimit::from_raw((raw >> 32) as u32)),
+                locked: raw.get_bit(63),
+            }
+        } else {
+            RaplPowerLimit {
+                limit1: PowerLimit::from_raw(raw as u32),
+                limit2: None,
+                locked: raw.get_bit(31),
+            }
+        }
+    }
+
+    /// Encode the power limit for the MSR of `domain`.
+    pub fn to_raw(&self, domain: RaplDomain) -> u64 {
+        let mut raw = self.limit1.to_raw() as u64;
+        if domain.has_second_limit() {
+            if let Some(limit2) = self.limit2 {
+                raw |= (limit2.to_raw() as u64) << 32;
+            }
+            raw.set_bit(63, self.locked);
+        } else {
+            raw.set_bit(31, self.locked);
+        }
+        raw
+    }
+}
+
+/// Read the power limit of `domain`.
+///
+/// # Safety
+/// Needs CPL 0 and a processor that supports the given RAPL domain.
+pub unsafe fn power_limit(domain: RaplDomain) -> RaplPowerLimit {
+    RaplPowerLimit::from_raw(domain, rdmsr(domain.power_limit_msr()))
+}
+
+/// Program the power limit of `domain`.
+///
+/// # Safety
+/// Needs CPL 0 and a processor that supports the given RAPL domain.
+/// Causes a #GP if the register is locked.
+pub unsafe fn set_power_limit(domain: RaplDomain, limit: RaplPowerLimit) {
+    wrmsr(domain.power_limit_msr(), limit.to_raw(domain))
+}
+
+/// Decoded content of `MSR_PKG_POWER_INFO` or `MSR_DRAM_POWER_INFO`.
+///
+/// All power values are in power units, the time window in time units.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PowerInfo {
+    /// Thermal specification power (TDP) of the domain.
+    pub thermal_spec_power: u16,
+    /// Minimal power setting allowed for the domain.
+    pub minimum_power: u16,
+    /// Maximal power setting allowed for the domain.
+    pub maximum_power: u16,
+    /// Maximal time window that can be programmed.
+    pub maximum_time_window: u8,
+}
+
+impl PowerInfo {
+    /// Decode the raw value of a power info MSR.
+    pub fn from_raw(raw: u64) -> PowerInfo {
+        PowerInfo {
+            thermal_spec_power: raw.get_bits(0..=14) as u16,
+            minimum_power: raw.get_bits(16..=30) as u16,
+            maximum_power: raw.get_bits(32..=46) as u16,
+            maximum_time_window: raw.get_bits(48..=53) as u8,
+        }
+    }
+}
+
+/// Read the power info of `domain`.
+///
+/// Returns `None` for domains without a power info MSR (see
+/// [`RaplDomain::power_info_msr`]).
+///
+/// # Safety
+/// Needs CPL 0 and a processor that supports the given RAPL domain.
+pub unsafe fn power_info(domain: RaplDomain) -> Option<PowerInfo> {
+    domain
+        .power_info_msr()
+        .map(|msr| PowerInfo::from_raw(rdmsr(msr)))
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_units() {
+        // Typical client value: 1/8 W, 1/16384 J (61 uJ), 1/1024 s.
+        let units = RaplUnits::from_raw(0xa0e03);
+        assert_eq!(units.power, 3);
+        assert_eq!(units.energy, 14);
+        assert_eq!(units.time, 10);
+        assert_eq!(units.dram_energy, 14);
+
+        assert_eq!(units.power_milliwatts(8), 1000);
+        assert_eq!(units.power_milliwatts(1), 125);
+        assert_eq!(units.power_watts(12), 1.5);
+        assert_eq!(units.milliwatts_to_power(15_000), 120);
+        assert_eq!(units.milliwatts_to_power(u64::MAX), PowerLimit::MAX_POWER);
+
+        assert_eq!(
+            units.energy_microjoules(RaplDomain::Package, 16384),
+            1_000_000
+        );
+        assert_eq!(units.energy_microjoules(RaplDomain::Package, 1), 61);
+        assert_eq!(units.energy_joules(RaplDomain::Core, 8192), 0.5);
+
+        let server = units.with_dram_energy_unit(DRAM_FIXED_ENERGY_UNIT);
+        assert_eq!(
+            server.energy_microjoules(RaplDomain::Dram, 1 << 16),
+            1_000_000
+        );
+        assert_eq!(server.energy_microjoules(RaplDomain::Dram, 1), 15);
+        assert_eq!(server.energy_microjoules(RaplDomain::Package, 1), 61);
+
+        assert_eq!(units.time_microseconds(1024), 1_000_000);
+    }
+
+    #[test]
+    fn time_window() {
+        let units = RaplUnits::from_raw(0xa0e03);
+        // Y = 0, Z = 0: one time unit.
+        assert_eq!(units.time_window_microseconds(0), 976);
+        // Y = 10, Z = 0: 1024 time units = 1 s.
+        assert_eq!(units.time_window_microseconds(10), 1_000_000);
+        // Y = 10, Z = 2: 1.5 s.
+        assert_eq!(units.time_window_microseconds(2 << 5 | 10), 1_500_000);
+        // Y = 31, Z = 3 must not overflow.
+        assert_eq!(
+            units.time_window_microseconds(0x7f),
+            ((7u128 << 31) * 1_000_000 >> 12) as u64
+        );
+    }
+
+    #[test]
+    fn energy_wraparound() {
+        assert_eq!(energy_delta(10, 30), 20);
+        assert_eq!(energy_delta(u32::MAX - 4, 5), 10);
+        assert_eq!(energy_delta(7, 7), 0);
+
+        let mut counter = EnergyCounter::new(RaplDomain::Package, u32::MAX - 99);
+        assert_eq!(counter.update(u32::MAX), 99);
+        assert_eq!(counter.update(100), 101);
+        assert_eq!(counter.update(u32::MAX - 99), u32::MAX - 199);
+        assert_eq!(counter.total(), 99 + 101 + (u32::MAX - 199) as u64);
+
+        let units = RaplUnits::from_raw(0xa0e03);
+        let mut counter = EnergyCounter::new(RaplDomain::Core, 0);
+        counter.update(1 << 31);
+        counter.update(0);
+        counter.update(1 << 31);
+        assert_eq!(counter.total(), 3 << 31);
+        assert_eq!(counter.total_joules(&units), (3u64 << 31) as f64 / 16384.0);
+        assert_eq!(
+            counter.total_microjoules(&units),
+            (3u64 << 31) * 1_000_000 / 16384
+        );
+    }
+
+    #[test]
+    fn power_limit() {
+        // PL1 = 15 W (120 * 1/8 W), enabled, clamped, 28 s window;
+        // PL2 = 25 W, enabled, 2.44 ms window; locked.
+        let raw: u64 = 0x8042_80c8_00dd_8078;
+        let limit = RaplPowerLimit::from_raw(RaplDomain::Package, raw);
+        assert_eq!(
+            limit.limit1,
+            PowerLimit {
+                power: 120,
+                enabled: true,
+                clamp: true,
+                time_window: 0x6e,
+            }
+        );
+        assert_eq!(
+            limit.limit2,
+            Some(PowerLimit {
+                power: 200,
+                enabled: true,
+                clamp: false,
+                time_window: 0x21,
+            })
+        );
+        assert!(limit.locked);
+        assert_eq!(limit.to_raw(RaplDomain::Package), raw);
+
+        let units = RaplUnits::from_raw(0xa0e03);
+        assert_eq!(units.time_window_microseconds(0x6e), 28_000_000);
+
+        let limit = RaplPowerLimit::from_raw(RaplDomain::Dram, raw);
+        assert_eq!(limit.limit2, None);
+        assert!(!limit.locked);
+        assert_eq!(limit.to_raw(RaplDomain::Dram), raw & 0xffff_ffff);
+    }
+
+    #[test]
+    fn power_info() {
+        let info = PowerInfo::from_raw(0x0012_0190_0060_0078);
+        assert_eq!(info.thermal_spec_power, 120);
+        assert_eq!(info.minimum_power, 96);
+        assert_eq!(info.maximum_power, 400);
+        assert_eq!(info.maximum_time_window, 0x12);
+
+        assert_eq!(RaplDomain::Package.power_info_msr(), Some(0x614));
+        assert_eq!(RaplDomain::Dram.power_info_msr(), Some(0x61c));
+        assert_eq!(RaplDomain::Core.power_info_msr(), None);
+        assert_eq!(RaplDomain::Uncore.power_info_msr(), None);
+    }
+}
@@ -0,0 +1,584 @@
+//! Intel Resource Director Technology (RDT).
+//!
+//! * Allocation: cache allocation (CAT, with code and data prioritization
+//!   CDP) and memory bandwidth allocation (MBA) limit the resources a class
+//!   of service (CLOS) may use.
+//! * Monitoring: cache monitoring (CMT) and memory bandwidth monitoring (MBM)
+//!   count the resources used by a resource monitoring ID (RMID).
+//!
+//! A logical processor is associated with a CLOS and an RMID through
+//! `IA32_PQR_ASSOC`, which is usually updated on context switch.
+//!
+//! See Intel Vol. 3b Section 18.18, "Intel Resource Director Technology
+//! (Intel RDT) Monitoring Features" and Section 18.19, "Intel Resource
+//! Director Technology (Intel RDT) Allocation Features".
+
+use bit_field::BitField;
+
+use crate::cpuid::CpuId;
+use crate::msr::{rdmsr, wrmsr, IA32_PQR_ASSOC, IA32_QM_CTR, IA32_QM_EVTSEL};
+
+/// L3 QoS configuration (bit 0 enables L3 CDP).
+pub const IA32_L3_QOS_CFG: u32 = 0xc81;
+/// L2 QoS configuration (bit 0 enables L2 CDP).
+pub const IA32_L2_QOS_CFG: u32 = 0xc82;
+/// First L3 capacity bitmask MSR (`IA32_L3_QOS_MASK_0`).
+pub const IA32_L3_QOS_MASK_0: u32 = 0xc90;
+/// First L2 capacity bitmask MSR (`IA32_L2_QOS_MASK_0`).
+pub const IA32_L2_QOS_MASK_0: u32 = 0xd10;
+/// First MBA delay MSR (`IA32_L2_QOS_EXT_BW_THRTL_0`).
+pub const IA32_L2_QOS_EXT_BW_THRTL_0: u32 = 0xd50;
+
+/// Errors reported by RDT configuration and monitoring.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RdtError {
+    /// The capacity bitmask has no bits set.
+    EmptyMask,
+    /// The set bits of the capacity bitmask are not contiguous.
+    NonContiguousMask,
+    /// The capacity bitmask has fewer bits set than required.
+    MaskTooShort,
+    /// The capacity bitmask has bits set beyond the supported length.
+    MaskOutOfRange,
+    /// The MBA delay value is larger than supported or not a multiple of
+    /// the granularity.
+    InvalidDelay,
+    /// The monitoring counter reported an error (e.g., invalid RMID or event).
+    CounterError,
+    /// Monitoring data is not available for this RMID (yet).
+    CounterUnavailable,
+    /// The RMID is larger than the highest supported one.
+    InvalidRmid,
+}
+
+/// Cache allocation capabilities of a cache level.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CatCapabilities {
+    /// Number of bits in a capacity bitmask.
+    pub mask_length: u8,
+    /// Minimum number of bits that have to be set in a capacity bitmask.
+    ///
+    /// This is not enumerated by CPUID and defaults to 1; some processors
+    /// (e.g., Haswell server) require 2.
+    pub min_bits: u8,
+    /// Bitmap of allocation units shared with other entities (e.g., I/O).
+    pub shareable_bits: u32,
+    /// Highest supported CLOS.
+    pub highest_clos: u16,
+    /// Code and data prioritization is supported (only enumerated for L3).
+    pub cdp: bool,
+}
+
+impl CatCapabilities {
+    /// Check that `mask` is a valid capacity bitmask.
+    ///
+    /// A valid mask is non-empty, contiguous, at least `min_bits` wide and
+    /// does not exceed `mask_length`.
+    pub fn mask(&self, mask: u32) -> Result<CapacityMask, RdtError> {
+        if mask == 0 {
+            return Err(RdtError::EmptyMask);
+        }
+        if self.mask_length < 32 && mask >> self.mask_length != 0 {
+            return Err(RdtError::MaskOutOfRange);
+        }
+        // Shifting out the trailing zeros has to leave a value of the form 2^n - 1.
+        let normalized = mask >> mask.trailing_zeros();
+        if normalized & normalized.wrapping_add(1) != 0 {
+            return Err(RdtError::NonContiguousMask);
+        }
+        if mask.count_ones() < self.min_bits as u32 {
+            return Err(RdtError::MaskTooShort);
+        }
+        Ok(CapacityMask(mask))
+    }
+
+    /// The capacity bitmask granting the whole cache.
+    pub fn full_mask(&self) -> CapacityMask {
+        CapacityMask(((1u64 << self.mask_length) - 1) as u32)
+    }
+}
+
+/// A validated capacity bitmask (see [`CatCapabilities::mask`]).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CapacityMask(u32);
+
+impl CapacityMask {
+    /// The raw bitmask.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Memory bandwidth allocation capabilities.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MbaCapabilities {
+    /// Largest supported delay value.
+    pub max_delay: u16,
+    /// Delay values are linear (in percent of bandwidth).
+    pub linear: bool,
+    /// Highest supported CLOS.
+    pub highest_clos: u16,
+}
+
+impl MbaCapabilities {
+    /// Granularity of linear delay values.
+    pub fn granularity(&self) -> u16 {
+        if self.linear && self.max_delay < 100 {
+            100 - self.max_delay
+        } else {
+            1
+        }
+    }
+
+    /// Check that `delay` is a valid delay value (0 means no throttling).
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn delay(&self, delay: u16) -> Result<MbaDelay, RdtError> {
+        if delay > self.max_delay || delay % self.granularity() != 0 {
+            return Err(RdtError::InvalidDelay);
+        }
+        Ok(MbaDelay(delay))
+    }
+}
+
+/// A validated MBA delay value (see [`MbaCapabilities::delay`]).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MbaDelay(u16);
+
+impl MbaDelay {
+    /// The raw delay value.
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+/// Cache and memory bandwidth monitoring capabilities.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MonitoringCapabilities {
+    /// Highest supported RMID.
+    pub max_rmid: u32,
+    /// Factor to convert `IA32_QM_CTR` values to bytes.
+    pub upscaling_factor: u32,
+    /// L3 occupancy monitoring is supported.
+    pub occupancy: bool,
+    /// Total memory bandwidth monitoring is supported.
+    pub total_bandwidth: bool,
+    /// Local memory bandwidth monitoring is supported.
+    pub local_bandwidth: bool,
+}
+
+impl MonitoringCapabilities {
+    /// Check that `rmid` is a valid resource monitoring ID.
+    pub fn rmid(&self, rmid: u32) -> Result<Rmid, RdtError> {
+        if rmid > self.max_rmid || rmid >= 1 << 10 {
+            return Err(RdtError::InvalidRmid);
+        }
+        Ok(Rmid(rmid as u16))
+    }
+}
+
+/// A validated resource monitoring ID (see [`MonitoringCapabilities::rmid`]).
+///
+/// The default is RMID 0, which is always valid.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Rmid(u16);
+
+impl Rmid {
+    /// The raw RMID.
+    pub fn value(&self) -> u32 {
+        self.0 as u32
+    }
+}
+
+/// RDT capabilities (CPUID leaves 0xF and 0x10).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RdtCapabilities {
+    /// L3 cache allocation.
+    pub l3_cat: Option<CatCapabilities>,
+    /// L2 cache allocation.
+    pub l2_cat: Option<CatCapabilities>,
+    /// Memory bandwidth allocation.
+    pub mba: Option<MbaCapabilities>,
+    /// L3 cache and memory bandwidth monitoring.
+    pub monitoring: Option<MonitoringCapabilities>,
+}
+
+impl RdtCapabilities {
+    /// Read the capabilities from `cpuid`.
+    pub fn from_cpuid(cpuid: &CpuId) -> RdtCapabilities {
+        let mut caps = RdtCapabilities {
+            l3_cat: None,
+            l2_cat: None,
+            mba: None,
+            monitoring: None,
+        };
+        let features = match cpuid.get_extended_feature_info() {
+            Some(features) => features,
+            None => return caps,
+        };
+
+        if features.has_rdta() {
+            if let Some(alloc) = cpuid.get_rdt_allocation_info() {
+                caps.l3_cat = alloc.l3_cat().map(|l3| CatCapabilities {
+                    mask_length: l3.capacity_mask_length(),
+                    min_bits: 1,
+                    shareable_bits: l3.isolation_bitmap(),
+                    highest_clos: l3.highest_cos(),
+                    cdp: l3.has_code_data_prioritization(),
+                });
+                caps.l2_cat = alloc.l2_cat().map(|l2| CatCapabilities {
+                    mask_length: l2.capacity_mask_length(),
+                    min_bits: 1,
+                    shareable_bits: l2.isolation_bitmap(),
+                    highest_clos: l2.highest_cos(),
+                    cdp: false,
+                });
+                caps.mba = alloc
+                    .memory_bandwidth_allocation()
+                    .map(|mba| MbaCapabilities {
+                        max_delay: mba.max_hba_throttling(),
+                        linear: mba.has_linear_response_delay(),
+                        highest_clos: mba.highest_cos(),
+                    });
+            }
+        }
+
+        if features.has_rdtm() {
+            caps.monitoring = cpuid
+                .get_rdt_monitoring_info()
+                .and_then(|mon| mon.l3_monitoring())
+                .map(|l3| MonitoringCapabilities {
+                    max_rmid: l3.maximum_rmid_range(),
+                    upscaling_factor: l3.conversion_factor(),
+                    occupancy: l3.has_occupancy_monitoring(),
+                    total_bandwidth: l3.has_total_bandwidth_monitoring(),
+                    local_bandwidth: l3.has_local_bandwidth_monitoring(),
+                });
+        }
+
+        caps
+    }
+
+    /// Read the capabilities of the current processor.
+    pub fn probe() -> RdtCapabilities {
+        RdtCapabilities::from_cpuid(&CpuId::new())
+    }
+}
+
+/// Set the L3 capacity bitmask of `clos`.
+///
+/// With CDP enabled use [`l3_cdp_mask_write`] instead.
+///
+/// # Safety
+/// Needs CPL 0, L3 CAT support and `clos` must not exceed
+/// [`CatCapabilities::highest_clos`].
+pub unsafe fn l3_mask_write(clos: u16, mask: CapacityMask) {
+    wrmsr(IA32_L3_QOS_MASK_0 + clos as u32, mask.0 as u64);
+}
+
+/// Read the L3 capacity bitmask of `clos`.
+///
+/// # Safety
+/// Needs CPL 0, L3 CAT support and `clos` must not exceed
+/// [`CatCapabilities::highest_clos`].
+pub unsafe fn l3_mask(clos: u16) -> u32 {
+    rdmsr(IA32_L3_QOS_MASK_0 + clos as u32) as u32
+}
+
+/// Set the L3 data and code capacity bitmasks of `clos` while CDP is enabled.
+///
+/// # Safety
+/// Needs CPL 0, L3 CDP enabled and `clos` must not exceed half of
+/// [`CatCapabilities::highest_clos`].
+pub unsafe fn l3_cdp_mask_write(clos: u16, data: CapacityMask, code: CapacityMask) {
+    wrmsr(IA32_L3_QOS_MASK_0 + 2 * clos as u32, data.0 as u64);
+    wrmsr(IA32_L3_QOS_MASK_0 + 2 * clos as u32 + 1, code.0 as u64);
+}
+
+/// Enable or disable L3 code and data prioritization.
+///
+/// # Safety
+/// Needs CPL 0 and L3 CDP support. Should only be changed while no
+/// processor uses CLOS other than 0.
+pub unsafe fn l3_cdp_enable(enable: bool) {
+    let mut cfg = rdmsr(IA32_L3_QOS_CFG);
+    cfg.set_bit(0, enable);
+    wrmsr(IA32_L3_QOS_CFG, cfg);
+}
+
+/// Set the L2 capacity bitmask of `clos`.
+///
+/// # Safety
+/// Needs CPL 0, L2 CAT support and `clos` must not exceed
+/// [`CatCapabilities::highest_clos`].
+pub unsafe fn l2_mask_write(clos: u16, mask: CapacityMask) {
+    wrmsr(IA32_L2_QOS_MASK_0 + clos as u32, mask.0 as u64);
+}
+
+/// Read the L2 capacity bitmask of `clos`.
+///
+/// # Safety
+/// Needs CPL 0, L2 CAT support and `clos` must not exceed
+/// [`CatCapabilities::highest_clos`].
+pub unsafe fn l2_mask(clos: u16) -> u32 {
+    rdmsr(IA32_L2_QOS_MASK_0 + clos as u32) as u32
+}
+
+/// Set the memory bandwidth delay of `clos`.
+///
+/// # Safety
+/// Needs CPL 0, MBA support and `clos` must not exceed
+/// [`MbaCapabilities::highest_clos`].
+pub unsafe fn mba_delay_write(clos: u16, delay: MbaDelay) {
+    wrmsr(IA32_L2_QOS_EXT_BW_THRTL_0 + clos as u32, delay.0 as u64);
+}
+
+/// The CLOS and RMID a logical processor is associated with (`IA32_PQR_ASSOC`).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PqrAssoc {
+    /// Resource monitoring ID (bits 9:0).
+    pub rmid: Rmid,
+    /// Class of service (bits 63:32).
+    pub clos: u32,
+}
+
+impl PqrAssoc {
+    /// Decode a raw `IA32_PQR_ASSOC` value.
+    pub fn from_raw(raw: u64) -> PqrAssoc {
+        PqrAssoc {
+            rmid: Rmid(raw.get_bits(0..=9) as u16),
+            clos: raw.get_bits(32..=63) as u32,
+        }
+    }
+
+    /// Encode as raw `IA32_PQR_ASSOC` value.
+    pub fn to_raw(&self) -> u64 {
+        (self.clos as u64) << 32 | self.rmid.0 as u64
+    }
+}
+
+/// Read the current CLOS and RMID association.
+///
+/// # Safety
+/// Needs CPL 0 and RDT support.
+pub unsafe fn pqr_assoc() -> PqrAssoc {
+    PqrAssoc::from_raw(rdmsr(IA32_PQR_ASSOC))
+}
+
+/// Associate the current logical processor with a CLOS and RMID (e.g., on
+/// context switch).
+///
+/// # Safety
+/// Needs CPL 0, RDT support and both values need to be in range.
+pub unsafe fn pqr_assoc_write(assoc: PqrAssoc) {
+    wrmsr(IA32_PQR_ASSOC, assoc.to_raw());
+}
+
+/// Monitoring event IDs for `IA32_QM_EVTSEL`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MonitoringEvent {
+    /// L3 cache occupancy.
+    L3Occupancy = 1,
+    /// Total memory bandwidth.
+    TotalBandwidth = 2,
+    /// Local memory bandwidth.
+    LocalBandwidth = 3,
+}
+
+/// Encode an `IA32_QM_EVTSEL` value selecting `event` for `rmid`.
+pub fn qm_evtsel(rmid: Rmid, event: MonitoringEvent) -> u64 {
+    (rmid.0 as u64) << 32 | event as u64
+}
+
+/// Decode an `IA32_QM_CTR` value.
+pub fn qm_ctr(raw: u64) -> Result<u64, RdtError> {
+    if raw.get_bit(63) {
+        Err(RdtError::CounterError)
+    } else if raw.get_bit(62) {
+        Err(RdtError::CounterUnavailable)
+    } else {
+        Ok(raw.get_bits(0..=61))
+    }
+}
+
+/// Read the monitoring counter of `event` for `rmid`.
+///
+/// The result has to be multiplied with
+/// [`MonitoringCapabilities::upscaling_factor`] to get bytes. Bandwidth
+/// counters wrap around, see [`mbm_delta`].
+///
+/// # Safety
+/// Needs CPL 0 and RDT monitoring support. The select and read are not
+/// atomic, so this must not race with other users of `IA32_QM_EVTSEL`
+/// on the same logical processor.
+pub unsafe fn read_counter(rmid: Rmid, event: MonitoringEvent) -> Result<u64, RdtError> {
+    wrmsr(IA32_QM_EVTSEL, qm_evtsel(rmid, event));
+    qm_ctr(rdmsr(IA32_QM_CTR))
+}
+
+/// Difference between two readings of a memory bandwidth counter that is
+/// `width` bits wide (24 bits unless enumerated otherwise), accounting for
+/// a single wraparound.
+pub fn mbm_delta(earlier: u64, later: u64, width: u8) -> u64 {
+    let mask = if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+    later.wrapping_sub(earlier) & mask
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use crate::cpuid::CpuIdResult;
+
+    fn l3() -> CatCapabilities {
+        CatCapabilities {
+            mask_length: 11,
+            min_bits: 2,
+            shareable_bits: 0x600,
+            highest_clos: 15,
+            cdp: true,
+        }
+    }
+
+    #[test]
+    fn capacity_masks() {
+        let caps = l3();
+        assert_eq!(caps.mask(0x7ff).map(|m| m.bits()), Ok(0x7ff));
+        assert_eq!(caps.mask(0x00c).map(|m| m.bits()), Ok(0x00c));
+        assert_eq!(caps.mask(0x600).map(|m| m.bits()), Ok(0x600));
+        assert_eq!(caps.mask(0), Err(RdtError::EmptyMask));
+        assert_eq!(caps.mask(0x101), Err(RdtError::NonContiguousMask));
+        assert_eq!(caps.mask(0x0f0f), Err(RdtError::MaskOutOfRange));
+        assert_eq!(caps.mask(0x0f6), Err(RdtError::NonContiguousMask));
+        assert_eq!(caps.mask(0x010), Err(RdtError::MaskTooShort));
+        assert_eq!(caps.mask(0x800), Err(RdtError::MaskOutOfRange));
+        assert_eq!(caps.full_mask().bits(), 0x7ff);
+
+        let wide = CatCapabilities {
+            mask_length: 32,
+            min_bits: 1,
+            ..l3()
+        };
+        assert_eq!(wide.mask(u32::MAX).map(|m| m.bits()), Ok(u32::MAX));
+        assert_eq!(wide.mask(0x8000_0000).map(|m| m.bits()), Ok(0x8000_0000));
+        assert_eq!(wide.full_mask().bits(), u32::MAX);
+    }
+
+    #[test]
+    fn mba_delays() {
+        let linear = MbaCapabilities {
+            max_delay: 90,
+            linear: true,
+            highest_clos: 7,
+        };
+        assert_eq!(linear.granularity(), 10);
+        assert_eq!(linear.delay(0).map(|d| d.value()), Ok(0));
+        assert_eq!(linear.delay(30).map(|d| d.value()), Ok(30));
+        assert_eq!(linear.delay(35), Err(RdtError::InvalidDelay));
+        assert_eq!(linear.delay(100), Err(RdtError::InvalidDelay));
+
+        let non_linear = MbaCapabilities {
+            linear: false,
+            ..linear
+        };
+        assert_eq!(non_linear.delay(35).map(|d| d.value()), Ok(35));
+    }
+
+    #[test]
+    fn association_and_counters() {
+        let monitoring = MonitoringCapabilities {
+            max_rmid: 0x3ff,
+            upscaling_factor: 1,
+            occupancy: true,
+            total_bandwidth: true,
+            local_bandwidth: true,
+        };
+        let assoc = PqrAssoc {
+            rmid: monitoring.rmid(5).unwrap(),
+            clos: 3,
+        };
+        assert_eq!(assoc.to_raw(), 0x3_0000_0005);
+        assert_eq!(PqrAssoc::from_raw(0x3_0000_0005), assoc);
+        assert_eq!(PqrAssoc::default().to_raw(), 0);
+
+        assert_eq!(
+            qm_evtsel(Rmid(5), MonitoringEvent::L3Occupancy),
+            0x5_0000_0001
+        );
+        assert_eq!(
+            qm_evtsel(
+                monitoring.rmid(0x3ff).unwrap(),
+                MonitoringEvent::LocalBandwidth
+            ),
+            0x3ff_0000_0003
+        );
+        assert_eq!(monitoring.rmid(0x400), Err(RdtError::InvalidRmid));
+        let small = MonitoringCapabilities {
+            max_rmid: 0xdf,
+            ..monitoring
+        };
+        assert_eq!(small.rmid(0xdf).map(|r| r.value()), Ok(0xdf));
+        assert_eq!(small.rmid(0xe0), Err(RdtError::InvalidRmid));
+
+        assert_eq!(qm_ctr(0x1234), Ok(0x1234));
+        assert_eq!(qm_ctr(1 << 63 | 0x1234), Err(RdtError::CounterError));
+        assert_eq!(qm_ctr(1 << 62), Err(RdtError::CounterUnavailable));
+        assert_eq!(qm_ctr(3 << 62), Err(RdtError::CounterError));
+
+        assert_eq!(mbm_delta(100, 250, 24), 150);
+        assert_eq!(mbm_delta(0xff_fff0, 0x10, 24), 0x20);
+    }
+
+    fn cpuid_rdt(eax: u32, ecx: u32) -> CpuIdResult {
+        let (eax, ebx, ecx, edx) = match (eax, ecx) {
+            // "GenuineIntel", max. leaf 0x10
+            (0x0, _) => (0x10, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            // RDT-M and RDT-A
+            (0x7, 0) => (0, 1 << 12 | 1 << 15, 0, 0),
+            (0xf, 0) => (0, 0xdf, 0, 0b10),
+            (0xf, 1) => (0, 0x1_0000, 0xdf, 0b111),
+            (0x10, 0) => (0, 0b1010, 0, 0),
+            (0x10, 1) => (0xa, 0x600, 0b100, 0xf),
+            (0x10, 3) => (0x59, 0, 0b100, 0x7),
+            _ => (0, 0, 0, 0),
+        };
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    #[test]
+    fn capabilities() {
+        let caps = RdtCapabilities::from_cpuid(&CpuId::with_cpuid_fn(cpuid_rdt));
+        assert_eq!(
+            caps.l3_cat,
+            Some(CatCapabilities {
+                mask_length: 11,
+                min_bits: 1,
+                shareable_bits: 0x600,
+                highest_clos: 15,
+                cdp: true,
+            })
+        );
+        assert_eq!(caps.l2_cat, None);
+        assert_eq!(
+            caps.mba,
+            Some(MbaCapabilities {
+                max_delay: 90,
+                linear: true,
+                highest_clos: 7,
+            })
+        );
+        assert_eq!(
+            caps.monitoring,
+            Some(MonitoringCapabilities {
+                max_rmid: 0xdf,
+                upscaling_factor: 0x1_0000,
+                occupancy: true,
+                total_bandwidth: true,
+                local_bandwidth: true,
+            })
+        );
+    }
+}
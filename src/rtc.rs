@@ -0,0 +1,642 @@
+//! CMOS real-time clock (RTC) and NVRAM.
+//!
+//! The CMOS registers are selected by writing their index to port 0x70 and
+//! accessed through port 0x71. Bit 7 of the index port disables NMIs, so the
+//! driver keeps track of it and preserves it on every access.
+//!
+//! The clock registers hold the time in BCD or binary and the hours in 12 or
+//! 24-hour format, depending on status register B. They must not be read while
+//! an update is in progress (status register A).
+//!
+//! See the Motorola MC146818A datasheet.
+
+use bitflags::bitflags;
+
+use crate::io::{NativePortIo, PortIo};
+
+/// CMOS register index port (bit 7 disables NMIs).
+pub const CMOS_ADDRESS: u16 = 0x70;
+/// CMOS register data port.
+pub const CMOS_DATA: u16 = 0x71;
+
+/// Index port: disable NMIs.
+const NMI_DISABLE: u8 = 1 << 7;
+
+/// Seconds (0-59).
+pub const SECONDS: u8 = 0x00;
+/// Seconds alarm.
+pub const SECONDS_ALARM: u8 = 0x01;
+/// Minutes (0-59).
+pub const MINUTES: u8 = 0x02;
+/// Minutes alarm.
+pub const MINUTES_ALARM: u8 = 0x03;
+/// Hours (0-23 or 1-12 with PM in bit 7).
+pub const HOURS: u8 = 0x04;
+/// Hours alarm.
+pub const HOURS_ALARM: u8 = 0x05;
+/// Day of the week (1-7, unreliable).
+pub const WEEKDAY: u8 = 0x06;
+/// Day of the month (1-31).
+pub const DAY_OF_MONTH: u8 = 0x07;
+/// Month (1-12).
+pub const MONTH: u8 = 0x08;
+/// Year in the century (0-99).
+pub const YEAR: u8 = 0x09;
+/// Status register A (update in progress, divider and rate).
+pub const STATUS_A: u8 = 0x0a;
+/// Status register B, see [`StatusB`].
+pub const STATUS_B: u8 = 0x0b;
+/// Status register C, see [`StatusC`].
+pub const STATUS_C: u8 = 0x0c;
+/// Status register D (valid RAM and time).
+pub const STATUS_D: u8 = 0x0d;
+/// Usual location of the century register (the ACPI FADT has the actual one).
+pub const CENTURY: u8 = 0x32;
+
+/// Status A: update in progress.
+const STATUS_A_UIP: u8 = 1 << 7;
+/// Status A: rate selection bits.
+const STATUS_A_RATE: u8 = 0x0f;
+
+/// Hours register: PM flag in 12-hour format.
+const HOURS_PM: u8 = 1 << 7;
+/// Alarm registers: "don't care" value that matches every time.
+const ALARM_ANY: u8 = 0xc0;
+
+bitflags! {
+    /// Status register B.
+    pub struct StatusB: u8 {
+        /// Stop updates (to set the time).
+        const SET = 1 << 7;
+        /// Enable the periodic interrupt.
+        const PERIODIC_INTERRUPT = 1 << 6;
+        /// Enable the alarm interrupt.
+        const ALARM_INTERRUPT = 1 << 5;
+        /// Enable the update-ended interrupt.
+        const UPDATE_INTERRUPT = 1 << 4;
+        /// Enable the square-wave output.
+        const SQUARE_WAVE = 1 << 3;
+        /// Time and date in binary instead of BCD.
+        const BINARY = 1 << 2;
+        /// 24-hour instead of 12-hour format.
+        const HOUR_24 = 1 << 1;
+        /// Daylight saving enable.
+        const DAYLIGHT_SAVING = 1 << 0;
+    }
+}
+
+bitflags! {
+    /// Status register C (cleared by reading it).
+    pub struct StatusC: u8 {
+        /// An enabled interrupt is pending (IRQ 8).
+        const INTERRUPT_REQUEST = 1 << 7;
+        /// Periodic interrupt flag.
+        const PERIODIC = 1 << 6;
+        /// Alarm interrupt flag.
+        const ALARM = 1 << 5;
+        /// Update-ended interrupt flag.
+        const UPDATE_ENDED = 1 << 4;
+    }
+}
+
+/// Convert a BCD byte (e.g., `0x59`) to binary.
+pub const fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0f)
+}
+
+/// Convert a binary value below 100 to BCD.
+pub const fn binary_to_bcd(binary: u8) -> u8 {
+    ((binary / 10) << 4) | (binary % 10)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date (year, month, day) of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// A date and time (UTC, as the RTC is usually set up).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    /// 0-23
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch (1970-01-01 00:00:00).
+    pub fn to_unix_timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// The date and time of a Unix timestamp (years 0 to 65535).
+    pub fn from_unix_timestamp(timestamp: i64) -> DateTime {
+        let days = timestamp.div_euclid(86_400);
+        let seconds = timestamp.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+/// Clock register values as read from the CMOS.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RawTime {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+    /// Century register, if there is one.
+    pub century: Option<u8>,
+}
+
+impl RawTime {
+    /// Decode the register values using the format in `status`.
+    ///
+    /// Without a century register, years 00-69 are 20xx and 70-99 are 19xx.
+    pub fn decode(&self, status: StatusB) -> DateTime {
+        let binary = status.contains(StatusB::BINARY);
+        let convert = |value: u8| {
+            if binary {
+                value
+            } else {
+                bcd_to_binary(value)
+            }
+        };
+
+        let pm = self.hour & HOURS_PM != 0;
+        let mut hour = convert(self.hour & !HOURS_PM);
+        if !status.contains(StatusB::HOUR_24) {
+            // 12 AM is midnight, 12 PM is noon
+            hour = match (hour, pm) {
+                (12, false) => 0,
+                (12, true) => 12,
+                (hour, true) => hour + 12,
+                (hour, false) => hour,
+            };
+        }
+
+        let year = convert(self.year) as u16;
+        let year = match self.century {
+            Some(century) => convert(century) as u16 * 100 + year,
+            None if year < 70 => 2000 + year,
+            None => 1900 + year,
+        };
+
+        DateTime {
+            year,
+            month: convert(self.month),
+            day: convert(self.day),
+            hour,
+            minute: convert(self.minute),
+            second: convert(self.second),
+        }
+    }
+}
+
+/// Encode an hour (0-23) for the hours (or hours alarm) register.
+pub fn encode_hour(hour: u8, status: StatusB) -> u8 {
+    let (hour, pm) = if status.contains(StatusB::HOUR_24) {
+        (hour, false)
+    } else {
+        match hour {
+            0 => (12, false),
+            12 => (12, true),
+            13..=23 => (hour - 12, true),
+            _ => (hour, false),
+        }
+    };
+    let hour = if status.contains(StatusB::BINARY) {
+        hour
+    } else {
+        binary_to_bcd(hour)
+    };
+    if pm {
+        hour | HOURS_PM
+    } else {
+        hour
+    }
+}
+
+/// Driver for the CMOS RTC and NVRAM.
+#[derive(Debug)]
+pub struct Rtc<P: PortIo = NativePortIo> {
+    io: P,
+    nmi_disabled: bool,
+    century: Option<u8>,
+}
+
+impl Rtc {
+    /// Create a driver for the CMOS (NMIs enabled, century at [`CENTURY`]).
+    ///
+    /// # Safety
+    /// Needs IO privileges. There should be only one driver instance, and
+    /// accesses must not be interrupted by other CMOS accesses.
+    pub unsafe fn new() -> Self {
+        Rtc::with_io(NativePortIo::new())
+    }
+}
+
+impl<P: PortIo> Rtc<P> {
+    /// Create a driver doing port I/O through `io`.
+    pub fn with_io(io: P) -> Self {
+        Rtc {
+            io,
+            nmi_disabled: false,
+            century: Some(CENTURY),
+        }
+    }
+
+    /// Use `register` as the century register (`None` if there is none).
+    pub fn set_century_register(&mut self, register: Option<u8>) {
+        self.century = register;
+    }
+
+    /// Are NMIs disabled through the index port?
+    pub fn nmi_disabled(&self) -> bool {
+        self.nmi_disabled
+    }
+
+    /// Disable (`true`) or enable NMIs.
+    pub fn set_nmi_disabled(&mut self, disabled: bool) {
+        self.nmi_disabled = disabled;
+        self.select(STATUS_D);
+    }
+
+    fn select(&mut self, register: u8) {
+        let nmi = if self.nmi_disabled { NMI_DISABLE } else { 0 };
+        self.io
+            .write_u8(CMOS_ADDRESS, (register & !NMI_DISABLE) | nmi);
+    }
+
+    /// Read a CMOS register (0-127).
+    pub fn read(&mut self, register: u8) -> u8 {
+        self.select(register);
+        self.io.read_u8(CMOS_DATA)
+    }
+
+    /// Write a CMOS register (0-127).
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.select(register);
+        self.io.write_u8(CMOS_DATA, value);
+    }
+
+    /// Is the clock updating its registers?
+    pub fn update_in_progress(&mut self) -> bool {
+        self.read(STATUS_A) & STATUS_A_UIP != 0
+    }
+
+    /// Read status register B.
+    pub fn status_b(&mut self) -> StatusB {
+        StatusB::from_bits_truncate(self.read(STATUS_B))
+    }
+
+    /// Write status register B.
+    pub fn set_status_b(&mut self, status: StatusB) {
+        self.write(STATUS_B, status.bits());
+    }
+
+    /// Read (and clear) status register C.
+    ///
+    /// Has to be done in the IRQ 8 handler, or no further interrupts arrive.
+    pub fn acknowledge(&mut self) -> StatusC {
+        StatusC::from_bits_truncate(self.read(STATUS_C))
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        while self.update_in_progress() {
+            core::hint::spin_loop();
+        }
+        let century = self.century.map(|register| self.read(register));
+        RawTime {
+            second: self.read(SECONDS),
+            minute: self.read(MINUTES),
+            hour: self.read(HOURS),
+            day: self.read(DAY_OF_MONTH),
+            month: self.read(MONTH),
+            year: self.read(YEAR),
+            century,
+        }
+    }
+
+    /// Read the clock registers.
+    ///
+    /// Waits for updates to finish and reads until two consecutive reads
+    /// agree.
+    pub fn read_raw_time(&mut self) -> RawTime {
+        let mut time = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == time {
+                return time;
+            }
+            time = again;
+        }
+    }
+
+    /// Read the current date and time.
+    pub fn read_time(&mut self) -> DateTime {
+        let time = self.read_raw_time();
+        time.decode(self.status_b())
+    }
+
+    /// Read the current time as a Unix timestamp.
+    pub fn unix_timestamp(&mut self) -> i64 {
+        self.read_time().to_unix_timestamp()
+    }
+
+    /// Set the clock (in the format of status register B).
+    pub fn set_time(&mut self, time: &DateTime) {
+        let status = self.status_b();
+        let convert = |value: u8| {
+            if status.contains(StatusB::BINARY) {
+                value
+            } else {
+                binary_to_bcd(value)
+            }
+        };
+
+        self.set_status_b(status | StatusB::SET);
+        self.write(SECONDS, convert(time.second));
+        self.write(MINUTES, convert(time.minute));
+        self.write(HOURS, encode_hour(time.hour, status));
+        self.write(DAY_OF_MONTH, convert(time.day));
+        self.write(MONTH, convert(time.month));
+        self.write(YEAR, convert((time.year % 100) as u8));
+        if let Some(century) = self.century {
+            self.write(century, convert((time.year / 100) as u8));
+        }
+        self.set_status_b(status);
+    }
+
+    /// Set the periodic interrupt rate (3-15): 32768 >> (rate - 1) Hz.
+    ///
+    /// A rate of 0 disables the periodic interrupt (1 and 2 are not usable
+    /// with the default 32.768 kHz time base and are rejected).
+    pub fn set_periodic_rate(&mut self, rate: u8) -> bool {
+        if rate > 15 || rate == 1 || rate == 2 {
+            return false;
+        }
+        let a = self.read(STATUS_A);
+        self.write(STATUS_A, (a & !STATUS_A_RATE) | rate);
+        true
+    }
+
+    /// Set the alarm; `None` matches every value of that field.
+    pub fn set_alarm(&mut self, hour: Option<u8>, minute: Option<u8>, second: Option<u8>) {
+        let status = self.status_b();
+        let convert = |value: Option<u8>| match value {
+            Some(value) if status.contains(StatusB::BINARY) => value,
+            Some(value) => binary_to_bcd(value),
+            None => ALARM_ANY,
+        };
+        let hour = match hour {
+            Some(hour) => encode_hour(hour, status),
+            None => ALARM_ANY,
+        };
+        self.write(SECONDS_ALARM, convert(second));
+        self.write(MINUTES_ALARM, convert(minute));
+        self.write(HOURS_ALARM, hour);
+    }
+
+    /// Enable or disable the periodic, alarm and update-ended interrupts
+    /// (other bits in `interrupts` are ignored).
+    pub fn set_interrupts(&mut self, interrupts: StatusB) {
+        let mask =
+            StatusB::PERIODIC_INTERRUPT | StatusB::ALARM_INTERRUPT | StatusB::UPDATE_INTERRUPT;
+        let status = self.status_b();
+        self.set_status_b((status - mask) | (interrupts & mask));
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    #[test]
+    fn bcd() {
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(binary_to_bcd(42), 0x42);
+        for i in 0..100 {
+            assert_eq!(bcd_to_binary(binary_to_bcd(i)), i);
+        }
+    }
+
+    #[test]
+    fn unix_timestamps() {
+        let dates = [
+            ((1970, 1, 1, 0, 0, 0), 0),
+            ((1999, 12, 31, 23, 59, 59), 946_684_799),
+            ((2000, 1, 1, 0, 0, 0), 946_684_800),
+            ((2024, 2, 29, 12, 34, 56), 1_709_210_096),
+            ((2100, 3, 1, 0, 0, 0), 4_107_542_400),
+            ((1969, 12, 31, 23, 59, 59), -1),
+        ];
+        for ((year, month, day, hour, minute, second), timestamp) in dates.iter() {
+            let time = DateTime {
+                year: *year,
+                month: *month,
+                day: *day,
+                hour: *hour,
+                minute: *minute,
+                second: *second,
+            };
+            assert_eq!(time.to_unix_timestamp(), *timestamp);
+            assert_eq!(DateTime::from_unix_timestamp(*timestamp), time);
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let raw = RawTime {
+            second: 0x56,
+            minute: 0x34,
+            hour: 0x80 | 0x12,
+            day: 0x29,
+            month: 0x02,
+            year: 0x24,
+            century: Some(0x20),
+        };
+        let noon = raw.decode(StatusB::empty());
+        assert_eq!(noon.to_unix_timestamp(), 1_709_210_096);
+
+        let midnight = RawTime { hour: 0x12, ..raw }.decode(StatusB::empty());
+        assert_eq!(midnight.hour, 0);
+        let evening = RawTime {
+            hour: 0x80 | 0x11,
+            ..raw
+        }
+        .decode(StatusB::empty());
+        assert_eq!(evening.hour, 23);
+
+        let binary = RawTime {
+            second: 56,
+            minute: 34,
+            hour: 21,
+            day: 31,
+            month: 12,
+            year: 99,
+            century: None,
+        };
+        let time = binary.decode(StatusB::BINARY | StatusB::HOUR_24);
+        assert_eq!(
+            time,
+            DateTime {
+                year: 1999,
+                month: 12,
+                day: 31,
+                hour: 21,
+                minute: 34,
+                second: 56
+            }
+        );
+        let time = RawTime { year: 5, ..binary }.decode(StatusB::BINARY | StatusB::HOUR_24);
+        assert_eq!(time.year, 2005);
+
+        for hour in 0..24 {
+            for status in [StatusB::empty(), StatusB::HOUR_24, StatusB::BINARY].iter() {
+                let raw = RawTime {
+                    hour: encode_hour(hour, *status),
+                    ..raw
+                };
+                assert_eq!(raw.decode(*status).hour, hour);
+            }
+        }
+    }
+
+    /// Emulates the CMOS index and data ports.
+    #[derive(Default)]
+    struct Cmos {
+        index: Vec<u8>,
+        registers: Vec<u8>,
+        /// Reads of status A that report an update in progress.
+        updating: usize,
+    }
+
+    impl PortIo for Cmos {
+        fn read_u8(&mut self, port: u16) -> u8 {
+            assert_eq!(port, CMOS_DATA);
+            let register = (self.index.last().unwrap() & 0x7f) as usize;
+            if register == STATUS_A as usize && self.updating > 0 {
+                self.updating -= 1;
+                return self.registers[register] | STATUS_A_UIP;
+            }
+            self.registers[register]
+        }
+
+        fn write_u8(&mut self, port: u16, val: u8) {
+            match port {
+                CMOS_ADDRESS => self.index.push(val),
+                CMOS_DATA => {
+                    let register = (self.index.last().unwrap() & 0x7f) as usize;
+                    self.registers[register] = val;
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, _port: u16, _val: u16) {
+            unreachable!()
+        }
+
+        fn read_u32(&mut self, _port: u16) -> u32 {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, _port: u16, _val: u32) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn clock_and_interrupts() {
+        let mut cmos = Cmos {
+            registers: std::vec![0; 128],
+            updating: 3,
+            ..Default::default()
+        };
+        cmos.registers[STATUS_A as usize] = 0x26;
+        cmos.registers[STATUS_B as usize] = 0x02;
+        let mut rtc = Rtc::with_io(cmos);
+
+        let time = DateTime::from_unix_timestamp(1_709_210_096);
+        rtc.set_time(&time);
+        assert_eq!(rtc.io.registers[HOURS as usize], 0x12);
+        assert_eq!(rtc.io.registers[CENTURY as usize], 0x20);
+        assert_eq!(rtc.status_b(), StatusB::HOUR_24);
+        assert_eq!(rtc.read_time(), time);
+        assert_eq!(rtc.io.updating, 0);
+        assert!(rtc.io.index.iter().all(|i| i & NMI_DISABLE == 0));
+
+        rtc.set_nmi_disabled(true);
+        assert_eq!(rtc.unix_timestamp(), 1_709_210_096);
+        assert!(rtc.read(YEAR) == 0x24 && rtc.io.index.last() == Some(&0x89));
+
+        assert!(rtc.set_periodic_rate(6));
+        assert_eq!(rtc.io.registers[STATUS_A as usize], 0x26);
+        assert!(rtc.set_periodic_rate(15));
+        assert_eq!(rtc.io.registers[STATUS_A as usize], 0x2f);
+        assert!(!rtc.set_periodic_rate(2));
+        assert!(!rtc.set_periodic_rate(16));
+
+        rtc.set_alarm(Some(23), None, Some(0));
+        assert_eq!(rtc.io.registers[HOURS_ALARM as usize], 0x23);
+        assert_eq!(rtc.io.registers[MINUTES_ALARM as usize], 0xc0);
+        assert_eq!(rtc.io.registers[SECONDS_ALARM as usize], 0x00);
+
+        rtc.set_interrupts(StatusB::ALARM_INTERRUPT | StatusB::BINARY);
+        assert_eq!(rtc.io.registers[STATUS_B as usize], 0x22);
+        rtc.io.registers[STATUS_C as usize] = 0xa0;
+        assert_eq!(
+            rtc.acknowledge(),
+            StatusC::INTERRUPT_REQUEST | StatusC::ALARM
+        );
+    }
+}
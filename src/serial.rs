@@ -0,0 +1,746 @@
+//! Driver for 16550-compatible UARTs (serial ports).
+//!
+//! The registers are accessed through [`PortIo`] at `base + offset`. The
+//! divisor latch (DLL/DLM) shares offsets 0 and 1 with the data and interrupt
+//! enable registers and is selected with the DLAB bit of the line control
+//! register.
+//!
+//! See the National Semiconductor PC16550D datasheet.
+
+use core::fmt;
+
+use bitflags::bitflags;
+
+use crate::io::{NativePortIo, PortIo};
+
+/// Base port of COM1.
+pub const COM1: u16 = 0x3f8;
+/// Base port of COM2.
+pub const COM2: u16 = 0x2f8;
+/// Base port of COM3.
+pub const COM3: u16 = 0x3e8;
+/// Base port of COM4.
+pub const COM4: u16 = 0x2e8;
+
+/// Baud rate for a divisor of 1 (1.8432 MHz input clock / 16).
+pub const MAX_BAUD_RATE: u32 = 115_200;
+
+/// Receiver buffer (read), transmitter holding (write), DLL (DLAB = 1).
+const DATA: u16 = 0;
+/// Interrupt enable, DLM (DLAB = 1).
+const INTERRUPT_ENABLE: u16 = 1;
+/// Interrupt identification (read), FIFO control (write).
+const INTERRUPT_ID_FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+/// LCR: divisor latch access bit.
+const LCR_DLAB: u8 = 1 << 7;
+/// LCR: transmit a break.
+const LCR_BREAK: u8 = 1 << 6;
+
+/// FCR: enable FIFOs.
+const FCR_ENABLE: u8 = 1 << 0;
+/// FCR: clear receive FIFO.
+const FCR_CLEAR_RX: u8 = 1 << 1;
+/// FCR: clear transmit FIFO.
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+/// MCR: data terminal ready.
+const MCR_DTR: u8 = 1 << 0;
+/// MCR: request to send.
+const MCR_RTS: u8 = 1 << 1;
+/// MCR: OUT2, gates the interrupt line on PCs.
+const MCR_OUT2: u8 = 1 << 3;
+/// MCR: loopback mode.
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+/// Size of the transmit FIFO.
+const FIFO_SIZE: usize = 16;
+
+/// Number of data bits per character.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum DataBits {
+    Five = 0b00,
+    Six = 0b01,
+    Seven = 0b10,
+    Eight = 0b11,
+}
+
+/// Parity bit.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+    /// Parity bit always 1.
+    Mark = 0b101,
+    /// Parity bit always 0.
+    Space = 0b111,
+}
+
+/// Number of stop bits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum StopBits {
+    One = 0,
+    /// Two stop bits (1.5 with five data bits).
+    Two = 1,
+}
+
+/// Character format (line control register).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LineControl {
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineControl {
+    /// 8 data bits, no parity, one stop bit (8N1).
+    pub const fn new() -> LineControl {
+        LineControl {
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+
+    /// Decode the format bits of a line control register value.
+    pub fn from_raw(raw: u8) -> LineControl {
+        let data_bits = match raw & 0b11 {
+            0b00 => DataBits::Five,
+            0b01 => DataBits::Six,
+            0b10 => DataBits::Seven,
+            _ => DataBits::Eight,
+        };
+        let stop_bits = if raw & 0b100 != 0 {
+            StopBits::Two
+        } else {
+            StopBits::One
+        };
+        let parity = match (raw >> 3) & 0b111 {
+            0b001 => Parity::Odd,
+            0b011 => Parity::Even,
+            0b101 => Parity::Mark,
+            0b111 => Parity::Space,
+            _ => Parity::None,
+        };
+        LineControl {
+            data_bits,
+            parity,
+            stop_bits,
+        }
+    }
+
+    /// Line control register value (DLAB and break clear).
+    pub fn to_raw(&self) -> u8 {
+        self.data_bits as u8 | (self.stop_bits as u8) << 2 | (self.parity as u8) << 3
+    }
+}
+
+impl Default for LineControl {
+    fn default() -> Self {
+        LineControl::new()
+    }
+}
+
+/// Receive FIFO level that raises a received data interrupt.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum FifoTrigger {
+    One = 0b00,
+    Four = 0b01,
+    Eight = 0b10,
+    Fourteen = 0b11,
+}
+
+bitflags! {
+    /// Line status register.
+    pub struct LineStatus: u8 {
+        /// A character is in the receiver buffer (or FIFO).
+        const DATA_READY = 1 << 0;
+        /// A character was lost because the receiver buffer was full.
+        const OVERRUN_ERROR = 1 << 1;
+        /// The received character has a wrong parity bit.
+        const PARITY_ERROR = 1 << 2;
+        /// The received character has no valid stop bit.
+        const FRAMING_ERROR = 1 << 3;
+        /// The input was held low for longer than a character.
+        const BREAK_INTERRUPT = 1 << 4;
+        /// The transmitter holding register (or FIFO) is empty.
+        const THR_EMPTY = 1 << 5;
+        /// The transmitter holding and shift registers are empty.
+        const TRANSMITTER_EMPTY = 1 << 6;
+        /// At least one error is in the receive FIFO.
+        const FIFO_ERROR = 1 << 7;
+    }
+}
+
+impl LineStatus {
+    /// The receive error reported by this status, if any.
+    pub fn error(&self) -> Option<SerialError> {
+        if self.contains(LineStatus::BREAK_INTERRUPT) {
+            Some(SerialError::Break)
+        } else if self.contains(LineStatus::FRAMING_ERROR) {
+            Some(SerialError::Framing)
+        } else if self.contains(LineStatus::PARITY_ERROR) {
+            Some(SerialError::Parity)
+        } else if self.contains(LineStatus::OVERRUN_ERROR) {
+            Some(SerialError::Overrun)
+        } else {
+            None
+        }
+    }
+}
+
+bitflags! {
+    /// Interrupt enable register.
+    pub struct InterruptEnable: u8 {
+        /// Received data available (or character timeout).
+        const RECEIVED_DATA = 1 << 0;
+        /// Transmitter holding register empty.
+        const THR_EMPTY = 1 << 1;
+        /// Receiver line status (errors, break).
+        const LINE_STATUS = 1 << 2;
+        /// Modem status change.
+        const MODEM_STATUS = 1 << 3;
+    }
+}
+
+/// Pending interrupt with the highest priority (interrupt identification
+/// register).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InterruptSource {
+    /// Receive error or break; cleared by reading the line status.
+    LineStatus,
+    /// Received data reached the FIFO trigger level.
+    ReceivedData,
+    /// Characters are in the receive FIFO but none arrived for a while.
+    CharacterTimeout,
+    /// The transmitter can accept more data.
+    ThrEmpty,
+    /// Modem status change; cleared by reading the modem status.
+    ModemStatus,
+}
+
+impl InterruptSource {
+    /// Decode an interrupt identification register value.
+    pub fn from_raw(raw: u8) -> Option<InterruptSource> {
+        match raw & 0b1111 {
+            0b0110 => Some(InterruptSource::LineStatus),
+            0b0100 => Some(InterruptSource::ReceivedData),
+            0b1100 => Some(InterruptSource::CharacterTimeout),
+            0b0010 => Some(InterruptSource::ThrEmpty),
+            0b0000 => Some(InterruptSource::ModemStatus),
+            _ => None,
+        }
+    }
+}
+
+/// Errors of the serial port.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SerialError {
+    /// The operation can't complete without waiting.
+    WouldBlock,
+    /// The baud rate can't be generated from the UART clock.
+    InvalidBaudRate,
+    /// A received character was lost.
+    Overrun,
+    /// A character with a wrong parity bit was received.
+    Parity,
+    /// A character without a valid stop bit was received.
+    Framing,
+    /// A break condition was received.
+    Break,
+}
+
+/// A 16550-compatible UART.
+#[derive(Debug)]
+pub struct SerialPort<P: PortIo = NativePortIo> {
+    io: P,
+    base: u16,
+    /// An overrun seen while receiving a good character, reported by the
+    /// next [`SerialPort::try_receive`].
+    overrun: bool,
+}
+
+impl SerialPort {
+    /// Create a driver for the UART at `base` (e.g., [`COM1`]).
+    ///
+    /// The UART is not touched until [`SerialPort::init`] is called.
+    ///
+    /// # Safety
+    /// Needs IO privileges.
+    pub unsafe fn new(base: u16) -> Self {
+        SerialPort::with_io(NativePortIo::new(), base)
+    }
+}
+
+impl<P: PortIo> SerialPort<P> {
+    /// Create a driver doing port I/O through `io`.
+    pub fn with_io(io: P, base: u16) -> Self {
+        SerialPort {
+            io,
+            base,
+            overrun: false,
+        }
+    }
+
+    /// Base port of the UART.
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    fn read(&mut self, register: u16) -> u8 {
+        self.io.read_u8(self.base + register)
+    }
+
+    fn write(&mut self, register: u16, val: u8) {
+        self.io.write_u8(self.base + register, val)
+    }
+
+    /// Initialize the UART: interrupts disabled, `baud_rate` with `line`
+    /// format, FIFOs enabled and cleared, DTR, RTS and OUT2 set.
+    pub fn init(&mut self, baud_rate: u32, line: LineControl) -> Result<(), SerialError> {
+        self.set_interrupts(InterruptEnable::empty());
+        self.set_baud_rate(baud_rate)?;
+        self.set_line_control(line);
+        self.set_fifo(Some(FifoTrigger::Fourteen));
+        self.write(MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+        Ok(())
+    }
+
+    /// Program the divisor latch for `baud_rate`.
+    ///
+    /// The baud rate has to divide [`MAX_BAUD_RATE`].
+    pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), SerialError> {
+        if baud_rate == 0 || baud_rate > MAX_BAUD_RATE {
+            return Err(SerialError::InvalidBaudRate);
+        }
+        let divisor = MAX_BAUD_RATE / baud_rate;
+        if divisor * baud_rate != MAX_BAUD_RATE {
+            return Err(SerialError::InvalidBaudRate);
+        }
+        self.set_divisor(divisor as u16);
+        Ok(())
+    }
+
+    /// Current baud rate (from the divisor latch).
+    pub fn baud_rate(&mut self) -> u32 {
+        match self.divisor() {
+            0 => 0,
+            divisor => MAX_BAUD_RATE / divisor as u32,
+        }
+    }
+
+    /// Write the divisor latch.
+    pub fn set_divisor(&mut self, divisor: u16) {
+        let lcr = self.read(LINE_CONTROL);
+        self.write(LINE_CONTROL, lcr | LCR_DLAB);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, lcr & !LCR_DLAB);
+    }
+
+    /// Read the divisor latch.
+    pub fn divisor(&mut self) -> u16 {
+        let lcr = self.read(LINE_CONTROL);
+        self.write(LINE_CONTROL, lcr | LCR_DLAB);
+        let divisor = self.read(DATA) as u16 | (self.read(INTERRUPT_ENABLE) as u16) << 8;
+        self.write(LINE_CONTROL, lcr & !LCR_DLAB);
+        divisor
+    }
+
+    /// Read the character format.
+    pub fn line_control(&mut self) -> LineControl {
+        LineControl::from_raw(self.read(LINE_CONTROL))
+    }
+
+    /// Set the character format (clears DLAB and break).
+    pub fn set_line_control(&mut self, line: LineControl) {
+        self.write(LINE_CONTROL, line.to_raw());
+    }
+
+    /// Start (`true`) or stop sending a break.
+    pub fn set_break(&mut self, enabled: bool) {
+        let lcr = self.read(LINE_CONTROL) & !LCR_DLAB;
+        if enabled {
+            self.write(LINE_CONTROL, lcr | LCR_BREAK);
+        } else {
+            self.write(LINE_CONTROL, lcr & !LCR_BREAK);
+        }
+    }
+
+    /// Enable and clear the FIFOs with a receive `trigger` level, or disable
+    /// them (`None`).
+    pub fn set_fifo(&mut self, trigger: Option<FifoTrigger>) {
+        let fcr = match trigger {
+            Some(trigger) => FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | (trigger as u8) << 6,
+            None => 0,
+        };
+        self.write(INTERRUPT_ID_FIFO_CONTROL, fcr);
+    }
+
+    /// Read the line status register.
+    ///
+    /// Reading clears the error bits.
+    pub fn line_status(&mut self) -> LineStatus {
+        LineStatus::from_bits_truncate(self.read(LINE_STATUS))
+    }
+
+    /// Read the modem status register (clears the delta bits).
+    pub fn modem_status(&mut self) -> u8 {
+        self.read(MODEM_STATUS)
+    }
+
+    /// Read the enabled interrupts.
+    pub fn interrupts(&mut self) -> InterruptEnable {
+        InterruptEnable::from_bits_truncate(self.read(INTERRUPT_ENABLE))
+    }
+
+    /// Set the enabled interrupts.
+    pub fn set_interrupts(&mut self, interrupts: InterruptEnable) {
+        self.write(INTERRUPT_ENABLE, interrupts.bits());
+    }
+
+    /// The pending interrupt with the highest priority.
+    pub fn interrupt_source(&mut self) -> Option<InterruptSource> {
+        InterruptSource::from_raw(self.read(INTERRUPT_ID_FIFO_CONTROL))
+    }
+
+    /// Send `byte` if the transmitter can accept it.
+    pub fn try_send(&mut self, byte: u8) -> Result<(), SerialError> {
+        if self.line_status().contains(LineStatus::THR_EMPTY) {
+            self.write(DATA, byte);
+            Ok(())
+        } else {
+            Err(SerialError::WouldBlock)
+        }
+    }
+
+    /// Send `byte`, waiting until the transmitter can accept it.
+    pub fn send(&mut self, byte: u8) {
+        while self.try_send(byte).is_err() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Receive a character if one is available.
+    ///
+    /// A character received during a break or with a framing or parity
+    /// error is dropped and the error is returned. An overrun means an
+    /// earlier character was lost: the current character is still returned
+    /// and [`SerialError::Overrun`] is reported by the next call.
+    pub fn try_receive(&mut self) -> Result<u8, SerialError> {
+        if core::mem::take(&mut self.overrun) {
+            return Err(SerialError::Overrun);
+        }
+        let status = self.line_status();
+        if !status.contains(LineStatus::DATA_READY) {
+            return Err(status.error().unwrap_or(SerialError::WouldBlock));
+        }
+        let byte = self.read(DATA);
+        self.overrun = status.contains(LineStatus::OVERRUN_ERROR);
+        match status.error() {
+            Some(SerialError::Overrun) | None => Ok(byte),
+            Some(error) => Err(error),
+        }
+    }
+
+    /// Receive a character, waiting until one is available.
+    pub fn receive(&mut self) -> Result<u8, SerialError> {
+        loop {
+            match self.try_receive() {
+                Err(SerialError::WouldBlock) => core::hint::spin_loop(),
+                result => return result,
+            }
+        }
+    }
+
+    /// Service all pending interrupts.
+    ///
+    /// Received characters and errors are passed to `received`, `transmit`
+    /// supplies the next characters to send when the transmitter is empty
+    /// (up to the FIFO size). Returns the number of handled interrupts.
+    pub fn handle_interrupt<R, T>(&mut self, mut received: R, mut transmit: T) -> usize
+    where
+        R: FnMut(Result<u8, SerialError>),
+        T: FnMut() -> Option<u8>,
+    {
+        let mut handled = 0;
+        while let Some(source) = self.interrupt_source() {
+            match source {
+                InterruptSource::LineStatus => {
+                    if let Some(error) = self.line_status().error() {
+                        received(Err(error));
+                    }
+                }
+                InterruptSource::ReceivedData | InterruptSource::CharacterTimeout => loop {
+                    match self.try_receive() {
+                        Err(SerialError::WouldBlock) => break,
+                        result => received(result),
+                    }
+                },
+                InterruptSource::ThrEmpty => {
+                    for _ in 0..FIFO_SIZE {
+                        match transmit() {
+                            Some(byte) => self.write(DATA, byte),
+                            None => break,
+                        }
+                    }
+                }
+                InterruptSource::ModemStatus => {
+                    self.modem_status();
+                }
+            }
+            handled += 1;
+        }
+        handled
+    }
+
+    /// Check that a UART is present by sending `0xae` in loopback mode.
+    ///
+    /// Restores the modem control register.
+    pub fn self_test(&mut self) -> bool {
+        let mcr = self.read(MODEM_CONTROL);
+        self.write(MODEM_CONTROL, MCR_LOOPBACK | MCR_RTS | MCR_DTR);
+        self.send(0xae);
+
+        let mut received = false;
+        for _ in 0..1000 {
+            if self.line_status().contains(LineStatus::DATA_READY) {
+                received = self.read(DATA) == 0xae;
+                break;
+            }
+        }
+        self.write(MODEM_CONTROL, mcr);
+        received
+    }
+}
+
+/// Sends the string, translating `\n` to `\r\n`.
+impl<P: PortIo> fmt::Write for SerialPort<P> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    extern crate std;
+    use core::fmt::Write;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// Emulates the registers of a 16550 at COM1.
+    #[derive(Default)]
+    struct Uart {
+        regs: [u8; 8],
+        divisor: [u8; 2],
+        rx: VecDeque<(u8, LineStatus)>,
+        tx: Vec<u8>,
+        iir: Vec<u8>,
+        busy: usize,
+        fcr: Vec<u8>,
+    }
+
+    impl Uart {
+        fn dlab(&self) -> bool {
+            self.regs[LINE_CONTROL as usize] & LCR_DLAB != 0
+        }
+    }
+
+    impl PortIo for Uart {
+        fn read_u8(&mut self, port: u16) -> u8 {
+            let reg = port - COM1;
+            match reg {
+                DATA | INTERRUPT_ENABLE if self.dlab() => self.divisor[reg as usize],
+                DATA => self.rx.pop_front().map_or(0, |(byte, _)| byte),
+                INTERRUPT_ID_FIFO_CONTROL => {
+                    if self.iir.is_empty() {
+                        0b0001
+                    } else {
+                        self.iir.remove(0)
+                    }
+                }
+                LINE_STATUS => {
+                    let mut status = LineStatus::empty();
+                    if let Some((_, errors)) = self.rx.front() {
+                        status |= LineStatus::DATA_READY | *errors;
+                    }
+                    if self.busy > 0 {
+                        self.busy -= 1;
+                    } else {
+                        status |= LineStatus::THR_EMPTY | LineStatus::TRANSMITTER_EMPTY;
+                    }
+                    status.bits()
+                }
+                _ => self.regs[reg as usize],
+            }
+        }
+
+        fn write_u8(&mut self, port: u16, val: u8) {
+            let reg = port - COM1;
+            match reg {
+                DATA | INTERRUPT_ENABLE if self.dlab() => self.divisor[reg as usize] = val,
+                DATA => {
+                    self.tx.push(val);
+                    if self.regs[MODEM_CONTROL as usize] & MCR_LOOPBACK != 0 {
+                        self.rx.push_back((val, LineStatus::empty()));
+                    }
+                }
+                INTERRUPT_ID_FIFO_CONTROL => self.fcr.push(val),
+                _ => self.regs[reg as usize] = val,
+            }
+        }
+
+        fn read_u16(&mut self, _port: u16) -> u16 {
+            unreachable!()
+        }
+
+        fn write_u16(&mut self, _port: u16, _val: u16) {
+            unreachable!()
+        }
+
+        fn read_u32(&mut self, _port: u16) -> u32 {
+            unreachable!()
+        }
+
+        fn write_u32(&mut self, _port: u16, _val: u32) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn line_control() {
+        let line = LineControl {
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+        };
+        assert_eq!(line.to_raw(), 0b0001_1110);
+        assert_eq!(LineControl::from_raw(0b0001_1110), line);
+        assert_eq!(LineControl::new().to_raw(), 0x03);
+        assert_eq!(LineControl::from_raw(0x83), LineControl::new());
+    }
+
+    #[test]
+    fn init() {
+        let mut port = SerialPort::with_io(Uart::default(), COM1);
+        assert_eq!(port.init(38_400, LineControl::new()), Ok(()));
+
+        assert_eq!(port.io.divisor, [3, 0]);
+        assert_eq!(port.divisor(), 3);
+        assert_eq!(port.baud_rate(), 38_400);
+        assert_eq!(port.line_control(), LineControl::new());
+        assert_eq!(port.io.regs[LINE_CONTROL as usize], 0x03);
+        assert_eq!(port.io.fcr, [0xc7]);
+        assert_eq!(port.io.regs[MODEM_CONTROL as usize], 0x0b);
+        assert_eq!(port.interrupts(), InterruptEnable::empty());
+
+        assert_eq!(port.set_baud_rate(50), Ok(()));
+        assert_eq!(port.io.divisor, [0x00, 0x09]);
+        assert_eq!(port.set_baud_rate(0), Err(SerialError::InvalidBaudRate));
+        assert_eq!(port.set_baud_rate(7_000), Err(SerialError::InvalidBaudRate));
+        assert_eq!(
+            port.set_baud_rate(230_400),
+            Err(SerialError::InvalidBaudRate)
+        );
+        assert_eq!(port.baud_rate(), 50);
+    }
+
+    #[test]
+    fn polled_transfer() {
+        let mut port = SerialPort::with_io(Uart::default(), COM1);
+        port.io.busy = 3;
+        assert_eq!(port.try_send(b'x'), Err(SerialError::WouldBlock));
+        write!(port, "a\nb").unwrap();
+        assert_eq!(port.io.tx, b"a\r\nb");
+
+        assert_eq!(port.try_receive(), Err(SerialError::WouldBlock));
+        port.io.rx.push_back((b'k', LineStatus::empty()));
+        port.io.rx.push_back((b'?', LineStatus::PARITY_ERROR));
+        port.io
+            .rx
+            .push_back((0, LineStatus::BREAK_INTERRUPT | LineStatus::FRAMING_ERROR));
+        assert_eq!(port.receive(), Ok(b'k'));
+        assert_eq!(port.receive(), Err(SerialError::Parity));
+        assert_eq!(port.receive(), Err(SerialError::Break));
+        assert_eq!(port.try_receive(), Err(SerialError::WouldBlock));
+
+        port.io
+            .rx
+            .push_back((b'!', LineStatus::PARITY_ERROR | LineStatus::OVERRUN_ERROR));
+        assert_eq!(port.try_receive(), Err(SerialError::Parity));
+        assert_eq!(port.try_receive(), Err(SerialError::Overrun));
+        assert_eq!(port.try_receive(), Err(SerialError::WouldBlock));
+
+        assert!(port.self_test());
+        assert_eq!(port.io.regs[MODEM_CONTROL as usize], 0);
+    }
+
+    #[test]
+    fn interrupt_driven() {
+        let mut port = SerialPort::with_io(Uart::default(), COM1);
+        port.set_interrupts(InterruptEnable::RECEIVED_DATA | InterruptEnable::THR_EMPTY);
+        assert_eq!(
+            port.interrupts(),
+            InterruptEnable::RECEIVED_DATA | InterruptEnable::THR_EMPTY
+        );
+
+        port.io.iir = std::vec![0b1100_0100, 0b1100_0010, 0b1100_0110];
+        port.io.rx.push_back((b'h', LineStatus::empty()));
+        port.io.rx.push_back((b'i', LineStatus::OVERRUN_ERROR));
+
+        let mut received = Vec::new();
+        let mut outgoing = (0..20u8).collect::<VecDeque<u8>>();
+        let handled = port.handle_interrupt(|r| received.push(r), || outgoing.pop_front());
+
+        assert_eq!(handled, 3);
+        assert_eq!(received, [Ok(b'h'), Ok(b'i'), Err(SerialError::Overrun)]);
+        assert_eq!(port.io.tx, (0..16).collect::<Vec<u8>>());
+        assert_eq!(outgoing.len(), 4);
+        assert_eq!(port.interrupt_source(), None);
+        assert_eq!(
+            InterruptSource::from_raw(0xcc),
+            Some(InterruptSource::CharacterTimeout)
+        );
+        assert_eq!(
+            InterruptSource::from_raw(0xc0),
+            Some(InterruptSource::ModemStatus)
+        );
+    }
+}
+
+#[cfg(all(test, feature = "vmtest"))]
+mod x86testing {
+    use super::*;
+    use core::fmt::Write;
+    use x86test::*;
+
+    // The harness only emulates the line status and data registers of COM1,
+    // so this uses the UART as configured by the hypervisor.
+    #[x86test]
+    fn check_serial_write() {
+        unsafe {
+            let mut port = SerialPort::new(COM1);
+            kassert!(
+                writeln!(port, "Hello from the 16550 driver").is_ok(),
+                "Failed to write to COM1"
+            );
+        }
+    }
+}
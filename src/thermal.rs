@@ -0,0 +1,309 @@
+//! Digital thermal sensor status and thermal interrupt control.
+//!
+//! See Intel Vol. 3b Section 14.8, "Thermal Monitoring and Protection",
+//! especially Section 14.8.5, "Platform Specific Thermal Management Support".
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use crate::msr::{
+    rdmsr, wrmsr, IA32_PACKAGE_THERM_INTERRUPT, IA32_PACKAGE_THERM_STATUS, IA32_THERM_INTERRUPT,
+    IA32_THERM_STATUS, MSR_TEMPERATURE_TARGET,
+};
+
+bitflags! {
+    /// Status and sticky log flags in `IA32_THERM_STATUS` and
+    /// `IA32_PACKAGE_THERM_STATUS`.
+    ///
+    /// The log flags are sticky: they are set by the processor and need to
+    /// be cleared by software (by writing 0).
+    pub struct ThermalStatusFlags: u64 {
+        /// Thermal sensor high temperature output signal (PROCHOT#) is active.
+        const THERMAL_STATUS = 1 << 0;
+        /// Thermal status has been active since last cleared.
+        const THERMAL_STATUS_LOG = 1 << 1;
+        /// PROCHOT# or FORCEPR# was asserted by external agent.
+        const PROCHOT_EVENT = 1 << 2;
+        /// PROCHOT# or FORCEPR# has been asserted since last cleared.
+        const PROCHOT_LOG = 1 << 3;
+        /// The critical temperature detector output signal is active.
+        const CRITICAL_TEMPERATURE = 1 << 4;
+        /// Critical temperature has been reached since last cleared.
+        const CRITICAL_TEMPERATURE_LOG = 1 << 5;
+        /// Actual temperature is currently higher than or equal to threshold #1.
+        const THRESHOLD1 = 1 << 6;
+        /// Threshold #1 has been crossed since last cleared.
+        const THRESHOLD1_LOG = 1 << 7;
+        /// Actual temperature is currently higher than or equal to threshold #2.
+        const THRESHOLD2 = 1 << 8;
+        /// Threshold #2 has been crossed since last cleared.
+        const THRESHOLD2_LOG = 1 << 9;
+        /// Processor is operating below OS-requested P-state or clock
+        /// modulation duty cycle.
+        const POWER_LIMITATION = 1 << 10;
+        /// Power limitation has been active since last cleared.
+        const POWER_LIMITATION_LOG = 1 << 11;
+        /// Voltage regulator current limit is active (core only).
+        const CURRENT_LIMIT = 1 << 12;
+        /// Current limit has been active since last cleared (core only).
+        const CURRENT_LIMIT_LOG = 1 << 13;
+        /// Cross-domain limit is active (core only).
+        const CROSS_DOMAIN_LIMIT = 1 << 14;
+        /// Cross-domain limit has been active since last cleared (core only).
+        const CROSS_DOMAIN_LIMIT_LOG = 1 << 15;
+    }
+}
+
+/// Content of the thermal status register of a core (`IA32_THERM_STATUS`)
+/// or the package (`IA32_PACKAGE_THERM_STATUS`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ThermalStatus(pub u64);
+
+impl ThermalStatus {
+    /// The status and log flags.
+    pub fn flags(&self) -> ThermalStatusFlags {
+        ThermalStatusFlags::from_bits_truncate(self.0)
+    }
+
+    /// Digital readout (bits 22:16): the temperature in degrees Celsius
+    /// *below* the TCC activation temperature (see [`TemperatureTarget`]).
+    pub fn digital_readout(&self) -> u8 {
+        self.0.get_bits(16..=22) as u8
+    }
+
+    /// Resolution of the digital readout in degrees Celsius (bits 30:27, core only).
+    pub fn resolution(&self) -> u8 {
+        self.0.get_bits(27..=30) as u8
+    }
+
+    /// Is the digital readout valid (bit 31, core only)?
+    pub fn reading_valid(&self) -> bool {
+        self.0.get_bit(31)
+    }
+
+    /// Is PROCHOT# (or FORCEPR#) currently asserted?
+    pub fn prochot(&self) -> bool {
+        self.flags().contains(ThermalStatusFlags::PROCHOT_EVENT)
+    }
+
+    /// Is the critical temperature detector signal active?
+    pub fn critical(&self) -> bool {
+        self.flags()
+            .contains(ThermalStatusFlags::CRITICAL_TEMPERATURE)
+    }
+
+    /// Absolute temperature in degrees Celsius given the TCC activation
+    /// temperature `tj_max` (see [`TemperatureTarget::target`]).
+    pub fn temperature(&self, tj_max: u8) -> u8 {
+        tj_max.saturating_sub(self.digital_readout())
+    }
+
+    /// Returns the status with all sticky log flags cleared. Writing this
+    /// back acknowledges all logged events.
+    pub fn clear_logs(&self) -> ThermalStatus {
+        let logs = ThermalStatusFlags::THERMAL_STATUS_LOG
+            | ThermalStatusFlags::PROCHOT_LOG
+            | ThermalStatusFlags::CRITICAL_TEMPERATURE_LOG
+            | ThermalStatusFlags::THRESHOLD1_LOG
+            | ThermalStatusFlags::THRESHOLD2_LOG
+            | ThermalStatusFlags::POWER_LIMITATION_LOG
+            | ThermalStatusFlags::CURRENT_LIMIT_LOG
+            | ThermalStatusFlags::CROSS_DOMAIN_LIMIT_LOG;
+        ThermalStatus(self.0 & !logs.bits())
+    }
+}
+
+/// Decoded `MSR_TEMPERATURE_TARGET`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TemperatureTarget(pub u64);
+
+impl TemperatureTarget {
+    /// Minimum temperature in degrees Celsius at which PROCHOT# will be
+    /// asserted (TjMax, bits 23:16).
+    pub fn target(&self) -> u8 {
+        self.0.get_bits(16..=23) as u8
+    }
+
+    /// Offset in degrees Celsius from `target` at which the thermal control
+    /// circuit activates (bits 29:24, not supported by all processors).
+    pub fn tcc_activation_offset(&self) -> u8 {
+        self.0.get_bits(24..=29) as u8
+    }
+}
+
+bitflags! {
+    /// Interrupt enable bits in `IA32_THERM_INTERRUPT` and
+    /// `IA32_PACKAGE_THERM_INTERRUPT`.
+    pub struct ThermalInterruptFlags: u64 {
+        /// Interrupt when the temperature rises above the TCC activation temperature.
+        const HIGH_TEMPERATURE = 1 << 0;
+        /// Interrupt when the temperature drops below the TCC activation temperature.
+        const LOW_TEMPERATURE = 1 << 1;
+        /// Interrupt on PROCHOT# assertion by an external agent.
+        const PROCHOT = 1 << 2;
+        /// Interrupt on FORCEPR# assertion by an external agent.
+        const FORCEPR = 1 << 3;
+        /// Interrupt when the critical temperature is reached.
+        const CRITICAL_TEMPERATURE = 1 << 4;
+        /// Interrupt when threshold #1 is crossed.
+        const THRESHOLD1 = 1 << 15;
+        /// Interrupt when threshold #2 is crossed.
+        const THRESHOLD2 = 1 << 23;
+        /// Interrupt on power limit notification.
+        const POWER_LIMIT_NOTIFICATION = 1 << 24;
+    }
+}
+
+/// Content of `IA32_THERM_INTERRUPT` or `IA32_PACKAGE_THERM_INTERRUPT`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct ThermalInterrupt(pub u64);
+
+impl ThermalInterrupt {
+    /// The interrupt enable flags.
+    pub fn flags(&self) -> ThermalInterruptFlags {
+        ThermalInterruptFlags::from_bits_truncate(self.0)
+    }
+
+    /// Replace the interrupt enable flags.
+    pub fn set_flags(&mut self, flags: ThermalInterruptFlags) {
+        self.0 = (self.0 & !ThermalInterruptFlags::all().bits()) | flags.bits();
+    }
+
+    /// Threshold #1 (bits 14:8), in degrees Celsius below TjMax.
+    pub fn threshold1(&self) -> u8 {
+        self.0.get_bits(8..=14) as u8
+    }
+
+    /// Set threshold #1, in degrees Celsius below TjMax.
+    pub fn set_threshold1(&mut self, value: u8) {
+        self.0.set_bits(8..=14, (value & 0x7f) as u64);
+    }
+
+    /// Threshold #2 (bits 22:16), in degrees Celsius below TjMax.
+    pub fn threshold2(&self) -> u8 {
+        self.0.get_bits(16..=22) as u8
+    }
+
+    /// Set threshold #2, in degrees Celsius below TjMax.
+    pub fn set_threshold2(&mut self, value: u8) {
+        self.0.set_bits(16..=22, (value & 0x7f) as u64);
+    }
+}
+
+/// Read the thermal status of the current core.
+///
+/// # Safety
+/// Needs CPL 0 and a processor with a digital thermal sensor (CPUID.06H:EAX\[0\]).
+pub unsafe fn therm_status() -> ThermalStatus {
+    ThermalStatus(rdmsr(IA32_THERM_STATUS))
+}
+
+/// Write the thermal status of the current core (to clear log flags).
+///
+/// # Safety
+/// Needs CPL 0 and a processor with a digital thermal sensor (CPUID.06H:EAX\[0\]).
+pub unsafe fn therm_status_write(status: ThermalStatus) {
+    wrmsr(IA32_THERM_STATUS, status.0)
+}
+
+/// Read the thermal status of the package.
+///
+/// # Safety
+/// Needs CPL 0 and package thermal management support (CPUID.06H:EAX\[6\]).
+pub unsafe fn package_therm_status() -> ThermalStatus {
+    ThermalStatus(rdmsr(IA32_PACKAGE_THERM_STATUS))
+}
+
+/// Write the thermal status of the package (to clear log flags).
+///
+/// # Safety
+/// Needs CPL 0 and package thermal management support (CPUID.06H:EAX\[6\]).
+pub unsafe fn package_therm_status_write(status: ThermalStatus) {
+    wrmsr(IA32_PACKAGE_THERM_STATUS, status.0)
+}
+
+/// Read the thermal interrupt control of the current core.
+///
+/// # Safety
+/// Needs CPL 0.
+pub unsafe fn therm_interrupt() -> ThermalInterrupt {
+    ThermalInterrupt(rdmsr(IA32_THERM_INTERRUPT))
+}
+
+/// Write the thermal interrupt control of the current core.
+///
+/// # Safety
+/// Needs CPL 0.
+pub unsafe fn therm_interrupt_write(val: ThermalInterrupt) {
+    wrmsr(IA32_THERM_INTERRUPT, val.0)
+}
+
+/// Read the thermal interrupt control of the package.
+///
+/// # Safety
+/// Needs CPL 0 and package thermal management support (CPUID.06H:EAX\[6\]).
+pub unsafe fn package_therm_interrupt() -> ThermalInterrupt {
+    ThermalInterrupt(rdmsr(IA32_PACKAGE_THERM_INTERRUPT))
+}
+
+/// Write the thermal interrupt control of the package.
+///
+/// # Safety
+/// Needs CPL 0 and package thermal management support (CPUID.06H:EAX\[6\]).
+pub unsafe fn package_therm_interrupt_write(val: ThermalInterrupt) {
+    wrmsr(IA32_PACKAGE_THERM_INTERRUPT, val.0)
+}
+
+/// Read the temperature target (TjMax).
+///
+/// # Safety
+/// Needs CPL 0 and a processor that implements `MSR_TEMPERATURE_TARGET`.
+pub unsafe fn temperature_target() -> TemperatureTarget {
+    TemperatureTarget(rdmsr(MSR_TEMPERATURE_TARGET))
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_therm_status() {
+        // Valid reading, 1 degree resolution, 42 degrees below TjMax,
+        // PROCHOT logged, critical temperature active.
+        let status = ThermalStatus(0x882a_0018);
+        assert!(status.reading_valid());
+        assert_eq!(status.resolution(), 1);
+        assert_eq!(status.digital_readout(), 42);
+        assert!(!status.prochot());
+        assert!(status.critical());
+        assert_eq!(
+            status.flags(),
+            ThermalStatusFlags::PROCHOT_LOG | ThermalStatusFlags::CRITICAL_TEMPERATURE
+        );
+
+        let target = TemperatureTarget(0x0064_0000);
+        assert_eq!(target.target(), 100);
+        assert_eq!(target.tcc_activation_offset(), 0);
+        assert_eq!(status.temperature(target.target()), 58);
+        assert_eq!(status.temperature(20), 0);
+
+        let cleared = ThermalStatus(0xaaaa).clear_logs();
+        assert_eq!(cleared.0, 0);
+        assert_eq!(ThermalStatus(0x882a_5555).clear_logs().0, 0x882a_5555);
+    }
+
+    #[test]
+    fn therm_interrupt() {
+        let mut int = ThermalInterrupt::default();
+        int.set_flags(ThermalInterruptFlags::HIGH_TEMPERATURE | ThermalInterruptFlags::THRESHOLD1);
+        int.set_threshold1(10);
+        int.set_threshold2(0xff);
+        assert_eq!(int.0, 0x007f_8a01);
+        assert_eq!(int.threshold1(), 10);
+        assert_eq!(int.threshold2(), 0x7f);
+
+        int.set_flags(ThermalInterruptFlags::CRITICAL_TEMPERATURE);
+        assert_eq!(int.0, 0x007f_0a10);
+        assert_eq!(int.flags(), ThermalInterruptFlags::CRITICAL_TEMPERATURE);
+    }
+}
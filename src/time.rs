@@ -1,7 +1,15 @@
 //! Functions to read time stamp counters on x86.
+//!
+//! Besides reading the raw counter, this module discovers the TSC frequency
+//! (see [`TscFrequency`]) and converts cycles to nanoseconds (see
+//! [`TscClock`]).
 use core::arch::asm;
 
+use bit_field::BitField;
+
 use crate::arch::_rdtsc;
+use crate::cpuid::CpuId;
+use crate::msr::{rdmsr, MSR_PLATFORM_INFO};
 
 /// Read the time stamp counter.
 ///
@@ -59,10 +67,373 @@ pub unsafe fn rdtscp() -> (u64, u32) {
     (counter, ecx)
 }
 
+/// Where the TSC frequency was obtained from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TscFrequencySource {
+    /// CPUID leaf 0x15: core crystal clock frequency and TSC/crystal ratio.
+    CpuidTscLeaf,
+    /// CPUID leaf 0x16: processor base frequency.
+    CpuidFrequencyLeaf,
+    /// Hypervisor timing leaf 0x4000_0010 (VMware, KVM, ...).
+    Hypervisor,
+    /// Maximum non-turbo ratio in `MSR_PLATFORM_INFO` (times 100 MHz).
+    PlatformInfo,
+}
+
+/// The TSC frequency and where it came from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TscFrequency {
+    /// Frequency in Hz.
+    pub hz: u64,
+    /// The source used to determine the frequency.
+    pub source: TscFrequencySource,
+}
+
+impl TscFrequency {
+    /// Determine the TSC frequency.
+    ///
+    /// Tries CPUID leaf 0x15, then 0x16, then the hypervisor timing leaf and
+    /// finally the raw `MSR_PLATFORM_INFO` value (if `platform_info` is
+    /// provided).
+    pub fn from_cpuid(cpuid: &CpuId, platform_info: Option<u64>) -> Option<TscFrequency> {
+        let base_frequency_hz = cpuid
+            .get_processor_frequency_info()
+            .map(|f| f.processor_base_frequency() as u64 * 1_000_000)
+            .filter(|hz| *hz > 0);
+
+        if let Some(tsc) = cpuid.get_tsc_info() {
+            if let Some(hz) = tsc.tsc_frequency() {
+                return Some(TscFrequency {
+                    hz,
+                    source: TscFrequencySource::CpuidTscLeaf,
+                });
+            }
+        }
+
+        // Leaf 0x15 may only enumerate the ratio, in which case the TSC runs
+        // at the base frequency (see Linux `native_calibrate_tsc`).
+        if let Some(hz) = base_frequency_hz {
+            return Some(TscFrequency {
+                hz,
+                source: TscFrequencySource::CpuidFrequencyLeaf,
+            });
+        }
+
+        if let Some(khz) = cpuid
+            .get_hypervisor_info()
+            .and_then(|hv| hv.tsc_frequency())
+            .filter(|khz| *khz > 0)
+        {
+            return Some(TscFrequency {
+                hz: khz as u64 * 1000,
+                source: TscFrequencySource::Hypervisor,
+            });
+        }
+
+        platform_info
+            .map(|info| info.get_bits(8..=15) * 100_000_000)
+            .filter(|hz| *hz > 0)
+            .map(|hz| TscFrequency {
+                hz,
+                source: TscFrequencySource::PlatformInfo,
+            })
+    }
+
+    /// Determine the TSC frequency of the current processor.
+    ///
+    /// # Safety
+    /// Reads `MSR_PLATFORM_INFO` on Intel processors if CPUID does not
+    /// report the frequency, which needs CPL 0.
+    pub unsafe fn detect() -> Option<TscFrequency> {
+        let cpuid = CpuId::new();
+        TscFrequency::from_cpuid(&cpuid, None).or_else(|| {
+            let intel = cpuid
+                .get_vendor_info()
+                .map(|v| v.as_str() == "GenuineIntel");
+            if intel == Some(true) {
+                TscFrequency::from_cpuid(&cpuid, Some(rdmsr(MSR_PLATFORM_INFO)))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Does the TSC run at a constant rate in all ACPI P-, C- and T-states
+/// (CPUID.80000007H:EDX\[8\])?
+pub fn has_invariant_tsc(cpuid: &CpuId) -> bool {
+    matches!(cpuid.get_advanced_power_mgmt_info(), Some(apm) if apm.has_invariant_tsc())
+}
+
+/// Errors when setting up a [`TscClock`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TscClockError {
+    /// The TSC rate is not invariant, so it can not be used as a clock.
+    NotInvariant,
+    /// The TSC frequency could not be determined.
+    UnknownFrequency,
+}
+
+/// Converts TSC cycles to nanoseconds.
+///
+/// Uses a multiplier and shift (like a Linux clocksource): `ns = (cycles *
+/// mult) >> shift`. The multiplication is done in 128 bits, so conversions
+/// never overflow; `mult` and `shift` are chosen such that it also fits in
+/// 64 bits for intervals of up to ten minutes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TscClock {
+    frequency: u64,
+    mult: u32,
+    shift: u32,
+}
+
+impl TscClock {
+    /// Longest interval (in seconds) for which `cycles * mult` fits in 64 bits.
+    const MAX_INTERVAL_SECS: u64 = 600;
+    const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+    /// Create a clock for a TSC running at `frequency` Hz.
+    pub fn new(frequency: u64) -> TscClock {
+        assert!(frequency > 0, "TSC frequency can not be zero.");
+        let (mult, shift) = TscClock::calc_mult_shift(
+            frequency,
+            TscClock::NSEC_PER_SEC,
+            TscClock::MAX_INTERVAL_SECS,
+        );
+        TscClock {
+            frequency,
+            mult,
+            shift,
+        }
+    }
+
+    /// Create a clock from CPUID, checking that the TSC is invariant.
+    pub fn from_cpuid(
+        cpuid: &CpuId,
+        platform_info: Option<u64>,
+    ) -> Result<TscClock, TscClockError> {
+        if !has_invariant_tsc(cpuid) {
+            return Err(TscClockError::NotInvariant);
+        }
+        TscFrequency::from_cpuid(cpuid, platform_info)
+            .map(|f| TscClock::new(f.hz))
+            .ok_or(TscClockError::UnknownFrequency)
+    }
+
+    /// Create a clock for the current processor.
+    ///
+    /// # Safety
+    /// See [`TscFrequency::detect`].
+    pub unsafe fn detect() -> Result<TscClock, TscClockError> {
+        if !has_invariant_tsc(&CpuId::new()) {
+            return Err(TscClockError::NotInvariant);
+        }
+        TscFrequency::detect()
+            .map(|f| TscClock::new(f.hz))
+            .ok_or(TscClockError::UnknownFrequency)
+    }
+
+    /// Compute the multiplier and shift to convert `from` Hz to `to` Hz
+    /// (see Linux `clocks_calc_mult_shift`).
+    fn calc_mult_shift(from: u64, to: u64, max_secs: u64) -> (u32, u32) {
+        // Number of bits needed to represent `max_secs * from` beyond 32 bits
+        // limits the precision of `mult`.
+        let mut tmp = (max_secs * from) >> 32;
+        let mut sftacc = 32;
+        while tmp > 0 {
+            tmp >>= 1;
+            sftacc -= 1;
+        }
+
+        let mut shift = 32;
+        let mut mult = 0;
+        while shift > 0 {
+            mult = ((to << shift) + from / 2) / from;
+            if mult >> sftacc == 0 {
+                break;
+            }
+            shift -= 1;
+        }
+        (mult as u32, shift)
+    }
+
+    /// TSC frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// The multiplier used for the conversion.
+    pub fn mult(&self) -> u32 {
+        self.mult
+    }
+
+    /// The shift used for the conversion.
+    pub fn shift(&self) -> u32 {
+        self.shift
+    }
+
+    /// Convert `cycles` to nanoseconds.
+    pub fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        ((cycles as u128 * self.mult as u128) >> self.shift) as u64
+    }
+
+    /// Convert `ns` to cycles.
+    pub fn ns_to_cycles(&self, ns: u64) -> u64 {
+        ((ns as u128 * self.frequency as u128) / TscClock::NSEC_PER_SEC as u128) as u64
+    }
+
+    /// Nanoseconds between two TSC readings.
+    pub fn elapsed_ns(&self, start: u64, end: u64) -> u64 {
+        self.cycles_to_ns(end.wrapping_sub(start))
+    }
+
+    /// Current TSC value in nanoseconds.
+    ///
+    /// # Safety
+    /// See [`rdtsc`].
+    pub unsafe fn now_ns(&self) -> u64 {
+        self.cycles_to_ns(rdtsc())
+    }
+}
+
 #[cfg(all(test, feature = "utest"))]
 mod test {
     use super::*;
 
+    use crate::cpuid::CpuIdResult;
+
+    const fn res(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdResult {
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    /// GenuineIntel with max leaf 0x16 and an invariant TSC.
+    fn intel_base(leaf: u32) -> Option<CpuIdResult> {
+        match leaf {
+            0x0 => Some(res(0x16, 0x756e_6547, 0x6c65_746e, 0x4965_6e69)),
+            0x8000_0000 => Some(res(0x8000_0008, 0, 0, 0)),
+            0x8000_0007 => Some(res(0, 0, 0, 1 << 8)),
+            _ => None,
+        }
+    }
+
+    fn cpuid_skylake(leaf: u32, _subleaf: u32) -> CpuIdResult {
+        intel_base(leaf).unwrap_or(match leaf {
+            // 24 MHz crystal, ratio 216/2
+            0x15 => res(2, 216, 24_000_000, 0),
+            0x16 => res(2600, 4000, 100, 0),
+            _ => res(0, 0, 0, 0),
+        })
+    }
+
+    fn cpuid_ratio_only(leaf: u32, _subleaf: u32) -> CpuIdResult {
+        intel_base(leaf).unwrap_or(match leaf {
+            0x15 => res(2, 168, 0, 0),
+            0x16 => res(2100, 3700, 100, 0),
+            _ => res(0, 0, 0, 0),
+        })
+    }
+
+    fn cpuid_hypervisor(leaf: u32, _subleaf: u32) -> CpuIdResult {
+        match leaf {
+            0x0 => res(0xd, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            0x1 => res(0, 0, 1 << 31, 0),
+            0x4000_0000 => res(0x4000_0010, 0x6e65_7458, 0x6e65_7458, 0x6e65_7458),
+            0x4000_0010 => res(2_900_000, 0, 0, 0),
+            _ => res(0, 0, 0, 0),
+        }
+    }
+
+    fn cpuid_legacy(leaf: u32, _subleaf: u32) -> CpuIdResult {
+        match leaf {
+            0x0 => res(0xd, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            _ => res(0, 0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn tsc_frequency_source_selection() {
+        let cpuid = CpuId::with_cpuid_fn(cpuid_skylake);
+        assert_eq!(
+            TscFrequency::from_cpuid(&cpuid, Some(0x1c00)),
+            Some(TscFrequency {
+                hz: 2_592_000_000,
+                source: TscFrequencySource::CpuidTscLeaf
+            })
+        );
+
+        let cpuid = CpuId::with_cpuid_fn(cpuid_ratio_only);
+        assert_eq!(
+            TscFrequency::from_cpuid(&cpuid, None),
+            Some(TscFrequency {
+                hz: 2_100_000_000,
+                source: TscFrequencySource::CpuidFrequencyLeaf
+            })
+        );
+
+        let cpuid = CpuId::with_cpuid_fn(cpuid_hypervisor);
+        assert_eq!(
+            TscFrequency::from_cpuid(&cpuid, Some(0x1c00)),
+            Some(TscFrequency {
+                hz: 2_900_000_000,
+                source: TscFrequencySource::Hypervisor
+            })
+        );
+
+        let cpuid = CpuId::with_cpuid_fn(cpuid_legacy);
+        assert_eq!(TscFrequency::from_cpuid(&cpuid, None), None);
+        assert_eq!(TscFrequency::from_cpuid(&cpuid, Some(0)), None);
+        assert_eq!(
+            TscFrequency::from_cpuid(&cpuid, Some(0x8000_1c00)),
+            Some(TscFrequency {
+                hz: 2_800_000_000,
+                source: TscFrequencySource::PlatformInfo
+            })
+        );
+    }
+
+    #[test]
+    fn tsc_clock_invariant() {
+        let cpuid = CpuId::with_cpuid_fn(cpuid_skylake);
+        assert!(has_invariant_tsc(&cpuid));
+        let clock = TscClock::from_cpuid(&cpuid, None).unwrap();
+        assert_eq!(clock.frequency(), 2_592_000_000);
+
+        let cpuid = CpuId::with_cpuid_fn(cpuid_legacy);
+        assert!(!has_invariant_tsc(&cpuid));
+        assert_eq!(
+            TscClock::from_cpuid(&cpuid, Some(0x1c00)),
+            Err(TscClockError::NotInvariant)
+        );
+    }
+
+    #[test]
+    fn tsc_clock_conversion() {
+        let clock = TscClock::new(2_500_000_000);
+        assert_eq!((clock.mult(), clock.shift()), (6_710_886, 24));
+
+        // Within 1 ppm for one second
+        let ns = clock.cycles_to_ns(2_500_000_000);
+        assert!(ns.abs_diff(1_000_000_000) <= 1_000, "{}", ns);
+
+        // Fits in 64 bits for the maximum interval
+        let max_cycles = 600 * 2_500_000_000u64;
+        assert!(max_cycles.checked_mul(clock.mult() as u64).is_some());
+
+        // Conversions past the maximum interval don't overflow
+        let ns = clock.cycles_to_ns(u64::MAX);
+        assert!(ns.abs_diff(u64::MAX / 5 * 2) < u64::MAX / 1_000_000);
+
+        assert_eq!(clock.ns_to_cycles(1_000), 2_500);
+        // 2500 cycles across the wrap-around, rounded down
+        assert_eq!(clock.elapsed_ns(u64::MAX - 1249, 1250), 999);
+
+        for hz in [1_000_000, 19_200_000, 1_000_000_000, 5_000_000_000] {
+            let clock = TscClock::new(hz);
+            let ns = clock.cycles_to_ns(hz);
+            assert!(ns.abs_diff(1_000_000_000) <= 1_000, "{} Hz: {}", hz, ns);
+        }
+    }
+
     #[test]
     fn check_rdtsc() {
         let cpuid = crate::cpuid::CpuId::new();
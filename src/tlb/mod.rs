@@ -2,6 +2,8 @@
 
 use core::arch::asm;
 
+pub mod shootdown;
+
 /// Invalidate the given address in the TLB using the `invlpg` instruction.
 ///
 /// # Safety
@@ -21,6 +23,36 @@ pub unsafe fn flush_all() {
     cr3_write(cr3())
 }
 
+/// Invalidation type of the `invpcid` instruction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u64)]
+pub enum InvPcidType {
+    /// Invalidate the mapping of one linear address tagged with a PCID.
+    Address = 0,
+    /// Invalidate all mappings tagged with a PCID (except global ones).
+    SingleContext = 1,
+    /// Invalidate all mappings of all PCIDs, including global ones.
+    AllIncludingGlobal = 2,
+    /// Invalidate all mappings of all PCIDs, except global ones.
+    AllExcludingGlobal = 3,
+}
+
+/// Invalidate TLB entries by PCID using the `invpcid` instruction.
+///
+/// `pcid` and `addr` are ignored by the invalidation types that don't use them.
+///
+/// # Safety
+/// Needs CPL 0 and CPU support for INVPCID (CPUID.(EAX=07H,ECX=0):EBX[bit 10]).
+pub unsafe fn invpcid(kind: InvPcidType, pcid: u16, addr: u64) {
+    let descriptor: [u64; 2] = [pcid as u64, addr];
+    asm!(
+        "invpcid ({0}), {1}",
+        in(reg) &descriptor,
+        in(reg) kind as usize,
+        options(att_syntax, nostack, preserves_flags)
+    );
+}
+
 #[cfg(all(test, feature = "vmtest"))]
 mod x86testing {
     use super::*;
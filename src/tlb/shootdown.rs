@@ -0,0 +1,620 @@
+//! TLB shootdown: invalidating translations on other CPUs.
+//!
+//! `invlpg` and reloading CR3 only affect the local TLB. After changing page
+//! tables that other CPUs may have cached (see Intel SDM Vol. 3A Section
+//! 4.10.5, Propagation of Paging-Structure Changes to Multiple Processors), the
+//! initiating CPU posts a [`FlushRange`] to the [`Mailbox`] of every target,
+//! sends them an IPI and waits until all of them acknowledged.
+//!
+//! The IPI handler on the receiving side calls [`Shootdown::handle`], which
+//! applies the posted flushes through a [`TlbFlush`] implementation and
+//! acknowledges them.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, AtomicUsize, Ordering};
+
+use super::{flush, flush_all, invpcid, InvPcidType};
+use crate::apic::{
+    ApicControl, ApicId, DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr,
+    Level, TriggerMode,
+};
+
+/// Ranges larger than this many pages are invalidated with a full flush.
+pub const FULL_FLUSH_THRESHOLD: usize = 32;
+
+/// Size of a page invalidated by [`FlushRange::Pages`].
+const PAGE_SIZE: usize = 4096;
+
+/// Translations to invalidate.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlushRange {
+    /// All non-global translations of the current address space.
+    All,
+    /// `count` pages starting at `start` in the current address space.
+    Pages { start: usize, count: usize },
+    /// All non-global translations tagged with a PCID.
+    Pcid(u16),
+    /// `count` pages starting at `start` tagged with a PCID.
+    PcidPages {
+        pcid: u16,
+        start: usize,
+        count: usize,
+    },
+}
+
+impl FlushRange {
+    /// Invalidate the range on the local CPU.
+    ///
+    /// # Safety
+    /// Needs CPL 0. The PCID variants need CPU support for INVPCID.
+    pub unsafe fn flush(self) {
+        match self {
+            FlushRange::All => flush_all(),
+            FlushRange::Pages { count, .. } if count > FULL_FLUSH_THRESHOLD => flush_all(),
+            FlushRange::Pages { start, count } => {
+                for page in 0..count {
+                    flush(start.wrapping_add(page * PAGE_SIZE));
+                }
+            }
+            FlushRange::Pcid(pcid) => invpcid(InvPcidType::SingleContext, pcid, 0),
+            FlushRange::PcidPages { pcid, count, .. } if count > FULL_FLUSH_THRESHOLD => {
+                invpcid(InvPcidType::SingleContext, pcid, 0)
+            }
+            FlushRange::PcidPages { pcid, start, count } => {
+                for page in 0..count {
+                    let addr = start.wrapping_add(page * PAGE_SIZE);
+                    invpcid(InvPcidType::Address, pcid, addr as u64);
+                }
+            }
+        }
+    }
+}
+
+/// Applies flushes on the CPU that handles a shootdown.
+///
+/// [`LocalFlush`] invalidates the local TLB, other implementations can be
+/// used to test the protocol.
+pub trait TlbFlush {
+    /// Invalidate `range` on the calling CPU.
+    fn flush(&self, range: FlushRange);
+}
+
+/// Flushes the local TLB with [`FlushRange::flush`].
+#[derive(Debug)]
+pub struct LocalFlush(());
+
+impl LocalFlush {
+    /// Create a new local TLB flusher.
+    ///
+    /// # Safety
+    /// Needs CPL 0 on all CPUs that use it (and INVPCID support if PCID ranges
+    /// are flushed).
+    pub const unsafe fn new() -> LocalFlush {
+        LocalFlush(())
+    }
+}
+
+impl TlbFlush for LocalFlush {
+    fn flush(&self, range: FlushRange) {
+        unsafe { range.flush() }
+    }
+}
+
+const KIND_ALL: u8 = 0;
+const KIND_PAGES: u8 = 1;
+const KIND_PCID: u8 = 2;
+const KIND_PCID_PAGES: u8 = 3;
+
+/// Per-CPU shootdown request slot.
+///
+/// Every posted request bumps a generation counter, the receiving CPU
+/// acknowledges it by storing the generation it handled. If a request is
+/// posted before the previous one was acknowledged (e.g., after a timeout),
+/// the receiver does a full flush instead.
+#[derive(Debug)]
+pub struct Mailbox {
+    apic_id: ApicId,
+    posted: AtomicUsize,
+    done: AtomicUsize,
+    overflow: AtomicBool,
+    kind: AtomicU8,
+    pcid: AtomicU16,
+    start: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl Mailbox {
+    /// Create an empty mailbox for the CPU with local APIC ID `apic_id`.
+    pub const fn new(apic_id: ApicId) -> Mailbox {
+        Mailbox {
+            apic_id,
+            posted: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            overflow: AtomicBool::new(false),
+            kind: AtomicU8::new(KIND_ALL),
+            pcid: AtomicU16::new(0),
+            start: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// APIC ID of the CPU that owns this mailbox.
+    pub fn apic_id(&self) -> ApicId {
+        self.apic_id
+    }
+
+    /// Is a request waiting to be acknowledged?
+    pub fn is_pending(&self) -> bool {
+        self.done.load(Ordering::Acquire) != self.posted.load(Ordering::Acquire)
+    }
+
+    fn post(&self, range: FlushRange) {
+        if self.is_pending() {
+            // The receiver may be reading the fields, make it flush everything
+            self.overflow.store(true, Ordering::Release);
+        } else {
+            let (kind, pcid, start, count) = match range {
+                FlushRange::All => (KIND_ALL, 0, 0, 0),
+                FlushRange::Pages { start, count } => (KIND_PAGES, 0, start, count),
+                FlushRange::Pcid(pcid) => (KIND_PCID, pcid, 0, 0),
+                FlushRange::PcidPages { pcid, start, count } => {
+                    (KIND_PCID_PAGES, pcid, start, count)
+                }
+            };
+            self.kind.store(kind, Ordering::Relaxed);
+            self.pcid.store(pcid, Ordering::Relaxed);
+            self.start.store(start, Ordering::Relaxed);
+            self.count.store(count, Ordering::Relaxed);
+        }
+        self.posted.fetch_add(1, Ordering::Release);
+    }
+
+    fn take(&self) -> Option<(usize, FlushRange)> {
+        let generation = self.posted.load(Ordering::Acquire);
+        if self.done.load(Ordering::Relaxed) == generation {
+            return None;
+        }
+        if self.overflow.swap(false, Ordering::AcqRel) {
+            return Some((generation, FlushRange::All));
+        }
+
+        let pcid = self.pcid.load(Ordering::Relaxed);
+        let start = self.start.load(Ordering::Relaxed);
+        let count = self.count.load(Ordering::Relaxed);
+        let range = match self.kind.load(Ordering::Relaxed) {
+            KIND_PAGES => FlushRange::Pages { start, count },
+            KIND_PCID => FlushRange::Pcid(pcid),
+            KIND_PCID_PAGES => FlushRange::PcidPages { pcid, start, count },
+            _ => FlushRange::All,
+        };
+        Some((generation, range))
+    }
+
+    fn complete(&self, generation: usize) {
+        self.done.store(generation, Ordering::Release);
+    }
+}
+
+/// ICR encoding used by the local APIC.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IcrFormat {
+    XApic,
+    X2Apic,
+}
+
+impl IcrFormat {
+    fn icr(self, vector: u8, destination: ApicId, shorthand: DestinationShorthand) -> Icr {
+        let icr = match self {
+            IcrFormat::XApic => Icr::for_xapic,
+            IcrFormat::X2Apic => Icr::for_x2apic,
+        };
+        icr(
+            vector,
+            destination,
+            shorthand,
+            DeliveryMode::Fixed,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Assert,
+            TriggerMode::Edge,
+        )
+    }
+}
+
+/// CPUs to send a shootdown to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Targets<'a> {
+    /// Every other CPU, with a single broadcast IPI.
+    ///
+    /// All CPUs that receive the IPI must have a mailbox.
+    AllExcludingSelf,
+    /// The CPUs with the given mailbox indices (the initiator is skipped).
+    Cpus(&'a [usize]),
+}
+
+/// Errors of a shootdown.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShootdownError {
+    /// A CPU index is out of range of the mailboxes.
+    InvalidCpu(usize),
+    /// Not all targets acknowledged the request in time.
+    Timeout { acked: usize, expected: usize },
+}
+
+/// Coordinates TLB shootdowns between the CPUs owning `mailboxes`.
+///
+/// CPUs are identified by their index into the mailboxes.
+#[derive(Debug)]
+pub struct Shootdown<'a, T: TlbFlush = LocalFlush> {
+    mailboxes: &'a [Mailbox],
+    vector: u8,
+    format: IcrFormat,
+    flusher: T,
+    lock: AtomicBool,
+}
+
+impl<'a> Shootdown<'a, LocalFlush> {
+    /// Create a coordinator that sends shootdown IPIs with `vector` and
+    /// flushes the local TLB.
+    ///
+    /// # Safety
+    /// See [`LocalFlush::new`].
+    pub unsafe fn new(mailboxes: &'a [Mailbox], vector: u8, format: IcrFormat) -> Self {
+        Shootdown::with_flusher(mailboxes, vector, format, LocalFlush::new())
+    }
+}
+
+impl<'a, T: TlbFlush> Shootdown<'a, T> {
+    /// Create a coordinator that applies flushes with `flusher`.
+    pub fn with_flusher(
+        mailboxes: &'a [Mailbox],
+        vector: u8,
+        format: IcrFormat,
+        flusher: T,
+    ) -> Self {
+        Shootdown {
+            mailboxes,
+            vector,
+            format,
+            flusher,
+            lock: AtomicBool::new(false),
+        }
+    }
+
+    /// The mailboxes of all CPUs.
+    pub fn mailboxes(&self) -> &'a [Mailbox] {
+        self.mailboxes
+    }
+
+    /// The IPI vector; its handler has to call [`Shootdown::handle`].
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// Apply and acknowledge the requests posted to `cpu`.
+    ///
+    /// Called by the IPI handler of `cpu` (which still has to send an EOI).
+    /// Returns the number of handled requests.
+    pub fn handle(&self, cpu: usize) -> usize {
+        let mailbox = match self.mailboxes.get(cpu) {
+            Some(mailbox) => mailbox,
+            None => return 0,
+        };
+
+        let mut handled = 0;
+        while let Some((generation, range)) = mailbox.take() {
+            self.flusher.flush(range);
+            mailbox.complete(generation);
+            handled += 1;
+        }
+        handled
+    }
+
+    /// Invalidate `range` on `targets` and wait until they acknowledged.
+    ///
+    /// `cpu` is the index of the calling CPU, which is not flushed (see
+    /// [`Shootdown::flush`]). `expired` is polled while waiting and aborts
+    /// the shootdown with [`ShootdownError::Timeout`] when it returns `true`;
+    /// targets that did not acknowledge will still flush once they handle
+    /// their mailbox.
+    ///
+    /// # Safety
+    /// Sends IPIs with `apic`, all targets must handle the vector.
+    pub unsafe fn flush_others<A, E>(
+        &self,
+        apic: &mut A,
+        cpu: usize,
+        targets: Targets,
+        range: FlushRange,
+        mut expired: E,
+    ) -> Result<(), ShootdownError>
+    where
+        A: ApicControl + ?Sized,
+        E: FnMut() -> bool,
+    {
+        if cpu >= self.mailboxes.len() {
+            return Err(ShootdownError::InvalidCpu(cpu));
+        }
+        if let Targets::Cpus(cpus) = targets {
+            if let Some(invalid) = cpus.iter().find(|c| **c >= self.mailboxes.len()) {
+                return Err(ShootdownError::InvalidCpu(*invalid));
+            }
+        }
+
+        // One shootdown at a time; keep serving ours to avoid deadlocking
+        // with the current initiator.
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.handle(cpu);
+            spin_loop();
+        }
+
+        let mut expected = 0;
+        self.for_each_target(cpu, targets, |mailbox| {
+            mailbox.post(range);
+            expected += 1;
+        });
+
+        match targets {
+            Targets::AllExcludingSelf => apic.send_ipi(self.format.icr(
+                self.vector,
+                ApicId::XApic(0),
+                DestinationShorthand::AllExcludingSelf,
+            )),
+            Targets::Cpus(_) => self.for_each_target(cpu, targets, |mailbox| {
+                apic.send_ipi(self.format.icr(
+                    self.vector,
+                    mailbox.apic_id(),
+                    DestinationShorthand::NoShorthand,
+                ))
+            }),
+        }
+
+        let result = loop {
+            let mut acked = 0;
+            self.for_each_target(cpu, targets, |mailbox| {
+                if !mailbox.is_pending() {
+                    acked += 1;
+                }
+            });
+            if acked == expected {
+                break Ok(());
+            }
+            if expired() {
+                break Err(ShootdownError::Timeout { acked, expected });
+            }
+            spin_loop();
+        };
+
+        self.lock.store(false, Ordering::Release);
+        result
+    }
+
+    /// Invalidate `range` on the calling CPU and on `targets`.
+    ///
+    /// # Safety
+    /// See [`Shootdown::flush_others`].
+    pub unsafe fn flush<A, E>(
+        &self,
+        apic: &mut A,
+        cpu: usize,
+        targets: Targets,
+        range: FlushRange,
+        expired: E,
+    ) -> Result<(), ShootdownError>
+    where
+        A: ApicControl + ?Sized,
+        E: FnMut() -> bool,
+    {
+        self.flusher.flush(range);
+        self.flush_others(apic, cpu, targets, range, expired)
+    }
+
+    fn for_each_target<F: FnMut(&Mailbox)>(&self, cpu: usize, targets: Targets, mut f: F) {
+        match targets {
+            Targets::AllExcludingSelf => {
+                for (i, mailbox) in self.mailboxes.iter().enumerate() {
+                    if i != cpu {
+                        f(mailbox);
+                    }
+                }
+            }
+            Targets::Cpus(cpus) => {
+                for i in cpus.iter().filter(|i| **i != cpu) {
+                    f(&self.mailboxes[*i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "utest"))]
+mod test {
+    use super::*;
+    use crate::apic::mock::MockApic;
+
+    extern crate std;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        flushes: Mutex<Vec<FlushRange>>,
+    }
+
+    impl TlbFlush for Recorder {
+        fn flush(&self, range: FlushRange) {
+            self.flushes.lock().unwrap().push(range);
+        }
+    }
+
+    fn mailboxes() -> [Mailbox; 4] {
+        [
+            Mailbox::new(ApicId::XApic(0)),
+            Mailbox::new(ApicId::XApic(2)),
+            Mailbox::new(ApicId::XApic(4)),
+            Mailbox::new(ApicId::XApic(6)),
+        ]
+    }
+
+    #[test]
+    fn mailbox_ranges() {
+        let mailboxes = mailboxes();
+        let shootdown =
+            Shootdown::with_flusher(&mailboxes, 0xf0, IcrFormat::XApic, Recorder::default());
+
+        let ranges = [
+            FlushRange::All,
+            FlushRange::Pages {
+                start: 0x7000_0000,
+                count: 3,
+            },
+            FlushRange::Pcid(12),
+            FlushRange::PcidPages {
+                pcid: 7,
+                start: 0x1000,
+                count: 1,
+            },
+        ];
+        for range in ranges.iter() {
+            assert_eq!(shootdown.handle(1), 0);
+            mailboxes[1].post(*range);
+            assert!(mailboxes[1].is_pending());
+            assert_eq!(shootdown.handle(1), 1);
+            assert!(!mailboxes[1].is_pending());
+        }
+        assert_eq!(*shootdown.flusher.flushes.lock().unwrap(), ranges);
+        assert_eq!(shootdown.handle(4), 0);
+    }
+
+    #[test]
+    fn mailbox_overflow() {
+        let mailboxes = mailboxes();
+        let shootdown =
+            Shootdown::with_flusher(&mailboxes, 0xf0, IcrFormat::XApic, Recorder::default());
+
+        mailboxes[2].post(FlushRange::Pcid(1));
+        mailboxes[2].post(FlushRange::Pcid(2));
+        assert_eq!(shootdown.handle(2), 1);
+        assert_eq!(
+            *shootdown.flusher.flushes.lock().unwrap(),
+            [FlushRange::All]
+        );
+
+        mailboxes[2].post(FlushRange::Pcid(3));
+        assert_eq!(shootdown.handle(2), 1);
+        assert_eq!(
+            shootdown.flusher.flushes.lock().unwrap()[1],
+            FlushRange::Pcid(3)
+        );
+    }
+
+    #[test]
+    fn broadcast_with_threads() {
+        let mailboxes = mailboxes();
+        let shootdown =
+            Shootdown::with_flusher(&mailboxes, 0xf0, IcrFormat::XApic, Recorder::default());
+        let stop = AtomicBool::new(false);
+        let range = FlushRange::Pages {
+            start: 0x40_0000,
+            count: 2,
+        };
+
+        let mut apic = MockApic::default();
+        thread::scope(|s| {
+            for cpu in 1..4 {
+                let (shootdown, stop) = (&shootdown, &stop);
+                s.spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        shootdown.handle(cpu);
+                        thread::yield_now();
+                    }
+                });
+            }
+
+            for _ in 0..10 {
+                let result = unsafe {
+                    shootdown.flush(&mut apic, 0, Targets::AllExcludingSelf, range, || false)
+                };
+                assert_eq!(result, Ok(()));
+            }
+            stop.store(true, Ordering::Release);
+        });
+
+        // One broadcast (shorthand all excluding self, fixed) per shootdown
+        assert_eq!(apic.sent.len(), 10);
+        for icr in apic.sent.iter() {
+            assert_eq!(icr.lower(), 0b11 << 18 | 1 << 14 | 0xf0);
+        }
+        let flushes = shootdown.flusher.flushes.lock().unwrap();
+        assert_eq!(flushes.len(), 40);
+        assert!(flushes.iter().all(|r| *r == range));
+    }
+
+    #[test]
+    fn targeted_timeout() {
+        let mailboxes = mailboxes();
+        let shootdown =
+            Shootdown::with_flusher(&mailboxes, 0x40, IcrFormat::X2Apic, Recorder::default());
+        let mut apic = MockApic::default();
+
+        let mut polls = 0;
+        let result = unsafe {
+            shootdown.flush_others(
+                &mut apic,
+                1,
+                Targets::Cpus(&[1, 2, 3]),
+                FlushRange::Pcid(5),
+                || {
+                    polls += 1;
+                    polls > 100
+                },
+            )
+        };
+        assert_eq!(
+            result,
+            Err(ShootdownError::Timeout {
+                acked: 0,
+                expected: 2
+            })
+        );
+        assert_eq!(apic.sent.len(), 2);
+        assert_eq!(apic.sent[0].upper(), 4);
+        assert_eq!(apic.sent[1].upper(), 6);
+        assert_eq!(apic.sent[0].lower(), 1 << 14 | 0x40);
+
+        // Late acknowledgement and a new round for one of them
+        assert_eq!(shootdown.handle(2), 1);
+        let result = unsafe {
+            shootdown.flush_others(&mut apic, 1, Targets::Cpus(&[2]), FlushRange::All, || {
+                shootdown.handle(2);
+                false
+            })
+        };
+        assert_eq!(result, Ok(()));
+        assert!(mailboxes[3].is_pending());
+
+        assert_eq!(
+            unsafe {
+                shootdown.flush_others(&mut apic, 0, Targets::Cpus(&[4]), FlushRange::All, || true)
+            },
+            Err(ShootdownError::InvalidCpu(4))
+        );
+        assert_eq!(
+            unsafe {
+                shootdown.flush_others(
+                    &mut apic,
+                    9,
+                    Targets::AllExcludingSelf,
+                    FlushRange::All,
+                    || true,
+                )
+            },
+            Err(ShootdownError::InvalidCpu(9))
+        );
+    }
+}
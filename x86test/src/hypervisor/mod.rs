@@ -250,52 +250,98 @@ pub(crate) enum IoHandleStatus {
     TestPanic(u8),
 }
 
+/// Find the `kvm_run` structure shared with the vCPU.
+///
+/// The data of an I/O exit is at `data_offset` in this mapping: `count`
+/// values of `size` bytes (more than one for `rep ins`/`rep outs`). kvm-sys
+/// only returns a copy of `kvm_run`, so look the mapping
+/// (`anon_inode:kvm-vcpu:<id>`) up in our address space instead.
+fn kvm_run_mapping() -> *mut u8 {
+    let f = File::open("/proc/self/maps").unwrap();
+    let reader = BufReader::new(f);
+
+    let mut mapping = None;
+    for line in reader.lines() {
+        let line = line.unwrap();
+        if line.contains("anon_inode:kvm-vcpu") {
+            let begin = line.split('-').next().unwrap();
+            mapping = Some(usize::from_str_radix(begin, 16).unwrap() as *mut u8);
+        }
+    }
+    mapping.expect("Can't find the kvm_run mapping of the vCPU")
+}
+
+fn handle_in(meta: &X86TestFn, port: u16) -> Result<u32, IoHandleError> {
+    if port == 0x3fd || port == 0x2fd {
+        // Mark serial line ready to write
+        Ok(0x20)
+    } else if port == meta.ioport_enable.0 {
+        Ok(meta.ioport_enable.1)
+    } else {
+        Err(IoHandleError::UnexpectedRead(port))
+    }
+}
+
+fn handle_out(
+    meta: &X86TestFn,
+    port: u16,
+    value: u32,
+    printer: &mut SerialPrinter,
+) -> Result<IoHandleStatus, IoHandleError> {
+    if port == 0x3f8 {
+        printer.write(&[value as u8]).ok();
+        return Ok(IoHandleStatus::Handled);
+    } else if port == 0x2f8 {
+        // ignore the other serial port that klogger outputs to by default
+        return Ok(IoHandleStatus::Handled);
+    } else if port == 0xf4 && value as u8 == 0x0 {
+        // Magic shutdown command for exiting the test.
+        // The line unsafe { x86::shared::io::outw(0xf4, 0x00); }
+        // is automatically inserted at the end of every test!
+        return Ok(IoHandleStatus::TestSuccessful);
+    } else if port == meta.ioport_enable.0 && value == meta.ioport_enable.1 {
+        return Ok(IoHandleStatus::Handled);
+    } else if port == 0xf4 {
+        return Ok(IoHandleStatus::TestPanic(value as u8));
+    }
+
+    Err(IoHandleError::UnexpectedWrite(port, value))
+}
+
 pub(crate) fn handle_ioexit(
     meta: &X86TestFn,
-    cpu: &mut Vcpu,
     run: &kvm::Run,
     printer: &mut SerialPrinter,
 ) -> Result<IoHandleStatus, IoHandleError> {
     let io = unsafe { *run.io() };
     //println!("io = {:?}", io);
+    let size = io.size as usize;
+    let data = unsafe { kvm_run_mapping().add(io.data_offset as usize) };
+
     match io.direction {
         IoDirection::In => {
-            let mut regs = cpu.get_regs().unwrap();
-            if io.port == 0x3fd {
-                regs.rax = 0x20; // Mark serial line ready to write
-                cpu.set_regs(&regs).unwrap();
-                return Ok(IoHandleStatus::Handled);
-            } else if io.port == 0x2fd {
-                regs.rax = 0x20; // Mark serial line ready to write
-                cpu.set_regs(&regs).unwrap();
-                return Ok(IoHandleStatus::Handled);
-            } else if io.port == meta.ioport_enable.0 {
-                regs.rax = meta.ioport_enable.1 as u64;
-                cpu.set_regs(&regs).unwrap();
-                return Ok(IoHandleStatus::Handled);
+            // KVM moves the values to eax or, for `rep ins`, to the buffer
+            // at rdi when the vCPU runs again.
+            for i in 0..io.count as usize {
+                let value = handle_in(meta, io.port)?.to_le_bytes();
+                unsafe {
+                    std::ptr::copy_nonoverlapping(value.as_ptr(), data.add(i * size), size);
+                }
             }
-            return Err(IoHandleError::UnexpectedRead(io.port));
+            Ok(IoHandleStatus::Handled)
         }
         IoDirection::Out => {
-            let regs = cpu.get_regs().unwrap();
-            if io.port == 0x3f8 {
-                printer.write(&[regs.rax as u8]).ok();
-                return Ok(IoHandleStatus::Handled);
-            } else if io.port == 0x2f8 {
-                // ignore the other serial port that klogger outputs to by default
-                return Ok(IoHandleStatus::Handled);
-            } else if io.port == 0xf4 && regs.rax as u8 == 0x0 {
-                // Magic shutdown command for exiting the test.
-                // The line unsafe { x86::shared::io::outw(0xf4, 0x00); }
-                // is automatically inserted at the end of every test!
-                return Ok(IoHandleStatus::TestSuccessful);
-            } else if io.port == meta.ioport_enable.0 && regs.rax == meta.ioport_enable.1 as u64 {
-                return Ok(IoHandleStatus::Handled);
-            } else if io.port == 0xf4 {
-                return Ok(IoHandleStatus::TestPanic(regs.rax as u8));
+            for i in 0..io.count as usize {
+                let mut value = [0u8; 4];
+                unsafe {
+                    std::ptr::copy_nonoverlapping(data.add(i * size), value.as_mut_ptr(), size);
+                }
+                match handle_out(meta, io.port, u32::from_le_bytes(value), printer)? {
+                    IoHandleStatus::Handled => {}
+                    status => return Ok(status),
+                }
             }
-
-            return Err(IoHandleError::UnexpectedWrite(io.port, regs.rax as u32));
+            Ok(IoHandleStatus::Handled)
         }
-    };
+    }
 }
@@ -79,7 +79,7 @@ pub fn runner(tests: &[&X86TestFn]) {
                 let run = unsafe { vcpu.run() }.unwrap();
                 match run.exit_reason {
                     Exit::Io => {
-                        match handle_ioexit(test, &mut vcpu, &run, &mut printer) {
+                        match handle_ioexit(test, &run, &mut printer) {
                             Result::Ok(IoHandleStatus::Handled) => { /* Continue */ }
                             Result::Ok(IoHandleStatus::TestSuccessful) => vm_is_done = true,
                             Result::Ok(IoHandleStatus::TestPanic(code)) => {